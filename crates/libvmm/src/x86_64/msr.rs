@@ -21,6 +21,7 @@ use x86::msr::{rdmsr, wrmsr};
 #[derive(Debug, Copy, Clone)]
 #[allow(non_camel_case_types)]
 pub enum Msr {
+    IA32_APIC_BASE = 0x1b,
     IA32_FEATURE_CONTROL = 0x3a,
 
     IA32_SYSENTER_CS = 0x174,
@@ -48,6 +49,8 @@ pub enum Msr {
     IA32_VMX_TRUE_EXIT_CTLS = 0x48f,
     IA32_VMX_TRUE_ENTRY_CTLS = 0x490,
 
+    IA32_X2APIC_ICR = 0x830,
+
     IA32_EFER = 0xc000_0080,
     IA32_STAR = 0xc000_0081,
     IA32_LSTAR = 0xc000_0082,
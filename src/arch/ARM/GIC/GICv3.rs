@@ -0,0 +1,28 @@
+//! GICv3 CPU interface, only sending SGIs for now.
+
+use aarch64_cpu::registers::MPIDR_EL1;
+use tock_registers::interfaces::Readable;
+
+use crate::interrupt::InterruptController;
+
+/// SGIs use interrupt ids 0-15.
+const SGI_INTID_MASK: u64 = 0xf;
+
+/// CPU ids are the `Aff0` field of `MPIDR_EL1`, CPUs are assumed to be in a single cluster
+/// (`Aff1..Aff3` = 0) with at most 16 CPUs.
+pub struct GicV3;
+
+impl InterruptController for GicV3 {
+    fn send_ipi(&self, cpu_id: usize, vector: u8) {
+        // ICC_SGI1R_EL1: INTID [27:24], TargetList [15:0] (bitmap of Aff0 values).
+        let sgi = ((vector as u64 & SGI_INTID_MASK) << 24) | (1 << (cpu_id & 0xf));
+        unsafe {
+            asm!("msr icc_sgi1r_el1, {}", in(reg) sgi);
+            asm!("isb");
+        }
+    }
+
+    fn current_cpu_id(&self) -> usize {
+        (MPIDR_EL1.get() & 0xff) as usize
+    }
+}
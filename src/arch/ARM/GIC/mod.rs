@@ -0,0 +1,4 @@
+#[allow(non_snake_case)]
+mod GICv3;
+
+pub use self::GICv3::GicV3;
@@ -0,0 +1,205 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decoding of the guest access behind a data abort, for MMIO emulation.
+//!
+//! The syndrome in `ESR_EL2` describes the access when `ISS.ISV` is set. It's clear for the
+//! instructions with a base register writeback, pairs, SIMD and atomics, for those the
+//! instruction at `ELR_EL2` is decoded instead, only the single general-purpose register loads
+//! and stores are supported.
+
+use crate::error::HvResult;
+
+/// ARM exception classes of the data aborts, from a lower or the current EL.
+const ESR_EC_SHIFT: u64 = 26;
+const ESR_EC_DABT_LOWER: u64 = 0x24;
+const ESR_EC_DABT_CUR: u64 = 0x25;
+/// Data abort ISS fields: Instruction Syndrome Valid, Syndrome Access Size, Syndrome Sign
+/// Extend, Syndrome Register Transfer, Sixty-Four bit register and Write not Read.
+const ESR_ISS_ISV: u64 = 1 << 24;
+const ESR_ISS_SAS_SHIFT: u64 = 22;
+const ESR_ISS_SSE: u64 = 1 << 21;
+const ESR_ISS_SRT_SHIFT: u64 = 16;
+const ESR_ISS_SF: u64 = 1 << 15;
+const ESR_ISS_WNR: u64 = 1 << 6;
+
+/// The access made by the faulting instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessInfo {
+    /// The access size in bytes: 1, 2, 4 or 8.
+    pub size: usize,
+    /// Whether it's a store.
+    pub write: bool,
+    /// The transfer register, 31 stands for XZR/WZR.
+    pub reg: usize,
+    /// Whether a load sign-extends the value.
+    pub sign_extend: bool,
+    /// Whether the transfer register is 64-bit (Xt), 32-bit (Wt) otherwise.
+    pub reg_64bit: bool,
+    /// The base register and the offset added to it after the access, for the pre-indexed and
+    /// post-indexed instructions. Only reported when decoding the instruction.
+    pub writeback: Option<(usize, i64)>,
+}
+
+/// Decode the access of a data abort from `ESR_EL2`, falling back to the instruction at `elr`
+/// if the syndrome isn't valid.
+///
+/// # Safety
+///
+/// `elr` is dereferenced in the fallback, it must then be a valid hypervisor address of the
+/// faulting instruction. That's not the case for an abort from a lower EL, where `elr` is a
+/// guest virtual address: translate it first, e.g. with the `gaccess` helpers.
+pub unsafe fn decode_faulting_access(elr: u64, esr: u64) -> HvResult<AccessInfo> {
+    decode_access(esr, || (elr as *const u32).read_volatile())
+}
+
+/// Like `decode_faulting_access()`, with `fetch` returning the faulting instruction.
+fn decode_access(esr: u64, fetch: impl FnOnce() -> u32) -> HvResult<AccessInfo> {
+    let ec = (esr >> ESR_EC_SHIFT) & 0x3f;
+    if ec != ESR_EC_DABT_LOWER && ec != ESR_EC_DABT_CUR {
+        return hv_result_err!(EINVAL, format!("Not a data abort: ESR {:#x}", esr));
+    }
+    if esr & ESR_ISS_ISV != 0 {
+        return Ok(decode_iss(esr));
+    }
+    let insn = fetch();
+    decode_insn(insn).ok_or_else(|| {
+        hv_err!(
+            ENOSYS,
+            format!("Unsupported faulting instruction {:#010x}", insn)
+        )
+    })
+}
+
+const fn decode_iss(esr: u64) -> AccessInfo {
+    AccessInfo {
+        size: 1 << ((esr >> ESR_ISS_SAS_SHIFT) & 0b11),
+        write: esr & ESR_ISS_WNR != 0,
+        reg: ((esr >> ESR_ISS_SRT_SHIFT) & 0x1f) as usize,
+        sign_extend: esr & ESR_ISS_SSE != 0,
+        reg_64bit: esr & ESR_ISS_SF != 0,
+        writeback: None,
+    }
+}
+
+/// Decode a load or store of a single general-purpose register, with an unsigned immediate,
+/// a register, an unscaled, an unprivileged, a pre-indexed or a post-indexed offset.
+fn decode_insn(insn: u32) -> Option<AccessInfo> {
+    // size:2 | 111 | V | op:2 | opc:2 | ...
+    if (insn >> 27) & 0b111 != 0b111 || insn & (1 << 26) != 0 {
+        return None;
+    }
+    let (rt, rn) = ((insn & 0x1f) as usize, ((insn >> 5) & 0x1f) as usize);
+    let imm9 = (((insn >> 12) & 0x1ff) as i64) << 55 >> 55;
+    let writeback = match (insn >> 24) & 0b11 {
+        // Unsigned immediate.
+        0b01 => None,
+        0b00 if insn & (1 << 21) == 0 => match (insn >> 10) & 0b11 {
+            // Unscaled and unprivileged.
+            0b00 | 0b10 => None,
+            // Post-indexed and pre-indexed, the offset is added to the base either way.
+            _ => Some((rn, imm9)),
+        },
+        // Register offset, the other encodings with bit 21 set are atomics.
+        0b00 if (insn >> 10) & 0b11 == 0b10 => None,
+        _ => return None,
+    };
+    let size = (insn >> 30) & 0b11;
+    let (write, sign_extend, reg_64bit) = match (insn >> 22) & 0b11 {
+        0b00 => (true, false, size == 3),
+        0b01 => (false, false, size == 3),
+        // LDRSB/LDRSH/LDRSW to Xt, PRFM for size 3.
+        0b10 if size < 3 => (false, true, true),
+        // LDRSB/LDRSH to Wt.
+        0b11 if size < 2 => (false, true, false),
+        _ => return None,
+    };
+    Some(AccessInfo {
+        size: 1 << size,
+        write,
+        reg: rt,
+        sign_extend,
+        reg_64bit,
+        writeback,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A data abort from a lower EL with a valid syndrome.
+    fn esr(sas: u64, srt: u64, flags: u64) -> u64 {
+        (ESR_EC_DABT_LOWER << ESR_EC_SHIFT)
+            | (1 << 25)
+            | ESR_ISS_ISV
+            | (sas << ESR_ISS_SAS_SHIFT)
+            | (srt << ESR_ISS_SRT_SHIFT)
+            | flags
+    }
+
+    fn no_fetch() -> u32 {
+        panic!("the instruction must not be fetched with a valid syndrome")
+    }
+
+    #[test]
+    fn test_decode_iss() {
+        // ldr w1, [...]
+        let info = decode_access(esr(2, 1, 0), no_fetch).unwrap();
+        assert_eq!((info.size, info.write, info.reg), (4, false, 1));
+        assert!(!info.sign_extend && !info.reg_64bit);
+        // strb w3, [...]
+        let info = decode_access(esr(0, 3, ESR_ISS_WNR), no_fetch).unwrap();
+        assert_eq!((info.size, info.write, info.reg), (1, true, 3));
+        // ldrsh x5, [...]
+        let info = decode_access(esr(1, 5, ESR_ISS_SSE | ESR_ISS_SF), no_fetch).unwrap();
+        assert_eq!((info.size, info.write, info.reg), (2, false, 5));
+        assert!(info.sign_extend && info.reg_64bit);
+        // str xzr, [...] from the current EL.
+        let cur = (esr(3, 31, ESR_ISS_WNR | ESR_ISS_SF) & !(0x3f << ESR_EC_SHIFT))
+            | (ESR_EC_DABT_CUR << ESR_EC_SHIFT);
+        let info = decode_access(cur, no_fetch).unwrap();
+        assert_eq!((info.size, info.write, info.reg), (8, true, 31));
+        assert_eq!(info.writeback, None);
+        // An instruction abort.
+        assert!(decode_access(0x20 << ESR_EC_SHIFT, no_fetch).is_err());
+    }
+
+    #[test]
+    fn test_decode_insn() {
+        let esr = (ESR_EC_DABT_LOWER << ESR_EC_SHIFT) | (1 << 25);
+        let decode = |insn| decode_access(esr, || insn);
+        // ldr w0, [x1]
+        let info = decode(0xb940_0020).unwrap();
+        assert_eq!((info.size, info.write, info.reg), (4, false, 0));
+        assert!(!info.reg_64bit && info.writeback.is_none());
+        // str x2, [x1, #16]!
+        let info = decode(0xf801_0c22).unwrap();
+        assert_eq!((info.size, info.write, info.reg), (8, true, 2));
+        assert!(info.reg_64bit);
+        assert_eq!(info.writeback, Some((1, 16)));
+        // ldrsb w3, [x4], #-1
+        let info = decode(0x38df_f483).unwrap();
+        assert_eq!((info.size, info.write, info.reg), (1, false, 3));
+        assert!(info.sign_extend && !info.reg_64bit);
+        assert_eq!(info.writeback, Some((4, -1)));
+        // ldrh w5, [x6, x7]
+        let info = decode(0x7867_68c5).unwrap();
+        assert_eq!((info.size, info.write, info.reg), (2, false, 5));
+        // ldp x0, x1, [x2], prfm pldl1keep, [x0] and ldr q0, [x0] are not supported.
+        assert!(decode(0xa940_0440).is_err());
+        assert!(decode(0xf980_0000).is_err());
+        assert!(decode(0x3dc0_0000).is_err());
+    }
+}
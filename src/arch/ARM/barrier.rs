@@ -0,0 +1,40 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory barriers, all of them apply to the full system.
+
+/// Data synchronization barrier: no instruction after it executes until all memory accesses,
+/// cache and TLB maintenance before it have completed.
+#[inline(always)]
+pub fn dsb() {
+    unsafe { asm!("dsb sy") };
+}
+
+/// Instruction synchronization barrier: flushes the pipeline, so that instructions after it
+/// observe the effects of context-changing operations (e.g. system register writes) before it.
+#[inline(always)]
+pub fn isb() {
+    unsafe { asm!("isb") };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_barriers() {
+        dsb();
+        isb();
+    }
+}
@@ -1,10 +1,14 @@
 #![allow(unused_macros)]
-use core::arch::asm;
 use aarch64_cpu::asm::barrier;
 use aarch64_cpu::registers::ESR_EL2::EC::Value;
 use aarch64_cpu::registers::*;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
+use super::el::ExceptionLevel;
+use super::s1pt::flush_tlb_all;
+use super::tables::VectorTable;
+use crate::error::HvResult;
+
 const SAVED_LINUX_REGS: usize = 31;
 
 #[repr(C)]
@@ -76,6 +80,8 @@ pub struct LinuxContext {
     pub elr: u64,
     pub sctlr: u64,
     pub sp: u64,
+    /// Linux's exception vector base, replaced by the hypervisor's one while it's enabled.
+    pub vbar: u64,
 }
 
 #[allow(unused_unsafe)]
@@ -92,30 +98,66 @@ impl LinuxContext {
             elr: 0,
             sctlr: 0,
             sp: 0,
+            vbar: 0,
         }
     }
-    pub fn load_from(linux_sp: usize) -> Self {
+    /// Save the Linux context, then install the hypervisor vectors in place of Linux's ones at
+    /// EL2, like the hypervisor IDT on x86. It never fails on ARM but matches the x86 signature.
+    pub fn load_from(linux_sp: usize) -> HvResult<Self> {
         let regs = unsafe { core::slice::from_raw_parts(linux_sp as *const u64, SAVED_LINUX_REGS) };
-        let mut ret = Self {
-            usr: [0; 31],
-            spsr: SPSR_EL2.get(),
-            elr: ELR_EL2.get(),
-            sctlr: SCTLR_EL2.get(),
-            sp: SP.get(),
+        let mut ret = match Self::el() {
+            ExceptionLevel::EL2 => Self {
+                usr: [0; 31],
+                spsr: SPSR_EL2.get(),
+                elr: ELR_EL2.get(),
+                sctlr: SCTLR_EL2.get(),
+                sp: SP.get(),
+                vbar: VectorTable::vbar() as _,
+            },
+            ExceptionLevel::EL1 => Self {
+                usr: [0; 31],
+                spsr: SPSR_EL1.get(),
+                elr: ELR_EL1.get(),
+                sctlr: SCTLR_EL1.get(),
+                sp: SP.get(),
+                vbar: VBAR_EL1.get(),
+            },
         };
+        if Self::el() == ExceptionLevel::EL2 {
+            VectorTable::new().load();
+        }
         for i in 0..31 {
             ret.usr[i] = regs[i];
         }
-        ret
+        Ok(ret)
     }
 
-    /// Restore system registers.
+    /// Restore system registers of the EL the hypervisor runs at.
     pub fn restore(&self) {
-        unsafe {
-            SPSR_EL2.set(self.spsr);
-            ELR_EL2.set(self.elr);
-            SCTLR_EL2.set(self.sctlr);
-            SP.set(self.sp);
-        }        
+        match Self::el() {
+            ExceptionLevel::EL2 => {
+                unsafe {
+                    SPSR_EL2.set(self.spsr);
+                    ELR_EL2.set(self.elr);
+                    SCTLR_EL2.set(self.sctlr);
+                    SP.set(self.sp);
+                }
+                VectorTable::set_vbar(self.vbar as _);
+            }
+            ExceptionLevel::EL1 => {
+                SPSR_EL1.set(self.spsr);
+                ELR_EL1.set(self.elr);
+                SCTLR_EL1.set(self.sctlr);
+                SP.set(self.sp);
+                VBAR_EL1.set(self.vbar);
+                barrier::isb(barrier::SY);
+            }
+        }
+        // Drop the translations cached while the hypervisor was running.
+        flush_tlb_all();
     }
-}
\ No newline at end of file
+
+    fn el() -> ExceptionLevel {
+        ExceptionLevel::current().expect("Unsupported exception level for the Linux context")
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CPU identification and time source.
+
+use aarch64_cpu::registers::{CNTFRQ_EL0, CNTPCT_EL0, MPIDR_EL1};
+use tock_registers::interfaces::Readable;
+
+use super::barrier::isb;
+
+/// The id of the current CPU: the `Aff0` field of `MPIDR_EL1`, CPUs are assumed to be in a single
+/// cluster (same as [`super::GIC::GicV3`]).
+pub fn id() -> usize {
+    (MPIDR_EL1.get() & 0xff) as usize
+}
+
+/// Read the physical count of the generic timer, the counterpart of the x86 TSC.
+pub fn time_now() -> u64 {
+    // Reads of CNTPCT_EL0 can be performed out of order, the ISB keeps the read after the
+    // preceding instructions.
+    isb();
+    CNTPCT_EL0.get()
+}
+
+/// The frequency of the generic timer, programmed by the firmware in `CNTFRQ_EL0`, `None` if
+/// it's unknown. Named after the x86 `tsc_hz()`.
+pub fn tsc_hz() -> Option<u64> {
+    match CNTFRQ_EL0.get() & 0xffff_ffff {
+        0 => None,
+        hz => Some(hz),
+    }
+}
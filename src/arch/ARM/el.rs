@@ -0,0 +1,78 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The exception level the hypervisor runs at.
+//!
+//! The hypervisor is expected to boot at EL2, but it also runs at EL1 (without stage-2
+//! translation) when the firmware drops to EL1 before starting Linux. The EL1 and EL2 system
+//! registers are not interchangeable, accessing the EL2 ones from EL1 is UNDEFINED.
+
+use aarch64_cpu::registers::CurrentEL;
+use tock_registers::interfaces::Readable;
+
+use crate::error::HvResult;
+
+/// `CurrentEL.EL`, bits [3:2].
+const CURRENT_EL_SHIFT: u64 = 2;
+const CURRENT_EL_MASK: u64 = 0b11;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExceptionLevel {
+    EL1,
+    EL2,
+}
+
+impl ExceptionLevel {
+    /// The exception level the hypervisor is running at, fails at EL0 and EL3.
+    pub fn current() -> HvResult<Self> {
+        Self::from_el(current_el())
+    }
+
+    fn from_el(el: u8) -> HvResult<Self> {
+        match el {
+            1 => Ok(Self::EL1),
+            2 => Ok(Self::EL2),
+            _ => hv_result_err!(ENODEV, format!("Hypervisor running at unexpected EL{}", el)),
+        }
+    }
+}
+
+/// Returns the exception level the CPU is executing at, from 0 to 3.
+pub fn current_el() -> u8 {
+    decode_current_el(CurrentEL.get())
+}
+
+const fn decode_current_el(raw: u64) -> u8 {
+    ((raw >> CURRENT_EL_SHIFT) & CURRENT_EL_MASK) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_current_el() {
+        assert_eq!(decode_current_el(0b0000), 0);
+        assert_eq!(decode_current_el(0b0100), 1);
+        assert_eq!(decode_current_el(0b1000), 2);
+        assert_eq!(decode_current_el(0b1100), 3);
+        // RES0 bits are ignored.
+        assert_eq!(decode_current_el(!0b1100 | 0b1000), 2);
+
+        assert_eq!(ExceptionLevel::from_el(1).unwrap(), ExceptionLevel::EL1);
+        assert_eq!(ExceptionLevel::from_el(2).unwrap(), ExceptionLevel::EL2);
+        assert!(ExceptionLevel::from_el(0).is_err());
+        assert!(ExceptionLevel::from_el(3).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The EL2 exception vectors, `exception_vectors` is the table returned by `VectorTable::new()`.
+//!
+//! Every entry saves the registers in the `save_regs_to_stack!` layout and calls
+//! `arch_handle_exception()` with the entry index, then returns with `eret`.
+
+use aarch64_cpu::registers::{ESR_EL2, FAR_EL2};
+use tock_registers::interfaces::Readable;
+
+use super::abort::decode_faulting_access;
+use super::s1pt::with_active_table;
+use super::sysreg::{handle_sysreg_trap, update_vm_traps, ESR_EC_SHIFT, ESR_EC_SYSREG};
+use super::tables::{ExceptionKind, ExceptionSource, VectorTable};
+use crate::error::HvResult;
+use crate::memory::PageFault;
+
+global_asm!(
+    "
+.macro VECTOR index
+    .balign 0x80
+    sub     sp, sp, 34 * 8
+    stp     x0, x1, [sp]
+    mov     x1, \\index
+    b       exception_common
+.endm
+
+    .section .text.exception_vectors, \"ax\"
+    .balign 0x800
+    .global exception_vectors
+exception_vectors:
+    VECTOR 0
+    VECTOR 1
+    VECTOR 2
+    VECTOR 3
+    VECTOR 4
+    VECTOR 5
+    VECTOR 6
+    VECTOR 7
+    VECTOR 8
+    VECTOR 9
+    VECTOR 10
+    VECTOR 11
+    VECTOR 12
+    VECTOR 13
+    VECTOR 14
+    VECTOR 15
+
+exception_common:
+    stp     x2, x3, [sp, 2 * 8]
+    stp     x4, x5, [sp, 4 * 8]
+    stp     x6, x7, [sp, 6 * 8]
+    stp     x8, x9, [sp, 8 * 8]
+    stp     x10, x11, [sp, 10 * 8]
+    stp     x12, x13, [sp, 12 * 8]
+    stp     x14, x15, [sp, 14 * 8]
+    stp     x16, x17, [sp, 16 * 8]
+    stp     x18, x19, [sp, 18 * 8]
+    stp     x20, x21, [sp, 20 * 8]
+    stp     x22, x23, [sp, 22 * 8]
+    stp     x24, x25, [sp, 24 * 8]
+    stp     x26, x27, [sp, 26 * 8]
+    stp     x28, x29, [sp, 28 * 8]
+    mrs     x9, sp_el1
+    mrs     x10, elr_el2
+    mrs     x11, spsr_el2
+    stp     x30, x9, [sp, 30 * 8]
+    stp     x10, x11, [sp, 32 * 8]
+
+    mov     x0, sp
+    bl      arch_handle_exception
+
+    ldp     x10, x11, [sp, 32 * 8]
+    ldp     x30, x9, [sp, 30 * 8]
+    msr     sp_el1, x9
+    msr     elr_el2, x10
+    msr     spsr_el2, x11
+    ldp     x28, x29, [sp, 28 * 8]
+    ldp     x26, x27, [sp, 26 * 8]
+    ldp     x24, x25, [sp, 24 * 8]
+    ldp     x22, x23, [sp, 22 * 8]
+    ldp     x20, x21, [sp, 20 * 8]
+    ldp     x18, x19, [sp, 18 * 8]
+    ldp     x16, x17, [sp, 16 * 8]
+    ldp     x14, x15, [sp, 14 * 8]
+    ldp     x12, x13, [sp, 12 * 8]
+    ldp     x10, x11, [sp, 10 * 8]
+    ldp     x8, x9, [sp, 8 * 8]
+    ldp     x6, x7, [sp, 6 * 8]
+    ldp     x4, x5, [sp, 4 * 8]
+    ldp     x2, x3, [sp, 2 * 8]
+    ldp     x0, x1, [sp]
+    add     sp, sp, 34 * 8
+    eret
+"
+);
+
+/// The registers saved by the vectors.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    /// x0-x30.
+    pub usr: [u64; 31],
+    pub sp_el1: u64,
+    pub elr: u64,
+    pub spsr: u64,
+}
+
+#[no_mangle]
+extern "C" fn arch_handle_exception(frame: &mut TrapFrame, index: usize) {
+    let esr = ESR_EL2.get();
+    let vector = VectorTable::decode_index(index);
+    let res = match vector {
+        Some((ExceptionSource::LowerAArch64, ExceptionKind::Synchronous)) => {
+            handle_lower_sync(frame, esr)
+        }
+        Some((ExceptionSource::CurrentSpElx, ExceptionKind::Synchronous)) => {
+            handle_current_sync(frame, esr)
+        }
+        vector => hv_result_err!(ENOSYS, format!("Unhandled exception {:?}", vector)),
+    };
+    if let Err(e) = res {
+        panic!("{:?}, ESR {:#x}: {:#x?}", e, esr, frame);
+    }
+    if let Some((ExceptionSource::LowerAArch64, _)) = vector {
+        // A TTBR hook may have been registered or unregistered since the guest was entered.
+        update_vm_traps();
+    }
+}
+
+/// Handle a synchronous exception taken from the guest.
+fn handle_lower_sync(frame: &mut TrapFrame, esr: u64) -> HvResult {
+    match (esr >> ESR_EC_SHIFT) & 0x3f {
+        ESR_EC_SYSREG => handle_sysreg_trap(esr, &frame.usr)?,
+        ec => return hv_result_err!(ENOSYS, format!("Unhandled trap, EC {:#x}", ec)),
+    }
+    // Step over the trapped instruction, AArch64 instructions are all 4 bytes long.
+    frame.elr += 4;
+    Ok(())
+}
+
+/// Handle a synchronous exception taken from the hypervisor itself, which is never recoverable.
+/// For an abort, the translation path of the faulting address is printed first, and for a data
+/// abort the faulting access too.
+fn handle_current_sync(frame: &TrapFrame, esr: u64) -> HvResult {
+    let far = FAR_EL2.get();
+    let fault = match PageFault::from_arm(esr, far) {
+        Some(fault) => fault,
+        None => return hv_result_err!(ENOSYS, "Unhandled synchronous exception"),
+    };
+    let path = with_active_table(|pt| pt.walk_path(far as _));
+    error!("Translation path of {:#x}: {:#x?}", far, path);
+    // Safety: `ELR_EL2` is the hypervisor address of the faulting instruction.
+    if let Ok(access) = unsafe { decode_faulting_access(frame.elr, esr) } {
+        error!("Faulting access: {:?}", access);
+    }
+    hv_result_err!(
+        EFAULT,
+        format!("Unhandled hypervisor page fault: {:#x?}", fault)
+    )
+}
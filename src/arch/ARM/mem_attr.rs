@@ -0,0 +1,218 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memory types shared by the stage-1 and stage-2 translations, so that both stages agree on
+//! the cacheability of a page.
+
+use crate::memory::MemFlags;
+
+/// Memory types used by the hypervisor mappings.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MemType {
+    /// Device-nGnRnE (strongly ordered), used for MMIO pass-through.
+    Device,
+    /// Normal memory, Inner/Outer Write-Back.
+    Normal,
+}
+
+impl MemType {
+    pub const ALL: [Self; 2] = [Self::Device, Self::Normal];
+
+    /// Index of the type in `MAIR_ELx`, that is the value of the stage-1 `AttrIndx` field.
+    pub const fn mair_index(self) -> u64 {
+        match self {
+            Self::Device => 0,
+            Self::Normal => 1,
+        }
+    }
+
+    /// The type with the stage-1 `AttrIndx` `idx`.
+    pub fn from_mair_index(idx: u64) -> Option<Self> {
+        Self::ALL.iter().copied().find(|t| t.mair_index() == idx)
+    }
+
+    /// The `MAIR_ELx` attribute: Device-nGnRnE, or Normal Inner/Outer Write-Back Non-transient
+    /// Read/Write-Allocate.
+    pub const fn mair_attr(self) -> u64 {
+        match self {
+            Self::Device => 0x00,
+            Self::Normal => 0xff,
+        }
+    }
+
+    /// The stage-2 `MemAttr` field (with `HCR_EL2.FWB` clear): Device-nGnRnE, or Normal
+    /// Inner/Outer Write-Back. The allocation hints come from stage 1.
+    pub const fn s2_mem_attr(self) -> u64 {
+        match self {
+            Self::Device => 0b0000,
+            Self::Normal => 0b1111,
+        }
+    }
+
+    /// The type with the stage-2 `MemAttr` field `attr`.
+    pub fn from_s2_mem_attr(attr: u64) -> Option<Self> {
+        Self::ALL.iter().copied().find(|t| t.s2_mem_attr() == attr)
+    }
+}
+
+/// The attributes of a stage-1 or stage-2 page descriptor, so that generic code can convert
+/// `MemFlags` regardless of the stage.
+///
+/// Both stages agree on `READ`, `WRITE`, `EXECUTE`, `IO` and `NO_PRESENT`, the other flags are
+/// stage specific or software only.
+pub trait StageDescriptor: Sized {
+    /// The `MemFlags` of a page descriptor with these attributes.
+    fn to_memflags(self) -> MemFlags;
+    /// The attributes of a page descriptor mapping memory with `flags`.
+    fn from_memflags(flags: MemFlags) -> Self;
+}
+
+/// Conformance checks of a `StageDescriptor` implementation, shared by both stages.
+#[cfg(test)]
+pub mod conformance {
+    use core::fmt::Debug;
+
+    use super::StageDescriptor;
+    use crate::memory::MemFlags;
+
+    const R: MemFlags = MemFlags::READ;
+    const W: MemFlags = MemFlags::WRITE;
+    const X: MemFlags = MemFlags::EXECUTE;
+
+    /// Flags that every stage converts back unchanged.
+    const ROUND_TRIP: &[MemFlags] = &[
+        R,
+        MemFlags::from_bits_truncate(R.bits() | W.bits()),
+        MemFlags::from_bits_truncate(R.bits() | X.bits()),
+        MemFlags::from_bits_truncate(R.bits() | W.bits() | X.bits()),
+        MemFlags::from_bits_truncate(R.bits() | MemFlags::IO.bits()),
+        MemFlags::from_bits_truncate(R.bits() | W.bits() | MemFlags::IO.bits()),
+        MemFlags::NO_PRESENT,
+    ];
+
+    /// Software-only flags, no stage has a descriptor bit for them.
+    const SOFTWARE_ONLY: &[MemFlags] = &[
+        MemFlags::DMA,
+        MemFlags::COMM_REGION,
+        MemFlags::NO_HUGEPAGES,
+        MemFlags::ENCRYPTED,
+    ];
+
+    pub fn check<D: StageDescriptor + Copy + Debug + PartialEq>() {
+        for &flags in ROUND_TRIP {
+            let attr = D::from_memflags(flags);
+            assert_eq!(attr.to_memflags(), flags, "{:?}", attr);
+        }
+        for &soft in SOFTWARE_ONLY {
+            let flags = R | W;
+            assert_eq!(
+                D::from_memflags(flags | soft),
+                D::from_memflags(flags),
+                "{:?}",
+                soft
+            );
+        }
+        // Device memory is never executable.
+        let io = D::from_memflags(R | X | MemFlags::IO).to_memflags();
+        assert_eq!(io, R | MemFlags::IO);
+    }
+}
+
+/// `MAIR_EL2` (or `MAIR_EL1`) value with the attribute of every `MemType` at its index.
+pub const MAIR_VALUE: u64 = (MemType::Device.mair_attr() << (MemType::Device.mair_index() * 8))
+    | (MemType::Normal.mair_attr() << (MemType::Normal.mair_index() * 8));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Cacheability {
+        NonCacheable,
+        WriteThrough,
+        WriteBack,
+    }
+
+    /// The physical attributes, without the allocation hints.
+    #[derive(Debug, PartialEq)]
+    enum PhysAttr {
+        /// Device memory, the value is the `nG`/`nR`/`nE` encoding (0 for nGnRnE).
+        Device(u64),
+        Normal {
+            outer: Cacheability,
+            inner: Cacheability,
+        },
+    }
+
+    /// Decode a half of a `MAIR_ELx` attribute of Normal memory.
+    fn mair_cacheability(bits: u64) -> Cacheability {
+        match bits {
+            0b0100 => Cacheability::NonCacheable,
+            0b0000..=0b0011 | 0b1000..=0b1011 => Cacheability::WriteThrough,
+            _ => Cacheability::WriteBack,
+        }
+    }
+
+    fn decode_mair_attr(attr: u64) -> PhysAttr {
+        if attr >> 4 == 0 {
+            PhysAttr::Device((attr >> 2) & 0b11)
+        } else {
+            PhysAttr::Normal {
+                outer: mair_cacheability(attr >> 4),
+                inner: mair_cacheability(attr & 0xf),
+            }
+        }
+    }
+
+    /// Decode a half of a stage-2 `MemAttr` of Normal memory.
+    fn s2_cacheability(bits: u64) -> Cacheability {
+        match bits {
+            0b01 => Cacheability::NonCacheable,
+            0b10 => Cacheability::WriteThrough,
+            _ => Cacheability::WriteBack,
+        }
+    }
+
+    fn decode_s2_mem_attr(attr: u64) -> PhysAttr {
+        if attr >> 2 == 0 {
+            PhysAttr::Device(attr & 0b11)
+        } else {
+            PhysAttr::Normal {
+                outer: s2_cacheability(attr >> 2),
+                inner: s2_cacheability(attr & 0b11),
+            }
+        }
+    }
+
+    #[test]
+    fn test_stages_agree() {
+        for t in MemType::ALL {
+            let s1 = decode_mair_attr((MAIR_VALUE >> (t.mair_index() * 8)) & 0xff);
+            assert_eq!(s1, decode_s2_mem_attr(t.s2_mem_attr()), "{:?}", t);
+            assert_eq!(MemType::from_mair_index(t.mair_index()), Some(t));
+            assert_eq!(MemType::from_s2_mem_attr(t.s2_mem_attr()), Some(t));
+        }
+        assert_eq!(
+            decode_mair_attr(MemType::Device.mair_attr()),
+            PhysAttr::Device(0)
+        );
+        assert_eq!(
+            decode_s2_mem_attr(MemType::Normal.s2_mem_attr()),
+            PhysAttr::Normal {
+                outer: Cacheability::WriteBack,
+                inner: Cacheability::WriteBack,
+            }
+        );
+    }
+}
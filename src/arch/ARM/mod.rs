@@ -1 +1,37 @@
-pub mod ARM
\ No newline at end of file
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! AArch64 support, only built for `target_arch = "aarch64"` (see `main.rs`).
+
+#[allow(non_snake_case)]
+mod GIC;
+mod abort;
+mod context;
+mod el;
+mod exception;
+mod mem_attr;
+mod mem_encrypt;
+mod s1pt;
+mod s2pt;
+mod sysreg;
+mod tables;
+mod tcr;
+mod vcpu;
+
+pub mod barrier;
+pub mod cpu;
+pub mod topology;
+
+pub use context::{GeneralRegisters, LinuxContext};
+pub use s1pt::{flush_tlb_all, EnclaveGuestPageTableUnlocked, PTEntry};
@@ -1,9 +1,20 @@
 use core::fmt;
-use crate::memory::PagingResult;
-use crate::memory::{GenericPTE, MemFlags, PageTableLevel, PagingInstr, PhysAddr, VirtAddr};
-use crate::memory::{Level4PageTable, Level4PageTableImmut, Level4PageTableUnlocked};
+
+use aarch64_cpu::registers::{ID_AA64MMFR0_EL1, MAIR_EL1, MAIR_EL2, TCR_EL1, TCR_EL2};
+use aarch64_cpu::registers::{TTBR0_EL1, TTBR0_EL2};
+use spin::Once;
+use tock_registers::interfaces::{Readable, Writeable};
+
 use crate::memory::PAGE_SIZE;
+use crate::memory::{GenericPTE, GenericPageTableImmut, MemFlags, PageTableLevel, PagingInstr};
+use crate::memory::{Level4PageTable, Level4PageTableImmut, Level4PageTableUnlocked};
+use crate::memory::{PagingError, PagingResult};
+use crate::memory::{PhysAddr, VirtAddr};
 
+use super::barrier::{dsb, isb};
+use super::el::ExceptionLevel;
+use super::mem_attr::{MemType, StageDescriptor, MAIR_VALUE};
+use super::tcr::TcrBuilder;
 
 bitflags::bitflags! {
     /// Memory attribute fields in the VMSAv8-64 translation table format descriptors.
@@ -55,32 +66,81 @@ bitflags::bitflags! {
     }
 }
 
-
-#[repr(u64)]
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
-enum MemType {
-    Device = 0,
-    Normal = 1,
-}
-
 impl DescriptorAttr {
     const ATTR_INDEX_MASK: u64 = 0b111_00;
 
     const fn from_mem_type(mem_type: MemType) -> Self {
-        let mut bits = (mem_type as u64) << 2;
+        let mut bits = mem_type.mair_index() << 2;
         if matches!(mem_type, MemType::Normal) {
             bits |= Self::INNER.bits() | Self::SHAREABLE.bits();
         }
         Self::from_bits_truncate(bits)
     }
 
-    fn mem_type(&self) -> MemType {
+    /// The memory type of the `AttrIndx` field, `None` for the `MAIR_ELx` entries the hypervisor
+    /// doesn't program, which a table adopted from the guest may still use.
+    fn mem_type(&self) -> Option<MemType> {
+        MemType::from_mair_index((self.bits() & Self::ATTR_INDEX_MASK) >> 2)
+    }
+
+    /// The SMEP equivalent (PAN covers the data accesses): a page accessible at EL0 must never
+    /// be executable at a privileged level, so `AP_EL0` requires `PXN`.
+    fn check_el0_pxn(self) -> PagingResult<Self> {
+        if self.contains(Self::AP_EL0) && !self.contains(Self::PXN) {
+            return Err(PagingError::InsecureAttr(self.bits()));
+        }
+        Ok(self)
+    }
+}
+
+/// Compact form of the attributes for logged descriptors, e.g. `[V|AI0|ISH|AF|PXN|UXN]`.
+///
+/// `B` marks a valid block descriptor (`NON_BLOCK` clear), the memory attribute index is
+/// `AI<n>`, the shareability `ISH` or `OSH`, and the table descriptor limits end with `T`.
+impl fmt::Display for DescriptorAttr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const ATTR_INDX: [&str; 8] = ["AI0", "AI1", "AI2", "AI3", "AI4", "AI5", "AI6", "AI7"];
+        const TOKENS: [(DescriptorAttr, &str); 13] = [
+            (DescriptorAttr::NS, "NS"),
+            (DescriptorAttr::AP_EL0, "EL0"),
+            (DescriptorAttr::AP_RO, "RO"),
+            (DescriptorAttr::AF, "AF"),
+            (DescriptorAttr::NG, "NG"),
+            (DescriptorAttr::CONTIGUOUS, "CONT"),
+            (DescriptorAttr::PXN, "PXN"),
+            (DescriptorAttr::UXN, "UXN"),
+            (DescriptorAttr::PXN_TABLE, "PXNT"),
+            (DescriptorAttr::XN_TABLE, "XNT"),
+            (DescriptorAttr::AP_NO_EL0_TABLE, "NOEL0T"),
+            (DescriptorAttr::AP_NO_WRITE_TABLE, "ROT"),
+            (DescriptorAttr::NS_TABLE, "NST"),
+        ];
+        let valid = self.contains(Self::VALID);
+        let shareable = self.contains(Self::SHAREABLE);
         let idx = (self.bits() & Self::ATTR_INDEX_MASK) >> 2;
-        match idx {
-            0 => MemType::Device,
-            1 => MemType::Normal,
-            _ => panic!("Invalid memory attribute index"),
+        let head = [
+            (valid, "V"),
+            (valid && !self.contains(Self::NON_BLOCK), "B"),
+            (true, ATTR_INDX[idx as usize]),
+            (shareable && self.contains(Self::INNER), "ISH"),
+            (shareable && !self.contains(Self::INNER), "OSH"),
+        ];
+        let tail = TOKENS
+            .iter()
+            .map(|&(attr, name)| (self.contains(attr), name));
+        let tokens = IntoIterator::into_iter(head)
+            .chain(tail)
+            .filter(|&(set, _)| set)
+            .map(|(_, name)| name);
+
+        f.write_str("[")?;
+        for (i, name) in tokens.enumerate() {
+            if i > 0 {
+                f.write_str("|")?;
+            }
+            f.write_str(name)?;
         }
+        f.write_str("]")
     }
 }
 
@@ -104,12 +164,17 @@ impl From<DescriptorAttr> for MemFlags {
     // 用户访问：DescriptorAttr 中的 AP_EL0 控制页面是否可以被用户模式访问，这与 MemFlags 中的 USER 类似，都是为了限制页面的访问级别
     // 大页面控制：MemFlags 的 NO_HUGEPAGES 标志用于防止使用大页面，而 DescriptorAttr 的 NON_BLOCK 用于指示非块（即小页面），功能上有些重叠。
     // 其他特性：MemFlags 中的一些标志（如 DMA、IO、COMM_REGION、ENCRYPTED）主要用于软件层次的内存管理，没有直接对应的硬件层面标志。DescriptorAttr 专注于硬件层面，主要管理内存页面的属性和安全性。
+    //
+    // The correspondence is enforced by `MEM_FLAGS_ATTR_TABLE` in the tests.
     fn from(attr: DescriptorAttr) -> Self {
         let mut flags = Self::empty();
         if !attr.contains(DescriptorAttr::VALID) {
             flags |= Self::NO_PRESENT;
         } else {
             flags |= Self::READ;
+            if attr.mem_type() == Some(MemType::Device) {
+                flags |= Self::IO;
+            }
             if !attr.contains(DescriptorAttr::AP_RO) {
                 flags |= Self::WRITE;
             }
@@ -126,29 +191,158 @@ impl From<DescriptorAttr> for MemFlags {
     }
 }
 
+impl From<MemFlags> for DescriptorAttr {
+    fn from(flags: MemFlags) -> Self {
+        let mut attr = if flags.contains(MemFlags::IO) {
+            // Never execute from device memory.
+            Self::from_mem_type(MemType::Device) | Self::PXN | Self::UXN
+        } else {
+            Self::from_mem_type(MemType::Normal)
+        };
+        if !flags.contains(MemFlags::NO_PRESENT) {
+            attr |= Self::VALID | Self::AF;
+        }
+        if !flags.contains(MemFlags::WRITE) {
+            attr |= Self::AP_RO;
+        }
+        if flags.contains(MemFlags::USER) {
+            // `EXECUTE` only means executable at EL0 for user pages, see `check_el0_pxn()`.
+            attr |= Self::AP_EL0 | Self::PXN;
+            if !flags.contains(MemFlags::EXECUTE) {
+                attr |= Self::UXN;
+            }
+        } else if !flags.contains(MemFlags::EXECUTE) {
+            attr |= Self::PXN;
+        }
+        attr
+    }
+}
+
+impl StageDescriptor for DescriptorAttr {
+    fn to_memflags(self) -> MemFlags {
+        self.into()
+    }
+
+    fn from_memflags(flags: MemFlags) -> Self {
+        flags.into()
+    }
+}
+
 pub struct PTEntry(u64);
 
+impl PTEntry {
+    /// Set the raw attributes of a terminal entry. Attributes making an EL0 page executable at a
+    /// privileged level are rejected.
+    pub fn set_attr(&mut self, attr: DescriptorAttr, is_huge: bool) -> PagingResult {
+        let mut attr = attr.check_el0_pxn()?;
+        if is_huge {
+            attr.remove(DescriptorAttr::NON_BLOCK);
+        } else {
+            attr.insert(DescriptorAttr::NON_BLOCK);
+        }
+        self.0 = attr.bits() | (self.0 & !ATTR_MASK);
+        Ok(())
+    }
+}
+
 // PAGE_SIZE which could change
 const PHYS_ADDR_MASK: usize = 0xffff_ffff_ffff & !(PAGE_SIZE - 1); //
 
+/// The attribute fields rewritten by `set_flags()`. The output address, the RES0 bits [51:48]
+/// and the bits [58:55] reserved for software use are kept.
+const ATTR_MASK: u64 = DescriptorAttr::all().bits();
+
+/// The output address bits [51:48], RES0 without 52-bit physical addresses.
+const ADDR_RES0_MASK: u64 = 0xf << 48;
+
+/// `ID_AA64MMFR0_EL1.PARange` value of 52-bit physical addresses.
+const PA_RANGE_52: u64 = 0b0110;
+
+/// Layout of the output address in the descriptors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OaFormat {
+    /// Output address bits [47:12] in place.
+    Bits48,
+    /// `FEAT_LPA` with the 64KB granule: output address bits [47:16] in place, and bits
+    /// [51:48] in the descriptor bits [15:12].
+    Lpa52,
+}
+
+impl OaFormat {
+    const LPA_LOW_MASK: u64 = 0x0000_ffff_ffff_0000;
+    const LPA_HIGH_SHIFT: u64 = 48 - 12;
+
+    /// The format for a CPU reporting `pa_range` in `ID_AA64MMFR0_EL1.PARange`. 52-bit output
+    /// addresses also need the 64KB granule, the 48-bit format is kept otherwise.
+    pub const fn from_pa_range(pa_range: u64, page_size: usize) -> Self {
+        if pa_range & 0xf == PA_RANGE_52 && page_size == 0x10000 {
+            Self::Lpa52
+        } else {
+            Self::Bits48
+        }
+    }
+
+    /// The format used by this CPU, detected once.
+    pub fn current() -> Self {
+        static FORMAT: Once<OaFormat> = Once::new();
+        *FORMAT.call_once(|| Self::from_pa_range(ID_AA64MMFR0_EL1.get(), PAGE_SIZE))
+    }
+
+    /// Extract the output address from the descriptor `desc`.
+    pub const fn unpack(self, desc: u64) -> PhysAddr {
+        match self {
+            Self::Bits48 => (desc & PHYS_ADDR_MASK as u64) as _,
+            Self::Lpa52 => {
+                let high = (desc & (0xf << 12)) << Self::LPA_HIGH_SHIFT;
+                ((desc & Self::LPA_LOW_MASK) | high) as _
+            }
+        }
+    }
+
+    /// Returns the descriptor output address field of `paddr`.
+    pub const fn pack(self, paddr: PhysAddr) -> u64 {
+        let paddr = paddr as u64;
+        match self {
+            Self::Bits48 => paddr & PHYS_ADDR_MASK as u64,
+            Self::Lpa52 => {
+                let high = (paddr >> Self::LPA_HIGH_SHIFT) & (0xf << 12);
+                (paddr & Self::LPA_LOW_MASK) | high
+            }
+        }
+    }
+
+    /// The descriptor bits holding the output address.
+    pub const fn mask(self) -> u64 {
+        match self {
+            Self::Bits48 => PHYS_ADDR_MASK as u64,
+            Self::Lpa52 => Self::LPA_LOW_MASK | (0xf << 12),
+        }
+    }
+}
 
 impl GenericPTE for PTEntry {
     /// Returns the physical address mapped by this entry.
     fn addr(&self) -> PhysAddr {
-        (self.0 & PHYS_ADDR_MASK) as PhysAddr
+        OaFormat::current().unpack(self.0)
     }
     /// Returns the flags of this entry.
     fn flags(&self) -> MemFlags {
-        /// Returns whether this entry is zero.
-        //PTF::from_bits_truncate(self.0).into()
         DescriptorAttr::from_bits_truncate(self.0).into()
     }
+    /// Returns the raw descriptor value of this entry.
+    fn raw(&self) -> u64 {
+        self.0
+    }
+    /// Creates an entry from its raw descriptor value.
+    fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
     /// Returns whether this entry is zero.
-    fn is_unused(&self) -> bool{
-        self.0  ==  0
+    fn is_unused(&self) -> bool {
+        self.0 == 0
     }
     /// Returns whether this entry flag indicates present.
-    fn is_present(&self) -> bool{
+    fn is_present(&self) -> bool {
         self.0 & DescriptorAttr::VALID.bits() != 0
     }
     /// Returns whether this entry maps to a huge frame (terminate page translation).
@@ -156,92 +350,647 @@ impl GenericPTE for PTEntry {
         !DescriptorAttr::from_bits_truncate(self.0).contains(DescriptorAttr::NON_BLOCK)
     }
     /// Returns whether this entry's ACCESSED bit is set.
-    fn is_young(&self) -> bool{
-        self.0 & DescriptorAttr::AF.bits() != 0        
+    fn is_young(&self) -> bool {
+        self.0 & DescriptorAttr::AF.bits() != 0
     }
 
     /// Mark the PTE as non-ACCESSED.
-    fn set_old(&mut self){
+    fn set_old(&mut self) {
         let flags = !DescriptorAttr::AF;
         self.0 &= flags.bits() | PHYS_ADDR_MASK as u64;
     }
     /// Set physical address for terminal entries.
-    fn set_addr(&mut self, paddr: PhysAddr){
-        // TODO check this
-        self.0 = (self.0 & !PHYS_ADDR_MASK as u64) | (paddr as u64 & PHYS_ADDR_MASK as u64);
+    fn set_addr(&mut self, paddr: PhysAddr) {
+        let format = OaFormat::current();
+        self.0 = (self.0 & !format.mask()) | format.pack(paddr);
     }
     /// Set flags for terminal entries.
-    fn set_flags(&mut self, flags: MemFlags, is_huge: bool) -> PagingResult{
+    fn set_flags(&mut self, flags: MemFlags, is_huge: bool) -> PagingResult {
         // TODO check this
-        let mut attr = DescriptorAttr::from(flags);
+        debug_assert!(
+            !flags.contains(MemFlags::COMM_REGION | MemFlags::ENCRYPTED),
+            "COMM_REGION can't be ENCRYPTED"
+        );
+        // There is no memory encryption C-bit on ARM.
+        assert!(
+            !flags.contains(MemFlags::ENCRYPTED),
+            "ENCRYPTED isn't supported on ARM"
+        );
         if is_huge {
-            attr.remove(DescriptorAttr::NON_BLOCK);
-        } else {
-            attr.insert(DescriptorAttr::NON_BLOCK);
+            // The level of the block isn't known here, but its output address is aligned to
+            // the smallest block size (level 2) at least.
+            let paddr = self.addr();
+            let block = PageTableLevel::L2;
+            block.validate_huge(paddr, paddr, block.entry_size())?;
         }
-        self.0 = attr.bits() | (self.0 & PHYS_ADDR_MASK as u64);
-        Ok(())
+        self.set_attr(DescriptorAttr::from(flags), is_huge)
     }
     /// Set physical address and flags for intermediate entry,
     /// `is_present` controls whether to setting its P bit.
     fn set_table(
         &mut self,
         paddr: PhysAddr,
-        next_level: PageTableLevel,
+        _next_level: PageTableLevel,
         is_present: bool,
-    ) -> PagingResult{
-        // TODO
+    ) -> PagingResult {
+        // A table descriptor has both bit 0 and bit 1 set, it carries no memory attributes.
+        let mut attr = DescriptorAttr::NON_BLOCK;
+        if is_present {
+            attr |= DescriptorAttr::VALID;
+        }
+        self.0 = OaFormat::current().pack(paddr) | attr.bits();
+        Ok(())
     }
     /// Mark the intermediate or terminal entry as present (or valid), its other parts remain unchanged.
-    fn set_present(&mut self) -> PagingResult{
-        // TODO
+    fn set_present(&mut self) -> PagingResult {
+        self.0 |= DescriptorAttr::VALID.bits();
+        Ok(())
     }
     /// Mark the intermediate or terminal entry as non-present (or invalid), its other parts remain unchanged.
-    fn set_notpresent(&mut self) -> PagingResult{
-        // TODO
+    fn set_notpresent(&mut self) -> PagingResult {
+        self.0 &= !DescriptorAttr::VALID.bits();
+        Ok(())
     }
     /// Set this entry to zero.
-    fn clear(&mut self){
-        // TODO
+    fn clear(&mut self) {
+        self.0 = 0;
+    }
+    /// There are no block descriptors at level 0, and a level 3 descriptor with bit 1 clear is
+    /// reserved. The output address bits [51:48] are RES0 at all levels.
+    fn check_reserved(&self, level: PageTableLevel) -> PagingResult {
+        let reserved_type = match level {
+            PageTableLevel::L4 | PageTableLevel::L1 => self.is_leaf(),
+            _ => false,
+        };
+        if reserved_type || self.0 & ADDR_RES0_MASK != 0 {
+            return Err(PagingError::ReservedBits(level));
+        }
+        Ok(())
     }
-}
 
+    /// Follow the break-before-make sequence: the entry is made invalid and the stale
+    /// translation is flushed before `f` rewrites it, then it's made valid again. The TLB
+    /// maintenance is issued in the shareability domain of the entry (see `share_domain()`)
+    /// rather than in the one of `I`.
+    ///
+    /// Required when changing the output address or the block size of a valid descriptor,
+    /// otherwise the TLB may hold both translations and raise a TLB conflict abort.
+    fn update_bbm<I: PagingInstr>(
+        &mut self,
+        vaddr: VirtAddr,
+        f: impl FnOnce(&mut Self),
+    ) -> PagingResult {
+        self.break_before_make(
+            |domain| {
+                // The invalid entry must be observed by the walkers before the TLB invalidation.
+                dsb();
+                flush_in(Some(vaddr), domain);
+            },
+            f,
+        )
+    }
+}
 
 impl fmt::Debug for PTEntry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Stage1PageTableEntry")
             .field("raw", &self.0)
             .field("paddr", &self.addr())
-            .field("attr", &DescriptorAttr::from_bits_truncate(self.0))
+            .field(
+                "attr",
+                &format_args!("{}", DescriptorAttr::from_bits_truncate(self.0)),
+            )
             .field("flags", &self.pt_flags())
             .field("memory_type", &self.memory_type())
             .finish()
     }
 }
 
+/// Shareability domain of the barriers and TLB maintenance issued after a table update.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ShareDomain {
+    /// Only the local PE needs to observe the update (`dsb nsh`, `tlbi ...` without `is`).
+    /// Only correct for tables that are never walked by another core, such as the per-CPU
+    /// private mapping.
+    NonShareable,
+    /// Every PE in the inner shareable domain must observe the update (`dsb ish`,
+    /// `tlbi ...is`). Required for mappings shared between cores (e.g. enclave memory),
+    /// otherwise other cores may keep using stale translations.
+    InnerShareable,
+}
+
+impl ShareDomain {
+    /// Global mappings (nG = 0) may be cached by the TLB of any core, so they need
+    /// inner-shareable maintenance. Non-global mappings are only used by per-CPU tables here.
+    pub fn of(attr: DescriptorAttr) -> Self {
+        if attr.contains(DescriptorAttr::NG) {
+            Self::NonShareable
+        } else {
+            Self::InnerShareable
+        }
+    }
+}
+
+impl PTEntry {
+    /// Shareability domain needed to maintain this entry, see [`ShareDomain::of`].
+    pub fn share_domain(&self) -> ShareDomain {
+        ShareDomain::of(DescriptorAttr::from_bits_truncate(self.0))
+    }
+
+    /// The break-before-make sequence of `GenericPTE::update_bbm()`, `flush` invalidates the
+    /// stale translation in the shareability domain of the entry.
+    fn break_before_make(
+        &mut self,
+        flush: impl FnOnce(ShareDomain),
+        make: impl FnOnce(&mut Self),
+    ) -> PagingResult {
+        let domain = self.share_domain();
+        self.set_notpresent()?;
+        flush(domain);
+        make(self);
+        self.set_present()
+    }
+}
+
+/// The exception level whose translation registers the hypervisor tables are installed in.
+fn hv_el() -> ExceptionLevel {
+    ExceptionLevel::current().expect("Unsupported exception level for the hypervisor page table")
+}
+
+/// Program `MAIR`, `TCR` and `TTBR0` of the current EL, then invalidate all its translations in
+/// `domain`.
+unsafe fn activate_in(root_paddr: PhysAddr, domain: ShareDomain) {
+    // Make the table writes visible to the walkers before switching to the new root.
+    match domain {
+        ShareDomain::NonShareable => asm!("dsb nshst"),
+        ShareDomain::InnerShareable => asm!("dsb ishst"),
+    }
+    let tcr = TcrBuilder::new();
+    match hv_el() {
+        ExceptionLevel::EL2 => {
+            MAIR_EL2.set(MAIR_VALUE);
+            TCR_EL2.set(tcr.tcr_el2());
+            TTBR0_EL2.set(root_paddr as _);
+        }
+        ExceptionLevel::EL1 => {
+            MAIR_EL1.set(MAIR_VALUE);
+            TCR_EL1.set(tcr.tcr_el1());
+            TTBR0_EL1.set(root_paddr as _);
+        }
+    }
+    asm!("isb");
+    flush_in(None, domain);
+}
+
+/// Invalidate the translation of `vaddr` (or all of them) of the current EL in `domain`, then
+/// wait for the invalidation to complete and resynchronize the instruction stream.
+fn flush_in(vaddr: Option<VirtAddr>, domain: ShareDomain) {
+    unsafe {
+        match (hv_el(), vaddr, domain) {
+            (ExceptionLevel::EL2, Some(vaddr), ShareDomain::NonShareable) => {
+                asm!("tlbi vae2, {}", in(reg) vaddr >> 12)
+            }
+            (ExceptionLevel::EL2, Some(vaddr), ShareDomain::InnerShareable) => {
+                asm!("tlbi vae2is, {}", in(reg) vaddr >> 12)
+            }
+            (ExceptionLevel::EL2, None, ShareDomain::NonShareable) => {
+                asm!("tlbi alle2")
+            }
+            (ExceptionLevel::EL2, None, ShareDomain::InnerShareable) => {
+                asm!("tlbi alle2is")
+            }
+            (ExceptionLevel::EL1, Some(vaddr), ShareDomain::NonShareable) => {
+                asm!("tlbi vae1, {}", in(reg) vaddr >> 12)
+            }
+            (ExceptionLevel::EL1, Some(vaddr), ShareDomain::InnerShareable) => {
+                asm!("tlbi vae1is, {}", in(reg) vaddr >> 12)
+            }
+            (ExceptionLevel::EL1, None, ShareDomain::NonShareable) => {
+                asm!("tlbi vmalle1")
+            }
+            (ExceptionLevel::EL1, None, ShareDomain::InnerShareable) => {
+                asm!("tlbi vmalle1is")
+            }
+        }
+        match domain {
+            ShareDomain::NonShareable => asm!("dsb nsh"),
+            ShareDomain::InnerShareable => asm!("dsb ish"),
+        }
+        asm!("isb");
+    }
+}
+
+/// Invalidate all EL1&0 stage-1 translations tagged with `asid` on every core.
+fn flush_asid_is(asid: u16) {
+    unsafe {
+        asm!("dsb ishst");
+        asm!("tlbi aside1is, {}", in(reg) (asid as u64) << 48);
+        asm!("dsb ish");
+        asm!("isb");
+    }
+}
+
+/// Paging instructions for stage-1 tables shared between cores.
 pub struct S1PTInstr;
 
 impl PagingInstr for S1PTInstr {
-    unsafe fn activate(root_paddr: PhysAddr){
-        // TODO need to check this
-        // why this need EL2
-        TTBR0_EL2.set(root_paddr as _);
-        core::arch::asm!("isb");
-        core::arch::asm!("tlbi alle2");
-        core::arch::asm!("dsb nsh");
+    unsafe fn activate(root_paddr: PhysAddr) {
+        activate_in(root_paddr, ShareDomain::InnerShareable)
+    }
 
+    fn post_activate_barrier() {
+        isb();
     }
-    fn flush(vaddr: Option<VirtAddr>){
-        // do nothing
+
+    fn flush(vaddr: Option<VirtAddr>) {
+        flush_in(vaddr, ShareDomain::InnerShareable)
     }
 
+    fn flush_asid(asid: u16) {
+        flush_asid_is(asid)
+    }
+}
+
+/// Paging instructions for stage-1 tables that are only walked by the local core.
+pub struct S1PTLocalInstr;
+
+impl PagingInstr for S1PTLocalInstr {
+    unsafe fn activate(root_paddr: PhysAddr) {
+        activate_in(root_paddr, ShareDomain::NonShareable)
+    }
+
+    fn post_activate_barrier() {
+        isb();
+    }
+
+    fn flush(vaddr: Option<VirtAddr>) {
+        flush_in(vaddr, ShareDomain::NonShareable)
+    }
+
+    fn flush_asid(asid: u16) {
+        // A recycled ASID may have been cached by any core, not only the local one.
+        flush_asid_is(asid)
+    }
+}
+
+/// Invalidate all the stage-1 translations of the current EL on every core (`tlbi vmalle1is` at
+/// EL1, `tlbi alle2is` at EL2), then wait for completion with `dsb ish` and `isb`. Unlike
+/// `PagingInstr::flush(None)`, it doesn't need a page table type in scope.
+pub fn flush_tlb_all() {
+    flush_in(None, ShareDomain::InnerShareable)
+}
+
+/// Returns the physical address of the root table referenced by the `TTBR` value `ttbr`, without
+/// the ASID and `CnP` fields.
+fn root_from_ttbr(ttbr: u64) -> PhysAddr {
+    (ttbr & 0x0000_ffff_ffff_f000) as _
+}
+
+/// Call `f` with the page table currently loaded in `TTBR0` of the current EL, e.g. to dump it
+/// or walk a faulting address.
+///
+/// The table is read through a temporary [`PageTableImmut`] view that doesn't own its frames, so
+/// nothing is deallocated after the call. The view must not outlive the active table, it's only
+/// lent to `f`.
+pub fn with_active_table<T>(f: impl FnOnce(&PageTableImmut) -> T) -> T {
+    let ttbr = match hv_el() {
+        ExceptionLevel::EL2 => TTBR0_EL2.get(),
+        ExceptionLevel::EL1 => TTBR0_EL1.get(),
+    };
+    // Safety: the active table stays alive while we are running on it.
+    let view = unsafe { PageTableImmut::from_root(root_from_ttbr(ttbr)) };
+    f(&view)
 }
 
 pub type PageTable = Level4PageTable<VirtAddr, PTEntry, S1PTInstr>;
 pub type PageTableImmut = Level4PageTableImmut<VirtAddr, PTEntry>;
+pub type LocalPageTable = Level4PageTable<VirtAddr, PTEntry, S1PTLocalInstr>;
 pub type EnclaveGuestPageTableUnlocked = Level4PageTableUnlocked<VirtAddr, PTEntry, S1PTInstr>;
 
+#[cfg(test)]
+mod tests {
+    use super::super::mem_attr::conformance;
+    use super::*;
+
+    const fn attr(bits: u64) -> DescriptorAttr {
+        DescriptorAttr::from_bits_truncate(bits)
+    }
+
+    const fn flags(bits: u64) -> MemFlags {
+        MemFlags::from_bits_truncate(bits)
+    }
+
+    const NORMAL_PAGE: u64 = DescriptorAttr::from_mem_type(MemType::Normal).bits()
+        | DescriptorAttr::VALID.bits()
+        | DescriptorAttr::AF.bits();
+    const DEVICE_PAGE: u64 = DescriptorAttr::from_mem_type(MemType::Device).bits()
+        | DescriptorAttr::VALID.bits()
+        | DescriptorAttr::AF.bits()
+        | DescriptorAttr::PXN.bits()
+        | DescriptorAttr::UXN.bits();
+    const R: u64 = MemFlags::READ.bits();
+    const W: u64 = MemFlags::WRITE.bits();
+    const X: u64 = MemFlags::EXECUTE.bits();
+    const RO: u64 = DescriptorAttr::AP_RO.bits();
+    const PXN: u64 = DescriptorAttr::PXN.bits();
+    const UXN: u64 = DescriptorAttr::UXN.bits();
+    const EL0: u64 = DescriptorAttr::AP_EL0.bits();
+    /// User pages are never executable at a privileged level.
+    const USER_PAGE: u64 = NORMAL_PAGE | EL0 | PXN;
 
+    /// The `MemFlags` and `DescriptorAttr` (of a page descriptor) that convert to each other.
+    const MEM_FLAGS_ATTR_TABLE: &[(MemFlags, DescriptorAttr)] = &[
+        (flags(R), attr(NORMAL_PAGE | RO | PXN)),
+        (flags(R | W), attr(NORMAL_PAGE | PXN)),
+        (flags(R | X), attr(NORMAL_PAGE | RO)),
+        (flags(R | W | X), attr(NORMAL_PAGE)),
+        (flags(R | MemFlags::USER.bits()), attr(USER_PAGE | RO | UXN)),
+        (flags(R | W | MemFlags::USER.bits()), attr(USER_PAGE | UXN)),
+        (flags(R | X | MemFlags::USER.bits()), attr(USER_PAGE | RO)),
+        (flags(R | MemFlags::IO.bits()), attr(DEVICE_PAGE | RO)),
+        (flags(R | W | MemFlags::IO.bits()), attr(DEVICE_PAGE)),
+        (
+            flags(MemFlags::NO_PRESENT.bits()),
+            attr(DescriptorAttr::from_mem_type(MemType::Normal).bits() | RO | PXN),
+        ),
+    ];
 
+    /// Software-only `MemFlags` that have no descriptor bit, they are dropped when converting to
+    /// `DescriptorAttr`.
+    const MEM_FLAGS_TO_ATTR_ONLY_TABLE: &[(MemFlags, DescriptorAttr)] = &[
+        (flags(R | W | MemFlags::DMA.bits()), attr(NORMAL_PAGE | PXN)),
+        (
+            flags(R | W | MemFlags::COMM_REGION.bits()),
+            attr(NORMAL_PAGE | PXN),
+        ),
+        (
+            flags(R | W | MemFlags::NO_HUGEPAGES.bits()),
+            attr(NORMAL_PAGE | PXN),
+        ),
+        (
+            flags(R | W | MemFlags::ENCRYPTED.bits()),
+            attr(NORMAL_PAGE | PXN),
+        ),
+        // Device memory is never executable.
+        (flags(R | X | MemFlags::IO.bits()), attr(DEVICE_PAGE | RO)),
+    ];
 
+    #[test]
+    fn test_mem_flags_attr_table() {
+        for &(flags, attr) in MEM_FLAGS_ATTR_TABLE {
+            assert_eq!(DescriptorAttr::from(flags), attr, "{:?}", flags);
+            assert_eq!(MemFlags::from(attr), flags, "{:?}", attr);
+        }
+        for &(flags, attr) in MEM_FLAGS_TO_ATTR_ONLY_TABLE {
+            assert_eq!(DescriptorAttr::from(flags), attr, "{:?}", flags);
+        }
+    }
 
+    #[test]
+    fn test_stage_descriptor_conformance() {
+        conformance::check::<DescriptorAttr>();
+    }
+
+    #[test]
+    fn test_user_page_is_pxn() {
+        let mut entry = PTEntry(0x8000_0000);
+        for f in [R | X, R | W | X, R | W] {
+            let f = flags(f | MemFlags::USER.bits());
+            entry.set_flags(f, false).unwrap();
+            assert!(DescriptorAttr::from_bits_truncate(entry.0).contains(attr(EL0 | PXN)));
+            assert_eq!(entry.flags(), f);
+        }
+
+        // EL0 access and privileged execution together are refused, the entry is unchanged.
+        let old = entry.0;
+        let bad = attr(NORMAL_PAGE | EL0);
+        let res = entry.set_attr(bad, false);
+        assert!(matches!(res, Err(PagingError::InsecureAttr(bits)) if bits == bad.bits()));
+        assert_eq!(entry.0, old);
+        assert!(entry.set_attr(attr(NORMAL_PAGE | EL0 | PXN), false).is_ok());
+    }
+
+    #[test]
+    fn test_io_region_is_strongly_ordered() {
+        let attr = DescriptorAttr::from(flags(R | W | MemFlags::IO.bits()));
+        assert_eq!(attr.mem_type(), Some(MemType::Device));
+        let idx = (attr.bits() & DescriptorAttr::ATTR_INDEX_MASK) >> 2;
+        assert_eq!((MAIR_VALUE >> (idx * 8)) & 0xff, 0x00);
+
+        let attr = DescriptorAttr::from(flags(R | W));
+        let idx = (attr.bits() & DescriptorAttr::ATTR_INDEX_MASK) >> 2;
+        assert_eq!((MAIR_VALUE >> (idx * 8)) & 0xff, 0xff);
+    }
+
+    #[test]
+    fn test_unknown_attr_index() {
+        // AttrIndx 7 isn't programmed in `MAIR_VALUE`.
+        let attr = DescriptorAttr::from_bits_truncate(0b111 << 2)
+            | DescriptorAttr::VALID
+            | DescriptorAttr::AF
+            | DescriptorAttr::PXN;
+        assert_eq!(attr.mem_type(), None);
+        assert_eq!(MemFlags::from(attr), MemFlags::READ | MemFlags::WRITE);
+    }
+
+    #[test]
+    fn test_comm_region_is_normal_shareable() {
+        let mut entry = PTEntry(0x8000_0000);
+        entry
+            .set_flags(flags(R | W | MemFlags::COMM_REGION.bits()), false)
+            .unwrap();
+        let attr = DescriptorAttr::from_bits_truncate(entry.0);
+        assert_eq!(attr.mem_type(), Some(MemType::Normal));
+        assert!(attr.contains(DescriptorAttr::INNER | DescriptorAttr::SHAREABLE));
+        assert_eq!(entry.addr(), 0x8000_0000);
+    }
+
+    #[test]
+    fn test_break_before_make_sequence() {
+        use core::cell::RefCell;
+
+        let seq = RefCell::new(Vec::new());
+        let mut entry = PTEntry(0x8000_0000 | NORMAL_PAGE);
+        entry
+            .break_before_make(
+                |domain| seq.borrow_mut().push(Some(domain)),
+                |entry| {
+                    assert!(!entry.is_present());
+                    seq.borrow_mut().push(None);
+                    entry.set_addr(0x9000_0000);
+                },
+            )
+            .unwrap();
+        assert_eq!(seq.take(), [Some(ShareDomain::InnerShareable), None]);
+        assert!(entry.is_present());
+        assert_eq!(entry.addr(), 0x9000_0000);
+        assert_eq!(entry.0 & ATTR_MASK, NORMAL_PAGE);
+
+        // Non-global entries are only maintained on the local PE.
+        let mut entry = PTEntry(0x8000_0000 | NORMAL_PAGE | DescriptorAttr::NG.bits());
+        entry
+            .break_before_make(|domain| seq.borrow_mut().push(Some(domain)), |_| {})
+            .unwrap();
+        assert_eq!(seq.take(), [Some(ShareDomain::NonShareable)]);
+    }
+
+    #[test]
+    fn test_set_table_and_clear() {
+        use PageTableLevel::L2;
+        let mut entry = PTEntry(NORMAL_PAGE | RO);
+        entry.set_table(0x8_1234_5000, L2, false).unwrap();
+        assert_eq!(entry.0, 0x8_1234_5000 | DescriptorAttr::NON_BLOCK.bits());
+        assert!(!entry.is_present());
+        assert!(!entry.is_leaf());
+
+        entry.set_table(0x8_1234_5000, L2, true).unwrap();
+        assert!(entry.is_present());
+        assert_eq!(entry.addr(), 0x8_1234_5000);
+
+        entry.clear();
+        assert!(entry.is_unused());
+    }
+
+    #[test]
+    fn test_set_flags_keeps_address() {
+        const SW_BITS: u64 = 0b1111 << 55;
+        let paddr = 0x8_1234_5000;
+        let mut entry = PTEntry(paddr | SW_BITS | RO | PXN);
+        entry.set_flags(flags(R | W | X), false).unwrap();
+        assert_eq!(entry.addr(), paddr as PhysAddr);
+        assert_eq!(entry.0 & SW_BITS, SW_BITS);
+        assert_eq!(entry.0 & !ATTR_MASK, paddr | SW_BITS);
+        assert_eq!(entry.flags(), flags(R | W | X));
+    }
+
+    #[test]
+    fn test_set_flags_huge_alignment() {
+        let mut entry = PTEntry(0x4020_0000 | RO);
+        entry.set_flags(flags(R | W), true).unwrap();
+        assert_eq!(entry.0 & DescriptorAttr::NON_BLOCK.bits(), 0);
+
+        let mut entry = PTEntry(0x4020_1000 | RO);
+        assert!(matches!(
+            entry.set_flags(flags(R | W), true),
+            Err(PagingError::MisalignedHugePage(_))
+        ));
+        entry.set_flags(flags(R | W), false).unwrap();
+    }
+
+    #[test]
+    fn test_active_table_view() {
+        // A synthetic TTBR0 value: root 0x8_0000 with ASID 5 and CnP set.
+        let root_paddr = root_from_ttbr((5 << 48) | 0x8_0000 | 1);
+        assert_eq!(root_paddr, 0x8_0000);
+
+        // The view only borrows the root, dropping it must not free the frame.
+        let view = unsafe { PageTableImmut::from_root(root_paddr) };
+        assert_eq!(view.root_paddr(), 0x8_0000);
+        drop(view);
+    }
+
+    #[test]
+    fn test_toggle_present_keeps_other_bits() {
+        let valid = DescriptorAttr::VALID.bits();
+        // Every bit set except VALID: address, attributes and the software-defined bits.
+        let raw = !valid;
+        let mut entry = PTEntry(raw);
+        entry.set_present().unwrap();
+        assert_eq!(entry.0, raw | valid);
+        entry.set_notpresent().unwrap();
+        assert_eq!(entry.0, raw);
+
+        let raw = 0x1234_5000 | NORMAL_PAGE | RO | (0xf << 55);
+        let mut entry = PTEntry(raw);
+        entry.set_notpresent().unwrap();
+        assert_eq!(entry.0, raw & !valid);
+        entry.set_present().unwrap();
+        assert_eq!(entry.0, raw);
+    }
+
+    #[test]
+    fn test_check_reserved() {
+        use PageTableLevel::*;
+        let table = 0x1234_5000 | DescriptorAttr::VALID.bits() | DescriptorAttr::NON_BLOCK.bits();
+        for level in [L4, L3, L2, L1] {
+            assert!(PTEntry(table).check_reserved(level).is_ok());
+        }
+        let block = 0x4000_0000 | NORMAL_PAGE;
+        assert!(PTEntry(block).check_reserved(L3).is_ok());
+        assert!(PTEntry(block).check_reserved(L2).is_ok());
+
+        // Level 0 blocks, level 3 descriptors without bit 1 and output address bits [51:48].
+        assert!(matches!(
+            PTEntry(block).check_reserved(L4),
+            Err(PagingError::ReservedBits(L4))
+        ));
+        assert!(PTEntry(block).check_reserved(L1).is_err());
+        for level in [L4, L3, L2, L1] {
+            assert!(PTEntry(table | (1 << 48)).check_reserved(level).is_err());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "ENCRYPTED isn't supported on ARM")]
+    fn test_encrypted_leaf() {
+        let f = flags(R | W | MemFlags::ENCRYPTED.bits());
+        PTEntry(0).set_leaf(0x8000_0000, f, false).unwrap();
+    }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let table = 0x1234_5000 | DescriptorAttr::VALID.bits() | DescriptorAttr::NON_BLOCK.bits();
+        for raw in [0, table, 0x4000_0000 | NORMAL_PAGE | RO, DEVICE_PAGE] {
+            let entry = PTEntry(raw);
+            let copy = PTEntry::from_raw(entry.raw());
+            assert_eq!(copy.0, raw);
+            assert_eq!(copy.addr(), entry.addr());
+            assert_eq!(copy.flags(), entry.flags());
+        }
+    }
+
+    #[test]
+    fn test_lpa_round_trip() {
+        assert_eq!(OaFormat::from_pa_range(0b0101, 0x10000), OaFormat::Bits48);
+        assert_eq!(OaFormat::from_pa_range(0b0110, 0x1000), OaFormat::Bits48);
+        let format = OaFormat::from_pa_range(0b0110, 0x10000);
+        assert_eq!(format, OaFormat::Lpa52);
+
+        let paddr = 0x000f_1234_5678_0000;
+        let field = format.pack(paddr);
+        assert_eq!(field, 0x0000_1234_5678_f000);
+        assert_eq!(field & !format.mask(), 0);
+        assert_eq!(format.unpack(field | NORMAL_PAGE | UXN), paddr);
+        // The 48-bit format drops the high bits.
+        assert_eq!(OaFormat::Bits48.pack(paddr), 0x0000_1234_5678_0000);
+        let low = 0x0000_1234_5678_9000;
+        assert_eq!(OaFormat::Bits48.unpack(OaFormat::Bits48.pack(low)), low);
+    }
+
+    #[test]
+    fn test_attr_display() {
+        let page = DescriptorAttr::VALID
+            | DescriptorAttr::NON_BLOCK
+            | DescriptorAttr::from_mem_type(MemType::Normal)
+            | DescriptorAttr::AF
+            | DescriptorAttr::AP_EL0
+            | DescriptorAttr::AP_RO
+            | DescriptorAttr::PXN;
+        assert_eq!(format!("{}", page), "[V|AI1|ISH|EL0|RO|AF|PXN]");
+
+        let block = DescriptorAttr::VALID
+            | DescriptorAttr::AF
+            | DescriptorAttr::NG
+            | DescriptorAttr::PXN
+            | DescriptorAttr::UXN;
+        assert_eq!(format!("{}", block), "[V|B|AI0|AF|NG|PXN|UXN]");
+
+        let table = DescriptorAttr::VALID
+            | DescriptorAttr::NON_BLOCK
+            | DescriptorAttr::XN_TABLE
+            | DescriptorAttr::AP_NO_WRITE_TABLE;
+        assert_eq!(format!("{}", table), "[V|AI0|XNT|ROT]");
+        // An invalid descriptor keeps its other bits.
+        assert_eq!(format!("{}", DescriptorAttr::AF), "[AI0|AF]");
+    }
+}
@@ -1,8 +1,17 @@
 use core::fmt;
-use crate::memory::PagingResult;
+
+use aarch64_cpu::registers::{VTCR_EL2, VTTBR_EL2};
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::error::HvResult;
+use crate::memory::{Frame, PagingResult};
 use crate::memory::{GenericPTE, MemFlags, PageTableLevel, PagingInstr, PhysAddr, VirtAddr};
 use crate::memory::{Level4PageTable, Level4PageTableImmut, Level4PageTableUnlocked};
 
+use super::barrier::isb;
+use super::mem_attr::{MemType, StageDescriptor};
+use super::tcr::TcrBuilder;
+
 // TODO finish stage-2 translation
 
 bitflags::bitflags! {
@@ -60,27 +69,213 @@ bitflags::bitflags! {
 }
 
 
-#[repr(u64)]
-#[derive(Debug,Clone,Copy,Eq,PartialEq)]
-enum MemType {
-    Device = 1,
-    Normal = 15,
+impl S2PTDescriptorAttr {
+    const ATTR_INDEX_MASK: u64 = 0b1111_00;
+
+    const fn from_mem_type(mem_type: MemType) -> Self {
+        let mut bits = mem_type.s2_mem_attr() << 2;
+        if matches!(mem_type, MemType::Normal) {
+            bits |= Self::INNER.bits() | Self::SHAREABLE.bits();
+        }
+        Self::from_bits_truncate(bits)
+    }
+
+    /// The memory type of the `MemAttr` field, `None` for the valid encodings the hypervisor
+    /// never sets (e.g. Normal Non-cacheable), which a stage-2 table may still hold.
+    fn mem_type(&self) -> Option<MemType> {
+        MemType::from_s2_mem_attr((self.bits() & Self::ATTR_INDEX_MASK) >> 2)
+    }
+}
+
+/// Stage 2 has no EL0/EL1 distinction, so `USER` is dropped. Without `FEAT_XNX` only `XN_1` is
+/// the execute-never bit, `XN_0` is RES0.
+impl StageDescriptor for S2PTDescriptorAttr {
+    fn to_memflags(self) -> MemFlags {
+        if !self.contains(Self::VALID) {
+            return MemFlags::NO_PRESENT;
+        }
+        let mut flags = MemFlags::empty();
+        if self.contains(Self::S2AP_R) {
+            flags |= MemFlags::READ;
+        }
+        if self.contains(Self::S2AP_W) {
+            flags |= MemFlags::WRITE;
+        }
+        if !self.intersects(Self::XN_0 | Self::XN_1) {
+            flags |= MemFlags::EXECUTE;
+        }
+        // Only the Device-nGnRnE type the hypervisor maps MMIO with is `IO`.
+        if self.mem_type() == Some(MemType::Device) {
+            flags |= MemFlags::IO;
+        }
+        flags
+    }
+
+    fn from_memflags(flags: MemFlags) -> Self {
+        let mut attr = if flags.contains(MemFlags::IO) {
+            // Never execute from device memory.
+            Self::from_mem_type(MemType::Device) | Self::XN_1
+        } else {
+            Self::from_mem_type(MemType::Normal)
+        };
+        if !flags.contains(MemFlags::NO_PRESENT) {
+            attr |= Self::VALID | Self::AF;
+        }
+        if flags.contains(MemFlags::READ) {
+            attr |= Self::S2AP_R;
+        }
+        if flags.contains(MemFlags::WRITE) {
+            attr |= Self::S2AP_W;
+        }
+        if !flags.contains(MemFlags::EXECUTE) {
+            attr |= Self::XN_1;
+        }
+        attr
+    }
 }
 
+/// VMID field of `VTTBR_EL2` (16-bit VMIDs).
+const VTTBR_VMID_MASK: u64 = 0xffff << 48;
 
-impl S2PTDescriptorAttr {
-    const ATTR_INDEX_MASK:u64  =0b1111_00;
+pub struct S2PTInstr;
 
+impl S2PTInstr {
+    /// Program `VTCR_EL2` with `vtcr` and `VTTBR_EL2` with the (possibly concatenated) root and
+    /// `vmid`.
+    unsafe fn activate_with(root_paddr: PhysAddr, vtcr: u64, vmid: u16) {
+        asm!("dsb ishst");
+        VTCR_EL2.set(vtcr);
+        VTTBR_EL2.set(root_paddr as u64 | ((vmid as u64) << 48));
+        asm!("isb");
+        asm!("tlbi vmalls12e1is");
+        asm!("dsb ish");
+        asm!("isb");
+    }
+}
 
-    const fn from_mem_type(mem_type: MemType) -> Self {
-        let mut bits = (mem_type as u64) << 2;
-        if match  {  }
+impl PagingInstr for S2PTInstr {
+    unsafe fn activate(root_paddr: PhysAddr) {
+        let vtcr = TcrBuilder::new().vtcr_el2().unwrap();
+        Self::activate_with(root_paddr, vtcr, 0);
+    }
+
+    fn post_activate_barrier() {
+        isb();
     }
 
+    fn flush(vaddr: Option<VirtAddr>) {
+        unsafe {
+            match vaddr {
+                Some(ipa) => {
+                    // Invalidating by IPA does not remove the combined stage-1 + stage-2
+                    // entries, so the stage-1 entries of the VMID must go as well.
+                    asm!("tlbi ipas2e1is, {}", in(reg) ipa >> 12);
+                    asm!("dsb ish");
+                    asm!("tlbi vmalle1is");
+                }
+                None => asm!("tlbi vmalls12e1is"),
+            }
+            asm!("dsb ish");
+            asm!("isb");
+        }
+    }
+
+    fn flush_vmid(vmid: u16) {
+        // TLBI VMALLS12E1IS applies to the VMID in VTTBR_EL2, so switch to the recycled VMID
+        // for the duration of the invalidation.
+        let vttbr = VTTBR_EL2.get();
+        unsafe {
+            VTTBR_EL2.set((vttbr & !VTTBR_VMID_MASK) | ((vmid as u64) << 48));
+            asm!("isb");
+            asm!("tlbi vmalls12e1is");
+            asm!("dsb ish");
+            VTTBR_EL2.set(vttbr);
+            asm!("isb");
+        }
+    }
+}
+
+/// The first level of a stage-2 table, made of `TcrBuilder::concatenated_tables()` contiguous
+/// tables, aligned to their total size as `VTTBR_EL2.BADDR` requires.
+pub struct S2Root {
+    frame: Frame,
+    tcr: TcrBuilder,
+    vtcr: u64,
+}
+
+impl S2Root {
+    pub fn new(tcr: TcrBuilder) -> HvResult<Self> {
+        let vtcr = match tcr.vtcr_el2() {
+            Some(vtcr) => vtcr,
+            None => return hv_result_err!(EINVAL, format!("Invalid stage-2 config: {:?}", tcr)),
+        };
+        let count = tcr.concatenated_tables();
+        let mut frame = Frame::new_contiguous(count, count.trailing_zeros() as usize)?;
+        frame.zero();
+        Ok(Self { frame, tcr, vtcr })
+    }
+
+    pub fn paddr(&self) -> PhysAddr {
+        self.frame.start_paddr()
+    }
 
+    /// Number of concatenated tables.
+    pub fn table_count(&self) -> usize {
+        self.tcr.concatenated_tables()
+    }
+
+    pub unsafe fn activate(&self) {
+        self.activate_vmid(0);
+    }
 
+    /// Activate the table for the guest tagged with `vmid`.
+    pub unsafe fn activate_vmid(&self, vmid: u16) {
+        S2PTInstr::activate_with(self.paddr(), self.vtcr, vmid);
+        S2PTInstr::post_activate_barrier();
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::super::mem_attr::conformance;
+    use super::*;
+
+    #[test]
+    fn test_stage_descriptor_conformance() {
+        conformance::check::<S2PTDescriptorAttr>();
+    }
+
+    #[test]
+    fn test_s2_permissions() {
+        let normal = S2PTDescriptorAttr::from_mem_type(MemType::Normal)
+            | S2PTDescriptorAttr::VALID
+            | S2PTDescriptorAttr::AF;
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let attr = S2PTDescriptorAttr::from_memflags(rw | MemFlags::USER);
+        let expected = normal
+            | S2PTDescriptorAttr::S2AP_R
+            | S2PTDescriptorAttr::S2AP_W
+            | S2PTDescriptorAttr::XN_1;
+        assert_eq!(attr, expected);
+        assert_eq!(attr.to_memflags(), rw);
 
+        // Unlike stage 1, a valid stage-2 page may deny reads.
+        let attr = S2PTDescriptorAttr::from_memflags(MemFlags::EXECUTE);
+        assert!(!attr.contains(S2PTDescriptorAttr::S2AP_R));
+        assert_eq!(attr.to_memflags(), MemFlags::EXECUTE);
 
+        // An execute-never in the `FEAT_XNX` encoding is still execute-never.
+        let attr = normal | S2PTDescriptorAttr::S2AP_R | S2PTDescriptorAttr::XN_0;
+        assert_eq!(attr.to_memflags(), MemFlags::READ);
+    }
 
+    #[test]
+    fn test_unknown_mem_attr() {
+        // Normal Inner/Outer Non-cacheable, a valid encoding not in `MemType::ALL`.
+        let attr = S2PTDescriptorAttr::from_bits_truncate(0b0101 << 2)
+            | S2PTDescriptorAttr::VALID
+            | S2PTDescriptorAttr::S2AP_R;
+        assert_eq!(attr.mem_type(), None);
+        assert_eq!(attr.to_memflags(), MemFlags::READ | MemFlags::EXECUTE);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Emulation of the guest writes to the EL1 virtual memory control registers.
+//!
+//! They are trapped with `HCR_EL2.TVM` while a TTBR hook is registered, so that the guest
+//! address space switches reach [`on_guest_ttbr_write()`]. `TVM` traps the writes to all of
+//! these registers, not only to the TTBRs, so they are all written back on behalf of the guest.
+
+use aarch64_cpu::registers::HCR_EL2;
+use tock_registers::interfaces::ReadWriteable;
+
+use crate::error::HvResult;
+use crate::memory::root_hook::{on_guest_ttbr_write, GUEST_TTBR_HOOK};
+
+/// Exception class of the trapped MSR, MRS and System instructions.
+pub(super) const ESR_EC_SHIFT: u64 = 26;
+pub(super) const ESR_EC_SYSREG: u64 = 0x18;
+
+/// A system register, named by the operands of its MSR/MRS encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysReg {
+    op0: u8,
+    op1: u8,
+    crn: u8,
+    crm: u8,
+    op2: u8,
+}
+
+impl SysReg {
+    pub const SCTLR_EL1: Self = Self::new(3, 0, 1, 0, 0);
+    pub const TTBR0_EL1: Self = Self::new(3, 0, 2, 0, 0);
+    pub const TTBR1_EL1: Self = Self::new(3, 0, 2, 0, 1);
+    pub const TCR_EL1: Self = Self::new(3, 0, 2, 0, 2);
+    pub const AFSR0_EL1: Self = Self::new(3, 0, 5, 1, 0);
+    pub const AFSR1_EL1: Self = Self::new(3, 0, 5, 1, 1);
+    pub const ESR_EL1: Self = Self::new(3, 0, 5, 2, 0);
+    pub const FAR_EL1: Self = Self::new(3, 0, 6, 0, 0);
+    pub const MAIR_EL1: Self = Self::new(3, 0, 10, 2, 0);
+    pub const AMAIR_EL1: Self = Self::new(3, 0, 10, 3, 0);
+    pub const CONTEXTIDR_EL1: Self = Self::new(3, 0, 13, 0, 1);
+
+    const fn new(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> Self {
+        Self {
+            op0,
+            op1,
+            crn,
+            crm,
+            op2,
+        }
+    }
+}
+
+/// A trapped MSR or MRS, decoded from the ISS of `ESR_EL2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysRegAccess {
+    pub reg: SysReg,
+    /// The transfer register, 31 stands for XZR.
+    pub rt: usize,
+    /// Whether it's an MSR, an MRS otherwise.
+    pub write: bool,
+}
+
+impl SysRegAccess {
+    pub fn decode(esr: u64) -> HvResult<Self> {
+        if (esr >> ESR_EC_SHIFT) & 0x3f != ESR_EC_SYSREG {
+            return hv_result_err!(
+                EINVAL,
+                format!("Not a system register trap: ESR {:#x}", esr)
+            );
+        }
+        // Op0 [21:20], Op2 [19:17], Op1 [16:14], CRn [13:10], Rt [9:5], CRm [4:1], Direction [0]
+        let field = |shift: u64, mask: u64| ((esr >> shift) & mask) as u8;
+        Ok(Self {
+            reg: SysReg::new(
+                field(20, 0b11),
+                field(14, 0b111),
+                field(10, 0xf),
+                field(1, 0xf),
+                field(17, 0b111),
+            ),
+            rt: field(5, 0x1f) as usize,
+            write: esr & 1 == 0,
+        })
+    }
+}
+
+/// Write `val` to the EL1 register `reg` on behalf of the guest, returning the old value.
+/// `None` if `reg` isn't one of the registers trapped by `HCR_EL2.TVM`.
+fn replace_el1_reg(reg: SysReg, val: u64) -> Option<u64> {
+    macro_rules! replace {
+        ($name:literal) => {{
+            let old: u64;
+            unsafe {
+                asm!(concat!("mrs {}, ", $name), out(reg) old);
+                asm!(concat!("msr ", $name, ", {}"), in(reg) val);
+            }
+            old
+        }};
+    }
+    Some(match reg {
+        SysReg::SCTLR_EL1 => replace!("sctlr_el1"),
+        SysReg::TTBR0_EL1 => replace!("ttbr0_el1"),
+        SysReg::TTBR1_EL1 => replace!("ttbr1_el1"),
+        SysReg::TCR_EL1 => replace!("tcr_el1"),
+        SysReg::AFSR0_EL1 => replace!("afsr0_el1"),
+        SysReg::AFSR1_EL1 => replace!("afsr1_el1"),
+        SysReg::ESR_EL1 => replace!("esr_el1"),
+        SysReg::FAR_EL1 => replace!("far_el1"),
+        SysReg::MAIR_EL1 => replace!("mair_el1"),
+        SysReg::AMAIR_EL1 => replace!("amair_el1"),
+        SysReg::CONTEXTIDR_EL1 => replace!("contextidr_el1"),
+        _ => return None,
+    })
+}
+
+/// Trap the guest writes to the EL1 virtual memory control registers only while a TTBR hook
+/// is registered. Called before returning to the guest from an exception.
+pub fn update_vm_traps() {
+    if GUEST_TTBR_HOOK.is_registered() {
+        HCR_EL2.modify(HCR_EL2::TVM::SET);
+    } else {
+        HCR_EL2.modify(HCR_EL2::TVM::CLEAR);
+    }
+}
+
+/// Emulate the trapped MSR of `esr`, `usr` holding the guest x0-x30. The TTBR writes are
+/// reported to the TTBR hook. The caller steps the guest over the instruction.
+pub fn handle_sysreg_trap(esr: u64, usr: &[u64; 31]) -> HvResult {
+    let access = SysRegAccess::decode(esr)?;
+    if !access.write {
+        return hv_result_err!(ENOSYS, format!("Unexpected trapped MRS: {:x?}", access));
+    }
+    let val = usr.get(access.rt).copied().unwrap_or(0);
+    let old = replace_el1_reg(access.reg, val)
+        .ok_or_else(|| hv_err!(ENOSYS, format!("Unsupported trapped MSR: {:x?}", access)))?;
+    if matches!(access.reg, SysReg::TTBR0_EL1 | SysReg::TTBR1_EL1) {
+        on_guest_ttbr_write(old, val);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ESR of a trapped MSR/MRS of `reg` with `rt`.
+    fn sysreg_esr(reg: SysReg, rt: u64, write: bool) -> u64 {
+        (ESR_EC_SYSREG << ESR_EC_SHIFT)
+            | (1 << 25) // IL
+            | ((reg.op0 as u64) << 20)
+            | ((reg.op2 as u64) << 17)
+            | ((reg.op1 as u64) << 14)
+            | ((reg.crn as u64) << 10)
+            | (rt << 5)
+            | ((reg.crm as u64) << 1)
+            | !write as u64
+    }
+
+    #[test]
+    fn test_decode_sysreg_access() {
+        // msr ttbr0_el1, x3
+        let access = SysRegAccess::decode(0x6230_0860).unwrap();
+        assert_eq!(access.reg, SysReg::TTBR0_EL1);
+        assert_eq!(access.rt, 3);
+        assert!(access.write);
+        assert_eq!(sysreg_esr(SysReg::TTBR0_EL1, 3, true), 0x6230_0860);
+
+        for reg in [SysReg::TTBR1_EL1, SysReg::AFSR1_EL1, SysReg::CONTEXTIDR_EL1] {
+            let access = SysRegAccess::decode(sysreg_esr(reg, 31, false)).unwrap();
+            assert_eq!(access.reg, reg);
+            assert_eq!(access.rt, 31);
+            assert!(!access.write);
+        }
+
+        // A data abort.
+        assert!(SysRegAccess::decode(0x9200_0046).is_err());
+    }
+}
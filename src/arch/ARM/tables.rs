@@ -0,0 +1,159 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! EL2 exception vector table, the ARM counterpart of the x86 GDT/IDT: there is no descriptor
+//! table, `VBAR_EL2` points directly to the handler code.
+
+use aarch64_cpu::registers::VBAR_EL2;
+use tock_registers::interfaces::{Readable, Writeable};
+
+use super::barrier::isb;
+
+/// Exception type of a vector entry.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExceptionKind {
+    Synchronous = 0,
+    Irq = 1,
+    Fiq = 2,
+    SError = 3,
+}
+
+/// Where the exception is taken from, selects a group of 4 entries.
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExceptionSource {
+    /// Current EL using `SP_EL0`.
+    CurrentSpEl0 = 0,
+    /// Current EL using `SP_ELx`.
+    CurrentSpElx = 1,
+    /// Lower EL in AArch64, i.e. a trap from the guest.
+    LowerAArch64 = 2,
+    /// Lower EL in AArch32.
+    LowerAArch32 = 3,
+}
+
+pub struct VectorTable {
+    base: usize,
+}
+
+impl VectorTable {
+    /// `VBAR_EL2` bits [10:0] are RES0.
+    pub const ALIGN: usize = 0x800;
+    /// Each entry holds up to 32 instructions.
+    pub const ENTRY_SIZE: usize = 0x80;
+
+    /// The vector table of the hypervisor, defined in the `exception` module.
+    pub fn new() -> Self {
+        extern "C" {
+            #[link_name = "exception_vectors"]
+            static VECTORS: [u8; VectorTable::ALIGN];
+        }
+        Self::from_base(unsafe { VECTORS.as_ptr() } as usize)
+    }
+
+    pub fn from_base(base: usize) -> Self {
+        assert_eq!(base % Self::ALIGN, 0, "misaligned vector table {:#x}", base);
+        Self { base }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Address of the entry for `kind` exceptions taken from `source`.
+    pub fn entry(&self, source: ExceptionSource, kind: ExceptionKind) -> usize {
+        self.base + (source as usize * 4 + kind as usize) * Self::ENTRY_SIZE
+    }
+
+    /// The source and kind of the entry numbered `index`, see `entry()`.
+    pub fn decode_index(index: usize) -> Option<(ExceptionSource, ExceptionKind)> {
+        use ExceptionKind::*;
+        use ExceptionSource::*;
+        let source = [CurrentSpEl0, CurrentSpElx, LowerAArch64, LowerAArch32].get(index / 4)?;
+        let kind = [Synchronous, Irq, Fiq, SError][index % 4];
+        Some((*source, kind))
+    }
+
+    /// Read the active vector base, like `sidt()` on x86.
+    pub fn vbar() -> usize {
+        VBAR_EL2.get() as _
+    }
+
+    /// Install `base` as the active vector base, like `lidt()` on x86.
+    pub fn set_vbar(base: usize) {
+        VBAR_EL2.set(base as _);
+        isb();
+    }
+
+    pub fn load(&self) {
+        self.load_with(Self::set_vbar);
+    }
+
+    fn load_with(&self, set_vbar: impl FnOnce(usize)) {
+        set_vbar(self.base);
+    }
+
+    /// Returns whether this table is the active one.
+    pub fn is_loaded(&self) -> bool {
+        Self::vbar() == self.base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_vector_table() {
+        let table = VectorTable::from_base(0x4000_0800);
+        assert_eq!(
+            table.entry(ExceptionSource::CurrentSpEl0, ExceptionKind::Synchronous),
+            0x4000_0800
+        );
+        assert_eq!(
+            table.entry(ExceptionSource::LowerAArch64, ExceptionKind::Irq),
+            0x4000_0c80
+        );
+
+        let vbar = Cell::new(0);
+        table.load_with(|base| vbar.set(base));
+        assert_eq!(vbar.get(), table.base());
+    }
+
+    #[test]
+    fn test_decode_index() {
+        let table = VectorTable::from_base(0x4000_0800);
+        for index in 0..16 {
+            let (source, kind) = VectorTable::decode_index(index).unwrap();
+            assert_eq!(
+                table.entry(source, kind),
+                table.base() + index * VectorTable::ENTRY_SIZE
+            );
+        }
+        assert_eq!(
+            VectorTable::decode_index(8),
+            Some((ExceptionSource::LowerAArch64, ExceptionKind::Synchronous))
+        );
+        assert_eq!(VectorTable::decode_index(16), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "misaligned vector table")]
+    fn test_misaligned_vector_table() {
+        VectorTable::from_base(0x4000_0400);
+    }
+}
@@ -0,0 +1,266 @@
+//! Builders of the translation control registers `TCR_EL2` (stage-1) and `VTCR_EL2` (stage-2).
+//!
+//! Field layouts follow the Arm Architecture Reference Manual (DDI 0487), D17.2.131 `TCR_EL2`
+//! (with `HCR_EL2.E2H == 0`) and D17.2.190 `VTCR_EL2`.
+
+/// Translation granule, encoded as the `TG0` field.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Granule {
+    Size4K = 0b00,
+    Size64K = 0b01,
+    Size16K = 0b10,
+}
+
+impl Granule {
+    const fn page_shift(self) -> u64 {
+        match self {
+            Self::Size4K => 12,
+            Self::Size16K => 14,
+            Self::Size64K => 16,
+        }
+    }
+}
+
+/// Physical address size, encoded as the `PS` field.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PaSize {
+    Bits32 = 0b000,
+    Bits36 = 0b001,
+    Bits40 = 0b010,
+    Bits42 = 0b011,
+    Bits44 = 0b100,
+    Bits48 = 0b101,
+    Bits52 = 0b110,
+}
+
+/// Shareability of the table walks, encoded as the `SH0` field.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Shareability {
+    NonShareable = 0b00,
+    OuterShareable = 0b10,
+    InnerShareable = 0b11,
+}
+
+/// Cacheability of the table walks, encoded as the `IRGN0`/`ORGN0` fields.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Cacheability {
+    NonCacheable = 0b00,
+    WriteBackWriteAllocate = 0b01,
+    WriteThrough = 0b10,
+    WriteBackNoWriteAllocate = 0b11,
+}
+
+/// `T0SZ`, bits [5:0]: the input address size is `64 - T0SZ` bits.
+const T0SZ_SHIFT: u64 = 0;
+/// `SL0`, bits [7:6] (`VTCR_EL2` only): starting level of the stage-2 walk.
+const SL0_SHIFT: u64 = 6;
+/// `IRGN0`, bits [9:8]: inner cacheability of the walks.
+const IRGN0_SHIFT: u64 = 8;
+/// `ORGN0`, bits [11:10]: outer cacheability of the walks.
+const ORGN0_SHIFT: u64 = 10;
+/// `SH0`, bits [13:12]: shareability of the walks.
+const SH0_SHIFT: u64 = 12;
+/// `TG0`, bits [15:14]: translation granule.
+const TG0_SHIFT: u64 = 14;
+/// `PS`, bits [18:16]: physical address size.
+const PS_SHIFT: u64 = 16;
+/// `IPS`, bits [34:32] (`TCR_EL1` only): intermediate physical address size.
+const IPS_SHIFT: u64 = 32;
+/// `EPD1`, bit 23 (`TCR_EL1` only): disable the `TTBR1_EL1` walks.
+const TCR_EL1_EPD1: u64 = 1 << 23;
+/// `TCR_EL2` bits 31 and 23 are RES1.
+const TCR_RES1: u64 = (1 << 31) | (1 << 23);
+/// `VTCR_EL2` bit 31 is RES1.
+const VTCR_RES1: u64 = 1 << 31;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TcrBuilder {
+    granule: Granule,
+    va_bits: u64,
+    pa_size: PaSize,
+    shareability: Shareability,
+    cacheability: Cacheability,
+}
+
+impl TcrBuilder {
+    /// 4K granule, 48-bit input and output addresses, inner shareable and write-back
+    /// cacheable table walks.
+    pub const fn new() -> Self {
+        Self {
+            granule: Granule::Size4K,
+            va_bits: 48,
+            pa_size: PaSize::Bits48,
+            shareability: Shareability::InnerShareable,
+            cacheability: Cacheability::WriteBackWriteAllocate,
+        }
+    }
+
+    pub const fn granule(mut self, granule: Granule) -> Self {
+        self.granule = granule;
+        self
+    }
+
+    pub const fn va_bits(mut self, va_bits: u64) -> Self {
+        self.va_bits = va_bits;
+        self
+    }
+
+    pub const fn pa_size(mut self, pa_size: PaSize) -> Self {
+        self.pa_size = pa_size;
+        self
+    }
+
+    pub const fn shareability(mut self, shareability: Shareability) -> Self {
+        self.shareability = shareability;
+        self
+    }
+
+    pub const fn cacheability(mut self, cacheability: Cacheability) -> Self {
+        self.cacheability = cacheability;
+        self
+    }
+
+    /// Fields shared by `TCR_EL2` and `VTCR_EL2`.
+    fn common(&self) -> u64 {
+        assert!((25..=48).contains(&self.va_bits), "invalid VA size");
+        ((64 - self.va_bits) << T0SZ_SHIFT)
+            | ((self.pa_size as u64) << PS_SHIFT)
+            | ((self.cacheability as u64) << IRGN0_SHIFT)
+            | ((self.cacheability as u64) << ORGN0_SHIFT)
+            | ((self.shareability as u64) << SH0_SHIFT)
+            | ((self.granule as u64) << TG0_SHIFT)
+    }
+
+    /// Number of lookup levels needed to resolve `bits` input address bits.
+    fn levels(&self, bits: u64) -> u64 {
+        let shift = self.granule.page_shift();
+        let stride = shift - 3;
+        (bits - shift + stride - 1) / stride
+    }
+
+    /// The level the stage-2 walk starts from.
+    ///
+    /// Unlike stage-1, the first stage-2 level can be made of up to 16 concatenated tables, each
+    /// concatenated table resolves one more input address bit. So the walk starts at the lowest
+    /// level able to resolve `64 - T0SZ - 4` bits, which saves one lookup level when the IPA size
+    /// exceeds what a single table resolves by 1 to 4 bits.
+    fn stage2_start_level(&self) -> u64 {
+        4 - self.levels(self.va_bits - 4)
+    }
+
+    /// Number of contiguous tables the stage-2 root is made of, see `stage2_start_level()`.
+    pub fn concatenated_tables(&self) -> usize {
+        let shift = self.granule.page_shift();
+        let levels = 4 - self.stage2_start_level();
+        let resolved = shift + (shift - 3) * levels;
+        1 << self.va_bits.saturating_sub(resolved)
+    }
+
+    /// Value of `TCR_EL2` for the EL2 stage-1 translation.
+    pub fn tcr_el2(&self) -> u64 {
+        self.common() | TCR_RES1
+    }
+
+    /// Value of `TCR_EL1` when the hypervisor runs at EL1. Only the lower VA range
+    /// (`TTBR0_EL1`) is used, like at EL2.
+    pub fn tcr_el1(&self) -> u64 {
+        let common = self.common() & !(0b111 << PS_SHIFT);
+        common | ((self.pa_size as u64) << IPS_SHIFT) | TCR_EL1_EPD1
+    }
+
+    /// Value of `VTCR_EL2` for the EL1&0 stage-2 translation.
+    ///
+    /// `T0SZ` gives the IPA size and `SL0` the starting level, they must agree: the starting
+    /// level with its concatenated tables must resolve exactly `64 - T0SZ` bits, otherwise the
+    /// walk faults with a level 0 translation fault.
+    ///
+    /// `None` if `SL0` can't encode the starting level, e.g. level 3 with the 4K granule.
+    pub fn vtcr_el2(&self) -> Option<u64> {
+        // SL0 encoding depends on the granule: for 4K 0b10 means level 0, for 16K and 64K
+        // 0b10 means level 1.
+        let sl0 = match self.granule {
+            Granule::Size4K => 2u64.checked_sub(self.stage2_start_level())?,
+            Granule::Size16K | Granule::Size64K => 3u64.checked_sub(self.stage2_start_level())?,
+        };
+        Some(self.common() | (sl0 << SL0_SHIFT) | VTCR_RES1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcr_48bit_4k() {
+        let tcr = TcrBuilder::new().tcr_el2();
+        assert_eq!(tcr & 0x3f, 16); // T0SZ
+        assert_eq!((tcr >> 8) & 0b11, 0b01); // IRGN0
+        assert_eq!((tcr >> 10) & 0b11, 0b01); // ORGN0
+        assert_eq!((tcr >> 12) & 0b11, 0b11); // SH0
+        assert_eq!((tcr >> 14) & 0b11, 0b00); // TG0
+        assert_eq!((tcr >> 16) & 0b111, 0b101); // PS
+        assert_eq!(tcr, 0x8085_3510);
+    }
+
+    #[test]
+    fn test_vtcr_48bit_4k() {
+        let vtcr = TcrBuilder::new().vtcr_el2().unwrap();
+        assert_eq!(vtcr & 0x3f, 16); // T0SZ
+        assert_eq!((vtcr >> 6) & 0b11, 0b10); // SL0: start at level 0
+        assert_eq!((vtcr >> 16) & 0b111, 0b101); // PS
+        assert_eq!(vtcr, 0x8005_3590);
+
+        // 39-bit input address starts from level 1.
+        let vtcr = TcrBuilder::new().va_bits(39).vtcr_el2().unwrap();
+        assert_eq!(vtcr & 0x3f, 25);
+        assert_eq!((vtcr >> 6) & 0b11, 0b01);
+
+        // 25-bit input address would start from level 3, SL0 can't encode it with 4K pages.
+        assert_eq!(TcrBuilder::new().va_bits(25).vtcr_el2(), None);
+        let vtcr = TcrBuilder::new()
+            .granule(Granule::Size64K)
+            .va_bits(25)
+            .vtcr_el2()
+            .unwrap();
+        assert_eq!((vtcr >> 6) & 0b11, 0b00);
+    }
+
+    #[test]
+    fn test_tcr_el1_48bit_4k() {
+        let tcr = TcrBuilder::new().tcr_el1();
+        assert_eq!(tcr & 0x3f, 16); // T0SZ
+        assert_eq!((tcr >> 16) & 0x3f, 0); // T1SZ
+        assert_eq!((tcr >> 23) & 1, 1); // EPD1
+        assert_eq!((tcr >> 32) & 0b111, 0b101); // IPS
+        assert_eq!(tcr, 0x5_0080_3510);
+    }
+
+    #[test]
+    fn test_concatenated_tables() {
+        // 48-bit and 39-bit IPA fit in a single table at level 0 and level 1.
+        assert_eq!(TcrBuilder::new().concatenated_tables(), 1);
+        assert_eq!(TcrBuilder::new().va_bits(39).concatenated_tables(), 1);
+
+        // 40-bit IPA: 2 tables at level 1.
+        let tcr = TcrBuilder::new().va_bits(40);
+        assert_eq!(tcr.concatenated_tables(), 2);
+        assert_eq!((tcr.vtcr_el2().unwrap() >> 6) & 0b11, 0b01);
+
+        // 42-bit IPA: 8 tables at level 1.
+        assert_eq!(TcrBuilder::new().va_bits(42).concatenated_tables(), 8);
+
+        // 44-bit IPA would need 32 tables, start at level 0 instead.
+        let tcr = TcrBuilder::new().va_bits(44);
+        assert_eq!(tcr.concatenated_tables(), 1);
+        assert_eq!((tcr.vtcr_el2().unwrap() >> 6) & 0b11, 0b10);
+
+        // 32-bit IPA: 4 tables at level 2.
+        let tcr = TcrBuilder::new().va_bits(32);
+        assert_eq!(tcr.concatenated_tables(), 4);
+        assert_eq!((tcr.vtcr_el2().unwrap() >> 6) & 0b11, 0b00);
+    }
+}
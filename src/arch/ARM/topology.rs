@@ -0,0 +1,66 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CPU topology decoded from the MPIDR affinity levels.
+
+/// Position of a logical CPU in the topology.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    pub package: usize,
+    pub core: usize,
+    pub thread: usize,
+}
+
+/// `MPIDR_EL1.MT`: the lowest affinity level is made of threads of a multithreaded core.
+const MPIDR_MT: u64 = 1 << 24;
+
+/// Decode the affinity levels of `mpidr`. The cluster is reported as the package.
+pub fn decode_mpidr(mpidr: u64) -> CpuTopology {
+    let aff = |shift: u64| ((mpidr >> shift) & 0xff) as usize;
+    if mpidr & MPIDR_MT != 0 {
+        CpuTopology {
+            package: aff(16),
+            core: aff(8),
+            thread: aff(0),
+        }
+    } else {
+        CpuTopology {
+            package: aff(8),
+            core: aff(0),
+            thread: 0,
+        }
+    }
+}
+
+/// The topology of the CPU `cpuid`. The CPU ids are the `Aff0` fields of a single cluster of
+/// single-threaded cores (see [`super::cpu::id()`]), so `cpuid` decodes as an `MPIDR_EL1` value.
+pub fn topology(cpuid: usize) -> CpuTopology {
+    decode_mpidr(cpuid as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_mpidr() {
+        // Cortex-A53 style: core 3 of cluster 1.
+        let topo = decode_mpidr(0x8000_0103);
+        assert_eq!((topo.package, topo.core, topo.thread), (1, 3, 0));
+        // Neoverse-E1 style (MT set): thread 1 of core 2 of cluster 0.
+        let topo = decode_mpidr(0x8100_0201);
+        assert_eq!((topo.package, topo.core, topo.thread), (0, 2, 1));
+        assert_eq!(topology(5).core, 5);
+    }
+}
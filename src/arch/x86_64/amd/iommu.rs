@@ -177,6 +177,12 @@ impl GenericPTE for IoPTEntry {
     fn flags(&self) -> MemFlags {
         IoPTFlags::from_bits_truncate(self.0).into()
     }
+    fn raw(&self) -> u64 {
+        self.0
+    }
+    fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
     fn is_unused(&self) -> bool {
         self.0 == 0
     }
@@ -221,9 +227,8 @@ impl GenericPTE for IoPTEntry {
         Ok(())
     }
     fn set_notpresent(&mut self) -> PagingResult {
-        let mut flags = IoPTFlags::from_bits_truncate(self.0);
-        flags -= IoPTFlags::V;
-        self.0 = self.addr() as u64 | flags.bits();
+        // Only clear the bit, the next level and other fields must be kept as well.
+        self.0 &= !IoPTFlags::V.bits();
         Ok(())
     }
     fn clear(&mut self) {
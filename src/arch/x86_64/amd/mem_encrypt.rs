@@ -12,11 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::arch::cpu::clflush_cache_range;
+use crate::arch::cpu::clflush_phys_range;
 use crate::enclave::sgx::SgxSecInfo;
 use crate::error::HvResult;
 use crate::memory::addr::{phys_to_virt, GuestPhysAddr};
-use crate::memory::{GuestVirtAddr, PAGE_SIZE};
+use crate::memory::{GuestVirtAddr, MemFlags, PAGE_SIZE};
 use crate::reclaim::{CryptoAlg, HmacValue, NonceValue, WriteBackInfo};
 
 use core::convert::TryInto;
@@ -72,7 +72,7 @@ impl CryptoAlg for HmacSWEncHW {
         let hash = sm3_enc(info_bytes);
 
         // Flush cacheline of the low addr from linux vm
-        clflush_cache_range(phys_to_virt(gpaddr_dst), PAGE_SIZE);
+        clflush_phys_range(gpaddr_dst, PAGE_SIZE, MemFlags::empty());
         // Copy src page data to the guest RAM page with c-bit set
         unsafe {
             core::ptr::copy_nonoverlapping(
@@ -83,7 +83,7 @@ impl CryptoAlg for HmacSWEncHW {
         }
         // Flush cacheline of the high addr with c-bit set, then linux vm will
         // get ciphertext from the low addr without c-bit set
-        clflush_cache_range(gpaddr_dst, PAGE_SIZE);
+        clflush_phys_range(gpaddr_dst, PAGE_SIZE, MemFlags::ENCRYPTED);
 
         Ok(hash)
     }
@@ -141,7 +141,7 @@ impl CryptoAlg for EncHW {
         gpaddr_dst: GuestPhysAddr,
     ) -> HvResult<HmacValue> {
         // Flush cacheline of the low addr from linux vm
-        clflush_cache_range(phys_to_virt(gpaddr_dst), PAGE_SIZE);
+        clflush_phys_range(gpaddr_dst, PAGE_SIZE, MemFlags::empty());
         // Copy src page data to the guest RAM page with c-bit set
         unsafe {
             core::ptr::copy_nonoverlapping(
@@ -152,7 +152,7 @@ impl CryptoAlg for EncHW {
         }
         // Flush cacheline of the high addr with c-bit set, then linux vm will
         // get ciphertext from the low addr without c-bit set
-        clflush_cache_range(gpaddr_dst, PAGE_SIZE);
+        clflush_phys_range(gpaddr_dst, PAGE_SIZE, MemFlags::ENCRYPTED);
 
         Ok(Default::default())
     }
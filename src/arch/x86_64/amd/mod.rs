@@ -22,6 +22,7 @@ mod vmexit;
 use libvmm::svm::flags::{VmCr, VmCrFlags};
 
 use crate::arch::cpu::check_cpuid;
+use crate::arch::guest_state::check_vendor;
 use crate::error::HvResult;
 
 pub use iommu::{IoPTEntry, IoPageTable, Iommu};
@@ -31,6 +32,7 @@ pub use vcpu::Vcpu;
 
 pub fn check_hypervisor_feature() -> HvResult {
     check_cpuid()?;
+    check_vendor()?;
     if VmCr::read().contains(VmCrFlags::SVMDIS) {
         return hv_result_err!(ENODEV, "SVM disabled by BIOS!");
     }
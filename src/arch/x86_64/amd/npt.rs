@@ -16,7 +16,7 @@ use crate::arch::page_table::PTEntry;
 use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
 use crate::memory::{
     EmptyPagingInstr, GenericPTE, Level4PageTable, Level4PageTableUnlocked, MemFlags,
-    PageTableLevel, PagingResult,
+    PageTableLevel, PagingResult, Stage2PageTable,
 };
 
 #[repr(transparent)]
@@ -30,6 +30,12 @@ impl GenericPTE for NPTEntry {
     fn flags(&self) -> MemFlags {
         self.0.flags()
     }
+    fn raw(&self) -> u64 {
+        self.0.raw()
+    }
+    fn from_raw(raw: u64) -> Self {
+        Self(PTEntry::from_raw(raw))
+    }
     fn is_unused(&self) -> bool {
         self.0.is_unused()
     }
@@ -76,3 +82,6 @@ impl GenericPTE for NPTEntry {
 pub type NestedPageTable = Level4PageTable<GuestPhysAddr, NPTEntry, EmptyPagingInstr>;
 pub type EnclaveNestedPageTableUnlocked =
     Level4PageTableUnlocked<GuestPhysAddr, NPTEntry, EmptyPagingInstr>;
+
+impl Stage2PageTable for NestedPageTable {}
+impl Stage2PageTable for EnclaveNestedPageTableUnlocked {}
@@ -24,6 +24,7 @@ use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::registers::rflags::RFlags;
 use x86_64::structures::DescriptorTablePointer;
 
+use crate::arch::guest_state::VmcbAccess;
 use crate::arch::segmentation::Segment;
 use crate::arch::vmm::VcpuAccessGuestState;
 use crate::arch::{GuestPageTableImmut, GuestRegisters, LinuxContext};
@@ -131,7 +132,7 @@ impl Vcpu {
         }
     }
 
-    pub fn deactivate_vmm(&self, linux: &LinuxContext) -> HvResult {
+    pub fn deactivate_vmm(&self, linux: &mut LinuxContext) -> HvResult {
         self.guest_regs.return_to_linux(linux)
     }
 
@@ -287,7 +288,8 @@ impl VcpuAccessGuestState for Vcpu {
     }
 
     fn set_stack_pointer(&mut self, sp: u64) {
-        self.vmcb.save.rsp = sp
+        let mut vmcb = VmcbAccess::new(&mut self.vmcb);
+        self.guest_regs.set_guest_rsp(&mut vmcb, sp).unwrap()
     }
 
     fn rflags(&self) -> u64 {
@@ -349,6 +351,7 @@ unsafe extern "sysv64" fn svm_run() -> ! {
         "mov r14, rax",         // save host RAX to r14 for VMRUN
         "mov r15, rsp",         // save temporary RSP to r15
         "mov rsp, [rsp + {0}]", // set RSP to Vcpu::host_stack_top
+        "mov rdi, r15",         // pass the saved registers to vmexit_handler
         "call {1}",
         "lea rsp, [r15 + 8]",   // load temporary RSP and skip one place for RAX
         "push r14",             // push saved RAX to restore RAX later
@@ -19,6 +19,7 @@ use crate::arch::vmm::{VcpuAccessGuestState, VmExit};
 use crate::arch::{EnclaveExceptionInfo, ExceptionType};
 use crate::enclave::{AexException, EnclaveStatsId};
 use crate::error::HvResult;
+use crate::memory::PageFault;
 use crate::percpu::CpuState;
 use crate::stats::Instant;
 
@@ -154,17 +155,15 @@ impl VmExit<'_> {
 
     fn handle_nested_page_fault(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let npt_vio_info = NptViolationInfo::from_exit_info(exit_info);
-        if self.cpu_data.state == CpuState::EnclaveRunning {
-            let enclave = self.cpu_data.get_current_enclave()?;
-            enclave
-                .handle_npt_violation(npt_vio_info.guest_paddr, npt_vio_info.final_translation)?;
-            return Ok(());
+        if self.cpu_data.state != CpuState::EnclaveRunning {
+            warn!(
+                "#VMEXIT(NPF) @ {:#x?} RIP({:#x?})",
+                npt_vio_info, exit_info.guest_rip,
+            );
         }
-        warn!(
-            "#VMEXIT(NPF) @ {:#x?} RIP({:#x?})",
-            npt_vio_info, exit_info.guest_rip,
-        );
-        hv_result_err!(ENOSYS)
+        // EXITINFO1 holds a #PF error code for the guest-physical address.
+        let fault = PageFault::from_x86(exit_info.exit_info_1, npt_vio_info.guest_paddr as u64);
+        self.handle_stage2_fault(&fault, npt_vio_info.final_translation)
     }
 
     pub fn handle_exit(&mut self) -> HvResult {
@@ -13,17 +13,77 @@
 // limitations under the License.
 
 use core::fmt::Debug;
+use core::mem::size_of;
+use core::sync::atomic::{compiler_fence, Ordering};
 
 use libvmm::msr::Msr;
 use x86::{segmentation, segmentation::SegmentSelector, task};
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr3Flags, Cr4, Cr4Flags};
 use x86_64::{addr::PhysAddr, structures::paging::PhysFrame, structures::DescriptorTablePointer};
 
+use super::cpuid::CpuFeatures;
+use super::guest_state::GuestStateAccess;
+use super::page_table::flush_tlb_all;
 use super::segmentation::Segment;
 use super::tables::{GDTStruct, IDTStruct, GDT, IDT};
+use crate::error::HvResult;
 
 const SAVED_LINUX_REGS: usize = 7;
 
+/// CR4 bits that raise #GP if they are set on a CPU without the feature.
+const CR4_CHECKED_FEATURES: Cr4Flags = Cr4Flags::from_bits_truncate(
+    Cr4Flags::PCID.bits()
+        | Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION.bits()
+        | Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION.bits()
+        | Cr4Flags::L5_PAGING.bits(),
+);
+
+/// Returns the `CR4_CHECKED_FEATURES` bits supported by the CPU.
+fn supported_cr4_features() -> Cr4Flags {
+    let features = CpuFeatures::new();
+    let mut supported = Cr4Flags::empty();
+    supported.set(Cr4Flags::PCID, features.has_pcid());
+    supported.set(
+        Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+        features.has_smep(),
+    );
+    supported.set(
+        Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION,
+        features.has_smap(),
+    );
+    supported.set(Cr4Flags::L5_PAGING, features.has_la57());
+    supported
+}
+
+/// Check that writing `cr4` over `current` doesn't raise #GP: it must not set a feature missing
+/// from `supported`, nor toggle LA57, which can't change in long mode.
+fn check_restored_cr4(cr4: Cr4Flags, current: Cr4Flags, supported: Cr4Flags) -> HvResult {
+    let missing = (cr4 & CR4_CHECKED_FEATURES) - supported;
+    if !missing.is_empty() {
+        return hv_result_err!(
+            EINVAL,
+            format!("CR4 features not supported by the CPU: {:?}", missing)
+        );
+    }
+    if (cr4 ^ current).contains(Cr4Flags::L5_PAGING) {
+        return hv_result_err!(EINVAL, "CR4.LA57 can't be changed in long mode");
+    }
+    Ok(())
+}
+
+/// Overwrite `value` with zero bytes, using volatile writes followed by a compiler fence so the
+/// optimizer can't elide them as dead stores of a value about to be dropped.
+///
+/// Only for types whose fields are plain integers (or bit flags over them), for which all-zero is
+/// a valid value.
+fn wipe_volatile<T>(value: &mut T) {
+    let ptr = value as *mut T as *mut u8;
+    for i in 0..size_of::<T>() {
+        unsafe { core::ptr::write_volatile(ptr.add(i), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
 #[derive(Debug)]
 pub struct LinuxContext {
 
@@ -75,6 +135,8 @@ pub struct GuestRegisters {
     pub rcx: u64,
     pub rdx: u64,
     pub rbx: u64,
+    /// Placeholder of the RSP slot in the push layout, it's NOT the guest RSP. Use
+    /// `guest_rsp()`/`set_guest_rsp()` instead.
     _unused_rsp: u64,
     pub rbp: u64,
     pub rsi: u64,
@@ -134,11 +196,18 @@ macro_rules! restore_regs_from_stack {
 }
 
 impl LinuxContext {
-    pub fn load_from(linux_sp: usize) -> Self {
+    /// Zero every saved register, see `GuestRegisters::wipe()`.
+    pub fn wipe(&mut self) {
+        wipe_volatile(self);
+    }
+
+    /// Save the Linux context, fails without switching to the hypervisor GDT if one of the
+    /// current segment selectors is beyond the Linux GDT limit.
+    pub fn load_from(linux_sp: usize) -> HvResult<Self> {
         let regs = unsafe { core::slice::from_raw_parts(linux_sp as *const u64, SAVED_LINUX_REGS) };
         let gdt = GDTStruct::sgdt();
-        let mut fs = Segment::from_selector(segmentation::fs(), &gdt);
-        let mut gs = Segment::from_selector(segmentation::gs(), &gdt);
+        let mut fs = Segment::from_selector(segmentation::fs(), &gdt)?;
+        let mut gs = Segment::from_selector(segmentation::gs(), &gdt)?;
         fs.base = Msr::IA32_FS_BASE.read();
         gs.base = Msr::IA32_GS_BASE.read();
 
@@ -151,12 +220,12 @@ impl LinuxContext {
             rbx: regs[4],
             rbp: regs[5],
             rip: regs[6],
-            cs: Segment::from_selector(segmentation::cs(), &gdt),
-            ds: Segment::from_selector(segmentation::ds(), &gdt),
-            es: Segment::from_selector(segmentation::es(), &gdt),
+            cs: Segment::from_selector(segmentation::cs(), &gdt)?,
+            ds: Segment::from_selector(segmentation::ds(), &gdt)?,
+            es: Segment::from_selector(segmentation::es(), &gdt)?,
             fs,
             gs,
-            tss: Segment::from_selector(task::tr(), &gdt),
+            tss: Segment::from_selector(task::tr(), &gdt)?,
             gdt,
             idt: IDTStruct::sidt(),
             cr0: Cr0::read(),
@@ -186,10 +255,18 @@ impl LinuxContext {
         // PAT0: WB, PAT1: WC, PAT2: UC
         unsafe { Msr::IA32_PAT.write(0x070106) };
 
-        ret
+        Ok(ret)
     }
 
-    pub fn restore(&self) {
+    /// Restore the Linux context, fails without touching any register if the saved GDT or
+    /// IDT pointer is corrupted, as reloading it would triple-fault on the next interrupt, or
+    /// if the saved CR4 can't be written back (see `check_restored_cr4()`).
+    pub fn restore(&self) -> HvResult {
+        let tss_idx = self.tss.selector.index() as usize;
+        GDTStruct::check_pointer(&self.gdt, tss_idx + 2)?;
+        IDTStruct::check_pointer(&self.idt)?;
+        check_restored_cr4(self.cr4, Cr4::read(), supported_cr4_features())?;
+
         unsafe {
             Msr::IA32_PAT.write(self.pat);
             Msr::IA32_EFER.write(self.efer);
@@ -199,12 +276,18 @@ impl LinuxContext {
             Msr::IA32_FMASK.write(self.fmask);
 
             Cr0::write(self.cr0);
+            // The hypervisor runs without SMEP, SMAP and PCIDE. Linux's bits are all set back
+            // by this single write, so none of them is seen enabled alone. Setting PCIDE needs
+            // CR3[11:0] to be zero, which holds as the hypervisor doesn't use PCIDs.
             Cr4::write(self.cr4);
             // cr3 must be last in case cr4 enables PCID
             Cr3::write(
                 PhysFrame::containing_address(PhysAddr::new(self.cr3)),
                 Cr3Flags::empty(), // clear PCID
             );
+            // Drop the translations cached while the hypervisor was running, the CR3 write keeps
+            // the global ones.
+            flush_tlb_all();
 
             // Copy Linux TSS descriptor into our GDT, clearing the busy flag,
             // then reload TR from it. We can't use Linux' GDT as it is r/o.
@@ -212,7 +295,6 @@ impl LinuxContext {
                 let mut hv_gdt_lock = GDT.lock();
                 let hv_gdt = GDTStruct::table_of_mut(hv_gdt_lock.pointer());
                 let liunx_gdt = GDTStruct::table_of(&self.gdt);
-                let tss_idx = self.tss.selector.index() as usize;
                 hv_gdt[tss_idx] = liunx_gdt[tss_idx];
                 hv_gdt[tss_idx + 1] = liunx_gdt[tss_idx + 1];
                 hv_gdt_lock.load_tss(self.tss.selector);
@@ -230,27 +312,427 @@ impl LinuxContext {
             Msr::IA32_FS_BASE.write(self.fs.base);
             Msr::IA32_GS_BASE.write(self.gs.base);
         }
+        Ok(())
     }
 }
 
 impl GuestRegisters {
-    pub fn return_to_linux(&self, linux: &LinuxContext) -> ! {
+    /// Zero every register, with writes the optimizer can't elide. It's called on drop too.
+    ///
+    /// This is best-effort: it only clears this copy, not the ones left in the stack frames the
+    /// registers were pushed to or moved through, nor those still in the CPU registers.
+    pub fn wipe(&mut self) {
+        wipe_volatile(self);
+    }
+
+    /// The general-purpose register numbered `index` in the instruction encodings (0 is RAX,
+    /// 15 is R15). `None` for RSP (4), which isn't saved here.
+    pub fn gpr(&self, index: usize) -> Option<u64> {
+        Some(match index {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            _ => return None,
+        })
+    }
+
+    /// Number of 64-bit stack slots pushed by `save_regs_to_stack!`.
+    const STACK_SLOTS: usize = core::mem::size_of::<Self>() / core::mem::size_of::<u64>();
+
+    /// Read the guest RSP. It's kept by the hardware in the guest state (VMCS or VMCB), not in
+    /// the registers saved on VM exits.
+    pub fn guest_rsp(&self, state: &impl GuestStateAccess) -> HvResult<u64> {
+        state.rsp()
+    }
+
+    /// Set the guest RSP in the guest state (VMCS or VMCB), see `guest_rsp()`.
+    pub fn set_guest_rsp(&mut self, state: &mut impl GuestStateAccess, rsp: u64) -> HvResult {
+        state.set_rsp(rsp)
+    }
+
+    /// The hypercall number, passed in RAX. See [`GuestRegisters::hypercall_args`].
+    pub fn hypercall_code(&self) -> u64 {
+        self.rax
+    }
+
+    /// The 6 hypercall arguments, passed in RDI, RSI, RDX, R10, R8 and R9 as for Linux system
+    /// calls (R10 replaces RCX, which `SYSCALL` clobbers). The number is in RAX, where the
+    /// return value is also set.
+    pub fn hypercall_args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    /// Register names in the order of `values()`, the unused RSP slot excluded.
+    const NAMES: [&'static str; 15] = [
+        "rax", "rcx", "rdx", "rbx", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+        "r14", "r15",
+    ];
+
+    fn values(&self) -> [u64; 15] {
+        [
+            self.rax, self.rcx, self.rdx, self.rbx, self.rbp, self.rsi, self.rdi, self.r8, self.r9,
+            self.r10, self.r11, self.r12, self.r13, self.r14, self.r15,
+        ]
+    }
+
+    /// Yields `(name, old, new)` for every register that differs from `prev`, e.g. to trace
+    /// what a single-stepped guest instruction changed.
+    pub fn diff(&self, prev: &Self) -> impl Iterator<Item = (&'static str, u64, u64)> {
+        let changes = IntoIterator::into_iter(prev.values()).zip(self.values());
+        IntoIterator::into_iter(Self::NAMES)
+            .zip(changes)
+            .filter(|(_, (old, new))| old != new)
+            .map(|(name, (old, new))| (name, old, new))
+    }
+
+    /// Read the general registers saved by `save_regs_to_stack!`, where `sp` is the stack
+    /// pointer right after the last `push rax`. The frame is laid out as follows (offsets
+    /// relative to `sp`):
+    ///
+    /// | offset | register      | offset | register |
+    /// |--------|---------------|--------|----------|
+    /// | 0x00   | rax           | 0x40   | r8       |
+    /// | 0x08   | rcx           | 0x48   | r9       |
+    /// | 0x10   | rdx           | 0x50   | r10      |
+    /// | 0x18   | rbx           | 0x58   | r11      |
+    /// | 0x20   | (rsp, unused) | 0x60   | r12      |
+    /// | 0x28   | rbp           | 0x68   | r13      |
+    /// | 0x30   | rsi           | 0x70   | r14      |
+    /// | 0x38   | rdi           | 0x78   | r15      |
+    ///
+    /// The slot at 0x20 is reserved by `sub rsp, 8` rather than a `push rsp`, so its content
+    /// is not the guest RSP.
+    ///
+    /// # Safety
+    ///
+    /// `sp` must be 8-byte aligned and valid for reads of `STACK_SLOTS` slots.
+    pub unsafe fn from_stack(sp: usize) -> Self {
+        let regs = core::slice::from_raw_parts(sp as *const u64, Self::STACK_SLOTS);
+        Self {
+            rax: regs[0],
+            rcx: regs[1],
+            rdx: regs[2],
+            rbx: regs[3],
+            _unused_rsp: regs[4],
+            rbp: regs[5],
+            rsi: regs[6],
+            rdi: regs[7],
+            r8: regs[8],
+            r9: regs[9],
+            r10: regs[10],
+            r11: regs[11],
+            r12: regs[12],
+            r13: regs[13],
+            r14: regs[14],
+            r15: regs[15],
+        }
+    }
+
+    /// Write the general registers to the stack frame at `sp`, in the layout pushed by
+    /// `save_regs_to_stack!` and popped by `restore_regs_from_stack!`. See
+    /// [`GuestRegisters::from_stack`] for the offsets.
+    ///
+    /// # Safety
+    ///
+    /// `sp` must be 8-byte aligned and valid for writes of `STACK_SLOTS` slots, which must not
+    /// overlap `self`.
+    unsafe fn to_stack(&self, sp: usize) {
+        let regs = core::slice::from_raw_parts_mut(sp as *mut u64, Self::STACK_SLOTS);
+        regs[0] = self.rax;
+        regs[1] = self.rcx;
+        regs[2] = self.rdx;
+        regs[3] = self.rbx;
+        regs[4] = self._unused_rsp;
+        regs[5] = self.rbp;
+        regs[6] = self.rsi;
+        regs[7] = self.rdi;
+        regs[8] = self.r8;
+        regs[9] = self.r9;
+        regs[10] = self.r10;
+        regs[11] = self.r11;
+        regs[12] = self.r12;
+        regs[13] = self.r13;
+        regs[14] = self.r14;
+        regs[15] = self.r15;
+    }
+
+    /// Build the frame consumed by `return_to_linux()` below `rsp`: the return address `rip`, the
+    /// stack pointer to switch to before returning, then the general registers. Returns the
+    /// stack pointer of the frame.
+    fn write_entry_frame(&self, rsp: u64, rip: u64) -> usize {
+        let ret_sp = rsp as usize - size_of::<u64>();
+        let frame_sp = ret_sp - size_of::<u64>() - size_of::<Self>();
+        unsafe {
+            *(ret_sp as *mut u64) = rip;
+            *((frame_sp + size_of::<Self>()) as *mut u64) = ret_sp as u64;
+        }
+        unsafe { self.to_stack(frame_sp) };
+        frame_sp
+    }
+
+    /// Load the registers and return to Linux at `linux.rip` on the stack `linux.rsp`. `linux`
+    /// is wiped before, as the hypervisor doesn't need it anymore.
+    pub fn return_to_linux(&self, linux: &mut LinuxContext) -> ! {
+        let frame_sp = self.write_entry_frame(linux.rsp, linux.rip);
+        linux.wipe();
         unsafe {
             asm!(
-                "mov rsp, {linux_rsp}",
-                "push {linux_rip}",
-                "mov rcx, rsp",
-                "mov rsp, {guest_regs}",
-                "mov [rsp + {guest_regs_size}], rcx",
+                "mov rsp, {frame_sp}",
                 restore_regs_from_stack!(),
                 "pop rsp",
                 "ret",
-                linux_rsp = in(reg) linux.rsp,
-                linux_rip = in(reg) linux.rip,
-                guest_regs = in(reg) self,
-                guest_regs_size = const core::mem::size_of::<Self>(),
+                frame_sp = in(reg) frame_sp,
                 options(noreturn),
             );
         }
     }
 }
+
+impl Drop for LinuxContext {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+impl Drop for GuestRegisters {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_restored_cr4, GuestRegisters, CR4_CHECKED_FEATURES};
+    use crate::arch::guest_state::{GuestField, GuestStateAccess};
+    use crate::error::HvResult;
+    use x86_64::registers::control::Cr4Flags;
+
+    /// Only keeps the guest RSP.
+    struct MockVmcs {
+        rsp: u64,
+    }
+
+    impl GuestStateAccess for MockVmcs {
+        fn read(&self, field: GuestField) -> HvResult<u64> {
+            match field {
+                GuestField::Rsp => Ok(self.rsp),
+                _ => hv_result_err!(EINVAL),
+            }
+        }
+        fn write(&mut self, field: GuestField, value: u64) -> HvResult {
+            match field {
+                GuestField::Rsp => self.rsp = value,
+                _ => return hv_result_err!(EINVAL),
+            }
+            Ok(())
+        }
+    }
+
+    /// Registers numbered from `base` in the stack order of `save_regs_to_stack!`.
+    fn numbered_regs(base: u64) -> GuestRegisters {
+        GuestRegisters {
+            rax: base,
+            rcx: base + 1,
+            rdx: base + 2,
+            rbx: base + 3,
+            _unused_rsp: base + 4,
+            rbp: base + 5,
+            rsi: base + 6,
+            rdi: base + 7,
+            r8: base + 8,
+            r9: base + 9,
+            r10: base + 10,
+            r11: base + 11,
+            r12: base + 12,
+            r13: base + 13,
+            r14: base + 14,
+            r15: base + 15,
+        }
+    }
+
+    #[test]
+    fn test_guest_regs_to_stack() {
+        let regs = numbered_regs(0x1000);
+        let mut stack = [0u64; GuestRegisters::STACK_SLOTS];
+        unsafe { regs.to_stack(stack.as_mut_ptr() as usize) };
+        for (i, slot) in stack.iter().enumerate() {
+            assert_eq!(*slot, 0x1000 + i as u64, "slot {:#x}", i * 8);
+        }
+    }
+
+    #[test]
+    fn test_guest_regs_from_stack() {
+        let mut stack = [0u64; GuestRegisters::STACK_SLOTS];
+        for (i, slot) in stack.iter_mut().enumerate() {
+            *slot = 0x1000 + i as u64;
+        }
+        let regs = unsafe { GuestRegisters::from_stack(stack.as_ptr() as usize) };
+        assert_eq!(regs.rax, 0x1000);
+        assert_eq!(regs.rcx, 0x1001);
+        assert_eq!(regs.rdx, 0x1002);
+        assert_eq!(regs.rbx, 0x1003);
+        assert_eq!(regs._unused_rsp, 0x1004);
+        assert_eq!(regs.rbp, 0x1005);
+        assert_eq!(regs.rsi, 0x1006);
+        assert_eq!(regs.rdi, 0x1007);
+        assert_eq!(regs.r8, 0x1008);
+        assert_eq!(regs.r9, 0x1009);
+        assert_eq!(regs.r10, 0x100a);
+        assert_eq!(regs.r11, 0x100b);
+        assert_eq!(regs.r12, 0x100c);
+        assert_eq!(regs.r13, 0x100d);
+        assert_eq!(regs.r14, 0x100e);
+        assert_eq!(regs.r15, 0x100f);
+    }
+
+    #[test]
+    fn test_guest_regs_stack_round_trip() {
+        let regs = numbered_regs(0x2000);
+        let mut stack = [0u64; GuestRegisters::STACK_SLOTS];
+        let sp = stack.as_mut_ptr() as usize;
+        let back = unsafe {
+            regs.to_stack(sp);
+            GuestRegisters::from_stack(sp)
+        };
+        assert_eq!(back.values(), regs.values());
+        assert_eq!(back._unused_rsp, regs._unused_rsp);
+    }
+
+    #[test]
+    fn test_guest_regs_gpr() {
+        let regs = numbered_regs(0x1000);
+        for index in 0..16 {
+            let expected = Some(0x1000 + index as u64).filter(|_| index != 4);
+            assert_eq!(regs.gpr(index), expected);
+        }
+        assert_eq!(regs.gpr(16), None);
+    }
+
+    #[test]
+    fn test_guest_regs_diff() {
+        let prev = GuestRegisters {
+            rax: 1,
+            rdi: 0x1000,
+            r15: 7,
+            ..Default::default()
+        };
+        let mut regs = GuestRegisters {
+            rax: 2,
+            rdi: 0x1000,
+            r8: 0xffff,
+            ..Default::default()
+        };
+        regs._unused_rsp = 0xdead;
+        let changes: Vec<_> = regs.diff(&prev).collect();
+        assert_eq!(changes, [("rax", 1, 2), ("r8", 0, 0xffff), ("r15", 7, 0)]);
+        assert_eq!(regs.diff(&regs).count(), 0);
+    }
+
+    #[test]
+    fn test_guest_rsp() {
+        let mut vmcs = MockVmcs { rsp: 0x7fff_f000 };
+        let mut regs = GuestRegisters {
+            _unused_rsp: 0xdead,
+            ..Default::default()
+        };
+        assert_eq!(regs.guest_rsp(&vmcs).unwrap(), 0x7fff_f000);
+
+        regs.set_guest_rsp(&mut vmcs, 0x7fff_eff8).unwrap();
+        assert_eq!(vmcs.rsp, 0x7fff_eff8);
+        assert_eq!(regs.guest_rsp(&vmcs).unwrap(), 0x7fff_eff8);
+        // The placeholder slot is left alone.
+        assert_eq!(regs._unused_rsp, 0xdead);
+    }
+
+    #[test]
+    fn test_guest_regs_entry_frame() {
+        let regs = numbered_regs(0x2000);
+        let mut linux_stack = [0u64; GuestRegisters::STACK_SLOTS + 4];
+        let rsp = linux_stack.as_mut_ptr_range().end as u64;
+        let frame_sp = regs.write_entry_frame(rsp, 0xdead_beef);
+
+        // Stop before the final jump: check what `restore_regs_from_stack!`, `pop rsp` and
+        // `ret` would load.
+        let base = linux_stack.as_ptr() as usize;
+        let n = GuestRegisters::STACK_SLOTS;
+        assert_eq!(frame_sp, base + 2 * 8);
+        for (i, slot) in linux_stack[2..2 + n].iter().enumerate() {
+            assert_eq!(*slot, 0x2000 + i as u64);
+        }
+        assert_eq!(linux_stack[2 + n], rsp - 8);
+        assert_eq!(linux_stack[3 + n], 0xdead_beef);
+    }
+
+    #[test]
+    fn test_check_restored_cr4() {
+        let base = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION | Cr4Flags::OSXSAVE;
+        let la57 = Cr4Flags::L5_PAGING;
+        let all = CR4_CHECKED_FEATURES;
+        for bit in [
+            Cr4Flags::PCID,
+            Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+            Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION,
+            la57,
+        ] {
+            // Absent from the saved value.
+            assert!(check_restored_cr4(base, base, all).is_ok());
+            assert!(check_restored_cr4(base, base, all - bit).is_ok());
+            // Present, and supported or not. LA57 is already set as it can't change.
+            let current = base | (bit & la57);
+            assert!(check_restored_cr4(base | bit, current, all).is_ok());
+            assert!(check_restored_cr4(base | bit, current, bit).is_ok());
+            assert!(check_restored_cr4(base | bit, current, all - bit).is_err());
+        }
+        // LA57 can't be toggled, even if supported.
+        assert!(check_restored_cr4(base | la57, base, la57).is_err());
+        assert!(check_restored_cr4(base, base | la57, la57).is_err());
+    }
+
+    #[test]
+    fn test_hypercall_args() {
+        let regs = GuestRegisters {
+            rax: 0x10,
+            rcx: 0xdead,
+            rdi: 1,
+            rsi: 2,
+            rdx: 3,
+            r10: 4,
+            r8: 5,
+            r9: 6,
+            ..Default::default()
+        };
+        assert_eq!(regs.hypercall_code(), 0x10);
+        assert_eq!(regs.hypercall_args(), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_wipe() {
+        let mut regs = GuestRegisters {
+            rax: 0x1234,
+            rbx: u64::MAX,
+            rdi: 0xdead_beef,
+            r15: 7,
+            _unused_rsp: 0xdead,
+            ..Default::default()
+        };
+        regs.wipe();
+        assert_eq!(regs.rax, 0);
+        assert_eq!(regs.rbx, 0);
+        assert_eq!(regs.rdi, 0);
+        assert_eq!(regs.r15, 0);
+        assert_eq!(regs._unused_rsp, 0);
+        assert_eq!(regs.diff(&GuestRegisters::default()).count(), 0);
+    }
+}
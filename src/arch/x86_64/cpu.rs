@@ -12,8 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::barrier::mfence;
 use super::cpuid::CpuFeatures;
+use crate::consts::SME_C_BIT_OFFSET;
 use crate::error::HvResult;
+use crate::memory::addr::{phys_access_alias, phys_to_virt_encrypted};
+use crate::memory::{MemFlags, PhysAddr, VirtAddr};
 
 pub fn id() -> usize {
     // 创建一个新的CpuId实例，并获取CPU特性信息，然后返回初始的本地APIC ID
@@ -28,6 +34,110 @@ pub fn time_now() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
 }
 
+/// Calibrated TSC frequency, 0 before any CPU is calibrated. The invariant TSCs of all the
+/// CPUs tick at the same rate, so one frequency is shared, it's `TSC_HZ_UNRELIABLE` if any CPU
+/// has an unknown or non-invariant TSC, or disagrees with the others.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+const TSC_HZ_UNRELIABLE: u64 = u64::MAX;
+
+/// P-state 0 definition of AMD family 17h and later.
+const MSR_AMD_PSTATE_DEF0: u32 = 0xc001_0064;
+
+/// Upper bound of TSC frequency, used to wait conservatively when the frequency is unknown.
+const MAX_TSC_HZ: u64 = 10_000_000_000;
+
+/// Record the TSC frequency of the current CPU, only if the TSC is invariant.
+pub fn calibrate_tsc() {
+    let features = CpuFeatures::new();
+    let hz = if features.has_invariant_tsc() {
+        read_tsc_hz(&features)
+    } else {
+        None
+    };
+    let hz = hz.unwrap_or_else(|| {
+        warn!("CPU {}: TSC frequency unknown or not invariant", id());
+        TSC_HZ_UNRELIABLE
+    });
+    let old = TSC_HZ
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+            Some(merge_tsc_hz(old, hz))
+        })
+        .unwrap();
+    if ![0, hz, TSC_HZ_UNRELIABLE].contains(&old) && hz != TSC_HZ_UNRELIABLE {
+        warn!("CPU {}: TSC at {} Hz, {} Hz on others", id(), hz, old);
+    }
+}
+
+/// The TSC frequency of the current CPU: from CPUID on Intel, and on AMD family 17h and later,
+/// the P0 frequency, which the invariant TSC ticks at.
+fn read_tsc_hz(features: &CpuFeatures) -> Option<u64> {
+    if !features.is_amd() {
+        return features.tsc_hz();
+    }
+    if features.family() < 0x17 {
+        return None;
+    }
+    amd_pstate_hz(unsafe { x86::msr::rdmsr(MSR_AMD_PSTATE_DEF0) })
+}
+
+/// The core frequency in Hz of an AMD P-state definition: `CpuFid[7:0] * 200 MHz /
+/// CpuDfsId[13:8]`, `None` if the P-state is disabled.
+const fn amd_pstate_hz(pstate_def: u64) -> Option<u64> {
+    let enabled = pstate_def >> 63 != 0;
+    let fid = pstate_def & 0xff;
+    let dfs_id = (pstate_def >> 8) & 0x3f;
+    if !enabled || fid == 0 || dfs_id == 0 {
+        return None;
+    }
+    Some(fid * 200_000_000 / dfs_id)
+}
+
+/// Combine the TSC frequency `old` recorded by the other CPUs with `hz` of the current one.
+const fn merge_tsc_hz(old: u64, hz: u64) -> u64 {
+    if old == 0 || old == hz {
+        hz
+    } else {
+        TSC_HZ_UNRELIABLE
+    }
+}
+
+/// The calibrated TSC frequency, `None` if it's unknown on any CPU.
+pub fn tsc_hz() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 | TSC_HZ_UNRELIABLE => None,
+        hz => Some(hz),
+    }
+}
+
+/// TSC ticks in `ns` nanoseconds at `hz`, rounded up.
+const fn ns_to_ticks(ns: u64, hz: u64) -> u64 {
+    ((ns as u128 * hz as u128 + 999_999_999) / 1_000_000_000) as u64
+}
+
+/// Poll `poll` until it returns true, or fail with `ETIMEDOUT` after `ns` nanoseconds. Used to
+/// bound the waits on other CPUs or on devices, which may never answer.
+pub fn with_timeout_ns(ns: u64, poll: impl FnMut() -> bool) -> HvResult {
+    let ticks = ns_to_ticks(ns, tsc_hz().unwrap_or(MAX_TSC_HZ));
+    poll_until(ticks, time_now, poll)
+}
+
+fn poll_until(
+    ticks: u64,
+    mut now: impl FnMut() -> u64,
+    mut poll: impl FnMut() -> bool,
+) -> HvResult {
+    let start = now();
+    loop {
+        if poll() {
+            return Ok(());
+        }
+        if now().wrapping_sub(start) >= ticks {
+            return hv_result_err!(ETIMEDOUT, format!("Not done after {} TSC ticks", ticks));
+        }
+        core::hint::spin_loop();
+    }
+}
+
 pub fn check_cpuid() -> HvResult {
     // 检查CPU是否支持PAE（Physical Address Extension）和OSXSAVE（操作系统扩展保存/恢复）。如果不支持任一功能，则返回错误，否则返回成功
     let features = CpuFeatures::new();
@@ -39,6 +149,10 @@ pub fn check_cpuid() -> HvResult {
     if !features.has_xsave() {
         return hv_result_err!(ENODEV, "OSXSAVE is not supported!");
     }
+    // The C-bit is only known to the memory controller of AMD CPUs.
+    if cfg!(feature = "sme") && !features.is_amd() {
+        return hv_result_err!(ENODEV, "SME is only supported on AMD CPUs!");
+    }
     Ok(())
 }
 
@@ -53,11 +167,131 @@ pub fn clflush_cache_range(vaddr: usize, length: usize) {
     //这个函数也是在unsafe块中实现的，因为它直接调用了底层的汇编指令
     // clflush is an unordered instruction which needs fencing with mfence or
     // sfence to avoid ordering issues.
-    unsafe { asm!("mfence") };
+    mfence();
     for addr in (vaddr..(vaddr + length)).step_by(CACHE_LINE_SIZE) {
         unsafe {
             core::arch::x86_64::_mm_clflush(addr as *const u8);
         }
     }
-    unsafe { asm!("mfence") };
+    mfence();
+}
+
+/// Returns the virtual address through which the hypervisor accesses `paddr`: its encrypted
+/// mapping if `encrypted` is set, its plaintext mapping otherwise.
+fn phys_alias(paddr: PhysAddr, encrypted: bool) -> VirtAddr {
+    if encrypted {
+        phys_to_virt_encrypted(paddr)
+    } else {
+        phys_access_alias(paddr & !SME_C_BIT_OFFSET)
+    }
+}
+
+/// Flush the cache lines of the physical range through its encrypted or plaintext alias
+/// according to `flags`, so that no stale lines of the other alias linger after a key change.
+pub fn clflush_phys_range(paddr: PhysAddr, length: usize, flags: MemFlags) {
+    clflush_phys_range_with(paddr, length, flags, phys_alias, clflush_cache_range)
+}
+
+fn clflush_phys_range_with(
+    paddr: PhysAddr,
+    length: usize,
+    flags: MemFlags,
+    translate: impl FnOnce(PhysAddr, bool) -> VirtAddr,
+    flush: impl FnOnce(VirtAddr, usize),
+) {
+    let vaddr = translate(paddr, flags.contains(MemFlags::ENCRYPTED));
+    flush(vaddr, length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_amd_pstate_hz() {
+        const ENABLED: u64 = 1 << 63;
+        // CpuFid 0x88, CpuDfsId 8: 136 * 200 MHz / 8.
+        assert_eq!(amd_pstate_hz(ENABLED | 0x08_88), Some(3_400_000_000));
+        assert_eq!(amd_pstate_hz(ENABLED | 0x0a_78), Some(2_400_000_000));
+        assert_eq!(amd_pstate_hz(0x08_88), None);
+        assert_eq!(amd_pstate_hz(ENABLED | 0x88), None);
+    }
+
+    #[test]
+    fn test_merge_tsc_hz() {
+        const GHZ: u64 = 1_000_000_000;
+        assert_eq!(merge_tsc_hz(0, 2 * GHZ), 2 * GHZ);
+        assert_eq!(merge_tsc_hz(2 * GHZ, 2 * GHZ), 2 * GHZ);
+        assert_eq!(merge_tsc_hz(2 * GHZ, 3 * GHZ), TSC_HZ_UNRELIABLE);
+        assert_eq!(merge_tsc_hz(0, TSC_HZ_UNRELIABLE), TSC_HZ_UNRELIABLE);
+        assert_eq!(merge_tsc_hz(TSC_HZ_UNRELIABLE, 2 * GHZ), TSC_HZ_UNRELIABLE);
+    }
+
+    #[test]
+    fn test_ns_to_ticks() {
+        assert_eq!(ns_to_ticks(0, 2_000_000_000), 0);
+        assert_eq!(ns_to_ticks(1, 2_000_000_000), 2);
+        assert_eq!(ns_to_ticks(1_000, 2_500_000_000), 2_500);
+        assert_eq!(ns_to_ticks(1_000_000_000, 3_000_000_000), 3_000_000_000);
+        // Rounded up, never waits less than requested.
+        assert_eq!(ns_to_ticks(1, 100_000_000), 1);
+        assert_eq!(ns_to_ticks(15, 100_000_000), 2);
+        // No overflow with long waits at the highest frequency.
+        assert_eq!(
+            ns_to_ticks(u64::MAX / 1_000, MAX_TSC_HZ),
+            (u64::MAX / 1_000) * 10
+        );
+    }
+
+    #[test]
+    fn test_poll_until() {
+        use crate::error::HvErrorNum;
+        use core::cell::Cell;
+
+        // The clock advances by 10 ticks per read.
+        let t = Cell::new(0u64);
+        let clock = || {
+            t.set(t.get().wrapping_add(10));
+            t.get()
+        };
+        let polls = Cell::new(0);
+        let done_at = |n| {
+            move || {
+                polls.set(polls.get() + 1);
+                polls.get() == n
+            }
+        };
+
+        // The poll count is never 0 when checked, so it never completes.
+        let res = poll_until(100, clock, done_at(0));
+        assert_eq!(res.unwrap_err().num(), HvErrorNum::ETIMEDOUT);
+        assert_eq!(polls.get(), 10);
+
+        // Succeeds as soon as the predicate holds, even with a wrapping clock.
+        t.set(u64::MAX - 15);
+        polls.set(0);
+        assert!(poll_until(100, clock, done_at(3)).is_ok());
+        assert_eq!(polls.get(), 3);
+        assert!(poll_until(0, clock, || true).is_ok());
+    }
+
+    #[test]
+    fn test_clflush_encrypted_alias() {
+        let paddr = 0x1234_5000;
+        let mut flushed = None;
+        let translate = |paddr, encrypted| if encrypted { paddr + 1 } else { paddr };
+        clflush_phys_range_with(
+            paddr,
+            0x1000,
+            MemFlags::READ | MemFlags::ENCRYPTED,
+            translate,
+            |vaddr, len| flushed = Some((vaddr, len)),
+        );
+        assert_eq!(flushed, Some((paddr + 1, 0x1000)));
+
+        clflush_phys_range_with(paddr, 0x40, MemFlags::READ, translate, |vaddr, len| {
+            flushed = Some((vaddr, len))
+        });
+        assert_eq!(flushed, Some((paddr, 0x40)));
+    }
 }
@@ -15,21 +15,52 @@
 #![cfg_attr(not(feature = "intel"), allow(dead_code))]
 
 use bitflags::bitflags;
+use spin::Once;
 
-pub use raw_cpuid::{cpuid, CpuId};
+pub use raw_cpuid::{cpuid, CpuId, CpuIdResult};
 
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[allow(dead_code)]
 pub(super) enum CpuIdEax {
     VendorInfo = 0x0,
     FeatureInfo = 0x1,
+    ExtendedTopologyInfo = 0xB,
     ExtendedStateInfo = 0xD,
+    V2ExtendedTopologyInfo = 0x1F,
     HypervisorInfo = 0x4000_0000,
     HypervisorFeatures = 0x4000_0001,
+    ExtendedFunctionInfo = 0x8000_0000,
     AmdFeatureInfo = 0x8000_0001,
+    AddressSizeInfo = 0x8000_0008,
 }
 
+/// Leaves memoized by `CpuFeatures::leaf()` (with sub-leaf 0).
+///
+/// Standard leaves start from 0x0, their max number is reported in EAX of leaf 0x0; extended
+/// leaves start from 0x8000_0000, their max number is reported in EAX of leaf 0x8000_0000.
+const MEMOIZED_LEAVES: [CpuIdEax; 5] = [
+    CpuIdEax::VendorInfo,
+    CpuIdEax::FeatureInfo,
+    CpuIdEax::ExtendedStateInfo,
+    CpuIdEax::ExtendedFunctionInfo,
+    CpuIdEax::AmdFeatureInfo,
+];
+
+/// Results of the `MEMOIZED_LEAVES`, filled on first use.
+struct LeafCache([Once<CpuIdResult>; MEMOIZED_LEAVES.len()]);
+
+impl LeafCache {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: Once<CpuIdResult> = Once::new();
+        Self([EMPTY; MEMOIZED_LEAVES.len()])
+    }
+}
+
+/// The leaves are the same on every CPU, so they are read once for all the `CpuFeatures`.
+static NATIVE_LEAVES: LeafCache = LeafCache::new();
+
 bitflags! {
     /// Copied from https://docs.rs/raw-cpuid/8.1.2/src/raw_cpuid/lib.rs.html#1290-1294
     pub(super) struct FeatureInfoFlags: u64 {
@@ -167,8 +198,38 @@ bitflags! {
     }
 }
 
+/// CPU vendor, decoded from the vendor string of CPUID leaf 0.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CpuVendor {
+    /// "GenuineIntel", supported by the VMX backend.
+    Intel,
+    /// "AuthenticAMD", supported by the SVM backend, and required by SME.
+    Amd,
+    Unknown,
+}
+
+impl CpuVendor {
+    /// Decode the 12-byte vendor string returned in EBX, EDX and ECX by CPUID leaf 0.
+    fn from_leaf(res: CpuIdResult) -> Self {
+        let mut vendor = [0u8; 12];
+        vendor[..4].copy_from_slice(&res.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&res.edx.to_le_bytes());
+        vendor[8..].copy_from_slice(&res.ecx.to_le_bytes());
+        match &vendor {
+            b"GenuineIntel" => Self::Intel,
+            b"AuthenticAMD" => Self::Amd,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// CPUID.80000001H:EDX[26], 1-GByte pages are available in the 4-level and 5-level paging.
+const PDPE1GB: u32 = 1 << 26;
+
 pub struct CpuFeatures {
     cpuid: CpuId,
+    read_leaf: fn(u32, u32) -> CpuIdResult,
+    leaves: &'static LeafCache,
     // pub struct CpuId {
     // read: CpuIdReader,
     // vendor: Vendor,
@@ -179,11 +240,50 @@ pub struct CpuFeatures {
 
 impl CpuFeatures {
     pub fn new() -> Self {
+        Self::with_reader(|eax, ecx| cpuid!(eax, ecx), &NATIVE_LEAVES)
+    }
+
+    fn with_reader(read_leaf: fn(u32, u32) -> CpuIdResult, leaves: &'static LeafCache) -> Self {
         Self {
             cpuid: CpuId::new(),
+            read_leaf,
+            leaves,
         }
     }
 
+    /// Execute CPUID with `eax` and `ecx` as leaf and sub-leaf, the result of the leaves in
+    /// `MEMOIZED_LEAVES` is cached globally.
+    pub fn leaf(&self, eax: u32, ecx: u32) -> CpuIdResult {
+        let cached = if ecx == 0 {
+            MEMOIZED_LEAVES.iter().position(|l| *l as u32 == eax)
+        } else {
+            None
+        };
+        match cached {
+            Some(i) => *self.leaves.0[i].call_once(|| (self.read_leaf)(eax, ecx)),
+            None => (self.read_leaf)(eax, ecx),
+        }
+    }
+
+    /// The max supported standard leaf.
+    pub fn max_leaf(&self) -> u32 {
+        self.leaf(CpuIdEax::VendorInfo as u32, 0).eax
+    }
+
+    /// The CPU vendor.
+    pub fn vendor(&self) -> CpuVendor {
+        CpuVendor::from_leaf(self.leaf(CpuIdEax::VendorInfo as u32, 0))
+    }
+
+    pub fn is_amd(&self) -> bool {
+        self.vendor() == CpuVendor::Amd
+    }
+
+    /// The max supported extended leaf.
+    pub fn max_extended_leaf(&self) -> u32 {
+        self.leaf(CpuIdEax::ExtendedFunctionInfo as u32, 0).eax
+    }
+
     pub fn perf_monitor_version_id(&self) -> u8 {
         if let Some(info) = self.cpuid.get_performance_monitoring_info() {
             info.version_id()
@@ -224,6 +324,85 @@ impl CpuFeatures {
         }
     }
 
+    /// Whether 1GB pages can be mapped by PDPTEs, see `PDPE1GB`.
+    pub fn has_1gb_pages(&self) -> bool {
+        self.max_extended_leaf() >= CpuIdEax::AmdFeatureInfo as u32
+            && self.leaf(CpuIdEax::AmdFeatureInfo as u32, 0).edx & PDPE1GB != 0
+    }
+
+    /// The number of physical address bits (MAXPHYADDR), 36 if it's not reported.
+    pub fn phys_addr_bits(&self) -> u32 {
+        if self.max_extended_leaf() >= CpuIdEax::AddressSizeInfo as u32 {
+            self.leaf(CpuIdEax::AddressSizeInfo as u32, 0).eax & 0xff
+        } else {
+            36
+        }
+    }
+
+    pub fn has_invariant_tsc(&self) -> bool {
+        if let Some(info) = self.cpuid.get_advanced_power_mgmt_info() {
+            info.has_invariant_tsc()
+        } else {
+            false
+        }
+    }
+
+    /// Display family, the extended family is added to a base family of 0xf.
+    pub fn family(&self) -> u32 {
+        let eax = self.leaf(CpuIdEax::FeatureInfo as u32, 0).eax;
+        let family = (eax >> 8) & 0xf;
+        if family == 0xf {
+            family + ((eax >> 20) & 0xff)
+        } else {
+            family
+        }
+    }
+
+    /// TSC frequency in Hz, from the TSC/crystal ratio (leaf 0x15) or the processor base
+    /// frequency (leaf 0x16). These leaves are Intel only.
+    pub fn tsc_hz(&self) -> Option<u64> {
+        let tsc_info = self.cpuid.get_tsc_info();
+        if let Some(hz) = tsc_info.and_then(|info| info.tsc_frequency()) {
+            return Some(hz);
+        }
+        self.cpuid
+            .get_processor_frequency_info()
+            .map(|info| info.processor_base_frequency() as u64 * 1_000_000)
+            .filter(|&hz| hz != 0)
+    }
+
+    pub fn has_pcid(&self) -> bool {
+        if let Some(info) = self.cpuid.get_feature_info() {
+            info.has_pcid()
+        } else {
+            false
+        }
+    }
+
+    pub fn has_smep(&self) -> bool {
+        if let Some(info) = self.cpuid.get_extended_feature_info() {
+            info.has_smep()
+        } else {
+            false
+        }
+    }
+
+    pub fn has_smap(&self) -> bool {
+        if let Some(info) = self.cpuid.get_extended_feature_info() {
+            info.has_smap()
+        } else {
+            false
+        }
+    }
+
+    pub fn has_la57(&self) -> bool {
+        if let Some(info) = self.cpuid.get_extended_feature_info() {
+            info.has_la57()
+        } else {
+            false
+        }
+    }
+
     pub fn has_invpcid(&self) -> bool {
         if let Some(info) = self.cpuid.get_extended_feature_info() {
             info.has_invpcid()
@@ -244,7 +423,7 @@ impl CpuFeatures {
     /// if it is supported in XCR0
     pub fn xsave_state_info(&self, sub_leaf: u32) -> (usize, usize) {
         if self.cpuid.get_extended_state_info().is_some() && sub_leaf > 1 {
-            let res = cpuid!(CpuIdEax::ExtendedStateInfo, sub_leaf);
+            let res = self.leaf(CpuIdEax::ExtendedStateInfo as u32, sub_leaf);
             // If ECX contains an invalid sub-leaf index, EAX/EBX/ECX/EDX return 0
             if res.eax != 0 && res.ebx != 0 && res.ecx != 0 && res.edx != 0 {
                 // Bit 00 in ECX is clear if sub_leaf n is supported in XCR0
@@ -261,10 +440,138 @@ impl CpuFeatures {
 
     pub fn xcr0_supported_bits(&self) -> u64 {
         if self.cpuid.get_extended_state_info().is_some() {
-            let res = cpuid!(CpuIdEax::ExtendedStateInfo, 0);
+            let res = self.leaf(CpuIdEax::ExtendedStateInfo as u32, 0);
             (res.eax as u64) | (res.edx as u64) << 32
         } else {
             0
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static READS: AtomicUsize = AtomicUsize::new(0);
+
+    fn mock_leaf(eax: u32, ecx: u32) -> CpuIdResult {
+        READS.fetch_add(1, Ordering::SeqCst);
+        let eax = match eax {
+            0x0 => 0xd,
+            0x8000_0000 => 0x8000_001f,
+            _ => eax ^ ecx,
+        };
+        CpuIdResult {
+            eax,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        }
+    }
+
+    /// `CpuFeatures` reading `read_leaf`, with a cache of its own.
+    fn mock_features(read_leaf: fn(u32, u32) -> CpuIdResult) -> CpuFeatures {
+        CpuFeatures::with_reader(read_leaf, Box::leak(Box::new(LeafCache::new())))
+    }
+
+    #[test]
+    fn test_leaf_memoized() {
+        static LEAVES: LeafCache = LeafCache::new();
+        let features = CpuFeatures::with_reader(mock_leaf, &LEAVES);
+        let reads = READS.load(Ordering::SeqCst);
+        assert_eq!(features.max_leaf(), 0xd);
+        // The cache is shared by every instance.
+        assert_eq!(CpuFeatures::with_reader(mock_leaf, &LEAVES).max_leaf(), 0xd);
+        assert_eq!(features.max_extended_leaf(), 0x8000_001f);
+        assert_eq!(features.max_extended_leaf(), 0x8000_001f);
+        assert_eq!(READS.load(Ordering::SeqCst) - reads, 2);
+
+        // Non-zero sub-leaves are never cached.
+        assert_eq!(features.leaf(0xd, 2).eax, 0xf);
+        assert_eq!(features.leaf(0xd, 2).eax, 0xf);
+        assert_eq!(READS.load(Ordering::SeqCst) - reads, 4);
+    }
+
+    #[test]
+    fn test_has_1gb_pages() {
+        fn with_1gb(eax: u32, _ecx: u32) -> CpuIdResult {
+            CpuIdResult {
+                eax: if eax == 0x8000_0000 { 0x8000_0008 } else { 0 },
+                ebx: 0,
+                ecx: 0,
+                edx: if eax == 0x8000_0001 { PDPE1GB } else { 0 },
+            }
+        }
+        fn without_1gb(eax: u32, _ecx: u32) -> CpuIdResult {
+            CpuIdResult {
+                eax: if eax == 0x8000_0000 { 0x8000_0008 } else { 0 },
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            }
+        }
+        fn no_extended_leaf(eax: u32, _ecx: u32) -> CpuIdResult {
+            let mut res = with_1gb(eax, 0);
+            if eax == 0x8000_0000 {
+                res.eax = 0x8000_0000;
+            }
+            res
+        }
+        assert!(mock_features(with_1gb).has_1gb_pages());
+        assert!(!mock_features(without_1gb).has_1gb_pages());
+        assert!(!mock_features(no_extended_leaf).has_1gb_pages());
+    }
+
+    #[test]
+    fn test_leaf_native() {
+        let features = CpuFeatures::new();
+        assert!(features.max_leaf() >= CpuIdEax::FeatureInfo as u32);
+        assert!(features.max_extended_leaf() >= CpuIdEax::ExtendedFunctionInfo as u32);
+        assert_eq!(features.leaf(0, 0), cpuid!(0, 0));
+    }
+
+    #[test]
+    fn test_vendor() {
+        /// Leaf 0 reporting `vendor`, other leaves are empty.
+        fn vendor_leaf(eax: u32, vendor: &[u8; 12]) -> CpuIdResult {
+            let reg = |i: usize| u32::from_le_bytes(vendor[i..i + 4].try_into().unwrap());
+            match eax {
+                0 => CpuIdResult {
+                    eax: 0xd,
+                    ebx: reg(0),
+                    ecx: reg(8),
+                    edx: reg(4),
+                },
+                _ => CpuIdResult {
+                    eax: 0,
+                    ebx: 0,
+                    ecx: 0,
+                    edx: 0,
+                },
+            }
+        }
+        fn intel(eax: u32, _ecx: u32) -> CpuIdResult {
+            vendor_leaf(eax, b"GenuineIntel")
+        }
+        fn amd(eax: u32, _ecx: u32) -> CpuIdResult {
+            vendor_leaf(eax, b"AuthenticAMD")
+        }
+        fn unknown(eax: u32, _ecx: u32) -> CpuIdResult {
+            vendor_leaf(eax, b"HygonGenuine")
+        }
+
+        let features = mock_features(intel);
+        assert_eq!(features.vendor(), CpuVendor::Intel);
+        assert!(!features.is_amd());
+
+        let features = mock_features(amd);
+        assert_eq!(features.vendor(), CpuVendor::Amd);
+        assert!(features.is_amd());
+
+        let features = mock_features(unknown);
+        assert_eq!(features.vendor(), CpuVendor::Unknown);
+        assert!(!features.is_amd());
+    }
+}
@@ -291,7 +291,7 @@ impl EnclaveThreadState {
         vcpu.store_enclave_thread_state(aep, normal_world_state, false)?;
 
         let regs = vcpu.regs_mut();
-        *regs = Default::default(); // scrub enclave context
+        regs.wipe(); // scrub enclave context
         regs.rax = crate::hypercall::HyperCallCode::EnclaveResume as _;
         regs.rbx = tcs_vaddr as _;
         regs.rcx = aep;
@@ -15,6 +15,8 @@
 use bitflags::bitflags;
 
 use super::context::GuestRegisters;
+use super::page_table::with_active_table;
+use crate::memory::PageFault;
 
 global_asm!(include_str!(concat!(env!("OUT_DIR"), "/exception.S")));
 
@@ -152,9 +154,12 @@ fn handle_nmi() {
 }
 
 fn handle_page_fault(frame: &ExceptionFrame) {
+    let cr2 = x86_64::registers::control::Cr2::read().as_u64();
+    let path = with_active_table(|pt| pt.walk_path(cr2 as _));
+    error!("Translation path of {:#x}: {:#x?}", cr2, path);
     panic!(
-        "Unhandled hypervisor page fault @ {:#x?}, error_code={:#x}: {:#x?}",
-        x86_64::registers::control::Cr2::read(),
+        "Unhandled hypervisor page fault: {:#x?}, error_code={:#x}: {:#x?}",
+        PageFault::from_x86(frame.error_code as u64, cr2),
         frame.error_code,
         frame
     );
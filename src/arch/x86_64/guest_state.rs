@@ -0,0 +1,270 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed access to the guest state kept by the hardware in the VMCS (Intel) or the VMCB (AMD).
+//!
+//! The backend is chosen by the `intel`/`amd` feature, `check_vendor()` makes sure it matches
+//! the vendor reported by CPUID.
+
+use core::convert::TryInto;
+
+use super::cpuid::{CpuFeatures, CpuVendor};
+use crate::error::HvResult;
+
+/// Guest state fields accessed on VM exits.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GuestField {
+    Rip,
+    Rsp,
+    Rflags,
+    Cr3,
+    Efer,
+}
+
+impl GuestField {
+    /// Field encoding used by VMREAD/VMWRITE, see SDM Vol. 3, Appendix B.
+    #[cfg(any(feature = "intel", test))]
+    pub const fn vmcs_encoding(self) -> u32 {
+        match self {
+            Self::Rip => 0x681e,
+            Self::Rsp => 0x681c,
+            Self::Rflags => 0x6820,
+            Self::Cr3 => 0x6802,
+            Self::Efer => 0x2806,
+        }
+    }
+
+    /// Byte offset of the field in the VMCB, see APM Vol. 2, Appendix B. The state save area
+    /// starts at 0x400, after the control area.
+    #[cfg(any(feature = "amd", test))]
+    pub const fn vmcb_offset(self) -> usize {
+        0x400
+            + match self {
+                Self::Rip => 0x178,
+                Self::Rsp => 0x1d8,
+                Self::Rflags => 0x170,
+                Self::Cr3 => 0x150,
+                Self::Efer => 0xd0,
+            }
+    }
+}
+
+/// Read and write guest state fields.
+pub trait GuestStateAccess {
+    fn read(&self, field: GuestField) -> HvResult<u64>;
+    fn write(&mut self, field: GuestField, value: u64) -> HvResult;
+
+    fn rip(&self) -> HvResult<u64> {
+        self.read(GuestField::Rip)
+    }
+    fn set_rip(&mut self, rip: u64) -> HvResult {
+        self.write(GuestField::Rip, rip)
+    }
+    fn rsp(&self) -> HvResult<u64> {
+        self.read(GuestField::Rsp)
+    }
+    fn set_rsp(&mut self, rsp: u64) -> HvResult {
+        self.write(GuestField::Rsp, rsp)
+    }
+    fn rflags(&self) -> HvResult<u64> {
+        self.read(GuestField::Rflags)
+    }
+    fn cr3(&self) -> HvResult<u64> {
+        self.read(GuestField::Cr3)
+    }
+    fn set_cr3(&mut self, cr3: u64) -> HvResult {
+        self.write(GuestField::Cr3, cr3)
+    }
+    fn efer(&self) -> HvResult<u64> {
+        self.read(GuestField::Efer)
+    }
+}
+
+/// Access the current VMCS with VMREAD/VMWRITE.
+#[cfg(feature = "intel")]
+pub struct VmcsAccess;
+
+#[cfg(feature = "intel")]
+impl GuestStateAccess for VmcsAccess {
+    fn read(&self, field: GuestField) -> HvResult<u64> {
+        unsafe { x86::bits64::vmx::vmread(field.vmcs_encoding()) }.map_err(Into::into)
+    }
+
+    fn write(&mut self, field: GuestField, value: u64) -> HvResult {
+        unsafe { x86::bits64::vmx::vmwrite(field.vmcs_encoding(), value) }.map_err(Into::into)
+    }
+}
+
+/// Access a VMCB in memory.
+#[cfg(any(feature = "amd", test))]
+pub struct VmcbAccess<'a> {
+    vmcb: &'a mut [u8],
+}
+
+#[cfg(any(feature = "amd", test))]
+impl<'a> VmcbAccess<'a> {
+    #[cfg(feature = "amd")]
+    pub fn new(vmcb: &'a mut libvmm::svm::Vmcb) -> Self {
+        let size = core::mem::size_of_val(vmcb);
+        Self::from_bytes(unsafe {
+            core::slice::from_raw_parts_mut(vmcb as *mut _ as *mut u8, size)
+        })
+    }
+
+    fn from_bytes(vmcb: &'a mut [u8]) -> Self {
+        assert!(vmcb.len() >= 0x1000);
+        Self { vmcb }
+    }
+}
+
+#[cfg(any(feature = "amd", test))]
+impl GuestStateAccess for VmcbAccess<'_> {
+    fn read(&self, field: GuestField) -> HvResult<u64> {
+        let offset = field.vmcb_offset();
+        let bytes = self.vmcb[offset..offset + 8].try_into().unwrap();
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn write(&mut self, field: GuestField, value: u64) -> HvResult {
+        let offset = field.vmcb_offset();
+        self.vmcb[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// CPU vendor supported by the compiled-in backend.
+#[cfg(feature = "intel")]
+const BACKEND_VENDOR: CpuVendor = CpuVendor::Intel;
+#[cfg(feature = "amd")]
+const BACKEND_VENDOR: CpuVendor = CpuVendor::Amd;
+
+/// Check that the CPU vendor matches the compiled-in VMCS or VMCB backend.
+pub fn check_vendor() -> HvResult {
+    check_backend_vendor(CpuFeatures::new().vendor(), BACKEND_VENDOR)
+}
+
+fn check_backend_vendor(vendor: CpuVendor, expected: CpuVendor) -> HvResult {
+    if vendor == CpuVendor::Unknown {
+        return hv_result_err!(ENODEV, "Unknown CPU vendor, expect Intel or AMD");
+    }
+    if vendor != expected {
+        return hv_result_err!(
+            ENODEV,
+            format!("Unsupported CPU vendor {:?}, expect {:?}", vendor, expected)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [GuestField; 5] = [
+        GuestField::Rip,
+        GuestField::Rsp,
+        GuestField::Rflags,
+        GuestField::Cr3,
+        GuestField::Efer,
+    ];
+
+    /// Keeps the fields in an array indexed by their VMCS encoding order.
+    struct MockAccess([u64; ALL.len()]);
+
+    impl GuestStateAccess for MockAccess {
+        fn read(&self, field: GuestField) -> HvResult<u64> {
+            Ok(self.0[ALL.iter().position(|&f| f == field).unwrap()])
+        }
+        fn write(&mut self, field: GuestField, value: u64) -> HvResult {
+            self.0[ALL.iter().position(|&f| f == field).unwrap()] = value;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_typed_access() {
+        let mut state = MockAccess([0; ALL.len()]);
+        state.set_rip(0xffff_8000_0000_1000).unwrap();
+        state.set_cr3(0x1234_5000).unwrap();
+        assert_eq!(state.rip().unwrap(), 0xffff_8000_0000_1000);
+        assert_eq!(state.cr3().unwrap(), 0x1234_5000);
+        assert_eq!(state.read(GuestField::Cr3).unwrap(), 0x1234_5000);
+        assert_eq!(state.rsp().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_vmcs_encoding() {
+        // Natural-width guest-state fields have type 0b11 (bits 14:13) and 0b10 (bits 11:10),
+        // EFER is a 64-bit guest-state field.
+        for field in ALL {
+            let encoding = field.vmcs_encoding();
+            if field == GuestField::Efer {
+                assert_eq!(encoding >> 10, 0b00_1010);
+            } else {
+                assert_eq!(encoding >> 10, 0b01_1010);
+            }
+        }
+        #[cfg(feature = "intel")]
+        {
+            use libvmm::vmx::vmcs::VmcsField64Guest;
+            assert_eq!(
+                GuestField::Rip.vmcs_encoding(),
+                VmcsField64Guest::RIP as u32
+            );
+            assert_eq!(
+                GuestField::Cr3.vmcs_encoding(),
+                VmcsField64Guest::CR3 as u32
+            );
+            assert_eq!(
+                GuestField::Efer.vmcs_encoding(),
+                VmcsField64Guest::IA32_EFER as u32
+            );
+        }
+    }
+
+    #[test]
+    fn test_vmcb_offset() {
+        let mut vmcb = [0u8; 0x1000];
+        vmcb[0x578..0x580].copy_from_slice(&0x1000u64.to_le_bytes());
+        let mut state = VmcbAccess::from_bytes(&mut vmcb);
+        assert_eq!(state.rip().unwrap(), 0x1000);
+        state.set_rsp(0x2000).unwrap();
+        assert_eq!(state.read(GuestField::Rsp).unwrap(), 0x2000);
+        assert_eq!(vmcb[0x5d9], 0x20);
+
+        #[cfg(feature = "amd")]
+        {
+            use libvmm::svm::vmcb::VmcbStateSaveArea;
+            use memoffset::offset_of;
+            assert_eq!(
+                GuestField::Rip.vmcb_offset(),
+                0x400 + offset_of!(VmcbStateSaveArea, rip)
+            );
+            assert_eq!(
+                GuestField::Efer.vmcb_offset(),
+                0x400 + offset_of!(VmcbStateSaveArea, efer)
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_vendor() {
+        use CpuVendor::*;
+
+        assert!(check_backend_vendor(Intel, Intel).is_ok());
+        assert!(check_backend_vendor(Amd, Amd).is_ok());
+        assert!(check_backend_vendor(Amd, Intel).is_err());
+        assert!(check_backend_vendor(Unknown, Intel).is_err());
+    }
+}
@@ -24,9 +24,10 @@ use libvmm::vmx::flags::{EptpFlags, InvEptType, VmxEptVpidCap};
 use crate::error::HvResult;
 use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
 use crate::memory::{
-    GenericPTE, Level4PageTable, Level4PageTableUnlocked, MemFlags, PageTableLevel, PagingInstr,
+    CachePolicy, GenericPTE, Level4PageTable, Level4PageTableUnlocked, MemFlags, PageTableLevel,
+    PagingInstr,
 };
-use crate::memory::{PagingError, PagingResult};
+use crate::memory::{PagingError, PagingResult, Stage2PageTable};
 
 bitflags! {
     struct EPTFlags: u64 {
@@ -129,6 +130,12 @@ impl GenericPTE for EPTEntry {
     fn flags(&self) -> MemFlags {
         self.ept_flags().into()
     }
+    fn raw(&self) -> u64 {
+        self.0
+    }
+    fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
     fn is_unused(&self) -> bool {
         self.0 == 0
     }
@@ -155,11 +162,17 @@ impl GenericPTE for EPTEntry {
         self.0.set_bits(12..52, paddr as u64 >> 12);
     }
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool) -> PagingResult {
+        // Device MMIO passed through to the guest must stay uncached.
+        let mem_type = match CachePolicy::from_flags(flags) {
+            CachePolicy::WriteBack => EPTMemType::WriteBack,
+            CachePolicy::WriteCombining => EPTMemType::WriteCombining,
+            CachePolicy::Uncached => EPTMemType::Uncached,
+        };
         let mut flags = EPTFlags::try_from(flags)?;
         if is_huge {
             flags |= EPTFlags::HUGE_PAGE;
         }
-        self.set_flags_and_mem_type(flags, EPTMemType::WriteBack);
+        self.set_flags_and_mem_type(flags, mem_type);
         Ok(())
     }
     fn set_table(
@@ -261,3 +274,6 @@ lazy_static! {
 pub type ExtendedPageTable = Level4PageTable<GuestPhysAddr, EPTEntry, EPTInstr>;
 pub type EnclaveExtendedPageTableUnlocked =
     Level4PageTableUnlocked<GuestPhysAddr, EPTEntry, EPTInstr>;
+
+impl Stage2PageTable for ExtendedPageTable {}
+impl Stage2PageTable for EnclaveExtendedPageTableUnlocked {}
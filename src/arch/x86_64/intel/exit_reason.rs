@@ -0,0 +1,246 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::convert::TryFrom;
+
+use bit_field::BitField;
+use libvmm::vmx::vmcs::{VmcsField32ReadOnly, VmcsField64ReadOnly};
+use libvmm::vmx::VmxExitReason;
+
+use crate::error::HvResult;
+use crate::memory::{FaultAccess, PageFault};
+
+/// Information about an EPT violation, decoded from the exit qualification (SDM Vol. 3,
+/// 27.2.1, Table 27-7).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct EptViolation {
+    pub read: bool,
+    pub write: bool,
+    pub instruction: bool,
+    /// The guest-physical address was mapped (readable, writable or executable), i.e. the
+    /// violation is a permission one.
+    pub present: bool,
+    /// The access is to the final guest-physical address, not to a guest paging structure.
+    pub final_translation: bool,
+    /// The faulting guest-physical address.
+    pub guest_paddr: usize,
+}
+
+impl EptViolation {
+    /// Decode the exit qualification of an EPT violation and the guest-physical address field.
+    pub fn decode(qualification: u64, guest_paddr: u64) -> Self {
+        Self {
+            read: qualification.get_bit(0),
+            write: qualification.get_bit(1),
+            instruction: qualification.get_bit(2),
+            present: qualification.get_bits(3..6) != 0,
+            // We donnot support PAE paging and TAPT, such bit is always valid.
+            final_translation: qualification.get_bit(8),
+            guest_paddr: guest_paddr as _,
+        }
+    }
+
+    /// Returns the generic description of the violation, whose address is the guest-physical
+    /// one. The qualification doesn't report the guest privilege level, `user` is always false.
+    pub fn to_fault(&self) -> PageFault {
+        let access = if self.instruction {
+            FaultAccess::Execute
+        } else if self.write {
+            FaultAccess::Write
+        } else {
+            FaultAccess::Read
+        };
+        PageFault {
+            addr: self.guest_paddr,
+            access,
+            present: self.present,
+            user: false,
+        }
+    }
+}
+
+/// A MOV to a control register, decoded from the exit qualification of a control-register
+/// access (SDM Vol. 3, 27.2.1, Table 27-3).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MovToCr {
+    /// The control register number.
+    pub cr: usize,
+    /// The source general-purpose register, in the `GuestRegisters` order: 0 is RAX, 4 is RSP.
+    pub gpr: usize,
+}
+
+impl MovToCr {
+    /// Decode the exit qualification of a control-register access, `None` if it's not a MOV
+    /// to a control register (MOV from CR, CLTS or LMSW).
+    pub fn decode(qualification: u64) -> Option<Self> {
+        if qualification.get_bits(4..6) != 0 {
+            return None;
+        }
+        Some(Self {
+            cr: qualification.get_bits(0..4) as _,
+            gpr: qualification.get_bits(8..12) as _,
+        })
+    }
+}
+
+/// Why the guest exited, decoded from the VM-exit information fields of the VMCS.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ExitReason {
+    ExceptionNmi,
+    ExternalInterrupt,
+    TripleFault,
+    Cpuid,
+    /// VMCALL, used for hypercalls.
+    Hypercall,
+    MsrRead,
+    MsrWrite,
+    EptViolation(EptViolation),
+    MovToCr(MovToCr),
+    /// Other exit reasons, not handled by the hypervisor.
+    Other(VmxExitReason),
+    /// A basic exit reason unknown to `VmxExitReason`.
+    Unknown(u16),
+}
+
+impl ExitReason {
+    /// Read and decode the exit reason of the last VM exit.
+    pub fn read() -> HvResult<Self> {
+        let full_reason = VmcsField32ReadOnly::VM_EXIT_REASON.read()?;
+        let basic = full_reason.get_bits(0..16);
+        let (qualification, guest_paddr) = if basic == VmxExitReason::EPT_VIOLATION as u32 {
+            (
+                VmcsField64ReadOnly::EXIT_QUALIFICATION.read()?,
+                VmcsField64ReadOnly::GUEST_PHYSICAL_ADDRESS.read()?,
+            )
+        } else if basic == VmxExitReason::CR_ACCESS as u32 {
+            (VmcsField64ReadOnly::EXIT_QUALIFICATION.read()?, 0)
+        } else {
+            (0, 0)
+        };
+        Ok(Self::decode(full_reason, qualification, guest_paddr))
+    }
+
+    /// Decode the full exit reason, whose bits 15:0 hold the basic exit reason (SDM Vol. 3,
+    /// 24.9.1), with the exit qualification and the guest-physical address fields.
+    pub fn decode(full_reason: u32, qualification: u64, guest_paddr: u64) -> Self {
+        let basic = full_reason.get_bits(0..16);
+        let reason = match VmxExitReason::try_from(basic) {
+            Ok(reason) => reason,
+            Err(_) => return Self::Unknown(basic as u16),
+        };
+        match reason {
+            VmxExitReason::EXCEPTION_NMI => Self::ExceptionNmi,
+            VmxExitReason::EXTERNAL_INTERRUPT => Self::ExternalInterrupt,
+            VmxExitReason::TRIPLE_FAULT => Self::TripleFault,
+            VmxExitReason::CPUID => Self::Cpuid,
+            VmxExitReason::VMCALL => Self::Hypercall,
+            VmxExitReason::MSR_READ => Self::MsrRead,
+            VmxExitReason::MSR_WRITE => Self::MsrWrite,
+            VmxExitReason::EPT_VIOLATION => {
+                Self::EptViolation(EptViolation::decode(qualification, guest_paddr))
+            }
+            VmxExitReason::CR_ACCESS => match MovToCr::decode(qualification) {
+                Some(info) => Self::MovToCr(info),
+                None => Self::Other(reason),
+            },
+            reason => Self::Other(reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_exit_reason() {
+        assert_eq!(ExitReason::decode(10, 0, 0), ExitReason::Cpuid);
+        assert_eq!(ExitReason::decode(18, 0, 0), ExitReason::Hypercall);
+        assert_eq!(ExitReason::decode(31, 0, 0), ExitReason::MsrRead);
+        assert_eq!(ExitReason::decode(32, 0, 0), ExitReason::MsrWrite);
+        // Upper bits of the full exit reason are ignored.
+        assert_eq!(
+            ExitReason::decode(1 | 1 << 27, 0, 0),
+            ExitReason::ExternalInterrupt
+        );
+        assert_eq!(
+            ExitReason::decode(12, 0, 0),
+            ExitReason::Other(VmxExitReason::HLT)
+        );
+        assert_eq!(ExitReason::decode(0xfff, 0, 0), ExitReason::Unknown(0xfff));
+    }
+
+    #[test]
+    fn test_decode_mov_to_cr() {
+        // mov cr3, rbx
+        assert_eq!(
+            ExitReason::decode(28, 0x303, 0),
+            ExitReason::MovToCr(MovToCr { cr: 3, gpr: 3 })
+        );
+        // mov cr3, r15
+        assert_eq!(
+            ExitReason::decode(28, 0xf03, 0),
+            ExitReason::MovToCr(MovToCr { cr: 3, gpr: 15 })
+        );
+        // mov rax, cr3
+        assert_eq!(
+            ExitReason::decode(28, 0x13, 0),
+            ExitReason::Other(VmxExitReason::CR_ACCESS)
+        );
+    }
+
+    #[test]
+    fn test_decode_ept_violation() {
+        // Write access to the final translation.
+        let reason = ExitReason::decode(48, 0x182, 0x1234_5678);
+        assert_eq!(
+            reason,
+            ExitReason::EptViolation(EptViolation {
+                read: false,
+                write: true,
+                instruction: false,
+                present: false,
+                final_translation: true,
+                guest_paddr: 0x1234_5678,
+            })
+        );
+
+        // Instruction fetch during a guest page walk.
+        match ExitReason::decode(48, 0x84, 0x1000) {
+            ExitReason::EptViolation(info) => {
+                assert!(info.instruction && !info.final_translation);
+                assert_eq!(info.guest_paddr, 0x1000);
+            }
+            reason => panic!("unexpected {:?}", reason),
+        }
+    }
+
+    #[test]
+    fn test_ept_violation_to_fault() {
+        let kind = |qualification| {
+            let pf = EptViolation::decode(qualification, 0x8000_1000).to_fault();
+            assert_eq!(pf.addr, 0x8000_1000);
+            assert!(!pf.user);
+            (pf.access, pf.present)
+        };
+        // Read of an unmapped page.
+        assert_eq!(kind(0x181), (FaultAccess::Read, false));
+        // Write to a read-only page.
+        assert_eq!(kind(0x18a), (FaultAccess::Write, true));
+        // Instruction fetch from a readable, writable but not executable page.
+        assert_eq!(kind(0x19c), (FaultAccess::Execute, true));
+        // Write to an execute-only page, during a guest page walk (A/D bits update).
+        assert_eq!(kind(0x0a2), (FaultAccess::Write, true));
+    }
+}
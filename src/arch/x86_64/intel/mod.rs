@@ -14,6 +14,7 @@
 
 mod enclave;
 mod ept;
+mod exit_reason;
 mod structs;
 mod vcpu;
 mod vmexit;
@@ -25,6 +26,7 @@ use x86::vmx::VmFail;
 
 use crate::arch::cpu::check_cpuid;
 use crate::arch::cpuid::CpuFeatures;
+use crate::arch::guest_state::check_vendor;
 use crate::error::{HvError, HvResult};
 
 pub use ept::EPTEntry as NPTEntry;
@@ -56,6 +58,7 @@ impl From<VmFail> for HvError {
 pub fn check_hypervisor_feature() -> HvResult {
     // Check cpuid
     check_cpuid()?;
+    check_vendor()?;
 
     if !CpuFeatures::new().has_vmx() {
         warn!("Feature VMX not supported!");
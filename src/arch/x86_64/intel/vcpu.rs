@@ -30,12 +30,14 @@ use x86_64::registers::rflags::RFlags;
 
 use super::structs::{MsrBitmap, VmxRegion};
 use crate::arch::cpuid::CpuFeatures;
+use crate::arch::guest_state::VmcsAccess;
 use crate::arch::segmentation::{Segment, SegmentAccessRights};
 use crate::arch::tables::{GDTStruct, GDT, IDT};
 use crate::arch::vmm::VcpuAccessGuestState;
 use crate::arch::{GuestPageTableImmut, GuestRegisters, LinuxContext};
 use crate::cell::Cell;
 use crate::error::HvResult;
+use crate::memory::root_hook::GUEST_CR3_HOOK;
 
 #[repr(C)]
 pub struct Vcpu {
@@ -158,7 +160,7 @@ impl Vcpu {
         hv_result_err!(EIO)
     }
 
-    pub fn deactivate_vmm(&self, linux: &LinuxContext) -> HvResult {
+    pub fn deactivate_vmm(&self, linux: &mut LinuxContext) -> HvResult {
         self.guest_regs.return_to_linux(linux)
     }
 
@@ -328,12 +330,19 @@ impl Vcpu {
         )?;
 
         use vmx::flags::PrimaryVmExecControls as CpuCtrl;
+        // NO UNCOND_IO_EXITING to pass-through PIO
+        let mut set = CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SEC_CONTROLS;
+        let mut clear = CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING;
+        // Guest CR3 loads only exit when a hook wants to see them.
+        if GUEST_CR3_HOOK.is_registered() {
+            set |= CpuCtrl::CR3_LOAD_EXITING;
+            clear -= CpuCtrl::CR3_LOAD_EXITING;
+        }
         Vmcs::set_control(
             VmcsField32Control::PROC_BASED_VM_EXEC_CONTROL,
             Msr::IA32_VMX_PROCBASED_CTLS.read(),
-            // NO UNCOND_IO_EXITING to pass-through PIO
-            (CpuCtrl::USE_MSR_BITMAPS | CpuCtrl::SEC_CONTROLS).bits(),
-            (CpuCtrl::CR3_LOAD_EXITING | CpuCtrl::CR3_STORE_EXITING).bits(),
+            set.bits(),
+            clear.bits(),
         )?;
 
         use vmx::flags::SecondaryVmExecControls as CpuCtrl2;
@@ -400,11 +409,11 @@ impl VcpuAccessGuestState for Vcpu {
     }
 
     fn stack_pointer(&self) -> u64 {
-        VmcsField64Guest::RSP.read().unwrap()
+        self.guest_regs.guest_rsp(&VmcsAccess).unwrap()
     }
 
     fn set_stack_pointer(&mut self, sp: u64) {
-        VmcsField64Guest::RSP.write(sp).unwrap()
+        self.guest_regs.set_guest_rsp(&mut VmcsAccess, sp).unwrap()
     }
 
     fn rflags(&self) -> u64 {
@@ -506,6 +515,7 @@ unsafe extern "sysv64" fn vmx_exit() -> ! {
         save_regs_to_stack!(),
         "mov r15, rsp",         // save temporary RSP to r15
         "mov rsp, [rsp + {0}]", // set RSP to Vcpu::host_stack_top
+        "mov rdi, r15",         // pass the saved registers to vmexit_handler
         "call {1}",             // call vmexit_handler
         "mov rsp, r15",         // load temporary RSP from r15
         restore_regs_from_stack!(),
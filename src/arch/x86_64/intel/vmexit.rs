@@ -13,15 +13,16 @@
 // limitations under the License.
 
 use libvmm::vmx::flags::{InterruptInfo, InterruptType};
-use libvmm::vmx::vmcs::{
-    EptViolationInfo, ExitInterruptInfo, VmExitInfo, VmcsField32ReadOnly, VmcsField64ReadOnly,
-};
-use libvmm::vmx::{Vmcs, VmxExitReason};
+use libvmm::vmx::vmcs::{ExitInterruptInfo, VmExitInfo, VmcsField32ReadOnly, VmcsField64ReadOnly};
+use libvmm::vmx::Vmcs;
 
-use crate::arch::vmm::VmExit;
+use super::exit_reason::{EptViolation, ExitReason, MovToCr};
+
+use crate::arch::vmm::{VcpuAccessGuestState, VmExit};
 use crate::arch::{EnclaveExceptionInfo, ExceptionType};
 use crate::enclave::{AexException, EnclaveStatsId};
 use crate::error::HvResult;
+use crate::memory::root_hook::on_guest_cr3_write;
 use crate::percpu::CpuState;
 use crate::stats::Instant;
 
@@ -91,19 +92,39 @@ impl VmExit<'_> {
         Ok(())
     }
 
-    fn handle_ept_violation(&mut self, exit_info: &VmExitInfo) -> HvResult {
-        let ept_vio_info = EptViolationInfo::new()?;
-        let guest_paddr = ept_vio_info.guest_paddr;
-        if self.cpu_data.state == CpuState::EnclaveRunning {
-            let enclave = self.cpu_data.get_current_enclave()?;
-            enclave.handle_npt_violation(guest_paddr, ept_vio_info.final_translation)?;
-            return Ok(());
+    fn handle_ept_violation(
+        &mut self,
+        exit_info: &VmExitInfo,
+        ept_vio_info: &EptViolation,
+    ) -> HvResult {
+        if self.cpu_data.state != CpuState::EnclaveRunning {
+            warn!(
+                "VM exit: EPT violation @ {:#x} RIP({:#x}, {}): {:#x?}",
+                ept_vio_info.guest_paddr,
+                exit_info.guest_rip,
+                exit_info.exit_instruction_length,
+                ept_vio_info
+            );
         }
-        warn!(
-            "VM exit: EPT violation @ {:#x} RIP({:#x}, {}): {:#x?}",
-            guest_paddr, exit_info.guest_rip, exit_info.exit_instruction_length, ept_vio_info
-        );
-        hv_result_err!(ENOSYS)
+        self.handle_stage2_fault(&ept_vio_info.to_fault(), ept_vio_info.final_translation)
+    }
+
+    /// Only MOV to CR3 exits, and only while a CR3 hook is registered, see `setup_vmcs_control()`.
+    fn handle_mov_to_cr(&mut self, exit_info: &VmExitInfo, info: &MovToCr) -> HvResult {
+        if info.cr != 3 {
+            return hv_result_err!(ENOSYS, format!("Unexpected MOV to CR{}", info.cr));
+        }
+        let vcpu = &mut self.cpu_data.vcpu;
+        let val = match vcpu.regs().gpr(info.gpr) {
+            Some(val) => val,
+            None => vcpu.stack_pointer(),
+        };
+        // Bit 63 only asks not to flush the PCID translations, it's not stored in CR3.
+        let val = val & !(1 << 63);
+        let old = vcpu.cr(3);
+        vcpu.set_cr(3, val);
+        on_guest_cr3_write(old, val);
+        vcpu.advance_rip(exit_info.exit_instruction_length as _)
     }
 
     pub fn inject_exception(&mut self, enclave_exception: EnclaveExceptionInfo) -> HvResult {
@@ -171,15 +192,17 @@ impl VmExit<'_> {
         //     exit_info.exit_instruction_length as _,
         // )?;
 
-        let res = match exit_info.exit_reason {
-            VmxExitReason::EXCEPTION_NMI => self.handle_exception_nmi(&exit_info),
-            VmxExitReason::EXTERNAL_INTERRUPT => self.handle_external_interrupt(&exit_info),
-            VmxExitReason::CPUID => self.handle_cpuid(),
-            VmxExitReason::VMCALL => self.handle_hypercall(),
-            VmxExitReason::MSR_READ => self.handle_msr_read(),
-            VmxExitReason::MSR_WRITE => self.handle_msr_write(),
-            VmxExitReason::EPT_VIOLATION => self.handle_ept_violation(&exit_info),
-            VmxExitReason::TRIPLE_FAULT => {
+        let reason = ExitReason::read()?;
+        let res = match reason {
+            ExitReason::ExceptionNmi => self.handle_exception_nmi(&exit_info),
+            ExitReason::ExternalInterrupt => self.handle_external_interrupt(&exit_info),
+            ExitReason::Cpuid => self.handle_cpuid(),
+            ExitReason::Hypercall => self.handle_hypercall(),
+            ExitReason::MsrRead => self.handle_msr_read(),
+            ExitReason::MsrWrite => self.handle_msr_write(),
+            ExitReason::EptViolation(info) => self.handle_ept_violation(&exit_info, &info),
+            ExitReason::MovToCr(info) => self.handle_mov_to_cr(&exit_info, &info),
+            ExitReason::TripleFault => {
                 error!("Triple fault: {:#x?}", exit_info);
                 self.cpu_data.vcpu.inject_fault()?;
                 Ok(())
@@ -193,7 +216,7 @@ impl VmExit<'_> {
                 {:#x?}\n\n\
                 Guest State Dump:\n\
                 {:#x?}",
-                exit_info.exit_reason, res, exit_info, self.cpu_data.vcpu,
+                reason, res, exit_info, self.cpu_data.vcpu,
             );
         }
         res
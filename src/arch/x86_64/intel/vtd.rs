@@ -13,9 +13,10 @@
 // limitations under the License.
 
 // kernel flags: intel_iommu=off iommu=off intremap=off
+use crate::arch::cpu::with_timeout_ns;
 use crate::error::HvResult;
 use crate::iommu::{GenericIommu, IommuInfo};
-use crate::memory::addr::{phys_to_virt, virt_to_phys, GuestPhysAddr, HostPhysAddr};
+use crate::memory::addr::{phys_to_virt, GuestPhysAddr, HostPhysAddr};
 use crate::memory::{EmptyPagingInstr, GenericPTE, GenericPageTableImmut, Level4PageTable};
 use crate::memory::{Frame, MemFlags, Mmio, PageTableLevel, PAGE_SIZE};
 use crate::memory::{PagingError, PagingResult};
@@ -31,6 +32,8 @@ const ROOT_TABLE_SIZE: usize = 256 * 16;
 const ROOT_TABLE_ENTRY_COUNT: usize = 256;
 const CTX_TABLE_ENTRY_COUNT: usize = 256;
 const INV_QUEUE_SIZE: usize = 4 * 1024; //size 4k
+/// Maximum time to wait for the hardware to complete a command or an invalidation.
+const VTD_TIMEOUT_NS: u64 = 1_000_000_000;
 
 /// VT-d MMIO registers, 4KB
 ///
@@ -106,7 +109,7 @@ bitflags! {
 }
 
 impl VtdMmioRegion {
-    fn set_global_command(&mut self, flags: CmdStsFlags, set_flags: bool) {
+    fn set_global_command(&mut self, flags: CmdStsFlags, set_flags: bool) -> HvResult {
         let flags = flags.bits();
         let mask: CmdStsFlags = CmdStsFlags::TE | CmdStsFlags::QIE | CmdStsFlags::IRE; //RTPS(1<<30) is always 0 before SRTP
         let origin_status: u32 = self.global_status.read() & mask.bits();
@@ -118,16 +121,16 @@ impl VtdMmioRegion {
         };
         self.global_command.write(new_status);
 
-        while (self.global_status.read() & flags) != (new_status & flags) {
-            core::hint::spin_loop();
-        }
+        with_timeout_ns(VTD_TIMEOUT_NS, || {
+            (self.global_status.read() & flags) == (new_status & flags)
+        })
     }
-    fn init_invalidation_queue(&mut self, queue_addr: HostPhysAddr) {
+    fn init_invalidation_queue(&mut self, queue_addr: HostPhysAddr) -> HvResult {
         // use Descriptor width 128bit, Queue Size 4KB
         self.inv_addr.write(queue_addr as u64);
         // invalidation queue head is read only
         self.inv_tail.write(0);
-        self.set_global_command(CmdStsFlags::QIE, true);
+        self.set_global_command(CmdStsFlags::QIE, true)
     }
 }
 
@@ -192,6 +195,12 @@ impl GenericPTE for IoPTEntry {
     fn flags(&self) -> MemFlags {
         IoPTFlags::from_bits_truncate(self.0).into()
     }
+    fn raw(&self) -> u64 {
+        self.0
+    }
+    fn from_raw(raw: u64) -> Self {
+        Self(raw)
+    }
     fn is_unused(&self) -> bool {
         self.0 == 0
     }
@@ -378,6 +387,11 @@ struct IommuInner {
     root_table_frame: Frame,
     ctx_table_frames: Vec<Frame>, //context table frames
     inv_queue_frame: Frame,
+    /// Written by the wait descriptors. It outlives any wait, so a completion arriving after a
+    /// timeout never writes to freed memory.
+    inv_status_frame: Frame,
+    /// Status data of the last queued wait descriptor.
+    inv_wait_seq: u32,
 }
 impl Iommu {
     pub fn new(iommu_info: &IommuInfo) -> HvResult<Self> {
@@ -399,12 +413,15 @@ impl Iommu {
 
         let mut inv_queue_frame = Frame::new_contiguous(INV_QUEUE_SIZE, 0)?;
         inv_queue_frame.zero();
+        let inv_status_frame = Frame::new_zero()?;
         Ok(Self {
             inner: Mutex::new(IommuInner {
                 regs,
                 root_table_frame,
                 ctx_table_frames,
                 inv_queue_frame,
+                inv_status_frame,
+                inv_wait_seq: 0,
             }),
         })
     }
@@ -424,7 +441,7 @@ fn flush_cpu_cache(addr: usize, length: usize) {
 impl IommuInner {
     const CONTEXT_INV: u128 = (1) | (1 << 4); // 1: type =  context cache invalidation 1<<4: granularity = global
     const IOTLB_INV: u128 = (2) | (1 << 4) | (1 << 6) | (1 << 7); //2: type = iotlb 1<<4: global 1<<6: drain read 1<<7: drain write
-    const WAIT_INV: u128 = (5) | (1 << 5) | (1 << 6) | (1 << 32); //5: type = wait  1<<5: status write 1<<6: fence 1<<32:status data
+    const WAIT_INV: u128 = (5) | (1 << 5) | (1 << 6); //5: type = wait  1<<5: status write 1<<6: fence
     fn root_table_entries(&mut self) -> &mut [RootTableEntry] {
         let ptr = self.root_table_frame.as_mut_ptr() as _;
         unsafe { core::slice::from_raw_parts_mut(ptr, ROOT_TABLE_ENTRY_COUNT) }
@@ -454,7 +471,7 @@ impl IommuInner {
             CONTEXT_TABLE_SIZE,
         ); //flush, or crash!
     }
-    fn send_invalidation(&mut self, inv_command: u128) {
+    fn send_invalidation(&mut self, inv_command: u128) -> HvResult {
         let ptr = self.inv_queue_frame.as_mut_ptr() as _;
         let inv_queue = unsafe { core::slice::from_raw_parts_mut(ptr, INV_QUEUE_SIZE / 16) };
         let mut tail = self.regs.inv_tail.read() as usize;
@@ -463,38 +480,53 @@ impl IommuInner {
 
         //add a wait descriptor after the request descriptor, ensure the command is finished
         tail = tail + (1 << 4);
-        let mut status_write: u32 = 0;
+        // A distinct status data per wait, so a late completion of a timed out wait is not
+        // taken for the completion of this one.
+        self.inv_wait_seq = self.inv_wait_seq.wrapping_add(1).max(1);
+        let seq = self.inv_wait_seq;
         let wait_command: u128 = Self::WAIT_INV
-            | ((virt_to_phys((&mut status_write) as *mut u32 as usize) as u128) << 64);
-        //wait_command[64:127]: address for write back status
+            | ((seq as u128) << 32)
+            | ((self.inv_status_frame.start_paddr() as u128) << 64);
+        //wait_command[32:63]: status data, wait_command[64:127]: address for write back status
         inv_queue[tail >> 4] = wait_command;
         flush_cpu_cache(&inv_queue[tail >> 4] as *const u128 as usize, 32);
         tail = tail + (1 << 4);
         self.regs.inv_tail.write(tail as u64);
-        while status_write == 0 {
-            // question: use volatile here?
-            core::hint::spin_loop();
-            flush_cpu_cache(&status_write as *const u32 as usize, 32);
-        }
+        let status_ptr = self.inv_status_frame.as_mut_ptr() as *const u32;
+        with_timeout_ns(VTD_TIMEOUT_NS, || {
+            flush_cpu_cache(status_ptr as usize, 32);
+            // Written by the hardware.
+            unsafe { status_ptr.read_volatile() == seq }
+        })
     }
-    fn set_enabled(&mut self, enabled: bool) {
-        if enabled {
-            self.regs.set_global_command(CmdStsFlags::QIE, false);
-            self.regs.set_global_command(CmdStsFlags::IRE, false);
-            self.regs.fault_record_0.write(0);
-            self.regs.fault_status.write(0);
-            let root_table_addr = self.root_table_frame.start_paddr();
-            self.regs.root_table_addr.write(root_table_addr as u64); //here, we set Translation Mode to 00, legacy mode
-            self.regs.set_global_command(CmdStsFlags::SRTP, true);
-
-            // invalidation
-            self.regs
-                .init_invalidation_queue(self.inv_queue_frame.start_paddr());
-            self.send_invalidation(Self::CONTEXT_INV); //context
-            self.send_invalidation(Self::IOTLB_INV); //iotlb
+    fn set_enabled(&mut self, enabled: bool) -> HvResult {
+        if !enabled {
+            return self.regs.set_global_command(CmdStsFlags::TE, false);
+        }
+        let res = self.enable();
+        if res.is_err() {
+            // Don't leave the unit half configured: translation and queued invalidation off.
+            let _ = self.regs.set_global_command(CmdStsFlags::TE, false);
+            let _ = self.regs.set_global_command(CmdStsFlags::QIE, false);
         }
+        res
+    }
+    fn enable(&mut self) -> HvResult {
+        self.regs.set_global_command(CmdStsFlags::QIE, false)?;
+        self.regs.set_global_command(CmdStsFlags::IRE, false)?;
+        self.regs.fault_record_0.write(0);
+        self.regs.fault_status.write(0);
+        let root_table_addr = self.root_table_frame.start_paddr();
+        self.regs.root_table_addr.write(root_table_addr as u64); //here, we set Translation Mode to 00, legacy mode
+        self.regs.set_global_command(CmdStsFlags::SRTP, true)?;
 
-        self.regs.set_global_command(CmdStsFlags::TE, enabled); //when enabled = false: disabled
+        // invalidation
+        self.regs
+            .init_invalidation_queue(self.inv_queue_frame.start_paddr())?;
+        self.send_invalidation(Self::CONTEXT_INV)?; //context
+        self.send_invalidation(Self::IOTLB_INV)?; //iotlb
+
+        self.regs.set_global_command(CmdStsFlags::TE, true)
     }
     fn set_io_page_table(&mut self, pt: &IoPageTable) -> HvResult {
         //set root entry
@@ -524,8 +556,7 @@ impl GenericIommu for Iommu {
     }
 
     fn set_enabled(&self, enabled: bool) -> HvResult {
-        self.inner.lock().set_enabled(enabled);
-        Ok(())
+        self.inner.lock().set_enabled(enabled)
     }
 }
 
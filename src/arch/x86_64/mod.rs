@@ -18,13 +18,17 @@ mod cpuid;
 mod enclave;
 mod entry;
 mod exception;
+mod guest_state;
+mod msr_policy;
 mod page_table;
 mod segmentation;
 mod tables;
 mod xsave;
 
+pub mod barrier;
 pub mod cpu;
 pub mod serial;
+pub mod topology;
 pub mod vmm;
 
 pub use context::{GuestRegisters, LinuxContext};
@@ -33,6 +37,6 @@ pub use exception::{ExceptionInfo, ExceptionType, PageFaultErrorCode};
 pub use page_table::PageTable as HostPageTable;
 pub use page_table::PageTable as GuestPageTable;
 pub use page_table::PageTableImmut as GuestPageTableImmut;
-pub use page_table::{EnclaveGuestPageTableUnlocked, PTEntry};
+pub use page_table::{flush_tlb_all, EnclaveGuestPageTableUnlocked, PTEntry};
 pub use vmm::{EnclaveNestedPageTableUnlocked, NPTEntry, NestedPageTable};
 pub use xsave::XsaveRegion;
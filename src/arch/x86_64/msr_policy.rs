@@ -0,0 +1,188 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Policy of the guest RDMSR and WRMSR accesses that cause VM exits.
+
+use alloc::collections::btree_map::BTreeMap;
+
+use crate::error::HvResult;
+
+const IA32_PAT: u32 = 0x277;
+const IA32_EFER: u32 = 0xc000_0080;
+const IA32_LSTAR: u32 = 0xc000_0082;
+
+/// Emulates an access to `msr`: `write` is the value written by WRMSR, or `None` for RDMSR.
+/// Returns the value read, ignored for WRMSR.
+pub type MsrEmulateFn = fn(msr: u32, write: Option<u64>) -> HvResult<u64>;
+
+/// What to do with a guest access to an MSR.
+#[derive(Clone, Copy)]
+pub enum MsrAction {
+    /// Access the hardware MSR.
+    Allow,
+    /// Inject #GP(0) into the guest.
+    Deny,
+    /// Emulate the access with the given function.
+    Emulate(MsrEmulateFn),
+}
+
+/// How to complete the exiting RDMSR or WRMSR.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MsrExit {
+    /// Skip the instruction, with the value read by RDMSR in EDX:EAX.
+    Complete(u64),
+    /// Inject #GP(0), the instruction is not skipped.
+    InjectGp,
+}
+
+/// MSR actions keyed by MSR number, separately for reads and writes.
+pub struct MsrPolicy {
+    read: BTreeMap<u32, MsrAction>,
+    write: BTreeMap<u32, MsrAction>,
+    /// The action for MSRs not in the maps.
+    default: MsrAction,
+}
+
+impl MsrPolicy {
+    pub fn new(default: MsrAction) -> Self {
+        Self {
+            read: BTreeMap::new(),
+            write: BTreeMap::new(),
+            default,
+        }
+    }
+
+    pub fn set_read(&mut self, msr: u32, action: MsrAction) -> &mut Self {
+        self.read.insert(msr, action);
+        self
+    }
+
+    pub fn set_write(&mut self, msr: u32, action: MsrAction) -> &mut Self {
+        self.write.insert(msr, action);
+        self
+    }
+
+    /// Apply the policy to the access to `msr`, `write` is the value written by WRMSR or `None`
+    /// for RDMSR. Allowed accesses are done by `hw`, with the same arguments as `MsrEmulateFn`.
+    pub fn access(
+        &self,
+        msr: u32,
+        write: Option<u64>,
+        hw: impl FnOnce(u32, Option<u64>) -> u64,
+    ) -> HvResult<MsrExit> {
+        let map = if write.is_some() {
+            &self.write
+        } else {
+            &self.read
+        };
+        let value = match map.get(&msr).unwrap_or(&self.default) {
+            MsrAction::Allow => hw(msr, write),
+            MsrAction::Deny => return Ok(MsrExit::InjectGp),
+            MsrAction::Emulate(emulate) => emulate(msr, write)?,
+        };
+        Ok(MsrExit::Complete(if write.is_some() { 0 } else { value }))
+    }
+}
+
+/// Reads return 0 and writes are dropped, as nothing else handles these MSRs.
+fn ignore_msr(msr: u32, write: Option<u64>) -> HvResult<u64> {
+    match write {
+        Some(value) => warn!("VM exit: WRMSR({:#x}) <- {:#x} ignored", msr, value),
+        None => warn!("VM exit: RDMSR({:#x}) returns 0", msr),
+    }
+    Ok(0)
+}
+
+impl Default for MsrPolicy {
+    /// The MSRs saved in `LinuxContext` and restored when the hypervisor is disabled can't be
+    /// written by the guest, the other ones are ignored.
+    fn default() -> Self {
+        let mut policy = Self::new(MsrAction::Emulate(ignore_msr));
+        for msr in [IA32_EFER, IA32_PAT, IA32_LSTAR] {
+            policy.set_write(msr, MsrAction::Deny);
+        }
+        policy
+    }
+}
+
+lazy_static! {
+    pub static ref MSR_POLICY: MsrPolicy = MsrPolicy::default();
+}
+
+/// Access the hardware MSR, see `MsrPolicy::access()`.
+pub fn hw_msr_access(msr: u32, write: Option<u64>) -> u64 {
+    unsafe {
+        match write {
+            Some(value) => {
+                x86::msr::wrmsr(msr, value);
+                0
+            }
+            None => x86::msr::rdmsr(msr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denied_write_injects_gp() {
+        let policy = MsrPolicy::default();
+        for msr in [IA32_EFER, IA32_PAT, IA32_LSTAR] {
+            let exit = policy.access(msr, Some(0), |_, _| panic!("passed through"));
+            assert_eq!(exit.unwrap(), MsrExit::InjectGp);
+        }
+    }
+
+    #[test]
+    fn test_allowed_access_passes_through() {
+        let mut policy = MsrPolicy::default();
+        policy.set_read(IA32_PAT, MsrAction::Allow);
+        policy.set_write(0x1a0, MsrAction::Allow);
+
+        let exit = policy.access(IA32_PAT, None, |msr, write| {
+            assert_eq!((msr, write), (IA32_PAT, None));
+            0x0007_0406_0007_0406
+        });
+        assert_eq!(exit.unwrap(), MsrExit::Complete(0x0007_0406_0007_0406));
+
+        let mut written = None;
+        let exit = policy.access(0x1a0, Some(0x850089), |msr, write| {
+            written = Some((msr, write));
+            0
+        });
+        assert_eq!(exit.unwrap(), MsrExit::Complete(0));
+        assert_eq!(written, Some((0x1a0, Some(0x850089))));
+    }
+
+    #[test]
+    fn test_emulated_access() {
+        fn emulate(msr: u32, write: Option<u64>) -> HvResult<u64> {
+            match write {
+                Some(_) => hv_result_err!(EINVAL),
+                None => Ok(msr as u64 + 1),
+            }
+        }
+        let mut policy = MsrPolicy::new(MsrAction::Deny);
+        policy
+            .set_read(0x10, MsrAction::Emulate(emulate))
+            .set_write(0x10, MsrAction::Emulate(emulate));
+        let hw = |_, _| panic!("passed through");
+        let exit = policy.access(0x10, None, hw);
+        assert_eq!(exit.unwrap(), MsrExit::Complete(0x11));
+        assert!(policy.access(0x10, Some(1), hw).is_err());
+        assert_eq!(policy.access(0x11, None, hw).unwrap(), MsrExit::InjectGp);
+    }
+}
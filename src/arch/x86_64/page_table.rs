@@ -14,17 +14,22 @@
 
 use core::fmt::{Debug, Formatter, Result};
 
+use spin::Once;
 use x86_64::{
     addr::{PhysAddr as X86PhysAddr, VirtAddr as X86VirtAddr},
     instructions::tlb,
-    registers::control::{Cr3, Cr3Flags},
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
     structures::paging::page_table::PageTableFlags as PTF,
     structures::paging::PhysFrame,
 };
 
-use crate::memory::PagingResult;
-use crate::memory::{GenericPTE, MemFlags, PageTableLevel, PagingInstr, PhysAddr, VirtAddr};
+use super::cpuid::CpuFeatures;
+use crate::consts::SME_C_BIT_OFFSET;
+use crate::memory::addr::{is_phys_encrypted, phys_encrypted};
+use crate::memory::{CachePolicy, GenericPTE, GenericPageTableImmut, MemFlags};
 use crate::memory::{Level4PageTable, Level4PageTableImmut, Level4PageTableUnlocked};
+use crate::memory::{PageSize, PagingError, PagingResult, PhysAddr, VirtAddr};
+use crate::memory::{PageTableLevel, PagingInstr};
 
 impl From<MemFlags> for PTF {
     fn from(f: MemFlags) -> Self {
@@ -44,6 +49,13 @@ impl From<MemFlags> for PTF {
         if f.contains(MemFlags::USER) {
             ret |= Self::USER_ACCESSIBLE;
         }
+        // `LinuxContext::load_from()` programs IA32_PAT = 0x070106 for the hypervisor: entry 0
+        // is WB, entry 1 (PWT) WC and entry 3 (PCD | PWT) UC.
+        match CachePolicy::from_flags(f) {
+            CachePolicy::WriteBack => {}
+            CachePolicy::WriteCombining => ret |= Self::WRITE_THROUGH,
+            CachePolicy::Uncached => ret |= Self::NO_CACHE | Self::WRITE_THROUGH,
+        }
         ret
     }
 }
@@ -66,21 +78,92 @@ impl From<PTF> for MemFlags {
         if f.contains(PTF::USER_ACCESSIBLE) {
             ret |= Self::USER;
         }
+        if f.contains(PTF::NO_CACHE) {
+            ret |= Self::IO;
+        } else if f.contains(PTF::WRITE_THROUGH) {
+            ret |= Self::WRITE_COMBINE;
+        }
         ret
     }
 }
 
+/// Raw bits of a terminal x86 PTE (the address excluded) mapping memory with `flags`:
+///
+/// - `PRESENT` unless `NO_PRESENT`, `WRITABLE` for `WRITE`, `USER_ACCESSIBLE` for `USER`, and
+///   `NO_EXECUTE` unless `EXECUTE`.
+/// - `PWT` and `PCD` select the `CachePolicy`.
+/// - The SME C-bit for `ENCRYPTED`, if SME is enabled.
+/// - `GLOBAL` is never set, no `MemFlags` asks for it.
+///
+/// The other flags are software-only and dropped. Empty flags give an empty entry.
+pub fn x86_pte_flags_from(flags: MemFlags) -> u64 {
+    let mut bits = PTF::from(flags).bits();
+    if flags.contains(MemFlags::ENCRYPTED) {
+        bits |= SME_C_BIT_OFFSET as u64;
+    }
+    bits
+}
+
+/// The inverse of `x86_pte_flags_from()`: the `MemFlags` of a terminal entry with the raw bits
+/// `pte`. The address bits other than the C-bit are ignored.
+pub fn mem_flags_from_x86_pte(pte: u64) -> MemFlags {
+    let mut flags = MemFlags::from(PTF::from_bits_truncate(pte));
+    if !flags.is_empty() && is_phys_encrypted((pte & PHYS_ADDR_MASK) as _) {
+        flags |= MemFlags::ENCRYPTED;
+    }
+    flags
+}
+
 const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // 12..52
 
+/// Returns the bits that must be clear in a present entry at `level`, on a CPU with
+/// `max_phys_bits` physical address bits. The SME C-bit may be above them but isn't reserved.
+fn reserved_bits(level: PageTableLevel, is_huge: bool, max_phys_bits: u32) -> u64 {
+    let above_max_phys = PHYS_ADDR_MASK & !((1 << max_phys_bits) - 1);
+    let mut bits = above_max_phys & !(SME_C_BIT_OFFSET as u64);
+    match (level, is_huge) {
+        // PS is reserved in PML4Es.
+        (PageTableLevel::L4, _) => bits |= PTF::HUGE_PAGE.bits(),
+        // Bits 29:13 of 1GB pages, bit 12 is PAT.
+        (PageTableLevel::L3, true) => bits |= 0x3fff_e000,
+        // Bits 20:13 of 2MB pages, bit 12 is PAT.
+        (PageTableLevel::L2, true) => bits |= 0x1f_e000,
+        _ => {}
+    }
+    bits
+}
+
+/// A x86 page table entry.
+///
+/// If the entry maps encrypted memory, the SME C-bit is set in its address field, but `addr()`
+/// always returns the plaintext physical address. Use `is_encrypted()` to test the C-bit.
 #[derive(Clone)]
 pub struct PTEntry(u64);
 
+impl PTEntry {
+    /// Returns whether the C-bit is set in the address field.
+    pub fn is_encrypted(&self) -> bool {
+        is_phys_encrypted(self.raw_addr() as _)
+    }
+
+    /// The address field including the C-bit.
+    fn raw_addr(&self) -> u64 {
+        self.0 & PHYS_ADDR_MASK
+    }
+}
+
 impl GenericPTE for PTEntry {
     fn addr(&self) -> PhysAddr {
-        (self.0 & PHYS_ADDR_MASK) as _
+        (self.raw_addr() & !(SME_C_BIT_OFFSET as u64)) as _
     }
     fn flags(&self) -> MemFlags {
-        PTF::from_bits_truncate(self.0).into()
+        mem_flags_from_x86_pte(self.0)
+    }
+    fn raw(&self) -> u64 {
+        self.0
+    }
+    fn from_raw(raw: u64) -> Self {
+        Self(raw)
     }
     fn is_unused(&self) -> bool {
         self.0 == 0
@@ -98,15 +181,31 @@ impl GenericPTE for PTEntry {
         let flags: PTF = !PTF::ACCESSED;
         self.0 &= flags.bits() | PHYS_ADDR_MASK;
     }
+    /// The C-bit is kept if the entry is encrypted, or set if `paddr` carries it. To change it,
+    /// rewrite the entry with `clear()` and `set_leaf()`.
     fn set_addr(&mut self, paddr: PhysAddr) {
+        let paddr = if self.is_encrypted() {
+            phys_encrypted(paddr)
+        } else {
+            paddr
+        };
         self.0 = (self.0 & !PHYS_ADDR_MASK) | (paddr as u64 & PHYS_ADDR_MASK);
     }
+    /// A `COMM_REGION` is shared with the host, its C-bit is cleared.
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool) -> PagingResult {
-        let mut flags = PTF::from(flags);
+        debug_assert!(
+            !flags.contains(MemFlags::COMM_REGION | MemFlags::ENCRYPTED),
+            "COMM_REGION can't be ENCRYPTED"
+        );
+        let mut raw_addr = self.raw_addr();
+        if flags.contains(MemFlags::COMM_REGION) {
+            raw_addr &= !(SME_C_BIT_OFFSET as u64);
+        }
+        let mut bits = x86_pte_flags_from(flags);
         if is_huge {
-            flags |= PTF::HUGE_PAGE;
+            bits |= PTF::HUGE_PAGE.bits();
         }
-        self.0 = self.addr() as u64 | flags.bits();
+        self.0 = raw_addr | bits;
         Ok(())
     }
     fn set_table(
@@ -127,14 +226,21 @@ impl GenericPTE for PTEntry {
         Ok(())
     }
     fn set_notpresent(&mut self) -> PagingResult {
-        let mut flags = PTF::from_bits_truncate(self.0);
-        flags -= PTF::PRESENT;
-        self.0 = self.addr() as u64 | flags.bits();
+        // Only clear the bit, the available and protection key bits must be kept as well.
+        self.0 &= !PTF::PRESENT.bits();
         Ok(())
     }
     fn clear(&mut self) {
         self.0 = 0
     }
+    fn check_reserved(&self, level: PageTableLevel) -> PagingResult {
+        static MAX_PHYS_BITS: Once<u32> = Once::new();
+        let max_phys_bits = *MAX_PHYS_BITS.call_once(|| CpuFeatures::new().phys_addr_bits());
+        if self.0 & reserved_bits(level, self.is_leaf(), max_phys_bits) != 0 {
+            return Err(PagingError::ReservedBits(level));
+        }
+        Ok(())
+    }
 }
 
 impl Debug for PTEntry {
@@ -157,6 +263,10 @@ impl PagingInstr for X86PagingInstr {
         );
     }
 
+    fn post_activate_barrier() {
+        // Nothing to do, writing CR3 is a serializing instruction.
+    }
+
     fn flush(vaddr: Option<usize>) {
         if let Some(vaddr) = vaddr {
             tlb::flush(X86VirtAddr::new(vaddr as u64))
@@ -164,8 +274,345 @@ impl PagingInstr for X86PagingInstr {
             tlb::flush_all()
         }
     }
+
+    fn max_page_size() -> PageSize {
+        static HAS_1GB_PAGES: Once<bool> = Once::new();
+        if *HAS_1GB_PAGES.call_once(|| CpuFeatures::new().has_1gb_pages()) {
+            PageSize::Size1G
+        } else {
+            PageSize::Size2M
+        }
+    }
+}
+
+/// How `flush_tlb_all()` drops every TLB entry of the current CPU.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum FlushAllMethod {
+    /// `invpcid` type 2: all contexts, including the global translations.
+    Invpcid,
+    /// Toggle `CR4.PGE`, which drops the global translations as well.
+    ToggleGlobal,
+    /// Reload `CR3`, enough when there are no global translations.
+    ReloadCr3,
+}
+
+impl FlushAllMethod {
+    fn select(has_invpcid: bool, cr4: Cr4Flags) -> Self {
+        if has_invpcid {
+            Self::Invpcid
+        } else if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+            Self::ToggleGlobal
+        } else {
+            Self::ReloadCr3
+        }
+    }
+}
+
+/// Invalidate all the TLB entries of the current CPU, for every PCID and including the global
+/// translations, without a page table type in scope as `PagingInstr::flush(None)` needs.
+pub fn flush_tlb_all() {
+    static HAS_INVPCID: Once<bool> = Once::new();
+    let has_invpcid = *HAS_INVPCID.call_once(|| CpuFeatures::new().has_invpcid());
+    let cr4 = Cr4::read();
+    match FlushAllMethod::select(has_invpcid, cr4) {
+        FlushAllMethod::Invpcid => {
+            // The descriptor is ignored by type 2.
+            let desc = [0u64; 2];
+            unsafe { asm!("invpcid {}, [{}]", in(reg) 2u64, in(reg) &desc) };
+        }
+        FlushAllMethod::ToggleGlobal => unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        },
+        FlushAllMethod::ReloadCr3 => tlb::flush_all(),
+    }
+}
+
+/// Returns the physical address of the root table referenced by the `CR3` value `cr3`, without
+/// the PCID/flag bits and the SME C-bit.
+fn root_from_cr3(cr3: u64) -> PhysAddr {
+    (cr3 & PHYS_ADDR_MASK & !(SME_C_BIT_OFFSET as u64)) as _
+}
+
+/// Call `f` with the page table currently loaded in `CR3`, e.g. to dump it or walk a faulting
+/// address.
+///
+/// The table is read through a temporary [`PageTableImmut`] view that doesn't own its frames, so
+/// nothing is deallocated after the call. The view must not outlive the active table, it's only
+/// lent to `f`.
+pub fn with_active_table<T>(f: impl FnOnce(&PageTableImmut) -> T) -> T {
+    let (frame, _) = Cr3::read();
+    let root_paddr = root_from_cr3(frame.start_address().as_u64());
+    // Safety: the active table stays alive while we are running on it.
+    let view = unsafe { PageTableImmut::from_root(root_paddr) };
+    f(&view)
 }
 
 pub type PageTable = Level4PageTable<VirtAddr, PTEntry, X86PagingInstr>;
 pub type EnclaveGuestPageTableUnlocked = Level4PageTableUnlocked<VirtAddr, PTEntry, X86PagingInstr>;
 pub type PageTableImmut = Level4PageTableImmut<VirtAddr, PTEntry>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypted_entry() {
+        let paddr = 0x1234_5000;
+        let flags = MemFlags::READ | MemFlags::WRITE;
+        let mut entry = PTEntry(0);
+        entry.set_addr(phys_encrypted(paddr));
+        entry.set_flags(flags, false).unwrap();
+        assert_eq!(entry.addr(), paddr);
+        let encrypted = if SME_C_BIT_OFFSET != 0 {
+            MemFlags::ENCRYPTED
+        } else {
+            MemFlags::empty()
+        };
+        assert_eq!(entry.flags(), flags | encrypted);
+        assert_eq!(entry.is_encrypted(), SME_C_BIT_OFFSET != 0);
+        assert_eq!(
+            entry.0,
+            (phys_encrypted(paddr) as u64) | PTF::from(flags).bits()
+        );
+
+        // The C-bit survives remapping and flag updates.
+        entry.set_addr(0x6789_a000);
+        assert_eq!(entry.addr(), 0x6789_a000);
+        assert_eq!(entry.is_encrypted(), SME_C_BIT_OFFSET != 0);
+        entry.set_notpresent().unwrap();
+        entry.set_present().unwrap();
+        assert_eq!(entry.addr(), 0x6789_a000);
+        assert_eq!(entry.is_encrypted(), SME_C_BIT_OFFSET != 0);
+
+        // Rewriting the entry (see `update()`) applies the C-bit of the new flags.
+        entry.clear();
+        entry.set_leaf(0x6789_a000, flags, false).unwrap();
+        assert!(!entry.is_encrypted());
+        assert_eq!(entry.flags(), flags);
+
+        let mut plain = PTEntry(0);
+        plain.set_addr(paddr);
+        assert!(!plain.is_encrypted());
+        assert_eq!(plain.addr(), paddr);
+    }
+
+    const fn flags(bits: u64) -> MemFlags {
+        MemFlags::from_bits_truncate(bits)
+    }
+
+    const R: u64 = MemFlags::READ.bits();
+    const W: u64 = MemFlags::WRITE.bits();
+    const X: u64 = MemFlags::EXECUTE.bits();
+    const P: u64 = PTF::PRESENT.bits();
+    const RW: u64 = PTF::PRESENT.bits() | PTF::WRITABLE.bits();
+    const US: u64 = PTF::USER_ACCESSIBLE.bits();
+    const NX: u64 = PTF::NO_EXECUTE.bits();
+    const PWT: u64 = PTF::WRITE_THROUGH.bits();
+    const PCD: u64 = PTF::NO_CACHE.bits();
+
+    /// The `MemFlags` and raw PTE bits that convert to each other.
+    const MEM_FLAGS_PTE_TABLE: &[(MemFlags, u64)] = &[
+        (flags(R), P | NX),
+        (flags(R | W), RW | NX),
+        (flags(R | X), P),
+        (flags(R | W | X), RW),
+        (flags(R | MemFlags::USER.bits()), P | US | NX),
+        (flags(R | W | MemFlags::IO.bits()), RW | PCD | PWT | NX),
+        (flags(R | W | MemFlags::WRITE_COMBINE.bits()), RW | PWT | NX),
+        (flags(R | MemFlags::NO_PRESENT.bits()), NX),
+        (flags(0), 0),
+    ];
+
+    #[test]
+    fn test_mem_flags_pte_table() {
+        for &(flags, pte) in MEM_FLAGS_PTE_TABLE {
+            assert_eq!(x86_pte_flags_from(flags), pte, "{:?}", flags);
+            assert_eq!(mem_flags_from_x86_pte(pte), flags, "{:#x}", pte);
+        }
+        // Software-only flags are dropped.
+        let sw_flags = MemFlags::DMA | MemFlags::COMM_REGION | MemFlags::NO_HUGEPAGES;
+        assert_eq!(x86_pte_flags_from(sw_flags | MemFlags::READ), P | NX);
+
+        // The C-bit is only there if SME is enabled.
+        let encrypted = flags(R | W | MemFlags::ENCRYPTED.bits());
+        let pte = x86_pte_flags_from(encrypted);
+        assert_eq!(pte, RW | NX | SME_C_BIT_OFFSET as u64);
+        if SME_C_BIT_OFFSET != 0 {
+            assert_eq!(mem_flags_from_x86_pte(pte), encrypted);
+        }
+    }
+
+    #[test]
+    fn test_io_entry_is_uncached() {
+        let flags = MemFlags::READ | MemFlags::WRITE | MemFlags::IO;
+        let mut entry = PTEntry(0);
+        entry.set_addr(0xfed0_0000);
+        entry.set_flags(flags, false).unwrap();
+        let ptf = PTF::from_bits_truncate(entry.0);
+        assert!(ptf.contains(PTF::NO_CACHE | PTF::WRITE_THROUGH));
+        assert_eq!(entry.flags(), flags);
+
+        entry.set_flags(flags - MemFlags::IO, false).unwrap();
+        let ptf = PTF::from_bits_truncate(entry.0);
+        assert!(!ptf.intersects(PTF::NO_CACHE | PTF::WRITE_THROUGH));
+    }
+
+    #[test]
+    fn test_write_combine_entry() {
+        let flags = MemFlags::READ | MemFlags::WRITE | MemFlags::WRITE_COMBINE;
+        let mut entry = PTEntry(0);
+        entry.set_addr(0xc000_0000);
+        entry.set_flags(flags, false).unwrap();
+        let ptf = PTF::from_bits_truncate(entry.0);
+        assert!(ptf.contains(PTF::WRITE_THROUGH) && !ptf.contains(PTF::NO_CACHE));
+        assert_eq!(entry.flags(), flags);
+
+        // A framebuffer is usually an IO region as well.
+        entry.set_flags(flags | MemFlags::IO, false).unwrap();
+        let ptf = PTF::from_bits_truncate(entry.0);
+        assert!(ptf.contains(PTF::WRITE_THROUGH) && !ptf.contains(PTF::NO_CACHE));
+    }
+
+    #[test]
+    fn test_comm_region_is_plaintext() {
+        let paddr = 0x1234_5000;
+        let flags = MemFlags::READ | MemFlags::WRITE | MemFlags::COMM_REGION;
+        let mut entry = PTEntry(0);
+        entry.set_addr(phys_encrypted(paddr));
+        entry.set_flags(flags, false).unwrap();
+        assert!(!entry.is_encrypted());
+        assert_eq!(entry.addr(), paddr);
+        assert_eq!(
+            entry.0,
+            paddr as u64 | (PTF::PRESENT | PTF::WRITABLE | PTF::NO_EXECUTE).bits()
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "COMM_REGION can't be ENCRYPTED")]
+    fn test_comm_region_not_encrypted() {
+        let flags = MemFlags::READ | MemFlags::COMM_REGION | MemFlags::ENCRYPTED;
+        PTEntry(0).set_flags(flags, false).unwrap();
+    }
+
+    #[test]
+    fn test_set_flags_keeps_address() {
+        // Address bits with the C-bit position set, whether or not SME is enabled.
+        let raw_addr = (1 << 47) | 0x1234_5000;
+        let mut entry = PTEntry(raw_addr | PTF::WRITABLE.bits());
+        entry
+            .set_flags(MemFlags::READ | MemFlags::EXECUTE, true)
+            .unwrap();
+        assert_eq!(entry.0 & PHYS_ADDR_MASK, raw_addr);
+        assert_eq!(
+            entry.0 & !PHYS_ADDR_MASK,
+            (PTF::PRESENT | PTF::HUGE_PAGE).bits()
+        );
+    }
+
+    #[test]
+    fn test_active_table_view() {
+        // A synthetic CR3 value: root 0x8_0000 with PWT|PCD and the C-bit if SME is enabled.
+        let cr3 = 0x8_0000 | SME_C_BIT_OFFSET as u64 | 0x18;
+        let root_paddr = root_from_cr3(cr3);
+        assert_eq!(root_paddr, 0x8_0000);
+
+        // The view only borrows the root, dropping it must not free the frame.
+        let view = unsafe { PageTableImmut::from_root(root_paddr) };
+        assert_eq!(view.root_paddr(), 0x8_0000);
+        drop(view);
+    }
+
+    #[test]
+    fn test_toggle_present_keeps_other_bits() {
+        // Every bit set except PRESENT: address, C-bit, available, protection key and NX bits.
+        let raw = !P;
+        let mut entry = PTEntry(raw);
+        entry.set_present().unwrap();
+        assert_eq!(entry.0, raw | P);
+        entry.set_notpresent().unwrap();
+        assert_eq!(entry.0, raw);
+
+        let raw = 0x1234_5000 | RW | US | NX | (0b101 << 9) | (0xf << 59);
+        let mut entry = PTEntry(raw);
+        entry.set_notpresent().unwrap();
+        assert_eq!(entry.0, raw & !P);
+        entry.set_present().unwrap();
+        assert_eq!(entry.0, raw);
+    }
+
+    #[test]
+    fn test_reserved_bits() {
+        use PageTableLevel::*;
+        let check = |raw: u64, level, is_huge| raw & reserved_bits(level, is_huge, 46) == 0;
+
+        // Valid entries, with the available, NX and (if any) C-bits set.
+        let table = 0x3fff_ffff_f000 | RW | US | NX | (0b111 << 9) | SME_C_BIT_OFFSET as u64;
+        for level in [L4, L3, L2, L1] {
+            assert!(check(table, level, false));
+        }
+        let huge = P | PTF::HUGE_PAGE.bits();
+        assert!(check(0x4000_0000 | (1 << 12) | huge, L3, true));
+        assert!(check(0x20_0000 | (1 << 12) | huge, L2, true));
+        assert!(check(0x1000 | (1 << 7) | P, L1, false));
+
+        // Address bits above MAXPHYADDR.
+        for level in [L4, L3, L2, L1] {
+            assert!(!check((1 << 46) | P, level, false));
+        }
+        // PS in a PML4E, misaligned 1GB and 2MB pages.
+        assert!(!check(0x1000 | huge, L4, true));
+        assert!(!check(0x4000_0000 | (1 << 13) | huge, L3, true));
+        assert!(!check(0x20_0000 | (1 << 20) | huge, L2, true));
+    }
+
+    #[test]
+    fn test_leaf_c_bit() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let cases = [
+            (rw, false),
+            (rw | MemFlags::ENCRYPTED, SME_C_BIT_OFFSET != 0),
+            (rw | MemFlags::COMM_REGION, false),
+        ];
+        for (flags, c_bit) in cases {
+            let mut entry = PTEntry(0);
+            entry.set_leaf(0x20_0000, flags, true).unwrap();
+            assert_eq!(entry.is_encrypted(), c_bit, "{:?}", flags);
+            assert_eq!(entry.addr(), 0x20_0000);
+        }
+
+        // The C-bit of the physical address is dropped for a COMM_REGION.
+        let mut entry = PTEntry(0);
+        let (paddr, flags) = (phys_encrypted(0x1000), rw | MemFlags::COMM_REGION);
+        entry.set_leaf(paddr, flags, false).unwrap();
+        assert!(!entry.is_encrypted());
+        assert_eq!(entry.addr(), 0x1000);
+    }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let mut entry = PTEntry(0);
+        let flags = MemFlags::READ | MemFlags::WRITE | MemFlags::ENCRYPTED;
+        entry.set_leaf(0x20_0000, flags, true).unwrap();
+        for raw in [0, entry.0, !P, 0x1234_5000 | RW | US | NX] {
+            let entry = PTEntry(raw);
+            let copy = PTEntry::from_raw(entry.raw());
+            assert_eq!(copy.0, raw);
+            assert_eq!(copy.addr(), entry.addr());
+            assert_eq!(copy.flags(), entry.flags());
+        }
+    }
+
+    #[test]
+    fn test_flush_all_method() {
+        use FlushAllMethod::*;
+        let pge = Cr4Flags::PAGE_GLOBAL | Cr4Flags::PHYSICAL_ADDRESS_EXTENSION;
+        let no_pge = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION;
+        assert_eq!(FlushAllMethod::select(true, pge), Invpcid);
+        assert_eq!(FlushAllMethod::select(true, no_pge), Invpcid);
+        assert_eq!(FlushAllMethod::select(false, pge), ToggleGlobal);
+        assert_eq!(FlushAllMethod::select(false, no_pge), ReloadCr3);
+    }
+}
@@ -19,6 +19,7 @@ use x86_64::structures::gdt::DescriptorFlags;
 use x86_64::structures::DescriptorTablePointer;
 
 use super::tables::GDTStruct;
+use crate::error::HvResult;
 
 bitflags! {
     /// Access rights for VMCS guest register states.
@@ -104,30 +105,174 @@ impl Segment {
         }
     }
 
-    pub fn from_selector(selector: SegmentSelector, gdt: &DescriptorTablePointer) -> Self {
+    /// A flat 64-bit kernel code segment.
+    ///
+    /// In the descriptor, L = 1 selects 64-bit mode, which requires D = 0; G = 1 scales the
+    /// limit by 4K pages, and DPL = 0 makes it accessible from ring 0 only. Base and limit are
+    /// ignored in 64-bit mode, but set to a flat 4G segment as usual.
+    pub fn kernel_code() -> Self {
+        Self {
+            selector: GDTStruct::KCODE_SELECTOR,
+            base: 0,
+            limit: 0xffff_ffff,
+            access_rights: SegmentAccessRights::ACCESSED
+                | SegmentAccessRights::WRITABLE
+                | SegmentAccessRights::EXECUTABLE
+                | SegmentAccessRights::CODE_DATA
+                | SegmentAccessRights::PRESENT
+                | SegmentAccessRights::LONG_MODE
+                | SegmentAccessRights::GRANULARITY,
+        }
+    }
+
+    /// A flat kernel data segment.
+    ///
+    /// L is reserved for data segments, D = 1 selects a 32-bit stack pointer outside of 64-bit
+    /// mode, G and DPL are the same as `kernel_code()`.
+    pub fn kernel_data() -> Self {
+        Self {
+            selector: GDTStruct::KDATA_SELECTOR,
+            base: 0,
+            limit: 0xffff_ffff,
+            access_rights: SegmentAccessRights::ACCESSED
+                | SegmentAccessRights::WRITABLE
+                | SegmentAccessRights::CODE_DATA
+                | SegmentAccessRights::PRESENT
+                | SegmentAccessRights::DB
+                | SegmentAccessRights::GRANULARITY,
+        }
+    }
+
+    /// An available 64-bit TSS at `base` with byte granular `limit` (G = 0).
+    pub fn tss(base: u64, limit: u32) -> Self {
+        Self {
+            selector: GDTStruct::TSS_SELECTOR,
+            base,
+            limit,
+            access_rights: SegmentAccessRights::TSS_AVAIL | SegmentAccessRights::PRESENT,
+        }
+    }
+
+    /// Encode the segment as GDT descriptor entries. System segments (e.g. TSS) take two
+    /// entries in 64-bit mode, the second one holds the upper 32 bits of the base.
+    pub fn to_descriptor(&self) -> (u64, Option<u64>) {
+        let rights = self.access_rights;
+        let limit = if rights.contains(SegmentAccessRights::GRANULARITY) {
+            self.limit as u64 >> 12
+        } else {
+            self.limit as u64
+        };
+        let mut desc = 0;
+        desc.set_bits(0..16, limit.get_bits(0..16));
+        desc.set_bits(16..40, self.base.get_bits(0..24));
+        desc.set_bits(40..56, (rights.bits() & 0xf0ff) as u64);
+        desc.set_bits(48..52, limit.get_bits(16..20));
+        desc.set_bits(56..64, self.base.get_bits(24..32));
+        if rights.contains(SegmentAccessRights::CODE_DATA) {
+            (desc, None)
+        } else {
+            (desc, Some(self.base.get_bits(32..64)))
+        }
+    }
+
+    /// Decode the descriptor of `selector` in the GDT `gdt`, fails if the selector is beyond
+    /// the GDT limit.
+    pub fn from_selector(
+        selector: SegmentSelector,
+        gdt: &DescriptorTablePointer,
+    ) -> HvResult<Self> {
+        Self::from_table(selector, GDTStruct::table_of(gdt))
+    }
+
+    /// Decode the descriptor of `selector` in the GDT entries `table`. Both entries of a system
+    /// descriptor must be in the table.
+    fn from_table(selector: SegmentSelector, table: &[u64]) -> HvResult<Self> {
         let index = selector.index() as usize;
-        let table = GDTStruct::table_of(gdt);
+        let out_of_bounds = || {
+            hv_err!(
+                EINVAL,
+                format!(
+                    "Segment selector index {} is beyond the GDT limit ({} entries)",
+                    index,
+                    table.len()
+                )
+            )
+        };
 
-        let entry_value = table[index];
+        let entry_value = *table.get(index).ok_or_else(out_of_bounds)?;
         let entry = DescriptorFlags::from_bits_truncate(entry_value);
         if entry.contains(DescriptorFlags::PRESENT) {
             let mut base = entry_value.get_bits(16..40) | entry_value.get_bits(56..64) << 24;
             let mut limit = entry_value.get_bits(0..16) | entry_value.get_bits(48..52) << 16;
             if !entry.contains(DescriptorFlags::USER_SEGMENT) {
-                let high = table[index + 1];
+                let high = *table.get(index + 1).ok_or_else(out_of_bounds)?;
                 base += high << 32;
             }
             if entry.contains(DescriptorFlags::GRANULARITY) {
                 limit = (limit << 12) | 0xfff;
             }
-            Self {
+            Ok(Self {
                 selector,
                 base,
                 limit: limit as _,
                 access_rights: SegmentAccessRights::from_descriptor(entry_value),
-            }
+            })
         } else {
-            Self::invalid()
+            Ok(Self::invalid())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kernel_code_descriptor() {
+        let (desc, high) = Segment::kernel_code().to_descriptor();
+        assert_eq!(high, None);
+        assert_eq!(desc, 0x00af_9b00_0000_ffff);
+        assert!(desc.get_bit(53)); // L
+        assert!(!desc.get_bit(54)); // D
+        assert!(desc.get_bit(55)); // G
+        assert_eq!(desc.get_bits(45..47), 0); // DPL
+        assert!(desc.get_bit(47)); // P
+        assert!(desc.get_bit(44)); // S
+        assert!(desc.get_bit(43)); // executable
+
+        let (desc, _) = Segment::kernel_data().to_descriptor();
+        assert_eq!(desc, 0x00cf_9300_0000_ffff);
+    }
+
+    #[test]
+    fn test_tss_descriptor() {
+        let (low, high) = Segment::tss(0xffff_8000_1234_5678, 0x67).to_descriptor();
+        assert_eq!(low, 0x1200_8934_5678_0067);
+        assert_eq!(high, Some(0xffff_8000));
+        assert_eq!(
+            SegmentAccessRights::from_descriptor(low),
+            SegmentAccessRights::TSS_AVAIL | SegmentAccessRights::PRESENT
+        );
+    }
+
+    #[test]
+    fn test_from_table_bounds() {
+        let selector = |index: u16| SegmentSelector::from_raw(index << 3);
+        let (code, _) = Segment::kernel_code().to_descriptor();
+        let (tss_low, tss_high) = Segment::tss(0xffff_8000_1234_5678, 0x67).to_descriptor();
+        let table = [0, code, tss_low, tss_high.unwrap()];
+
+        let null = Segment::from_table(selector(0), &table).unwrap();
+        assert!(null.access_rights.contains(SegmentAccessRights::UNUSABLE));
+        let cs = Segment::from_table(selector(1), &table).unwrap();
+        assert_eq!(cs.limit, 0xffff_ffff);
+        let tss = Segment::from_table(selector(2), &table).unwrap();
+        assert_eq!((tss.base, tss.limit), (0xffff_8000_1234_5678, 0x67));
+
+        // Out of the table.
+        assert!(Segment::from_table(selector(4), &table).is_err());
+        assert!(Segment::from_table(selector(0x1fff), &table).is_err());
+        // The upper half of the system descriptor is out of the table.
+        assert!(Segment::from_table(selector(2), &table[..3]).is_err());
+    }
+}
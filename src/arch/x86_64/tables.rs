@@ -12,21 +12,26 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use spin::Mutex;
+use core::mem::size_of;
+
 use x86::{segmentation::SegmentSelector, task, Ring};
 use x86_64::addr::VirtAddr;
 use x86_64::instructions::tables::{lgdt, lidt, sidt};
-use x86_64::structures::gdt::{Descriptor, DescriptorFlags};
 use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable};
 use x86_64::structures::{tss::TaskStateSegment, DescriptorTablePointer};
 
-use super::segmentation::SegmentAccessRights;
+use super::segmentation::{Segment, SegmentAccessRights};
+use crate::error::HvResult;
+use crate::spinlock::SpinLock;
 
 const TSS: TaskStateSegment = TaskStateSegment::new();
 
+/// Vectors 0-31 are reserved for exceptions, an IDT must cover them.
+const EXCEPTION_VECTORS: usize = 32;
+
 lazy_static! {
-    pub(super) static ref GDT: Mutex<GDTStruct> = Mutex::new(GDTStruct::new());
-    pub(super) static ref IDT: Mutex<IDTStruct> = Mutex::new(IDTStruct::new());
+    pub(super) static ref GDT: SpinLock<GDTStruct> = SpinLock::new(GDTStruct::new());
+    pub(super) static ref IDT: SpinLock<IDTStruct> = SpinLock::new(IDTStruct::new());
 }
 
 #[derive(Debug)]
@@ -38,18 +43,16 @@ pub(super) struct GDTStruct {
 impl GDTStruct {
     pub const KCODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, Ring::Ring0);
     pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(2, Ring::Ring0);
+    pub const KDATA_SELECTOR: SegmentSelector = SegmentSelector::new(4, Ring::Ring0);
 
     pub fn new() -> Self {
         let mut table = [0; 16];
-        table[1] = DescriptorFlags::KERNEL_CODE64.bits();
-        let tss_desc = Descriptor::tss_segment(&TSS);
-        match tss_desc {
-            Descriptor::SystemSegment(low, high) => {
-                table[2] = low;
-                table[3] = high;
-            }
-            _ => unreachable!(),
-        }
+        table[1] = Segment::kernel_code().to_descriptor().0;
+        let tss_limit = size_of::<TaskStateSegment>() as u32 - 1;
+        let (low, high) = Segment::tss(&TSS as *const _ as u64, tss_limit).to_descriptor();
+        table[2] = low;
+        table[3] = high.unwrap();
+        table[4] = Segment::kernel_data().to_descriptor().0;
         Self {
             table,
             pointer: DescriptorTablePointer {
@@ -72,6 +75,16 @@ impl GDTStruct {
         unsafe { lgdt(pointer) };
     }
 
+    /// Check that `pointer` describes a sane GDT before it's loaded: made of whole 8-byte
+    /// descriptors, with at least `min_entries` of them.
+    pub fn check_pointer(pointer: &DescriptorTablePointer, min_entries: usize) -> HvResult {
+        let size = pointer.limit as usize + 1;
+        if size % size_of::<u64>() != 0 || size / size_of::<u64>() < min_entries {
+            return hv_result_err!(EINVAL, format!("Invalid GDT limit {:#x}", pointer.limit));
+        }
+        Ok(())
+    }
+
     pub fn table_of(pointer: &DescriptorTablePointer) -> &[u64] {
         let entry_count = (pointer.limit as usize + 1) / core::mem::size_of::<u64>();
         unsafe { core::slice::from_raw_parts(pointer.base.as_ptr(), entry_count) }
@@ -140,6 +153,17 @@ impl IDTStruct {
         sidt()
     }
 
+    /// Check that `pointer` describes a sane IDT before it's loaded: made of whole 16-byte
+    /// gates, covering at least the exception vectors and at most the 256 vectors.
+    pub fn check_pointer(pointer: &DescriptorTablePointer) -> HvResult {
+        const GATE_SIZE: usize = 16;
+        let size = pointer.limit as usize + 1;
+        if size % GATE_SIZE != 0 || !(EXCEPTION_VECTORS..=256).contains(&(size / GATE_SIZE)) {
+            return hv_result_err!(EINVAL, format!("Invalid IDT limit {:#x}", pointer.limit));
+        }
+        Ok(())
+    }
+
     pub fn lidt(pointer: &DescriptorTablePointer) {
         unsafe { lidt(pointer) };
     }
@@ -154,3 +178,34 @@ impl IDTStruct {
         self.pointer = Self::sidt();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pointer(limit: u16) -> DescriptorTablePointer {
+        DescriptorTablePointer {
+            limit,
+            base: VirtAddr::new(0xffff_8000_0000_0000),
+        }
+    }
+
+    #[test]
+    fn test_check_gdt_pointer() {
+        assert!(GDTStruct::check_pointer(&pointer(16 * 8 - 1), 4).is_ok());
+        assert!(GDTStruct::check_pointer(&pointer(4 * 8 - 1), 4).is_ok());
+        assert!(GDTStruct::check_pointer(&pointer(3 * 8 - 1), 4).is_err());
+        assert!(GDTStruct::check_pointer(&pointer(0), 1).is_err());
+        assert!(GDTStruct::check_pointer(&pointer(16 * 8), 4).is_err());
+    }
+
+    #[test]
+    fn test_check_idt_pointer() {
+        assert!(IDTStruct::check_pointer(&pointer(256 * 16 - 1)).is_ok());
+        assert!(IDTStruct::check_pointer(&pointer(32 * 16 - 1)).is_ok());
+        assert!(IDTStruct::check_pointer(&pointer(0)).is_err());
+        assert!(IDTStruct::check_pointer(&pointer(31 * 16 - 1)).is_err());
+        assert!(IDTStruct::check_pointer(&pointer(256 * 16 + 15)).is_err());
+        assert!(IDTStruct::check_pointer(&pointer(256 * 16)).is_err());
+    }
+}
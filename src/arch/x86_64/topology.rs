@@ -0,0 +1,171 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CPU topology decoded from the APIC ids.
+
+use spin::Once;
+
+use super::cpuid::{CpuFeatures, CpuIdEax, CpuIdResult};
+
+/// Position of a logical CPU in the topology.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuTopology {
+    pub package: usize,
+    pub core: usize,
+    pub thread: usize,
+}
+
+/// Layout of the APIC id: the thread id is in the bits below `smt_shift`, the core id in the
+/// bits from `smt_shift` to `package_shift`, and the package id above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ApicIdLayout {
+    smt_shift: u32,
+    package_shift: u32,
+}
+
+/// Level types in ECX[15:8] of CPUID leaves 0xB and 0x1F.
+const LEVEL_TYPE_INVALID: u32 = 0;
+const LEVEL_TYPE_SMT: u32 = 1;
+
+impl ApicIdLayout {
+    /// Used without topology leaf: one thread per core, and all the (8-bit) initial APIC ids in a
+    /// single package.
+    const FLAT: Self = Self {
+        smt_shift: 0,
+        package_shift: 8,
+    };
+
+    /// Decode the layout from CPUID leaf 0x1F, or leaf 0xB on older CPUs. `max_leaf` is the max
+    /// supported standard leaf.
+    fn from_cpuid(max_leaf: u32, leaf: impl Fn(u32, u32) -> CpuIdResult) -> Self {
+        let topo_leaf = [
+            CpuIdEax::V2ExtendedTopologyInfo,
+            CpuIdEax::ExtendedTopologyInfo,
+        ]
+        .iter()
+        .map(|&l| l as u32)
+        // EBX of sub-leaf 0 is 0 if the leaf is not supported.
+        .find(|&l| l <= max_leaf && leaf(l, 0).ebx != 0);
+        let topo_leaf = match topo_leaf {
+            Some(l) => l,
+            None => return Self::FLAT,
+        };
+
+        let mut layout = Self {
+            smt_shift: 0,
+            package_shift: 0,
+        };
+        // The levels are listed from the lowest one, the shift of the last level gives the
+        // position of the package id.
+        for sub_leaf in 0.. {
+            let res = leaf(topo_leaf, sub_leaf);
+            let level_type = (res.ecx >> 8) & 0xff;
+            if level_type == LEVEL_TYPE_INVALID {
+                break;
+            }
+            let shift = res.eax & 0x1f;
+            if level_type == LEVEL_TYPE_SMT {
+                layout.smt_shift = shift;
+            }
+            layout.package_shift = shift;
+        }
+        layout
+    }
+
+    fn decode(&self, apic_id: usize) -> CpuTopology {
+        let cores_per_package = 1 << (self.package_shift - self.smt_shift);
+        CpuTopology {
+            package: apic_id >> self.package_shift,
+            core: (apic_id >> self.smt_shift) & (cores_per_package - 1),
+            thread: apic_id & ((1 << self.smt_shift) - 1),
+        }
+    }
+}
+
+/// All the CPUs are assumed to have the same APIC id layout, so it's read once.
+fn layout() -> &'static ApicIdLayout {
+    static LAYOUT: Once<ApicIdLayout> = Once::new();
+    LAYOUT.call_once(|| {
+        let features = CpuFeatures::new();
+        ApicIdLayout::from_cpuid(features.max_leaf(), |eax, ecx| features.leaf(eax, ecx))
+    })
+}
+
+/// The topology of the CPU `cpuid` (see [`super::cpu::id()`]).
+pub fn topology(cpuid: usize) -> CpuTopology {
+    layout().decode(cpuid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(eax: u32, ebx: u32, ecx: u32) -> CpuIdResult {
+        CpuIdResult {
+            eax,
+            ebx,
+            ecx,
+            edx: 0,
+        }
+    }
+
+    /// Leaf 0xB of a CPU with 2 threads per core and 8 cores per package.
+    fn leaf_0xb(eax: u32, ecx: u32) -> CpuIdResult {
+        match (eax, ecx) {
+            (0xb, 0) => result(1, 2, LEVEL_TYPE_SMT << 8),
+            (0xb, 1) => result(4, 16, 2 << 8 | 1),
+            (0xb, _) => result(0, 0, ecx),
+            _ => result(0, 0, 0),
+        }
+    }
+
+    /// Leaf 0x1F of a CPU with no SMT, 4 cores per module and 2 modules per die.
+    fn leaf_0x1f(eax: u32, ecx: u32) -> CpuIdResult {
+        match (eax, ecx) {
+            (0x1f, 0) => result(0, 1, LEVEL_TYPE_SMT << 8),
+            (0x1f, 1) => result(2, 4, 2 << 8 | 1),
+            (0x1f, 2) => result(3, 8, 3 << 8 | 2),
+            (0x1f, _) => result(0, 0, ecx),
+            _ => leaf_0xb(eax, ecx),
+        }
+    }
+
+    #[test]
+    fn test_decode_leaf_0xb() {
+        let layout = ApicIdLayout::from_cpuid(0x16, leaf_0xb);
+        assert_eq!(layout.smt_shift, 1);
+        assert_eq!(layout.package_shift, 4);
+        let topo = |package, core, thread| CpuTopology {
+            package,
+            core,
+            thread,
+        };
+        assert_eq!(layout.decode(0), topo(0, 0, 0));
+        assert_eq!(layout.decode(0b1011), topo(0, 5, 1));
+        assert_eq!(layout.decode(0b10110), topo(1, 3, 0));
+    }
+
+    #[test]
+    fn test_decode_leaf_0x1f() {
+        let layout = ApicIdLayout::from_cpuid(0x1f, leaf_0x1f);
+        assert_eq!(layout.smt_shift, 0);
+        assert_eq!(layout.package_shift, 3);
+        assert_eq!(layout.decode(13).package, 1);
+        assert_eq!(layout.decode(13).core, 5);
+
+        // Leaf 0x1F is ignored if the max leaf is lower.
+        assert_eq!(ApicIdLayout::from_cpuid(0x16, leaf_0x1f).package_shift, 4);
+        assert_eq!(ApicIdLayout::from_cpuid(0xa, leaf_0x1f), ApicIdLayout::FLAT);
+    }
+}
@@ -22,7 +22,10 @@ mod vendor;
 
 use x86_64::registers::control::Cr4Flags;
 
+use super::msr_policy::{hw_msr_access, MsrExit, MSR_POLICY};
 use super::GuestRegisters;
+use crate::memory::PageFault;
+use crate::percpu::CpuState;
 use crate::{error::HvResult, percpu::PerCpu};
 
 pub use vendor::{
@@ -83,24 +86,40 @@ impl VmExit<'_> {
     }
 
     pub fn handle_msr_read(&mut self) -> HvResult {
-        let guest_regs = self.cpu_data.vcpu.regs_mut();
-        let id = guest_regs.rcx;
-        warn!("VM exit: RDMSR({:#x})", id);
-        // TODO
-        guest_regs.rax = 0;
-        guest_regs.rdx = 0;
-        self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_RDMSR)?;
-        Ok(())
+        let id = self.cpu_data.vcpu.regs().rcx as u32;
+        match MSR_POLICY.access(id, None, hw_msr_access)? {
+            MsrExit::Complete(value) => {
+                let guest_regs = self.cpu_data.vcpu.regs_mut();
+                guest_regs.rax = value & 0xffff_ffff;
+                guest_regs.rdx = value >> 32;
+                self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_RDMSR)
+            }
+            MsrExit::InjectGp => self.cpu_data.vcpu.inject_fault(),
+        }
     }
 
     pub fn handle_msr_write(&mut self) -> HvResult {
         let guest_regs = self.cpu_data.vcpu.regs();
-        let id = guest_regs.rcx;
-        let value = guest_regs.rax | (guest_regs.rdx << 32);
-        warn!("VM exit: WRMSR({:#x}) <- {:#x}", id, value);
-        // TODO
-        self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_WRMSR)?;
-        Ok(())
+        let id = guest_regs.rcx as u32;
+        let value = (guest_regs.rax & 0xffff_ffff) | (guest_regs.rdx << 32);
+        match MSR_POLICY.access(id, Some(value), hw_msr_access)? {
+            MsrExit::Complete(_) => self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_WRMSR),
+            MsrExit::InjectGp => {
+                warn!("VM exit: WRMSR({:#x}) <- {:#x} denied", id, value);
+                self.cpu_data.vcpu.inject_fault()
+            }
+        }
+    }
+
+    /// Handle a stage-2 (EPT or NPT) fault decoded by the vendor exit handler, `fault.addr` is
+    /// the guest-physical address. Only the faults of a running enclave are handled, by mapping
+    /// the page lazily.
+    pub fn handle_stage2_fault(&mut self, fault: &PageFault, final_translation: bool) -> HvResult {
+        if self.cpu_data.state != CpuState::EnclaveRunning {
+            return hv_result_err!(ENOSYS, format!("Unhandled stage-2 fault: {:#x?}", fault));
+        }
+        let enclave = self.cpu_data.get_current_enclave()?;
+        enclave.handle_npt_violation(fault.addr, final_translation)
     }
 
     pub fn handle_cpuid(&mut self) -> HvResult {
@@ -148,7 +167,8 @@ impl VmExit<'_> {
         use crate::hypercall::HyperCall;
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_HYPERCALL)?;
         let guest_regs = self.cpu_data.vcpu.regs();
-        let (code, arg0, arg1) = (guest_regs.rax, guest_regs.rdi, guest_regs.rsi);
+        let (code, args) = (guest_regs.hypercall_code(), guest_regs.hypercall_args());
+        let (arg0, arg1) = (args[0], args[1]);
         match HyperCall::new(&mut self.cpu_data).hypercall(code as _, arg0, arg1) {
             None => (),
             Some(exception_info) => {
@@ -177,13 +197,24 @@ impl VmExit<'_> {
     }
 }
 
-pub(super) fn vmexit_handler() {
+/// Handle a VM exit, `regs_sp` is the frame the guest registers were saved to by
+/// `save_regs_to_stack!`.
+pub(super) fn vmexit_handler(regs_sp: usize) {
+    // Safety: the frame stays on the stack until the handler returns.
+    let saved_regs = || unsafe { GuestRegisters::from_stack(regs_sp) };
+    let prev = log_enabled!(log::Level::Trace).then(saved_regs);
     let mut vmexit = VmExit::new();
     let res = vmexit.handle_exit();
+    if let Some(prev) = prev {
+        for (name, old, new) in vmexit.cpu_data.vcpu.regs().diff(&prev) {
+            trace!("VM exit changed {}: {:#x} -> {:#x}", name, old, new);
+        }
+    }
     if let Err(err) = res {
+        let regs = saved_regs();
         error!(
-            "Failed to handle VM exit, inject fault to guest...\n{:?}",
-            err
+            "Failed to handle VM exit, inject fault to guest...\n{:?}\n{:#x?}",
+            err, regs
         );
         vmexit.cpu_data.fault().unwrap();
     }
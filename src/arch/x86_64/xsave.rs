@@ -57,28 +57,49 @@ impl XsaveRegion {
     }
 
     pub fn validate_at_resume(&self, xfrm: u64) -> HvResult {
-        let xstate_bv = &self.0[512..520].try_into().unwrap();
-        let xstate_bv = u64::from_ne_bytes(*xstate_bv);
-        if xstate_bv & xfrm != xstate_bv {
-            return hv_result_err!(
-                EINVAL,
-                "XsaveRegion::validate_at_resume(): xstate_bv must be a subset of xfrm"
-            );
-        }
+        validate_xsave_header(&self.0, xfrm)
+    }
+}
 
-        let slice: &[u8; 16] = &self.0[520..536].try_into().unwrap();
-        for i in (0..slice.len()).step_by(8) {
-            let arr: [u8; 8] = slice[i..i + 8].try_into().unwrap();
-            if u64::from_ne_bytes(arr) != 0 {
-                return hv_result_err!(
-                    EINVAL,
-                    "XsaveRegion::validate_at_resume(): Offsets 520 - 535 of XSAVE area should be 0"
-                );
-            }
-        }
+/// Offset of MXCSR in the legacy region.
+const MXCSR_OFFSET: usize = 24;
+/// MXCSR bits 31:16 are reserved, `xrstor` faults if any of them is set.
+const MXCSR_RESERVED: u32 = 0xffff_0000;
+/// XCR0 bits of the SSE and AVX states, `xrstor` loads MXCSR if either is requested.
+const XCR0_SSE_AVX: u64 = 0b110;
+
+/// Check the XSAVE area `buf` (in the standard format) before `xrstor` loads it with the
+/// requested-feature bitmap `xcr0`, which must not exceed the host XCR0. The area may come from
+/// the guest, so any header that would make `xrstor` fault or load a state outside of `xcr0` is
+/// rejected:
+///
+/// - XSTATE_BV must be a subset of `xcr0`.
+/// - XCOMP_BV and the following reserved bytes (offsets 520 - 535) must be 0.
+/// - The reserved bits of MXCSR must be 0 if MXCSR is loaded.
+pub fn validate_xsave_header(buf: &[u8], xcr0: u64) -> HvResult {
+    let header_end = XSAVE_LEGACY_REGION_SIZE + XSAVE_HEADER_SIZE;
+    if buf.len() < header_end {
+        return hv_result_err!(EINVAL, format!("XSAVE area too small: {:#x}", buf.len()));
+    }
+    let read_u64 = |offset: usize| u64::from_ne_bytes(buf[offset..offset + 8].try_into().unwrap());
 
-        Ok(())
+    let xstate_bv = read_u64(XSAVE_LEGACY_REGION_SIZE);
+    if xstate_bv & xcr0 != xstate_bv {
+        return hv_result_err!(
+            EINVAL,
+            format!("XSTATE_BV {:#x} must be a subset of {:#x}", xstate_bv, xcr0)
+        );
+    }
+    if read_u64(520) != 0 || read_u64(528) != 0 {
+        return hv_result_err!(EINVAL, "Offsets 520 - 535 of XSAVE area should be 0");
     }
+
+    let mxcsr_bytes = buf[MXCSR_OFFSET..MXCSR_OFFSET + 4].try_into().unwrap();
+    let mxcsr = u32::from_ne_bytes(mxcsr_bytes);
+    if xcr0 & XCR0_SSE_AVX != 0 && mxcsr & MXCSR_RESERVED != 0 {
+        return hv_result_err!(EINVAL, format!("Reserved MXCSR bits set: {:#x}", mxcsr));
+    }
+    Ok(())
 }
 
 impl Debug for XsaveRegion {
@@ -88,3 +109,46 @@ impl Debug for XsaveRegion {
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XCR0: u64 = 0b111; // x87, SSE, AVX
+
+    fn area(xstate_bv: u64) -> [u8; XSAVE_REGION_SIZE] {
+        let mut buf = [0; XSAVE_REGION_SIZE];
+        buf[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&0x1f80u32.to_ne_bytes());
+        buf[512..520].copy_from_slice(&xstate_bv.to_ne_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_valid_header() {
+        assert!(validate_xsave_header(&area(0b11), XCR0).is_ok());
+        assert!(validate_xsave_header(&area(0), XCR0).is_ok());
+        // Garbage in MXCSR is fine if it's not loaded.
+        let mut buf = area(0b1);
+        buf[MXCSR_OFFSET + 3] = 0xff;
+        assert!(validate_xsave_header(&buf, 0b1).is_ok());
+    }
+
+    #[test]
+    fn test_malformed_header() {
+        // A state not enabled in XCR0.
+        assert!(validate_xsave_header(&area(0b1011), XCR0).is_err());
+        // Compacted format or reserved bytes.
+        for offset in [520, 527, 535] {
+            let mut buf = area(0b11);
+            buf[offset] = 0x80;
+            assert!(validate_xsave_header(&buf, XCR0).is_err(), "{}", offset);
+        }
+        // Reserved MXCSR bits.
+        let mut buf = area(0b11);
+        buf[MXCSR_OFFSET + 2] = 1;
+        assert!(validate_xsave_header(&buf, XCR0).is_err());
+        // Truncated area.
+        let buf = area(0b11);
+        assert!(validate_xsave_header(&buf[..575], XCR0).is_err());
+    }
+}
@@ -14,13 +14,13 @@
 
 use crate::arch::{vmm::IoPageTable, HostPageTable, NestedPageTable};
 use crate::config::HvSystemConfig;
-use crate::consts::{HV_BASE, PER_CPU_SIZE};
+use crate::consts::PER_CPU_SIZE;
 use crate::error::HvResult;
 use crate::header::HvHeader;
 use crate::intervaltree::IntervalTree;
 use crate::memory::addr::{phys_to_virt, GuestPhysAddr, HostPhysAddr, HostVirtAddr};
 use crate::memory::cmr::NR_INIT_EPC_RANGES;
-use crate::memory::{MemFlags, MemoryRegion, MemorySet};
+use crate::memory::{build_hypervisor_tables, check_null_guard, MemFlags, MemoryRegion, MemorySet};
 
 #[derive(Debug)]
 pub struct Cell {
@@ -39,10 +39,9 @@ impl Cell {
         let header = HvHeader::get();
         let sys_config = HvSystemConfig::get();
 
-        let hv_phys_start = sys_config.hypervisor_memory.phys_start as usize;
-        let hv_phys_size = sys_config.hypervisor_memory.size as usize;
+        let (hv_region, _) = sys_config.hypervisor_region();
+        let (hv_phys_start, hv_phys_size) = (hv_region.start, hv_region.size);
         let mut gpm = MemorySet::new();
-        let mut hvm = MemorySet::new();
         let mut dma_regions = MemorySet::new();
         let mut normal_world_mem_region = IntervalTree::new();
 
@@ -88,48 +87,51 @@ impl Cell {
             ))?;
         }
 
-        // all physical memory regions
-        for region in sys_config.mem_regions() {
-            let r = MemoryRegion::new_with_offset_mapper(
+        // all physical memory regions, adjacent ones merged to use fewer mappings
+        for region in sys_config.coalesced_regions() {
+            gpm.insert(MemoryRegion::new_with_offset_mapper(
                 region.virt_start as GuestPhysAddr,
                 region.phys_start as HostPhysAddr,
                 region.size as usize,
                 region.flags - MemFlags::ENCRYPTED, // guest should not read decrypted data
+            ))?;
+        }
+        // DMA regions are decided on the original regions, so a region is never IOMMU mapped
+        // because of an adjacent DMA or RMRR region it was merged with.
+        for region in sys_config.mem_regions() {
+            let (gpa_range, flags) = region.as_virt_range();
+            let r = MemoryRegion::new_with_offset_mapper(
+                gpa_range.start as GuestPhysAddr,
+                region.phys_start as HostPhysAddr,
+                gpa_range.size,
+                flags - MemFlags::ENCRYPTED,
             );
-            if region.flags.contains(MemFlags::DMA) {
-                dma_regions.insert(r.clone())?;
+            if flags.contains(MemFlags::DMA) {
+                dma_regions.insert(r)?;
             } else {
                 for rmrr_range in sys_config.rmrr_ranges() {
                     //if region contains rmrr_range
                     if region.phys_start <= rmrr_range.base
                         && rmrr_range.limit <= region.phys_start + region.size
                     {
-                        dma_regions.insert(r.clone())?;
+                        dma_regions.insert(r)?;
                         break;
                     }
                 }
             }
-            gpm.insert(r)?;
         }
 
-        // Init host virtual memory set, create host page table.
-        let core_and_percpu_size =
-            header.core_size as usize + header.max_cpus as usize * PER_CPU_SIZE;
-        // hypervisor core
-        hvm.insert(MemoryRegion::new_with_offset_mapper(
-            HV_BASE,
-            hv_phys_start,
+        // Init host virtual memory set, create host page table with the hypervisor memory and
+        // the DMA regions.
+        let mut hvm = build_hypervisor_tables(
+            sys_config,
             header.core_size,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE | MemFlags::ENCRYPTED,
-        ))?;
-        // configurations & hypervisor free memory
-        hvm.insert(MemoryRegion::new_with_offset_mapper(
-            HV_BASE + core_and_percpu_size,
-            hv_phys_start + core_and_percpu_size,
-            hv_phys_size - core_and_percpu_size,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::ENCRYPTED,
-        ))?;
+            header.max_cpus as usize * PER_CPU_SIZE,
+        )?;
+        // Catch allocator and mapping bugs while only the configured memory is mapped.
+        hvm.page_table().self_check()?;
         // guest RAM
+        check_null_guard(header.tpm_mmio_pa, header.tpm_mmio_size as usize, false)?;
         hvm.insert(MemoryRegion::new_with_offset_mapper(
             header.tpm_mmio_pa,
             header.tpm_mmio_pa,
@@ -137,32 +139,20 @@ impl Cell {
             MemFlags::READ | MemFlags::WRITE,
         ))?;
         println!("tpm mmio is mapped va={:#x}", header.tpm_mmio_pa);
+        // comm region, shared with the host in plaintext
+        if let Some((comm, flags)) = sys_config.comm_region()? {
+            if flags.contains(MemFlags::DMA) {
+                return hv_result_err!(EINVAL, "The comm region can't be a DMA region");
+            }
+            hvm.insert(MemoryRegion::new_with_offset_mapper(
+                phys_to_virt(comm.start),
+                comm.start,
+                comm.size,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::COMM_REGION,
+            ))?;
+        }
         for region in sys_config.mem_regions() {
-            if region.flags.contains(MemFlags::DMA) {
-                let hv_virt_start = phys_to_virt(region.virt_start as GuestPhysAddr);
-                if hv_virt_start < region.virt_start as GuestPhysAddr {
-                    return hv_result_err!(
-                        EINVAL,
-                        format!(
-                            "Guest physical address {:#x} is too large",
-                            region.virt_start
-                        )
-                    );
-                }
-                hvm.insert(MemoryRegion::new_with_offset_mapper(
-                    hv_virt_start,
-                    region.phys_start as HostPhysAddr,
-                    region.size as usize,
-                    MemFlags::READ | MemFlags::WRITE,
-                ))?;
-                // Support hardware encrypt when swap out EPC page to guest RAM
-                #[cfg(feature = "sme")]
-                hvm.insert(MemoryRegion::new_with_offset_mapper(
-                    region.virt_start as HostVirtAddr,
-                    region.phys_start as HostPhysAddr,
-                    region.size as usize,
-                    MemFlags::READ | MemFlags::WRITE | MemFlags::ENCRYPTED,
-                ))?;
+            if region.flags().contains(MemFlags::DMA) {
                 normal_world_mem_region.insert(
                     (region.phys_start as usize)..(region.phys_start + region.size) as usize,
                 )?;
@@ -12,13 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use alloc::vec::Vec;
 use core::fmt::Debug;
 use core::{mem::size_of, slice};
 
-use crate::consts::HV_BASE;
-use crate::header::HvHeader;
-use crate::memory::MemFlags;
-use crate::percpu::PER_CPU_SIZE;
+use spin::Once;
+
+use crate::consts::{HV_BASE, PAGE_SIZE};
+use crate::error::HvResult;
+use crate::layout;
+use crate::memory::{AddrRange, MemFlags, PhysAddr};
 
 // 最大iommu单元数
 const HV_MAX_IOMMU_UNITS: usize = 16;
@@ -35,6 +38,47 @@ pub struct HvMemoryRegion {
     pub flags: MemFlags,
 }
 
+impl HvMemoryRegion {
+    /// Returns the flags of the region. The field is copied with `read_unaligned()`, as it may
+    /// be misaligned in this packed struct and must never be borrowed.
+    pub fn flags(&self) -> MemFlags {
+        unsafe { core::ptr::addr_of!(self.flags).read_unaligned() }
+    }
+
+    /// Returns the physical range and the flags of the region, copied out of the packed fields.
+    pub fn as_phys_range(&self) -> (AddrRange, MemFlags) {
+        let (start, size, flags) = (self.phys_start, self.size, self.flags());
+        (AddrRange::new(start as usize, size as usize), flags)
+    }
+
+    /// Returns the virtual range and the flags of the region, copied out of the packed fields.
+    pub fn as_virt_range(&self) -> (AddrRange, MemFlags) {
+        let (start, size, flags) = (self.virt_start, self.size, self.flags());
+        (AddrRange::new(start as usize, size as usize), flags)
+    }
+}
+
+/// A copy of a `HvMemoryRegion` with aligned fields, which can be reordered or merged without
+/// touching the read-only configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionView {
+    pub phys_start: u64,
+    pub virt_start: u64,
+    pub size: u64,
+    pub flags: MemFlags,
+}
+
+impl From<&HvMemoryRegion> for RegionView {
+    fn from(r: &HvMemoryRegion) -> Self {
+        Self {
+            phys_start: r.phys_start,
+            virt_start: r.virt_start,
+            size: r.size,
+            flags: r.flags(),
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct HvIommuInfo {
@@ -87,13 +131,48 @@ struct ConfigLayout {
     mem_regions: [HvMemoryRegion; 0],
 }
 
+/// Whether the location of the system config was found inside the hypervisor image.
+static LOCATION_CHECKED: Once<bool> = Once::new();
+
 impl HvSystemConfig {
+    /// Returns the system config. Its location is validated once at boot by
+    /// [`HvSystemConfig::check_location()`].
     pub fn get<'a>() -> &'a Self {
-        // 获取系统配置的静态方法，计算虚拟机监控器核心和每CPU大小的总和，然后返回指向系统配置的指针
-        let header = HvHeader::get();
-        let core_and_percpu_size =
-            header.core_size as usize + header.max_cpus as usize * PER_CPU_SIZE;
-        unsafe { &*((HV_BASE + core_and_percpu_size) as *const Self) }
+        debug_assert!(
+            LOCATION_CHECKED.get() == Some(&true),
+            "System config used before its location is checked"
+        );
+        unsafe { Self::get_unchecked() }
+    }
+
+    /// Check once that the system config lies inside the hypervisor image, failing after a
+    /// corruption of the header or config size fields by the loader. The result is cached.
+    ///
+    /// Doesn't use the heap, to be called before it is initialized.
+    pub fn check_location() -> HvResult {
+        let ok = *LOCATION_CHECKED.call_once(|| match layout::check_hv_image() {
+            Ok(()) => true,
+            Err(reason) => {
+                println!("Bogus hypervisor image layout: {}", reason);
+                false
+            }
+        });
+        if ok {
+            Ok(())
+        } else {
+            hv_result_err!(EINVAL)
+        }
+    }
+
+    /// Returns the system config without checking its location.
+    ///
+    /// # Safety
+    ///
+    /// The sizes in the header and the config are not validated, see
+    /// [`HvSystemConfig::check_location()`].
+    pub unsafe fn get_unchecked<'a>() -> &'a Self {
+        // 系统配置位于每CPU数组之后
+        &*(layout::config_base() as *const Self)
     }
 
     fn config_ptr<T>(&self) -> *const T {
@@ -108,6 +187,7 @@ impl HvSystemConfig {
 
     pub fn iommu_units(&self) -> &[HvIommuInfo] {
         // 返回IOMMU单元信息的切片
+        // The elements are packed (alignment 1), borrowing them out of the config is sound.
         let mut n = 0;
         while n < HV_MAX_IOMMU_UNITS && self.platform_info.arch.iommu_units[n].base != 0 {
             n += 1;
@@ -116,6 +196,7 @@ impl HvSystemConfig {
     }
     pub fn rmrr_ranges(&self) -> &[HvRmrrRange] {
         // 返回RMRR范围信息的切片
+        // The elements are packed (alignment 1), borrowing them out of the config is sound.
         let mut n = 0;
         while n < HV_MAX_RMRR_RANGE && self.platform_info.arch.rmrr_ranges[n].limit != 0 {
             n += 1;
@@ -127,4 +208,331 @@ impl HvSystemConfig {
         // 返回内存区域信息的切片
         unsafe { slice::from_raw_parts(self.config_ptr(), self.num_memory_regions as usize) }
     }
+
+    /// Returns the memory regions sorted by `phys_start`.
+    pub fn sorted_regions(&self) -> Vec<RegionView> {
+        sort_regions(self.mem_regions())
+    }
+
+    /// Returns the memory regions sorted by `phys_start`, with the adjacent regions of the same
+    /// flags merged.
+    pub fn coalesced_regions(&self) -> Vec<RegionView> {
+        coalesce_regions(self.sorted_regions())
+    }
+
+    /// Returns the physical range and the flags of the hypervisor memory, which is mapped at
+    /// `HV_BASE` and thus defines the linear mapping offset.
+    pub fn hypervisor_region(&self) -> (AddrRange, MemFlags) {
+        self.hypervisor_memory.as_phys_range()
+    }
+
+    /// Returns the offset from a physical address to its virtual address in the hypervisor's
+    /// linear mapping.
+    pub fn phys_virt_offset(&self) -> usize {
+        HV_BASE - self.hypervisor_region().0.start
+    }
+
+    /// Check that the hypervisor memory and the memory regions are page aligned, not empty,
+    /// don't overlap, and fit in the linear mapping.
+    pub fn validate(&self) -> HvResult {
+        let hv_memory = RegionView::from(&self.hypervisor_memory);
+        validate_regions(&hv_memory, &self.sorted_regions(), self.phys_virt_offset())
+    }
+
+    /// Returns the size of the physical memory described by the configuration, that is the end
+    /// address of the highest region (including the hypervisor memory).
+    pub fn total_memory_size(&self) -> usize {
+        memory_end(core::iter::once(&self.hypervisor_memory).chain(self.mem_regions()))
+    }
+
+    /// Returns the region containing the physical address `paddr`, looking at the hypervisor
+    /// memory first and then at the memory regions.
+    pub fn find_memory_region(&self, paddr: PhysAddr) -> Option<RegionView> {
+        find_region(
+            core::iter::once(&self.hypervisor_memory).chain(self.mem_regions()),
+            paddr,
+        )
+    }
+
+    /// Returns the physical range and the flags of the memory region flagged `COMM_REGION`,
+    /// shared with the host for the comm ABI. Fails if more than one region is flagged.
+    pub fn comm_region(&self) -> HvResult<Option<(AddrRange, MemFlags)>> {
+        find_comm_region(self.mem_regions())
+    }
+}
+
+fn find_comm_region<'a>(
+    regions: impl IntoIterator<Item = &'a HvMemoryRegion>,
+) -> HvResult<Option<(AddrRange, MemFlags)>> {
+    let mut comm = regions
+        .into_iter()
+        .filter(|r| r.flags().contains(MemFlags::COMM_REGION));
+    let found = comm.next();
+    if let Some(other) = comm.next() {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "More than one comm region: {:#x?} and {:#x?}",
+                found.map(RegionView::from),
+                RegionView::from(other)
+            )
+        );
+    }
+    Ok(found.map(HvMemoryRegion::as_phys_range))
+}
+
+pub(crate) fn find_region<'a>(
+    regions: impl IntoIterator<Item = &'a HvMemoryRegion>,
+    paddr: PhysAddr,
+) -> Option<RegionView> {
+    regions
+        .into_iter()
+        .find(|r| r.as_phys_range().0.contains(paddr))
+        .map(RegionView::from)
+}
+
+/// Check that the regions are page aligned, not empty, don't overlap each other nor the
+/// hypervisor memory, and fit in the linear mapping at `offset`. `sorted` must be sorted by
+/// `phys_start`.
+fn validate_regions(hv_memory: &RegionView, sorted: &[RegionView], offset: usize) -> HvResult {
+    let overlaps = |a: &RegionView, b: &RegionView| {
+        a.phys_start < b.phys_start + b.size && b.phys_start < a.phys_start + a.size
+    };
+    for (i, r) in core::iter::once(hv_memory).chain(sorted).enumerate() {
+        let (start, size) = (r.phys_start as usize, r.size as usize);
+        if size == 0 || start % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return hv_result_err!(EINVAL, format!("Invalid memory region: {:#x?}", r));
+        }
+        if (start + size).checked_add(offset).is_none() {
+            return hv_result_err!(
+                EINVAL,
+                format!("Memory region out of the linear mapping: {:#x?}", r)
+            );
+        }
+        let prev = if i > 1 { Some(&sorted[i - 2]) } else { None };
+        if (i > 0 && overlaps(r, hv_memory)) || prev.map_or(false, |prev| overlaps(r, prev)) {
+            return hv_result_err!(EINVAL, format!("Overlapped memory region: {:#x?}", r));
+        }
+    }
+    Ok(())
+}
+
+fn memory_end<'a>(regions: impl IntoIterator<Item = &'a HvMemoryRegion>) -> usize {
+    regions
+        .into_iter()
+        .map(|r| r.phys_start + r.size)
+        .max()
+        .unwrap_or(0) as usize
+}
+
+fn sort_regions(regions: &[HvMemoryRegion]) -> Vec<RegionView> {
+    let mut sorted: Vec<RegionView> = regions.iter().map(RegionView::from).collect();
+    sorted.sort_unstable_by_key(|r| r.phys_start);
+    sorted
+}
+
+/// Merge the regions of `sorted` that are both physically and virtually contiguous and have the
+/// same flags, so the merged region still maps with a single offset.
+fn coalesce_regions(sorted: Vec<RegionView>) -> Vec<RegionView> {
+    let mut merged: Vec<RegionView> = Vec::with_capacity(sorted.len());
+    for r in sorted {
+        if let Some(last) = merged.last_mut() {
+            if last.flags == r.flags
+                && last.phys_start + last.size == r.phys_start
+                && last.virt_start + last.size == r.virt_start
+            {
+                last.size += r.size;
+                continue;
+            }
+        }
+        merged.push(r);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(phys_start: u64, virt_start: u64, size: u64, flags: MemFlags) -> HvMemoryRegion {
+        HvMemoryRegion {
+            phys_start,
+            virt_start,
+            size,
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_sort_regions() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let regions = [
+            region(0x3000, 0x3000, 0x1000, rw),
+            region(0x1000, 0x1000, 0x1000, MemFlags::READ),
+            region(0x8000, 0x2000, 0x2000, rw),
+            region(0x0, 0x0, 0x1000, rw | MemFlags::IO),
+        ];
+        let sorted = sort_regions(&regions);
+        let starts: Vec<u64> = sorted.iter().map(|r| r.phys_start).collect();
+        assert_eq!(starts, [0x0, 0x1000, 0x3000, 0x8000]);
+        assert_eq!(sorted[1], RegionView::from(&regions[1]));
+        assert_eq!(sorted[3].virt_start, 0x2000);
+        // The source is left untouched.
+        assert_eq!({ regions[0].phys_start }, 0x3000);
+    }
+
+    #[test]
+    fn test_find_region() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let regions = [
+            region(0x10_0000, 0x10_0000, 0x1000, rw),
+            region(0x0, 0x0, 0x1000, rw | MemFlags::IO),
+        ];
+        let found = find_region(&regions, 0x10_0fff).unwrap();
+        assert_eq!(found.phys_start, 0x10_0000);
+        assert_eq!(find_region(&regions, 0x0).unwrap().flags, rw | MemFlags::IO);
+        assert!(find_region(&regions, 0x10_1000).is_none());
+        assert!(find_region(&regions, 0x1000).is_none());
+    }
+
+    #[test]
+    fn test_as_range() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let r = region(0x10_0000, 0xffff_8000_0010_0000, 0x3000, rw);
+        let (range, flags) = r.as_phys_range();
+        assert_eq!(range.start as u64, { r.phys_start });
+        assert_eq!(range.size as u64, { r.size });
+        assert_eq!(range.end(), 0x10_3000);
+        assert_eq!(flags, r.flags());
+
+        let (range, flags) = r.as_virt_range();
+        assert_eq!(range.start as u64, { r.virt_start });
+        assert_eq!(range.size as u64, { r.size });
+        assert!(range.contains(0xffff_8000_0010_2fff) && !range.contains(0xffff_8000_0010_3000));
+        assert_eq!(flags, rw);
+    }
+
+    #[test]
+    fn test_validate_regions() {
+        const OFFSET: usize = 0xffff_8000_0000_0000;
+        let view = |phys_start, size, flags| {
+            RegionView::from(&region(phys_start, phys_start, size, flags))
+        };
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let hv_memory = view(0x1_0000_0000, 0x400_0000, rw);
+        let check = |regions: &[RegionView]| validate_regions(&hv_memory, regions, OFFSET);
+
+        assert!(check(&[view(0x0, 0x1000, rw), view(0x1000, 0x1000, rw)]).is_ok());
+        // Misaligned or empty.
+        assert!(check(&[view(0x800, 0x1000, rw)]).is_err());
+        assert!(check(&[view(0x0, 0x800, rw)]).is_err());
+        assert!(check(&[view(0x0, 0, rw)]).is_err());
+        // Overlapped with each other or with the hypervisor memory.
+        assert!(check(&[view(0x0, 0x2000, rw), view(0x1000, 0x1000, rw)]).is_err());
+        assert!(check(&[view(0x1_03ff_f000, 0x2000, rw)]).is_err());
+        // Out of the linear mapping.
+        assert!(check(&[view(0x8000_0000_0000, 0x1000, rw)]).is_err());
+    }
+
+    #[test]
+    fn test_memory_end() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let regions = [
+            region(0x10_0000, 0x10_0000, 0x1000, rw),
+            region(0x0, 0x0, 0x1000, rw | MemFlags::IO),
+        ];
+        assert_eq!(memory_end(&regions), 0x10_1000);
+        assert_eq!(memory_end(&[]), 0);
+    }
+
+    #[test]
+    fn test_coalesce_regions() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let regions = [
+            region(0x2000, 0x2000, 0x1000, rw),
+            region(0x0, 0x0, 0x1000, rw),
+            region(0x1000, 0x1000, 0x1000, rw),
+            // Different flags.
+            region(0x3000, 0x3000, 0x1000, MemFlags::READ),
+            // Physically contiguous, but not virtually.
+            region(0x4000, 0x8000, 0x1000, MemFlags::READ),
+            // Contiguous with the previous one.
+            region(0x5000, 0x9000, 0x2000, MemFlags::READ),
+            // Gap.
+            region(0x8000, 0xc000, 0x1000, MemFlags::READ),
+        ];
+        let merged = coalesce_regions(sort_regions(&regions));
+        let expected = [
+            (0x0, 0x0, 0x3000, rw),
+            (0x3000, 0x3000, 0x1000, MemFlags::READ),
+            (0x4000, 0x8000, 0x3000, MemFlags::READ),
+            (0x8000, 0xc000, 0x1000, MemFlags::READ),
+        ];
+        assert_eq!(merged.len(), expected.len());
+        for (r, &(phys_start, virt_start, size, flags)) in merged.iter().zip(expected.iter()) {
+            let expected = region(phys_start, virt_start, size, flags);
+            assert_eq!(*r, RegionView::from(&expected));
+        }
+    }
+
+    #[test]
+    fn test_region_flags_unaligned() {
+        use core::mem::align_of;
+
+        let flags = MemFlags::READ | MemFlags::WRITE | MemFlags::IO;
+        // Put the region at an odd address, so its `flags` field is misaligned.
+        let mut buf = [0u8; size_of::<HvMemoryRegion>() + 1];
+        let ptr = buf[1..].as_mut_ptr() as *mut HvMemoryRegion;
+        let r = unsafe {
+            ptr.write(region(0x1000, 0x2000, 0x3000, flags));
+            &*ptr
+        };
+        assert_eq!(r.flags(), flags);
+        assert_eq!(r.as_phys_range(), (AddrRange::new(0x1000, 0x3000), flags));
+        assert_eq!(RegionView::from(r).flags, flags);
+
+        // Slices of these are returned by `mem_regions()`, `iommu_units()` and `rmrr_ranges()`.
+        assert_eq!(align_of::<HvMemoryRegion>(), 1);
+        assert_eq!(align_of::<HvIommuInfo>(), 1);
+        assert_eq!(align_of::<HvRmrrRange>(), 1);
+    }
+
+    #[test]
+    fn test_hypervisor_region() {
+        let flags = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
+        // All the fields are plain integers, zero is a valid empty config.
+        let mut config: HvSystemConfig = unsafe { core::mem::zeroed() };
+        config.hypervisor_memory = region(0x1_0000_0000, 0, 0x400_0000, flags);
+
+        let (range, f) = config.hypervisor_region();
+        assert_eq!(range.start as u64, { config.hypervisor_memory.phys_start });
+        assert_eq!(range.size as u64, { config.hypervisor_memory.size });
+        assert_eq!(f, flags);
+
+        // The linear mapping puts the start of the region at `HV_BASE`.
+        let offset = config.phys_virt_offset();
+        assert_eq!(range.start + offset, HV_BASE);
+        assert_eq!(range.end() - 1 + offset, HV_BASE + 0x400_0000 - 1);
+    }
+
+    #[test]
+    fn test_find_comm_region() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let comm = rw | MemFlags::COMM_REGION;
+        let mut regions = [
+            region(0x0, 0x0, 0x1000, rw),
+            region(0x10_0000, 0x10_0000, 0x2000, rw),
+            region(0x20_0000, 0x20_0000, 0x1000, rw | MemFlags::IO),
+        ];
+        assert_eq!(find_comm_region(&regions).unwrap(), None);
+
+        regions[1].flags = comm;
+        assert_eq!(
+            find_comm_region(&regions).unwrap(),
+            Some((AddrRange::new(0x10_0000, 0x2000), comm))
+        );
+
+        regions[2].flags = comm;
+        assert!(find_comm_region(&regions).is_err());
+    }
 }
@@ -17,6 +17,8 @@ use crate::HvResult;
 
 use core::mem::size_of;
 
+use spin::{RwLock, RwLockReadGuard};
+
 // NR_CPUS：最大支持的CPU数量，设置为512
 const NR_CPUS: usize = 512;
 // BITS_PER_BYTE：每个字节的位数，设置为8
@@ -27,11 +29,17 @@ const BITS_PER_USIZE: usize = size_of::<usize>() * BITS_PER_BYTE;
 pub const CPU_MASK_LEN: usize = (NR_CPUS + BITS_PER_USIZE - 1) / BITS_PER_USIZE;
 
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq, Eq)]
 // 定义了一个CpuMask结构体，包含一个长度为CPU_MASK_LEN的usize数组
 pub struct CpuMask([usize; CPU_MASK_LEN]);
 
+// The host driver lays out its cpumask as `NR_CPUS` bits.
+static_assertions::const_assert_eq!(size_of::<CpuMask>(), CpuMask::BYTE_LEN);
+
 impl CpuMask {
+    /// Size in bytes of the mask shared with the host.
+    pub const BYTE_LEN: usize = NR_CPUS / BITS_PER_BYTE;
+
     pub fn set_cpu(&mut self, cpuid: usize) {
         self.0[cpuid / BITS_PER_USIZE] |= 1 << (cpuid % BITS_PER_USIZE);
     }
@@ -47,6 +55,15 @@ impl CpuMask {
     pub fn clear(&mut self) {
         self.0 = [0; CPU_MASK_LEN];
     }
+
+    /// Iterate the ids of the set CPUs in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(i, &word)| {
+            (0..BITS_PER_USIZE)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * BITS_PER_USIZE + bit)
+        })
+    }
 }
 
 pub fn check_max_cpus() -> HvResult {
@@ -61,3 +78,111 @@ pub fn check_max_cpus() -> HvResult {
     }
     Ok(())
 }
+
+/// CPUs the hypervisor is activated on, among the first `max_cpus` ones.
+struct OnlineCpus {
+    max_cpus: usize,
+    mask: RwLock<CpuMask>,
+}
+
+impl OnlineCpus {
+    fn new(max_cpus: usize) -> Self {
+        Self {
+            max_cpus: max_cpus.min(NR_CPUS),
+            mask: RwLock::new(CpuMask::default()),
+        }
+    }
+
+    fn set(&self, cpuid: usize, online: bool) -> HvResult {
+        if cpuid >= self.max_cpus {
+            return hv_result_err!(
+                EINVAL,
+                format!("Invalid cpu id: {}, max_cpus: {}", cpuid, self.max_cpus)
+            );
+        }
+        let mut mask = self.mask.write();
+        if online {
+            mask.set_cpu(cpuid);
+        } else {
+            mask.clear_cpu(cpuid);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref ONLINE_CPUS: OnlineCpus = OnlineCpus::new(HvHeader::get().max_cpus as usize);
+}
+
+/// The CPUs the hypervisor is currently activated on, the target of shootdowns and broadcasts.
+/// Don't hold the guard for long, `set_online()` and `set_offline()` wait for it.
+///
+/// The CPUs are numbered by their Linux ids, the ones passed to the hypervisor entry and
+/// indexing the `PerCpu` regions, not by their APIC ids like in `arch::topology`.
+pub fn online_cpus() -> RwLockReadGuard<'static, CpuMask> {
+    ONLINE_CPUS.mask.read()
+}
+
+/// Add `cpuid` to `online_cpus()`, fails if it's not below `max_cpus`.
+pub fn set_online(cpuid: usize) -> HvResult {
+    ONLINE_CPUS.set(cpuid, true)
+}
+
+/// Remove `cpuid` from `online_cpus()`, fails if it's not below `max_cpus`.
+pub fn set_offline(cpuid: usize) -> HvResult {
+    ONLINE_CPUS.set(cpuid, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_of(ids: &[usize]) -> CpuMask {
+        let mut mask = CpuMask::default();
+        ids.iter().for_each(|&id| mask.set_cpu(id));
+        mask
+    }
+
+    #[test]
+    fn test_set_cpu() {
+        let mask = mask_of(&[0, 3, 64, NR_CPUS - 1]);
+        for id in 0..NR_CPUS {
+            let expected = matches!(id, 0 | 3 | 64) || id == NR_CPUS - 1;
+            assert_eq!(mask.test_cpu(id) != 0, expected);
+        }
+    }
+
+    #[test]
+    fn test_eq() {
+        let a = mask_of(&[1, 70, 300]);
+        let b = mask_of(&[300, 1, 70]);
+        assert_eq!(a, b);
+        assert_ne!(a, mask_of(&[1, 70]));
+        assert_eq!(CpuMask::default(), mask_of(&[]));
+    }
+
+    #[test]
+    fn test_iter() {
+        let ids = [0, 5, 63, 64, 200, NR_CPUS - 1];
+        let mask = mask_of(&ids);
+        assert!(mask.iter().eq(ids.iter().copied()));
+        assert_eq!(CpuMask::default().iter().count(), 0);
+    }
+
+    #[test]
+    fn test_online_cpus() {
+        let online = OnlineCpus::new(4);
+        online.set(0, true).unwrap();
+        online.set(2, true).unwrap();
+        online.set(3, true).unwrap();
+        assert!(online.mask.read().iter().eq([0, 2, 3]));
+
+        online.set(2, false).unwrap();
+        online.set(1, false).unwrap();
+        assert!(online.mask.read().iter().eq([0, 3]));
+
+        assert!(online.set(4, true).is_err());
+        assert!(online.set(NR_CPUS, false).is_err());
+        assert!(online.mask.read().iter().eq([0, 3]));
+    }
+}
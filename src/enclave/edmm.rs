@@ -219,17 +219,10 @@ impl Enclave {
                     pte.set_flags(new_gpt_flags, false)?;
 
                     // Perform NPT-E's permission restriction after Guest PTE's to avoid #NPF
-                    let hpaddr_aligned = gpaddr_aligned;
-                    let new_npt_flags = new_gpt_flags | MemFlags::ENCRYPTED;
                     if let Err(e) = self
                         .npt
                         .write()
-                        .update(&MemoryRegion::new_with_offset_mapper(
-                            gpaddr_aligned,
-                            hpaddr_aligned,
-                            PAGE_SIZE,
-                            new_npt_flags,
-                        ))
+                        .restrict_perms(gpaddr_aligned, new_gpt_flags)
                     {
                         return hypercall_hv_err_result!(
                             EFAULT,
@@ -44,8 +44,9 @@ use crate::intervaltree::IntervalTree;
 use crate::memory::addr::{is_aligned, phys_to_virt, GuestPhysAddr, GuestVirtAddr, HostPhysAddr};
 use crate::memory::cmr::NR_INIT_EPC_RANGES;
 use crate::memory::gaccess::{AsGuestPtr, GuestPtr};
+use crate::memory::{check_null_guard, MemFlags, MemoryRegion, PageSize, PagingError};
 use crate::memory::{GenericPTE, GenericPageTable, GenericPageTableImmut, GenericPageTableMut};
-use crate::memory::{MemFlags, MemoryRegion, PageSize, PagingError, PhysAddr, PAGE_SIZE};
+use crate::memory::{PhysAddr, PAGE_SIZE};
 use crate::percpu::CpuState;
 use crate::stats::{Instant, StatsValue};
 
@@ -218,6 +219,8 @@ impl Enclave {
         secs: SgxSecs,
     ) -> HvResult<Arc<Self>> {
         secs.validate()?;
+        // The enclave page table only maps pages in ELRANGE.
+        check_null_guard(secs.base_addr as _, secs.size as _, false)?;
 
         let elrange = secs.base_addr as _..(secs.base_addr + secs.size) as _;
         let mut measure = Measure::new();
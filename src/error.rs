@@ -32,6 +32,7 @@ pub enum HvErrorNum {
     EINVAL = 22,
     ERANGE = 34,
     ENOSYS = 38,
+    ETIMEDOUT = 110,
 }
 
 pub struct HvError {
@@ -60,12 +61,19 @@ impl HvErrorNum {
             EINVAL => "Invalid argument",
             ERANGE => "Math result not representable",
             ENOSYS => "Function not implemented",
+            ETIMEDOUT => "Connection timed out",
         }
     }
 
     pub fn code(&self) -> i32 {
         -(*self as u32 as i32)
     }
+
+    /// The value returned to the host in the return register of a failed hypercall: the negated
+    /// errno, e.g. -22 for `EINVAL`, as the Linux driver expects.
+    pub fn to_abi(&self) -> i64 {
+        self.code() as i64
+    }
 }
 
 impl HvError {
@@ -108,6 +116,21 @@ impl HvError {
     pub fn code(&self) -> i32 {
         self.num.code()
     }
+
+    /// See [`HvErrorNum::to_abi`].
+    pub fn to_abi(&self) -> i64 {
+        self.num.to_abi()
+    }
+
+    /// Prepend `ctx` to the message, to tell which operation the error comes from. The errno and
+    /// the location are kept.
+    pub fn context(mut self, ctx: &str) -> Self {
+        self.msg = Some(match self.msg.take() {
+            Some(msg) => format!("{}: {}", ctx, msg),
+            None => ctx.into(),
+        });
+        self
+    }
 }
 
 impl Debug for HvError {
@@ -148,3 +171,35 @@ macro_rules! hv_result_err {
         Err(hv_err!($num, $msg))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_abi() {
+        use HvErrorNum::*;
+
+        let table = [
+            (EPERM, -1),
+            (ENOENT, -2),
+            (EIO, -5),
+            (E2BIG, -7),
+            (ENOMEM, -12),
+            (EFAULT, -14),
+            (EBUSY, -16),
+            (EEXIST, -17),
+            (ENODEV, -19),
+            (EINVAL, -22),
+            (ERANGE, -34),
+            (ENOSYS, -38),
+            (ETIMEDOUT, -110),
+        ];
+        for (num, abi) in table {
+            assert_eq!(num.to_abi(), abi, "{:?}", num);
+            assert_eq!(HvError::new(num, file!(), 0, 0, None).to_abi(), abi);
+        }
+        // The value goes through a 64-bit register unchanged.
+        assert_eq!(hv_err!(EINVAL).to_abi() as usize, -22i64 as usize);
+    }
+}
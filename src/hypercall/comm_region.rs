@@ -0,0 +1,168 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Layout of the comm region, the page shared by the host and the enclave to exchange hypercall
+//! requests and responses.
+//!
+//! The requester writes the request slot then publishes it by setting the status word to
+//! `Request` with release ordering. The responder acquires it, moves the status to `Busy`, writes
+//! the response slot and publishes it with `Response`. The requester reads the response after an
+//! acquire load and gives the region back with `Idle`.
+//!
+//! The hypervisor takes no part in the exchange, it only resets the region when it's enabled.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use numeric_enum_macro::numeric_enum;
+
+use crate::config::HvSystemConfig;
+use crate::error::HvResult;
+use crate::memory::addr::phys_to_virt;
+use crate::memory::VirtAddr;
+
+/// Number of arguments in a `CommMessage`.
+pub const COMM_MSG_ARGS: usize = 6;
+
+numeric_enum! {
+    /// Value of the status word.
+    #[repr(u32)]
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum CommStatus {
+        /// Both slots are free, a request can be posted.
+        Idle = 0,
+        /// The request slot is filled.
+        Request = 1,
+        /// The request is being handled.
+        Busy = 2,
+        /// The response slot is filled.
+        Response = 3,
+    }
+}
+
+/// A request or a response slot.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct CommMessage {
+    pub code: u64,
+    pub args: [u64; COMM_MSG_ARGS],
+}
+
+/// The comm region, mapped with `MemFlags::COMM_REGION` on both sides.
+#[repr(C)]
+pub struct CommRegion {
+    status: AtomicU32,
+    _reserved: u32,
+    /// Sequence number of the last posted request.
+    req_seq: AtomicU64,
+    /// Sequence number of the request answered by the last response.
+    resp_seq: AtomicU64,
+    request: UnsafeCell<CommMessage>,
+    response: UnsafeCell<CommMessage>,
+}
+
+// The slots are only accessed by the side that owns them according to the status word.
+unsafe impl Sync for CommRegion {}
+
+/// Reset the comm region of the system config, if any, to `Idle`.
+///
+/// Must be called once the hypervisor page table, where `Cell::new_root()` maps the region at
+/// `phys_to_virt()` of its start, is active.
+pub fn init() -> HvResult {
+    if let Some((range, _)) = HvSystemConfig::get().comm_region()? {
+        if range.size < core::mem::size_of::<CommRegion>() {
+            return hv_result_err!(EINVAL, format!("Comm region is too small: {:#x?}", range));
+        }
+        let region = unsafe { CommRegion::from_va(phys_to_virt(range.start)) };
+        region.reset();
+        info!("Comm region is mapped va={:#x}", phys_to_virt(range.start));
+    }
+    Ok(())
+}
+
+impl CommRegion {
+    /// Get the comm region mapped at `va`.
+    ///
+    /// # Safety
+    ///
+    /// `va` must be the mapping of a `COMM_REGION` of at least `size_of::<CommRegion>()` bytes,
+    /// valid for `'a`.
+    pub unsafe fn from_va<'a>(va: VirtAddr) -> &'a Self {
+        &*(va as *const Self)
+    }
+
+    /// Drop any pending exchange and wipe both slots, the region becomes `Idle`.
+    fn reset(&self) {
+        unsafe {
+            self.request.get().write_volatile(CommMessage::default());
+            self.response.get().write_volatile(CommMessage::default());
+        }
+        self.req_seq.store(0, Ordering::Relaxed);
+        self.resp_seq.store(0, Ordering::Relaxed);
+        self.status
+            .store(CommStatus::Idle as u32, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_region() -> CommRegion {
+        CommRegion {
+            status: AtomicU32::new(CommStatus::Idle as u32),
+            _reserved: 0,
+            req_seq: AtomicU64::new(0),
+            resp_seq: AtomicU64::new(0),
+            request: UnsafeCell::new(CommMessage::default()),
+            response: UnsafeCell::new(CommMessage::default()),
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let region = new_region();
+        let base = &region as *const _ as usize;
+        assert_eq!(region.req_seq.as_ptr() as usize - base, 8);
+        assert_eq!(region.resp_seq.as_ptr() as usize - base, 16);
+        assert_eq!(region.request.get() as usize - base, 24);
+        assert_eq!(region.response.get() as usize - base, 80);
+        assert_eq!(core::mem::size_of::<CommRegion>(), 136);
+    }
+
+    #[test]
+    fn test_reset() {
+        let region = new_region();
+        let msg = CommMessage {
+            code: 0x10,
+            args: [1, 2, 3, 4, 5, 6],
+        };
+        unsafe { region.request.get().write(msg) };
+        region.req_seq.store(3, Ordering::Relaxed);
+        region
+            .status
+            .store(CommStatus::Busy as u32, Ordering::Relaxed);
+
+        region.reset();
+        assert_eq!(
+            region.status.load(Ordering::Acquire),
+            CommStatus::Idle as u32
+        );
+        assert_eq!(region.req_seq.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            unsafe { region.request.get().read() },
+            CommMessage::default()
+        );
+    }
+}
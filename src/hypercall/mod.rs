@@ -15,6 +15,7 @@
 #[macro_use]
 pub mod error;
 
+pub mod comm_region;
 mod enclave;
 pub mod tc;
 
@@ -26,12 +27,32 @@ use numeric_enum_macro::numeric_enum;
 
 use crate::arch::vmm::VcpuAccessGuestState;
 use crate::arch::{EnclaveExceptionInfo, GuestPageTableImmut};
+use crate::error::HvError;
 use crate::memory::gaccess::AsGuestPtr;
 use crate::percpu::{CpuState, PerCpu};
 
 use self::error::HyperCallResult;
 
 numeric_enum! {
+    /// Hypercall numbers.
+    ///
+    /// The number and arguments are passed in the following registers, the return value is
+    /// set in the same register as the number (see `GuestRegisters::hypercall_args()`):
+    ///
+    /// |        | x86_64 | aarch64 |
+    /// |--------|--------|---------|
+    /// | number | rax    | x0      |
+    /// | arg0   | rdi    | x1      |
+    /// | arg1   | rsi    | x2      |
+    /// | arg2   | rdx    | x3      |
+    /// | arg3   | r10    | x4      |
+    /// | arg4   | r8     | x5      |
+    /// | arg5   | r9     | x6      |
+    ///
+    /// The current hypercalls only use arg0 and arg1.
+    ///
+    /// Numbers with bit 30 or 31 set are issued from the enclave (user mode), others from the
+    /// driver (supervisor mode).
     #[repr(u32)]
     #[derive(Debug, Eq, PartialEq, Copy, Clone)]
     pub enum HyperCallCode {
@@ -80,6 +101,19 @@ numeric_enum! {
     }
 }
 
+impl TryFrom<u64> for HyperCallCode {
+    type Error = HvError;
+
+    /// Decode the full hypercall number register, numbers that don't fit in 32 bits are
+    /// rejected rather than truncated.
+    fn try_from(code: u64) -> Result<Self, Self::Error> {
+        u32::try_from(code)
+            .ok()
+            .and_then(|code| Self::try_from(code).ok())
+            .ok_or_else(|| hv_err!(ENOSYS, format!("Hypercall not supported: {:#x}", code)))
+    }
+}
+
 impl HyperCallCode {
     fn privilege_level(self) -> PrivilegeLevel {
         if (self as u32).get_bits(30..32) == 0 {
@@ -183,11 +217,11 @@ impl<'a> HyperCall<'a> {
     /// such function returns the infomation of the exception to its caller,
     /// by setting the result as `Some(EnclaveExceptionInfo)`.
     ///
-    pub fn hypercall(&mut self, code: u32, arg0: u64, arg1: u64) -> Option<EnclaveExceptionInfo> {
+    pub fn hypercall(&mut self, code: u64, arg0: u64, arg1: u64) -> Option<EnclaveExceptionInfo> {
         let code = match HyperCallCode::try_from(code) {
             Ok(code) => code,
-            Err(_) => {
-                warn!("Hypercall not supported: {}", code);
+            Err(err) => {
+                warn!("{:?}", err);
                 return None;
             }
         };
@@ -315,15 +349,12 @@ impl<'a> HyperCall<'a> {
             match ret {
                 Ok(ret) => self.cpu_data.vcpu.set_return_val(ret),
                 Err(err) => match err.error() {
-                    error::HyperCallErrorType::HvError(_) => {
+                    error::HyperCallErrorType::HvError(num) => {
                         warn!(
                             "Hypercall: {:?} encounters hypervisor error: {:?}",
                             code, err
                         );
-                        return Some(EnclaveExceptionInfo::general_protection(
-                            0,
-                            &self.cpu_data.state,
-                        ));
+                        self.cpu_data.vcpu.set_return_val(num.to_abi() as _)
                     }
                     error::HyperCallErrorType::EnclaveError(err_code) => {
                         self.cpu_data.vcpu.set_return_val(err_code.code() as _)
@@ -364,3 +395,32 @@ impl<'a> HyperCall<'a> {
         unreachable!();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_valid() {
+        assert_eq!(
+            HyperCallCode::try_from(0u64).unwrap(),
+            HyperCallCode::HypervisorDisable
+        );
+        assert_eq!(
+            HyperCallCode::try_from(0x12u64).unwrap(),
+            HyperCallCode::EnclaveInit
+        );
+        assert_eq!(
+            HyperCallCode::try_from(0x8000_0000u64).unwrap(),
+            HyperCallCode::EnclaveEnter
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(HyperCallCode::try_from(0x15u64).is_err());
+        assert!(HyperCallCode::try_from(u32::MAX as u64).is_err());
+        // Must not be truncated to `EnclaveCreate`.
+        assert!(HyperCallCode::try_from(0x1_0000_0010u64).is_err());
+    }
+}
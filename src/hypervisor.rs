@@ -0,0 +1,86 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ordered bring-up checks of the hypervisor.
+
+use crate::arch::vmm::check_hypervisor_feature;
+use crate::config::HvSystemConfig;
+use crate::cpumask::check_max_cpus;
+use crate::error::HvResult;
+use crate::memory::check_frame_pool;
+
+/// A named bring-up step.
+type InitStep = (&'static str, fn() -> HvResult);
+
+const INIT_STEPS: [InitStep; 4] = [
+    ("CPU features", check_hypervisor_feature),
+    ("hypervisor header", check_max_cpus),
+    ("system config", validate_config),
+    ("frame pool", check_hv_frame_pool),
+];
+
+fn validate_config() -> HvResult {
+    HvSystemConfig::get().validate()
+}
+
+fn check_hv_frame_pool() -> HvResult {
+    check_frame_pool(HvSystemConfig::get())
+}
+
+/// Check the CPU features, validate the header and the system config, then check the frame
+/// pool against the frame bitmap. Stops at the first failed step, whose name is added to the
+/// error message.
+///
+/// Must be called after the memory (heap and frame allocator) is initialized.
+pub fn init() -> HvResult {
+    run_steps(&INIT_STEPS)
+}
+
+fn run_steps(steps: &[InitStep]) -> HvResult {
+    for (name, step) in steps {
+        info!("Init: {}...", name);
+        step().map_err(|e| e.context(&format!("{} check failed", name)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::HvErrorNum;
+
+    fn ok() -> HvResult {
+        Ok(())
+    }
+
+    fn no_device() -> HvResult {
+        hv_result_err!(ENODEV, "VMX is not supported")
+    }
+
+    fn unreachable() -> HvResult {
+        panic!("a step after a failed one was run")
+    }
+
+    #[test]
+    fn test_run_steps() {
+        let steps: [InitStep; 3] = [("first", ok), ("second", no_device), ("third", unreachable)];
+        let err = run_steps(&steps).unwrap_err();
+        assert_eq!(err.num(), HvErrorNum::ENODEV);
+        assert_eq!(
+            err.msg().unwrap(),
+            "second check failed: VMX is not supported"
+        );
+        assert!(run_steps(&steps[..1]).is_ok());
+    }
+}
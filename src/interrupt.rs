@@ -0,0 +1,79 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inter-processor interrupts.
+
+use crate::cpumask::CpuMask;
+
+pub trait InterruptController {
+    /// Send an IPI with `vector` to the CPU `cpu_id`.
+    fn send_ipi(&self, cpu_id: usize, vector: u8);
+
+    /// Id of the current CPU, which is skipped by `broadcast()`.
+    fn current_cpu_id(&self) -> usize;
+
+    /// Send an IPI with `vector` to all CPUs in `mask` except the current one.
+    fn broadcast(&self, mask: &CpuMask, vector: u8) {
+        let self_id = self.current_cpu_id();
+        for cpu_id in mask.iter().filter(|&id| id != self_id) {
+            self.send_ipi(cpu_id, vector);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::*;
+
+    struct MockController {
+        self_id: usize,
+        sent: RefCell<Vec<(usize, u8)>>,
+    }
+
+    impl InterruptController for MockController {
+        fn send_ipi(&self, cpu_id: usize, vector: u8) {
+            self.sent.borrow_mut().push((cpu_id, vector));
+        }
+
+        fn current_cpu_id(&self) -> usize {
+            self.self_id
+        }
+    }
+
+    fn mask_of(ids: &[usize]) -> CpuMask {
+        let mut mask = CpuMask::default();
+        ids.iter().for_each(|&id| mask.set_cpu(id));
+        mask
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let ctrl = MockController {
+            self_id: 2,
+            sent: RefCell::new(Vec::new()),
+        };
+        ctrl.broadcast(&mask_of(&[0, 2, 3, 100]), 0xf0);
+        assert_eq!(*ctrl.sent.borrow(), [(0, 0xf0), (3, 0xf0), (100, 0xf0)]);
+
+        ctrl.sent.borrow_mut().clear();
+        ctrl.broadcast(&mask_of(&[2]), 0xf1);
+        assert!(ctrl.sent.borrow().is_empty());
+
+        ctrl.send_ipi(2, 0xf2);
+        assert_eq!(*ctrl.sent.borrow(), [(2, 0xf2)]);
+    }
+}
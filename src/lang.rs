@@ -15,13 +15,14 @@
 use core::alloc::Layout;
 use core::panic::PanicInfo;
 
+use crate::arch::vmm::VcpuAccessGuestState;
 use crate::error::HvResult;
 use crate::percpu::{CpuState, PerCpu};
 
 #[allow(dead_code)]
 fn try_handle_panic(cpu_data: &mut PerCpu) -> HvResult {
     let ret_code = if cpu_data.state != CpuState::HvDisabled && cpu_data.vcpu.in_hypercall() {
-        hv_err!(EIO).code() as usize
+        hv_err!(EIO).to_abi() as usize
     } else {
         0
     };
@@ -29,6 +30,8 @@ fn try_handle_panic(cpu_data: &mut PerCpu) -> HvResult {
         CpuState::HvEnabled => cpu_data.deactivate_vmm(ret_code)?,
         CpuState::EnclaveRunning => {
             cpu_data.enclave_exit(0)?;
+            // The enclave didn't exit on its own, its registers must not reach Linux.
+            cpu_data.vcpu.regs_mut().wipe();
             cpu_data.deactivate_vmm(ret_code)?;
         }
         _ => return hv_result_err!(EIO, "Hypervisor is not enabled!"),
@@ -0,0 +1,203 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Layout of the hypervisor image: the core (code and data), followed by the per-CPU array, then
+//! the system config.
+
+use core::mem::size_of;
+
+use crate::config::HvSystemConfig;
+use crate::consts::{HV_BASE, PER_CPU_SIZE};
+use crate::header::HvHeader;
+
+/// Virtual addresses of the hypervisor image parts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HvLayout {
+    base: usize,
+    core_size: usize,
+    max_cpus: usize,
+    percpu_size: usize,
+}
+
+lazy_static! {
+    static ref LAYOUT: HvLayout = HvLayout::from_header(HvHeader::get());
+}
+
+impl HvLayout {
+    const fn new(base: usize, core_size: usize, max_cpus: usize, percpu_size: usize) -> Self {
+        Self {
+            base,
+            core_size,
+            max_cpus,
+            percpu_size,
+        }
+    }
+
+    fn from_header(header: &HvHeader) -> Self {
+        Self::new(
+            HV_BASE,
+            header.core_size,
+            header.max_cpus as usize,
+            PER_CPU_SIZE,
+        )
+    }
+
+    /// The base address of the per-CPU data of CPU `cpuid`, `None` if `cpuid` is not less than
+    /// `max_cpus`.
+    pub fn percpu_base(&self, cpuid: usize) -> Option<usize> {
+        if cpuid < self.max_cpus {
+            Some(self.base + self.core_size + cpuid * self.percpu_size)
+        } else {
+            None
+        }
+    }
+
+    /// The base address of the system config, right after the per-CPU array.
+    pub fn config_base(&self) -> usize {
+        self.base + self.core_size + self.max_cpus * self.percpu_size
+    }
+
+    /// The size of the image, given the size of the system config.
+    pub fn total_size(&self, config_size: usize) -> usize {
+        self.config_base() + config_size - self.base
+    }
+
+    /// Returns whether `[ptr, ptr + len)` lies after the core in the first `mapped_size` bytes
+    /// of the image. Always false if the core is too small to even hold the header, which means
+    /// the header is corrupted.
+    pub fn within_image(&self, ptr: usize, len: usize, mapped_size: usize) -> bool {
+        if self.core_size < size_of::<HvHeader>() {
+            return false;
+        }
+        let start = self.base.checked_add(self.core_size);
+        let end = self.base.checked_add(mapped_size);
+        let ptr_end = ptr.checked_add(len);
+        match (start, end, ptr_end) {
+            (Some(start), Some(end), Some(ptr_end)) => ptr >= start && ptr_end <= end,
+            _ => false,
+        }
+    }
+
+    /// Check the image layout given by the header, then that the system config of
+    /// `config_size()` bytes lies in the first `mapped_size()` bytes of the image, the size of
+    /// the hypervisor memory mapped by the loader. Neither size is read if the header is bogus.
+    pub fn check_image(
+        &self,
+        mapped_size: impl FnOnce() -> usize,
+        config_size: impl FnOnce() -> usize,
+    ) -> Result<(), &'static str> {
+        if self.core_size < size_of::<HvHeader>() {
+            return Err("the core is smaller than the header");
+        }
+        if self.max_cpus == 0 {
+            return Err("max_cpus is 0");
+        }
+        let mapped_size = mapped_size();
+        // The fixed part first, the size of the memory regions is read from it.
+        if !self.within_image(self.config_base(), size_of::<HvSystemConfig>(), mapped_size)
+            || !self.within_image(self.config_base(), config_size(), mapped_size)
+        {
+            return Err("the system config is out of the hypervisor memory");
+        }
+        Ok(())
+    }
+}
+
+/// See [`HvLayout::percpu_base()`].
+pub fn percpu_base(cpuid: usize) -> Option<usize> {
+    LAYOUT.percpu_base(cpuid)
+}
+
+/// See [`HvLayout::config_base()`].
+pub fn config_base() -> usize {
+    LAYOUT.config_base()
+}
+
+/// Check the layout of the hypervisor image from the header and the size of the hypervisor
+/// memory, see [`HvLayout::check_image()`].
+pub fn check_hv_image() -> Result<(), &'static str> {
+    // Only read once the header is checked, the config is at the location it gives.
+    let config = || unsafe { HvSystemConfig::get_unchecked() };
+    LAYOUT.check_image(|| config().hypervisor_region().0.size, || config().size())
+}
+
+/// The size of the hypervisor image, from `HV_BASE` to the end of the system config.
+pub fn total_hv_size() -> usize {
+    LAYOUT.total_size(HvSystemConfig::get().size())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_contiguous() {
+        let (core_size, max_cpus, percpu_size) = (0x20_0000, 4, 0x9000);
+        let layout = HvLayout::new(HV_BASE, core_size, max_cpus, percpu_size);
+
+        let mut end = HV_BASE + core_size;
+        for cpuid in 0..max_cpus {
+            let base = layout.percpu_base(cpuid).unwrap();
+            // Each per-CPU area starts where the previous part ends.
+            assert_eq!(base, end);
+            end = base + percpu_size;
+        }
+        assert_eq!(layout.percpu_base(max_cpus), None);
+        assert_eq!(layout.config_base(), end);
+        assert_eq!(layout.total_size(0x1000), end + 0x1000 - HV_BASE);
+    }
+
+    #[test]
+    fn test_within_image() {
+        let (core_size, max_cpus, percpu_size) = (0x20_0000, 4, 0x9000);
+        let layout = HvLayout::new(HV_BASE, core_size, max_cpus, percpu_size);
+        let config_base = layout.config_base();
+        let mapped_size = layout.total_size(0x1000);
+
+        assert!(layout.within_image(config_base, 0x1000, mapped_size));
+        assert!(!layout.within_image(config_base, 0x1001, mapped_size));
+        assert!(!layout.within_image(config_base, 0x1000, mapped_size - 1));
+        // Inside the core, or overflowing.
+        assert!(!layout.within_image(HV_BASE, 0x10, mapped_size));
+        assert!(!layout.within_image(config_base, usize::MAX, mapped_size));
+
+        // A core size too small for the header itself.
+        let bogus = HvLayout::new(HV_BASE, size_of::<HvHeader>() - 1, max_cpus, percpu_size);
+        let config_base = bogus.config_base();
+        assert!(!bogus.within_image(config_base, 0x1000, mapped_size));
+    }
+
+    #[test]
+    fn test_check_image() {
+        let (core_size, max_cpus, percpu_size) = (0x20_0000, 4, 0x9000);
+        let layout = HvLayout::new(HV_BASE, core_size, max_cpus, percpu_size);
+        let config_size = size_of::<HvSystemConfig>() + 0x100;
+        let mapped_size = layout.total_size(config_size);
+
+        assert!(layout.check_image(|| mapped_size, || config_size).is_ok());
+        assert!(layout
+            .check_image(|| mapped_size, || config_size + 1)
+            .is_err());
+        assert!(layout
+            .check_image(|| mapped_size - config_size, || config_size)
+            .is_err());
+
+        // The config is never read with a bogus header.
+        let unread = || -> usize { panic!("config read with a bogus header") };
+        let bogus = HvLayout::new(HV_BASE, size_of::<HvHeader>() - 1, max_cpus, percpu_size);
+        assert!(bogus.check_image(unread, unread).is_err());
+        let bogus = HvLayout::new(HV_BASE, core_size, 0, percpu_size);
+        assert!(bogus.check_image(unread, unread).is_err());
+    }
+}
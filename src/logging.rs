@@ -24,7 +24,7 @@ use {
     core::fmt,
     core::sync::atomic::{AtomicUsize, Ordering},
     log::{self, Level, LevelFilter, Log, Metadata, Record},
-    spin::mutex::SpinMutex,
+    spin::{mutex::SpinMutex, Once},
 };
 
 pub fn init() {
@@ -208,6 +208,165 @@ pub fn set_vmm_state(cpuid: usize, state: i32) {
     }
 }
 
+/// Size of the log ring of each CPU.
+const RING_LOG_LEN: usize = 1024;
+
+/// Fixed-size ring of whole log messages, the oldest messages are dropped when it's full.
+pub struct RingLog {
+    buf: [u8; RING_LOG_LEN],
+    /// Index of the oldest byte.
+    head: usize,
+    /// Bytes of the complete messages.
+    len: usize,
+    /// Bytes of the message being written, after the complete ones.
+    pending: usize,
+    /// Number of dropped messages since the last drain.
+    lost: usize,
+}
+
+impl RingLog {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; RING_LOG_LEN],
+            head: 0,
+            len: 0,
+            pending: 0,
+            lost: 0,
+        }
+    }
+
+    /// Append a message, a newline is added if it doesn't end with one. A message longer than
+    /// the ring is dropped.
+    pub fn push(&mut self, args: fmt::Arguments) {
+        self.pending = 0;
+        let mut res = fmt::write(self, args);
+        if res.is_ok() && self.last_pending() != Some(b'\n') {
+            res = fmt::Write::write_str(self, "\n");
+        }
+        match res {
+            Ok(()) => self.len += self.pending,
+            Err(_) => self.lost += 1,
+        }
+        self.pending = 0;
+    }
+
+    /// Pass the buffered messages in order and the number of messages dropped since the last
+    /// drain to `out`, then empty the ring.
+    pub fn drain(&mut self, out: impl FnOnce(&str, usize)) {
+        // Make the messages contiguous.
+        self.buf.rotate_left(self.head);
+        self.head = 0;
+        // Only whole `str`s are appended, the bytes are valid UTF-8.
+        let msgs = core::str::from_utf8(&self.buf[..self.len]).unwrap_or_default();
+        out(msgs, self.lost);
+        self.len = 0;
+        self.lost = 0;
+    }
+
+    fn last_pending(&self) -> Option<u8> {
+        let end = self.head + self.len + self.pending;
+        (self.pending > 0).then(|| self.buf[(end - 1) % RING_LOG_LEN])
+    }
+
+    /// Drop the oldest complete message, fails if there is none.
+    fn drop_oldest(&mut self) -> bool {
+        if self.len == 0 {
+            return false;
+        }
+        let msg_len = (0..self.len)
+            .find(|i| self.buf[(self.head + i) % RING_LOG_LEN] == b'\n')
+            .map_or(self.len, |i| i + 1);
+        self.head = (self.head + msg_len) % RING_LOG_LEN;
+        self.len -= msg_len;
+        self.lost += 1;
+        true
+    }
+}
+
+impl fmt::Write for RingLog {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.pending + s.len() > RING_LOG_LEN {
+            return Err(fmt::Error);
+        }
+        while self.len + self.pending + s.len() > RING_LOG_LEN {
+            if !self.drop_oldest() {
+                return Err(fmt::Error);
+            }
+        }
+        for byte in s.bytes() {
+            self.buf[(self.head + self.len + self.pending) % RING_LOG_LEN] = byte;
+            self.pending += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Log rings of all CPUs, so the messages of concurrent CPUs don't interleave mid-line.
+pub struct PerCpuRingLog {
+    rings: Vec<SpinMutex<RingLog>>,
+}
+
+impl PerCpuRingLog {
+    /// Allocate the rings of `nr_cpus` CPUs, appending to them doesn't allocate.
+    pub fn new(nr_cpus: usize) -> Self {
+        Self {
+            rings: (0..nr_cpus)
+                .map(|_| SpinMutex::new(RingLog::new()))
+                .collect(),
+        }
+    }
+
+    /// Append a message to the ring of `cpu_id`.
+    pub fn push(&self, cpu_id: usize, args: fmt::Arguments) {
+        if let Some(ring) = self.rings.get(cpu_id) {
+            ring.lock().push(args);
+        }
+    }
+
+    /// Drain the ring of each CPU by increasing CPU ID, `out` gets the CPU ID and the arguments
+    /// of `RingLog::drain()`. CPUs without messages are skipped.
+    pub fn drain(&self, mut out: impl FnMut(usize, &str, usize)) {
+        for (cpu_id, ring) in self.rings.iter().enumerate() {
+            ring.lock().drain(|msgs, lost| {
+                if !msgs.is_empty() || lost != 0 {
+                    out(cpu_id, msgs, lost)
+                }
+            });
+        }
+    }
+}
+
+static RING_LOG: Once<PerCpuRingLog> = Once::new();
+
+/// Allocate the log rings of `HvHeader::max_cpus` CPUs, must be called after the heap is
+/// initialized.
+pub fn init_ring_log() {
+    RING_LOG.call_once(|| PerCpuRingLog::new(HvHeader::get().max_cpus as usize));
+}
+
+/// Buffer a message in the log ring of the current CPU, it's printed by `drain()`. The message
+/// is printed right away before `init_ring_log()`.
+pub fn ring_print(args: fmt::Arguments) {
+    match RING_LOG.get() {
+        Some(ring_log) => ring_log.push(crate::arch::cpu::id(), args),
+        None => crate::arch::serial::putfmt(args),
+    }
+}
+
+/// Print the messages buffered by `ring_print()`, one CPU after another.
+pub fn drain() {
+    let ring_log = match RING_LOG.get() {
+        Some(ring_log) => ring_log,
+        None => return,
+    };
+    ring_log.drain(|cpu_id, msgs, lost| {
+        crate::arch::serial::putfmt(format_args!("{}", msgs));
+        if lost != 0 {
+            crate::arch::serial::putfmt(format_args!("[{}] {} messages lost\n", cpu_id, lost));
+        }
+    });
+}
+
 lazy_static! {
     static ref NR_CPU_IDS: usize = HvHeader::get().max_cpus as usize;
     static ref VEC_PERCPU_VA: Vec<usize> = {
@@ -243,3 +402,64 @@ lazy_static! {
         vmm_states_va
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_ring_log_drops_oldest() {
+        let mut ring = RingLog::new();
+        let line = "x".repeat(RING_LOG_LEN / 4 - 1);
+        for i in 0..5 {
+            ring.push(format_args!("{}", i));
+            ring.push(format_args!("{}", line));
+        }
+        // A message that can never fit is dropped without evicting the others.
+        ring.push(format_args!("{}", "y".repeat(RING_LOG_LEN + 1)));
+
+        let mut drained = String::new();
+        ring.drain(|msgs, lost| {
+            drained = msgs.into();
+            assert_eq!(lost, 5);
+        });
+        let expected = format!("2\n{0}\n3\n{0}\n4\n{0}\n", line);
+        assert_eq!(drained, expected);
+        ring.drain(|msgs, lost| assert!(msgs.is_empty() && lost == 0));
+    }
+
+    #[test]
+    fn test_per_cpu_separation() {
+        const CPUS: usize = 4;
+        const MSGS: usize = 40;
+        let log = Arc::new(PerCpuRingLog::new(CPUS));
+
+        let threads: Vec<_> = (0..CPUS)
+            .map(|cpu_id| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    for i in 0..MSGS {
+                        log.push(cpu_id, format_args!("cpu {} msg {}", cpu_id, i));
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        // Out of range CPU IDs are ignored.
+        log.push(CPUS, format_args!("lost"));
+
+        let mut drained = Vec::new();
+        log.drain(|cpu_id, msgs, lost| {
+            assert_eq!(lost, 0);
+            drained.push((cpu_id, String::from(msgs)));
+        });
+        assert_eq!(drained.len(), CPUS);
+        for (cpu_id, msgs) in drained {
+            let expected: String = (0..MSGS)
+                .map(|i| format!("cpu {} msg {}\n", cpu_id, i))
+                .collect();
+            assert_eq!(msgs, expected);
+        }
+    }
+}
@@ -43,10 +43,14 @@ mod cpumask;
 mod enclave;
 mod ffi;
 mod header;
+mod hypervisor;
+mod interrupt;
 mod intervaltree;
 mod iommu;
+mod layout;
 mod memory;
 mod percpu;
+mod spinlock;
 mod stats;
 
 #[cfg(not(test))]
@@ -56,13 +60,17 @@ mod lang;
 #[path = "arch/x86_64/mod.rs"]
 mod arch;
 
+#[cfg(target_arch = "aarch64")]
+#[path = "arch/ARM/mod.rs"]
+mod arch;
+
 use core::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
 
 use config::HvSystemConfig;
 use enclave::reclaim;
 use error::HvResult;
 use header::HvHeader;
-use hypercall::tc;
+use hypercall::{comm_region, tc};
 use percpu::PerCpu;
 
 static ENTERED_CPUS: AtomicUsize = AtomicUsize::new(0);
@@ -93,6 +101,7 @@ fn primary_init_early() -> HvResult {
     logging::init();
     info!("Primary CPU init early...");
     cpumask::check_max_cpus()?;
+    HvSystemConfig::check_location()?;
 
     let system_config = HvSystemConfig::get();
     println!(
@@ -120,6 +129,8 @@ fn primary_init_early() -> HvResult {
 
     reclaim::init();
     memory::init()?;
+    logging::init_ring_log();
+    hypervisor::init()?;
     cell::init()?;
 
     INIT_EARLY_OK.store(1, Ordering::Release);
@@ -132,6 +143,7 @@ fn primary_init_late() -> HvResult {
     logging::hhbox_init()?;
 
     iommu::init()?;
+    comm_region::init()?;
     if !tc::tc_init() {
         println!("HyperEnclave: tpm or cyrpto module initialization failed");
         return hv_result_err!(EIO);
@@ -153,17 +165,19 @@ fn main(cpu_id: usize, linux_sp: usize) -> HvResult {
     );
 
     if is_primary {
+        percpu::init_boot_cpu();
         primary_init_early()?;
     } else {
         wait_for_other_completed(&INIT_EARLY_OK, 1)?;
     }
 
     cpu_data.init(cpu_id, linux_sp, &cell::ROOT_CELL)?;
-    println!("CPU {} init OK.", cpu_id);
+    logging::ring_print(format_args!("CPU {} init OK.\n", cpu_id));
     INITED_CPUS.fetch_add(1, Ordering::SeqCst);
     wait_for_other_completed(&INITED_CPUS, online_cpus)?;
 
-    if is_primary {
+    if percpu::is_boot_cpu() {
+        logging::drain();
         primary_init_late()?;
     } else {
         wait_for_other_completed(&INIT_LATE_OK, 1)?;
@@ -179,7 +193,9 @@ fn restore_states(cpu_id: usize) {
     }
 
     let _ = iommu::disable();
-    cpu_data.return_to_linux();
+    if let Err(e) = cpu_data.return_to_linux() {
+        error!("{:?}", e);
+    }
 }
 
 extern "sysv64" fn entry(cpu_id: usize, linux_sp: usize) -> i32 {
@@ -16,7 +16,10 @@
 
 #![allow(dead_code)]
 
-use crate::consts::{HV_BASE, PAGE_SIZE, SME_C_BIT_OFFSET};
+use crate::config::{HvSystemConfig, RegionView};
+use crate::consts::{PAGE_SIZE, SME_C_BIT_OFFSET};
+use crate::error::HvResult;
+use crate::memory::MemFlags;
 
 pub type VirtAddr = usize;
 pub type PhysAddr = usize;
@@ -27,12 +30,30 @@ pub type GuestPhysAddr = usize;
 pub type HostVirtAddr = VirtAddr;
 pub type HostPhysAddr = PhysAddr;
 
-// 使用lazy_static宏定义了一个静态变量PHYS_VIRT_OFFSET，它在第一次使用时被初始化。它的值是HV_BASE减去从配置中获取的物理内存起始地址
+/// A half-open address range `[start, start + size)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddrRange {
+    pub start: usize,
+    pub size: usize,
+}
+
+impl AddrRange {
+    pub const fn new(start: usize, size: usize) -> Self {
+        Self { start, size }
+    }
+
+    pub const fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    pub const fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+// 使用lazy_static宏定义了一个静态变量PHYS_VIRT_OFFSET，它在第一次使用时被初始化。它的值是HV_BASE减去从配置中获取的物理内存起始地址（见`HvSystemConfig::hypervisor_region()`）
 lazy_static! {
-    static ref PHYS_VIRT_OFFSET: usize = HV_BASE
-        - crate::config::HvSystemConfig::get()
-            .hypervisor_memory
-            .phys_start as usize;
+    static ref PHYS_VIRT_OFFSET: usize = HvSystemConfig::get().phys_virt_offset();
 }
 
 pub fn phys_encrypted(paddr: PhysAddr) -> PhysAddr {
@@ -40,14 +61,187 @@ pub fn phys_encrypted(paddr: PhysAddr) -> PhysAddr {
     paddr | SME_C_BIT_OFFSET
 }
 
+/// Returns whether the C-bit is set in `paddr`.
+pub const fn is_phys_encrypted(paddr: PhysAddr) -> bool {
+    paddr & SME_C_BIT_OFFSET != 0
+}
+
+/// Converts a virtual address in the linear mapping to its physical address.
+///
+/// The result never carries the C-bit, so `phys_to_virt(virt_to_phys(vaddr)) == vaddr` holds
+/// for every linear mapped `vaddr`.
 pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
     // 将虚拟地址转换为物理地址，减去PHYS_VIRT_OFFSET的值
-    vaddr - *PHYS_VIRT_OFFSET
+    linear_virt_to_phys(vaddr, *PHYS_VIRT_OFFSET)
 }
 
+/// Converts a physical address to its virtual address in the linear mapping.
+///
+/// The C-bit of `paddr` is ignored: the encrypted and plaintext physical addresses of a page
+/// have the same linear virtual address. Hence `virt_to_phys(phys_to_virt(paddr)) == paddr`
+/// only holds for plaintext `paddr`, for encrypted ones the C-bit is lost (use
+/// `phys_encrypted()` to restore it).
 pub fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
     // 将物理地址转换为虚拟地址，先与SME_C_BIT_OFFSET减1按位与，然后加上PHYS_VIRT_OFFSET的值
-    (paddr & (SME_C_BIT_OFFSET.wrapping_sub(1))) + *PHYS_VIRT_OFFSET
+    linear_phys_to_virt(paddr, *PHYS_VIRT_OFFSET)
+}
+
+/// Like `phys_to_virt()`, but fails with `EFAULT` if `paddr` is outside the hypervisor memory
+/// and all the configured memory regions, instead of returning an address that faults on access.
+///
+/// Prefer `phys_to_virt()` on hot paths where `paddr` is known to be valid.
+pub fn try_phys_to_virt(paddr: PhysAddr) -> HvResult<VirtAddr> {
+    checked_phys_to_virt(paddr, *PHYS_VIRT_OFFSET, |paddr| {
+        HvSystemConfig::get().find_memory_region(paddr)
+    })
+}
+
+/// Returns the virtual address the hypervisor maps `paddr` at with `MemFlags::ENCRYPTED`.
+///
+/// With SME, the guest `DMA` regions are mapped encrypted at their identity address
+/// (`virt_start`, except the null guard page), the hypervisor memory and the EPC at their linear
+/// address. Without SME there
+/// is no encrypted alias and the address used to access the page is returned. The C-bit of
+/// `paddr` is ignored.
+pub fn phys_to_virt_encrypted(paddr: PhysAddr) -> VirtAddr {
+    mapped_alias(paddr, *PHYS_VIRT_OFFSET, cfg!(feature = "sme"), |paddr| {
+        HvSystemConfig::get().find_memory_region(paddr)
+    })
+}
+
+/// Returns the virtual address to access `paddr`: the encrypted alias (see
+/// `phys_to_virt_encrypted()`) if the C-bit is set in `paddr`, the plaintext mapping otherwise.
+pub fn phys_access_alias(paddr: PhysAddr) -> VirtAddr {
+    let encrypted = cfg!(feature = "sme") && is_phys_encrypted(paddr);
+    mapped_alias(paddr, *PHYS_VIRT_OFFSET, encrypted, |paddr| {
+        HvSystemConfig::get().find_memory_region(paddr)
+    })
+}
+
+/// Copy `len` bytes from the physical address `src` to `dst`. Addresses with the C-bit set are
+/// accessed through their encrypted alias, so the data is decrypted and re-encrypted as needed.
+///
+/// # Safety
+///
+/// Both ranges must be valid hypervisor accessible memory and must not overlap.
+pub unsafe fn phys_copy(dst: PhysAddr, src: PhysAddr, len: usize) {
+    phys_copy_with(dst, src, len, phys_access_alias)
+}
+
+/// Fill `len` bytes at the physical address `paddr` with `val`, through the encrypted alias if
+/// the C-bit is set in `paddr`.
+///
+/// # Safety
+///
+/// The range must be valid hypervisor accessible memory.
+pub unsafe fn phys_set(paddr: PhysAddr, val: u8, len: usize) {
+    phys_set_with(paddr, val, len, phys_access_alias)
+}
+
+/// Returns the length of the next chunk of a `len` bytes access at `paddr` that doesn't cross a
+/// page boundary, so each chunk is translated on its own.
+const fn chunk_len(paddr: PhysAddr, len: usize) -> usize {
+    let left = PAGE_SIZE - (paddr & (PAGE_SIZE - 1));
+    if len < left {
+        len
+    } else {
+        left
+    }
+}
+
+unsafe fn phys_copy_with(
+    mut dst: PhysAddr,
+    mut src: PhysAddr,
+    mut len: usize,
+    translate: impl Fn(PhysAddr) -> VirtAddr,
+) {
+    while len > 0 {
+        let n = chunk_len(src, chunk_len(dst, len));
+        core::ptr::copy_nonoverlapping(translate(src) as *const u8, translate(dst) as *mut u8, n);
+        src += n;
+        dst += n;
+        len -= n;
+    }
+}
+
+unsafe fn phys_set_with(
+    mut paddr: PhysAddr,
+    val: u8,
+    mut len: usize,
+    translate: impl Fn(PhysAddr) -> VirtAddr,
+) {
+    while len > 0 {
+        let n = chunk_len(paddr, len);
+        core::ptr::write_bytes(translate(paddr) as *mut u8, val, n);
+        paddr += n;
+        len -= n;
+    }
+}
+
+/// Assert (in debug builds only) that `paddr` is recovered from its linear virtual address,
+/// with the C-bit restored if it was set.
+#[inline]
+pub fn assert_round_trip(paddr: PhysAddr) {
+    if cfg!(debug_assertions) {
+        let back = linear_round_trip(paddr, *PHYS_VIRT_OFFSET);
+        assert_eq!(back, paddr, "phys/virt round trip of {:#x} failed", paddr);
+    }
+}
+
+const fn linear_virt_to_phys(vaddr: VirtAddr, offset: usize) -> PhysAddr {
+    vaddr - offset
+}
+
+const fn linear_phys_to_virt(paddr: PhysAddr, offset: usize) -> VirtAddr {
+    (paddr & (SME_C_BIT_OFFSET.wrapping_sub(1))) + offset
+}
+
+/// Returns the virtual address `paddr` is mapped at, through the encrypted identity alias of the
+/// `DMA` regions if `encrypted` is set.
+pub(crate) fn mapped_alias(
+    paddr: PhysAddr,
+    offset: usize,
+    encrypted: bool,
+    find_region: impl FnOnce(PhysAddr) -> Option<RegionView>,
+) -> VirtAddr {
+    let plaintext = paddr & (SME_C_BIT_OFFSET.wrapping_sub(1));
+    match find_region(plaintext) {
+        Some(r) if r.flags.contains(MemFlags::DMA) => {
+            // Guest RAM shared for DMA: identity mapped encrypted, linear mapped (at its guest
+            // physical address) in plaintext.
+            let vaddr = (r.virt_start + (plaintext as u64 - r.phys_start)) as VirtAddr;
+            if encrypted {
+                vaddr
+            } else {
+                linear_phys_to_virt(vaddr, offset)
+            }
+        }
+        _ => linear_phys_to_virt(plaintext, offset),
+    }
+}
+
+const fn linear_round_trip(paddr: PhysAddr, offset: usize) -> PhysAddr {
+    let back = linear_virt_to_phys(linear_phys_to_virt(paddr, offset), offset);
+    if is_phys_encrypted(paddr) {
+        back | SME_C_BIT_OFFSET
+    } else {
+        back
+    }
+}
+
+fn checked_phys_to_virt(
+    paddr: PhysAddr,
+    offset: usize,
+    find_region: impl FnOnce(PhysAddr) -> Option<RegionView>,
+) -> HvResult<VirtAddr> {
+    let plaintext = paddr & (SME_C_BIT_OFFSET.wrapping_sub(1));
+    match find_region(plaintext) {
+        Some(_) => Ok(linear_phys_to_virt(paddr, offset)),
+        None => hv_result_err!(
+            EFAULT,
+            format!("Physical address {:#x} is not in any memory region", paddr)
+        ),
+    }
 }
 
 pub const fn align_down(addr: usize) -> usize {
@@ -74,3 +268,137 @@ pub const fn page_offset(addr: usize) -> usize {
     // 计算地址在页面中的偏移
     addr & (PAGE_SIZE - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consts::HV_BASE;
+
+    const OFFSET: usize = HV_BASE - 0x1_0000_0000;
+
+    #[test]
+    fn test_round_trip_plaintext() {
+        let paddr = 0x1_2345_6000;
+        assert!(!is_phys_encrypted(paddr));
+        let vaddr = linear_phys_to_virt(paddr, OFFSET);
+        assert_eq!(vaddr, paddr + OFFSET);
+        assert_eq!(linear_virt_to_phys(vaddr, OFFSET), paddr);
+        assert_eq!(linear_round_trip(paddr, OFFSET), paddr);
+    }
+
+    #[test]
+    fn test_checked_phys_to_virt() {
+        use crate::config::{find_region, HvMemoryRegion};
+        use crate::memory::MemFlags;
+
+        let regions = [HvMemoryRegion {
+            phys_start: 0x1_0000_0000,
+            virt_start: 0x1_0000_0000,
+            size: 0x10_0000,
+            flags: MemFlags::READ | MemFlags::WRITE,
+        }];
+        let find = |paddr| find_region(&regions, paddr);
+        let paddr = 0x1_0000_2000;
+        assert_eq!(
+            checked_phys_to_virt(paddr, OFFSET, find).unwrap(),
+            paddr + OFFSET
+        );
+        assert_eq!(
+            checked_phys_to_virt(phys_encrypted(paddr), OFFSET, find).unwrap(),
+            paddr + OFFSET
+        );
+        assert!(checked_phys_to_virt(0x1_0010_0000, OFFSET, find).is_err());
+        assert!(checked_phys_to_virt(0x2000, OFFSET, find).is_err());
+    }
+
+    #[test]
+    fn test_round_trip_encrypted() {
+        let paddr = 0x1_2345_6000;
+        let enc = phys_encrypted(paddr);
+        assert_eq!(is_phys_encrypted(enc), SME_C_BIT_OFFSET != 0);
+        // Both aliases share the linear virtual address, which converts back to plaintext.
+        let vaddr = linear_phys_to_virt(enc, OFFSET);
+        assert_eq!(vaddr, linear_phys_to_virt(paddr, OFFSET));
+        assert_eq!(linear_virt_to_phys(vaddr, OFFSET), paddr);
+        assert_eq!(linear_round_trip(enc, OFFSET), enc);
+    }
+
+    #[test]
+    fn test_mapped_alias() {
+        use crate::config::{find_region, HvMemoryRegion};
+
+        let regions = [
+            HvMemoryRegion {
+                phys_start: 0x1_0000_0000,
+                virt_start: 0x1_0000_0000,
+                size: 0x10_0000,
+                flags: MemFlags::READ | MemFlags::WRITE | MemFlags::ENCRYPTED,
+            },
+            HvMemoryRegion {
+                phys_start: 0x2_0000_0000,
+                virt_start: 0x8000_0000,
+                size: 0x10_0000,
+                flags: MemFlags::READ | MemFlags::WRITE | MemFlags::DMA,
+            },
+        ];
+        let find = |paddr| find_region(&regions, paddr);
+
+        // Hypervisor memory: the linear mapping, never the C-bit tagged physical address.
+        let paddr = 0x1_0000_2000;
+        for &encrypted in &[true, false] {
+            let vaddr = mapped_alias(phys_encrypted(paddr), OFFSET, encrypted, find);
+            assert_eq!(vaddr, paddr + OFFSET);
+            assert_eq!(vaddr, mapped_alias(paddr, OFFSET, encrypted, find));
+        }
+
+        // DMA region: the identity mapping of the guest physical address when encrypted, its
+        // linear mapping otherwise (not the linear mapping of the host physical address).
+        let paddr = 0x2_0000_3000;
+        let gpaddr = 0x8000_3000;
+        assert_eq!(
+            mapped_alias(phys_encrypted(paddr), OFFSET, true, find),
+            gpaddr
+        );
+        assert_eq!(mapped_alias(paddr, OFFSET, false, find), gpaddr + OFFSET);
+
+        // Unknown memory (e.g. EPC) falls back to the linear mapping.
+        assert_eq!(mapped_alias(0x3000, OFFSET, true, find), 0x3000 + OFFSET);
+    }
+
+    /// Fake translation over `pages` pages at `base`, mapped in reverse order so an access
+    /// crossing a page boundary is not virtually contiguous.
+    fn reversed_pages(base: VirtAddr, pages: usize) -> impl Fn(PhysAddr) -> VirtAddr {
+        move |paddr| base + (pages - 1 - paddr / PAGE_SIZE) * PAGE_SIZE + paddr % PAGE_SIZE
+    }
+
+    #[test]
+    fn test_phys_copy_set() {
+        let mut mem = vec![0u8; PAGE_SIZE * 4];
+        let translate = reversed_pages(mem.as_mut_ptr() as usize, 4);
+        let read = |paddr| unsafe { *(translate(paddr) as *const u8) };
+
+        unsafe { phys_set_with(0x800, 0xaa, 0x1123, &translate) };
+        assert_eq!(read(0x7ff), 0);
+        assert!((0x800..0x1923).all(|paddr| read(paddr) == 0xaa));
+        assert_eq!(read(0x1923), 0);
+
+        unsafe { phys_set_with(0x1000, 0x55, 1, &translate) };
+        unsafe { phys_copy_with(0x2100, 0x800, 0x1123, &translate) };
+        assert!((0..0x1123).all(|off| read(0x2100 + off) == read(0x800 + off)));
+        assert_eq!(read(0x2100 + 0x800), 0x55);
+        assert_eq!(read(0x20ff), 0);
+        assert_eq!(read(0x3223), 0);
+
+        // Nothing is touched with a zero length.
+        unsafe { phys_copy_with(0, 0x800, 0, &translate) };
+        unsafe { phys_set_with(0, 0xff, 0, &translate) };
+        assert_eq!(read(0), 0);
+    }
+
+    #[test]
+    fn test_chunk_len() {
+        assert_eq!(chunk_len(0x1000, 0x3000), PAGE_SIZE);
+        assert_eq!(chunk_len(0x1ff0, 0x3000), 0x10);
+        assert_eq!(chunk_len(0x1ff0, 0x8), 0x8);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Architecture-independent description of page faults.
+
+use super::VirtAddr;
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A page fault decoded from the architectural fault syndrome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    /// The faulting virtual address.
+    pub addr: VirtAddr,
+    /// The kind of the faulting access.
+    pub access: FaultAccess,
+    /// Whether the translation was present, i.e. the fault is a permission violation.
+    pub present: bool,
+    /// Whether the access was made from user mode (CPL 3 on x86, a lower EL on ARM).
+    pub user: bool,
+}
+
+#[cfg(any(target_arch = "aarch64", test))]
+use self::arm::*;
+#[cfg(any(target_arch = "x86_64", test))]
+use self::x86::*;
+
+#[cfg(any(target_arch = "x86_64", test))]
+mod x86 {
+    /// x86 page fault error code bits.
+    pub const X86_PF_PRESENT: u64 = 1 << 0;
+    pub const X86_PF_WRITE: u64 = 1 << 1;
+    pub const X86_PF_USER: u64 = 1 << 2;
+    pub const X86_PF_INSTR: u64 = 1 << 4;
+}
+
+#[cfg(any(target_arch = "aarch64", test))]
+mod arm {
+    /// ARM exception classes of the instruction and data aborts, from a lower or the current EL.
+    pub const ESR_EC_SHIFT: u64 = 26;
+    pub const ESR_EC_IABT_LOWER: u64 = 0x20;
+    pub const ESR_EC_IABT_CUR: u64 = 0x21;
+    pub const ESR_EC_DABT_LOWER: u64 = 0x24;
+    pub const ESR_EC_DABT_CUR: u64 = 0x25;
+    /// Data abort ISS bit: Write not Read.
+    pub const ESR_ISS_WNR: u64 = 1 << 6;
+    /// The fault status code field and its fault types, without the level in the low 2 bits.
+    pub const ESR_ISS_FSC_MASK: u64 = 0x3f;
+    pub const FSC_TRANSLATION: u64 = 0b0001_00;
+    pub const FSC_ACCESS_FLAG: u64 = 0b0010_00;
+    pub const FSC_PERMISSION: u64 = 0b0011_00;
+}
+
+#[cfg(any(target_arch = "x86_64", test))]
+impl PageFault {
+    /// Decode an x86 #PF from its error code and `CR2`.
+    pub fn from_x86(err_code: u64, cr2: u64) -> Self {
+        let access = if err_code & X86_PF_INSTR != 0 {
+            FaultAccess::Execute
+        } else if err_code & X86_PF_WRITE != 0 {
+            FaultAccess::Write
+        } else {
+            FaultAccess::Read
+        };
+        Self {
+            addr: cr2 as _,
+            access,
+            present: err_code & X86_PF_PRESENT != 0,
+            user: err_code & X86_PF_USER != 0,
+        }
+    }
+}
+
+#[cfg(any(target_arch = "aarch64", test))]
+impl PageFault {
+    /// Decode an ARM instruction or data abort from `ESR_ELx` and `FAR_ELx`. Returns `None`
+    /// for other exception classes, and for aborts that aren't translation, access flag or
+    /// permission faults.
+    pub fn from_arm(esr: u64, far: u64) -> Option<Self> {
+        let ec = (esr >> ESR_EC_SHIFT) & 0x3f;
+        let access = match ec {
+            ESR_EC_IABT_LOWER | ESR_EC_IABT_CUR => FaultAccess::Execute,
+            ESR_EC_DABT_LOWER | ESR_EC_DABT_CUR if esr & ESR_ISS_WNR != 0 => FaultAccess::Write,
+            ESR_EC_DABT_LOWER | ESR_EC_DABT_CUR => FaultAccess::Read,
+            _ => return None,
+        };
+        // An access flag fault is reported for a valid descriptor, like a permission fault.
+        let present = match esr & ESR_ISS_FSC_MASK & !0b11 {
+            FSC_TRANSLATION => false,
+            FSC_ACCESS_FLAG | FSC_PERMISSION => true,
+            _ => return None,
+        };
+        Some(Self {
+            addr: far as _,
+            access,
+            present,
+            user: matches!(ec, ESR_EC_IABT_LOWER | ESR_EC_DABT_LOWER),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FaultAccess::*;
+
+    fn kind(pf: PageFault) -> (FaultAccess, bool, bool) {
+        (pf.access, pf.present, pf.user)
+    }
+
+    #[test]
+    fn test_from_x86() {
+        let fault = |err_code| PageFault::from_x86(err_code, 0xdead_b000);
+        // Kernel read of a non-present page.
+        let pf = fault(0);
+        assert_eq!(pf.addr, 0xdead_b000);
+        assert_eq!(kind(pf), (Read, false, false));
+        // User write to a read-only page.
+        let pf = fault(X86_PF_PRESENT | X86_PF_WRITE | X86_PF_USER);
+        assert_eq!(kind(pf), (Write, true, true));
+        // Kernel instruction fetch from an NX page.
+        let pf = fault(X86_PF_PRESENT | X86_PF_INSTR);
+        assert_eq!(kind(pf), (Execute, true, false));
+    }
+
+    #[test]
+    fn test_from_arm() {
+        let esr = |ec: u64, iss: u64| (ec << ESR_EC_SHIFT) | (1 << 25) | iss;
+        // Data abort from a lower EL, write, level 3 translation fault.
+        let pf = PageFault::from_arm(esr(0x24, ESR_ISS_WNR | 0b0001_11), 0x4000_1234).unwrap();
+        assert_eq!(pf.addr, 0x4000_1234);
+        assert_eq!(kind(pf), (Write, false, true));
+        // Data abort from the current EL, read, level 2 permission fault.
+        let pf = PageFault::from_arm(esr(0x25, 0b0011_10), 0x1000).unwrap();
+        assert_eq!(kind(pf), (Read, true, false));
+        // Instruction abort from a lower EL, level 3 access flag fault.
+        let pf = PageFault::from_arm(esr(0x20, 0b0010_11), 0x2000).unwrap();
+        assert_eq!(kind(pf), (Execute, true, true));
+
+        // An SVC and a synchronous external abort aren't page faults.
+        assert_eq!(PageFault::from_arm(esr(0x15, 0), 0), None);
+        assert_eq!(PageFault::from_arm(esr(0x24, 0b0100_00), 0), None);
+    }
+}
@@ -18,11 +18,12 @@ use bitmap_allocator::BitAlloc;
 
 use spin::Mutex;
 
-use super::addr::{align_down, align_up, is_aligned, phys_encrypted, phys_to_virt, PhysAddr};
+use super::addr::{align_down, align_up, is_aligned, phys_encrypted, phys_to_virt};
+use super::addr::{AddrRange, PhysAddr};
 use crate::config::HvSystemConfig;
-use crate::consts::{PAGE_SIZE, PER_CPU_SIZE};
+use crate::consts::PAGE_SIZE;
 use crate::error::HvResult;
-use crate::header::HvHeader;
+use crate::layout;
 use crate::memory::addr::virt_to_phys;
 use crate::memory::cmr::{CMRM_SIZE_ALIGNED, CMRM_START_HVA};
 use crate::memory::HV_HEAP_SIZE;
@@ -216,19 +217,22 @@ impl Drop for Frame {
     }
 }
 
-/// Initialize the physical frame allocator.
-pub(super) fn init() {
-    let header = HvHeader::get();
+/// Returns the physical range of the hypervisor memory managed by the frame allocator: what
+/// follows the hypervisor image (core, per-CPU data and config), the heap and the CMRM.
+pub(super) fn mem_pool_range() -> AddrRange {
     let sys_config = HvSystemConfig::get();
-    let used_size = header.core_size as usize
-        + header.max_cpus as usize * PER_CPU_SIZE
-        + sys_config.size()
-        + *HV_HEAP_SIZE
-        + *CMRM_SIZE_ALIGNED;
+    let used_size = layout::total_hv_size() + *HV_HEAP_SIZE + *CMRM_SIZE_ALIGNED;
 
     let mem_pool_start_vaddr = align_up(*CMRM_START_HVA + *CMRM_SIZE_ALIGNED);
-    let mem_pool_start_paddr = virt_to_phys(mem_pool_start_vaddr);
     let mem_pool_size = align_down(sys_config.hypervisor_memory.size as usize - used_size);
+    AddrRange::new(virt_to_phys(mem_pool_start_vaddr), mem_pool_size)
+}
+
+/// Initialize the physical frame allocator.
+pub(super) fn init() {
+    let pool = mem_pool_range();
+    let (mem_pool_start_paddr, mem_pool_size) = (pool.start, pool.size);
+    let mem_pool_start_vaddr = phys_to_virt(mem_pool_start_paddr);
 
     *FRAME_ALLOCATOR.lock() =
         FrameAllocator::new(phys_encrypted(mem_pool_start_paddr), mem_pool_size);
@@ -0,0 +1,280 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Physical memory usage bitmap seeded from the system configuration.
+
+use alloc::vec::Vec;
+
+use super::addr::{align_down, align_up, AddrRange, PhysAddr};
+use super::frame::mem_pool_range;
+use super::PAGE_SIZE;
+use crate::config::{HvRmrrRange, HvSystemConfig};
+use crate::error::HvResult;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Allocator of single physical frames.
+pub trait FrameAllocator {
+    /// Allocate a free frame, returns its physical address.
+    fn alloc_frame(&mut self) -> Option<PhysAddr>;
+    /// Give back the frame at `paddr`, fails if it's not allocated.
+    fn dealloc_frame(&mut self, paddr: PhysAddr) -> HvResult;
+}
+
+/// One bit per physical frame of the memory it covers, a set bit means the frame is used.
+pub struct FrameBitmap {
+    base: PhysAddr,
+    words: Vec<u64>,
+    frame_count: usize,
+}
+
+impl FrameBitmap {
+    /// Create a bitmap covering the whole frames of `mem` where only the frames in `free` are
+    /// free, except the ones that are also in `reserved`.
+    pub fn from_ranges(
+        mem: AddrRange,
+        free: impl IntoIterator<Item = AddrRange>,
+        reserved: impl IntoIterator<Item = AddrRange>,
+    ) -> Self {
+        let base = align_up(mem.start);
+        let frame_count = align_down(mem.end()).saturating_sub(base) / PAGE_SIZE;
+        let mut bitmap = Self {
+            base,
+            words: vec![u64::MAX; (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD],
+            frame_count,
+        };
+        for range in free {
+            // Only whole frames inside the range are free.
+            let start = align_up(range.start);
+            let end = align_down(range.end());
+            if start < end {
+                bitmap.mark_free(AddrRange::new(start, end - start));
+            }
+        }
+        for range in reserved {
+            bitmap.mark_used(range);
+        }
+        bitmap
+    }
+
+    /// Create the bitmap of the hypervisor memory in `config`. Only the frame pool after the
+    /// hypervisor image (core, per-CPU data and config), the heap and the CMRM is free, the
+    /// memory regions are all given to the guest. The RMRR ranges are marked as used, fails if
+    /// one of them is malformed.
+    pub fn from_config(config: &HvSystemConfig) -> HvResult<Self> {
+        let reserved = rmrr_ranges(config.rmrr_ranges())?;
+        Ok(Self::from_ranges(
+            config.hypervisor_region().0,
+            core::iter::once(mem_pool_range()),
+            reserved,
+        ))
+    }
+
+    /// Returns the number of frames covered by the bitmap.
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+
+    /// Returns the number of free frames.
+    pub fn free_count(&self) -> usize {
+        let used: usize = self.words.iter().map(|w| w.count_ones() as usize).sum();
+        // The padding bits of the last word are always set.
+        self.words.len() * BITS_PER_WORD - used
+    }
+
+    /// Returns whether the frame at `paddr` is used. Frames out of the bitmap are always used.
+    pub fn is_used(&self, paddr: PhysAddr) -> bool {
+        match self.index(paddr) {
+            Some(idx) => self.words[idx / BITS_PER_WORD] & Self::bit(idx) != 0,
+            None => true,
+        }
+    }
+
+    /// Mark all frames overlapping `range` as used.
+    pub fn mark_used(&mut self, range: AddrRange) {
+        for idx in self.frame_indexes(range) {
+            self.words[idx / BITS_PER_WORD] |= Self::bit(idx);
+        }
+    }
+
+    /// Mark all frames overlapping `range` as free.
+    pub fn mark_free(&mut self, range: AddrRange) {
+        for idx in self.frame_indexes(range) {
+            self.words[idx / BITS_PER_WORD] &= !Self::bit(idx);
+        }
+    }
+
+    /// Allocate the lowest free frame.
+    pub fn alloc(&mut self) -> Option<PhysAddr> {
+        let (i, word) = self
+            .words
+            .iter_mut()
+            .enumerate()
+            .find(|(_, word)| **word != u64::MAX)?;
+        let idx = i * BITS_PER_WORD + word.trailing_ones() as usize;
+        if idx >= self.frame_count {
+            return None;
+        }
+        *word |= Self::bit(idx);
+        Some(self.base + idx * PAGE_SIZE)
+    }
+
+    /// Free the frame at `paddr`, fails on a double free or an address out of the bitmap.
+    pub fn dealloc(&mut self, paddr: PhysAddr) -> HvResult {
+        if paddr % PAGE_SIZE != 0 || self.index(paddr).is_none() {
+            return hv_result_err!(EINVAL, format!("Invalid frame: {:#x}", paddr));
+        }
+        if !self.is_used(paddr) {
+            return hv_result_err!(EINVAL, format!("Double free of frame: {:#x}", paddr));
+        }
+        self.mark_free(AddrRange::new(paddr, PAGE_SIZE));
+        Ok(())
+    }
+
+    const fn bit(idx: usize) -> u64 {
+        1 << (idx % BITS_PER_WORD)
+    }
+
+    fn index(&self, paddr: PhysAddr) -> Option<usize> {
+        let idx = paddr.checked_sub(self.base)? / PAGE_SIZE;
+        if idx < self.frame_count {
+            Some(idx)
+        } else {
+            None
+        }
+    }
+
+    fn frame_indexes(&self, range: AddrRange) -> core::ops::Range<usize> {
+        let start = range.start.saturating_sub(self.base) / PAGE_SIZE;
+        let end = align_up(range.end()).saturating_sub(self.base) / PAGE_SIZE;
+        let end = end.min(self.frame_count);
+        start.min(end)..end
+    }
+}
+
+/// Returns the physical ranges of `rmrrs`, fails if a limit (which is inclusive) is below its
+/// base.
+fn rmrr_ranges(rmrrs: &[HvRmrrRange]) -> HvResult<Vec<AddrRange>> {
+    rmrrs
+        .iter()
+        .map(|r| {
+            let (base, limit) = (r.base as usize, r.limit as usize);
+            match limit.checked_sub(base) {
+                Some(size) => Ok(AddrRange::new(base, size + 1)),
+                None => hv_result_err!(
+                    EINVAL,
+                    format!("Malformed RMRR range: [{:#x}, {:#x}]", base, limit)
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Build the frame bitmap of `config` and check that every frame of the frame allocator pool is
+/// free in it, that is no RMRR range (which devices may access at any time) overlaps the pool.
+pub fn check_frame_pool(config: &HvSystemConfig) -> HvResult {
+    let bitmap = FrameBitmap::from_config(config)?;
+    let pool_frames = mem_pool_range().size / PAGE_SIZE;
+    info!(
+        "Frame bitmap covers {} frames, {} free",
+        bitmap.frame_count(),
+        bitmap.free_count()
+    );
+    if pool_frames == 0 || bitmap.free_count() != pool_frames {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "Hypervisor frame pool of {} frames is empty or overlaps an RMRR range",
+                pool_frames
+            )
+        );
+    }
+    Ok(())
+}
+
+impl FrameAllocator for FrameBitmap {
+    fn alloc_frame(&mut self) -> Option<PhysAddr> {
+        self.alloc()
+    }
+
+    fn dealloc_frame(&mut self, paddr: PhysAddr) -> HvResult {
+        self.dealloc(paddr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_skips_reserved() {
+        // Frames 1..=6 are RAM, frames 2 and 3 are an RMRR range.
+        let free = [AddrRange::new(0x1000, 0x6000)];
+        let rmrr = [AddrRange::new(0x2000, 0x2000)];
+        let mut bitmap = FrameBitmap::from_ranges(AddrRange::new(0, 0x10_0000), free, rmrr);
+
+        let mut allocated = Vec::new();
+        while let Some(paddr) = bitmap.alloc_frame() {
+            allocated.push(paddr);
+        }
+        assert_eq!(allocated, [0x1000, 0x4000, 0x5000, 0x6000]);
+        assert!(bitmap.is_used(0x2000) && bitmap.is_used(0x3000));
+        assert!(bitmap.is_used(0x10_0000));
+    }
+
+    #[test]
+    fn test_double_free() {
+        let mem = AddrRange::new(0, 0x8000);
+        let mut bitmap = FrameBitmap::from_ranges(mem, [mem], []);
+        let paddr = bitmap.alloc_frame().unwrap();
+        assert_eq!(paddr, 0);
+        assert!(bitmap.dealloc_frame(paddr).is_ok());
+        assert!(bitmap.dealloc_frame(paddr).is_err());
+        assert!(bitmap.dealloc_frame(0x8000).is_err());
+        assert!(bitmap.dealloc_frame(0x800).is_err());
+    }
+
+    #[test]
+    fn test_partial_frames_stay_used() {
+        let free = [AddrRange::new(0x1800, 0x2000)];
+        let mut bitmap = FrameBitmap::from_ranges(AddrRange::new(0, 0x10000), free, []);
+        assert_eq!(bitmap.alloc(), Some(0x2000));
+        assert_eq!(bitmap.alloc(), None);
+    }
+
+    #[test]
+    fn test_high_base() {
+        // Only the hypervisor memory is covered, not everything from address 0.
+        let mem = AddrRange::new(0x1_0000_0000, 0x10_0000);
+        let free = [AddrRange::new(0x1_0008_0000, 0x2000)];
+        let mut bitmap = FrameBitmap::from_ranges(mem, free, []);
+        assert_eq!(bitmap.frame_count(), 0x100);
+        assert_eq!(bitmap.free_count(), 2);
+        assert!(bitmap.is_used(0x8_0000));
+        assert_eq!(bitmap.alloc(), Some(0x1_0008_0000));
+        assert!(bitmap.dealloc(0x8_0000).is_err());
+        assert!(bitmap.dealloc(0x1_0008_0000).is_ok());
+        assert_eq!(bitmap.free_count(), 2);
+    }
+
+    #[test]
+    fn test_malformed_rmrr() {
+        let rmrr = |base, limit| HvRmrrRange { base, limit };
+        assert_eq!(
+            rmrr_ranges(&[rmrr(0x2000, 0x3fff)]).unwrap(),
+            [AddrRange::new(0x2000, 0x2000)]
+        );
+        assert!(rmrr_ranges(&[rmrr(0x2000, 0x3fff), rmrr(0x3000, 0x2fff)]).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reference counts of the frames mapped by a page table.
+//!
+//! A frame can be mapped at several addresses of a page table, and a table cloned with
+//! `clone()` maps it too, so it must not be freed while another mapping remains. The counts are
+//! kept per page table and shared with its clonees, which share its lower level tables: an
+//! unmap through any of them removes the mapping for all of them. Frames mapped by unrelated
+//! tables (e.g. the hypervisor linear mapping and a guest NPT) are counted separately.
+//!
+//! The counts only decide which unmapped frames are returned as free, they never prevent a
+//! frame from being wiped when it's unmapped.
+
+use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::vec::Vec;
+
+use super::{PageSize, PhysAddr};
+
+/// Number of mappings of each frame, keyed by the start address of the mapped page.
+pub struct FrameRefCount {
+    counts: BTreeMap<PhysAddr, usize>,
+}
+
+impl FrameRefCount {
+    pub fn new() -> Self {
+        Self {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of mappings of the frame at `paddr`.
+    pub fn count(&self, paddr: PhysAddr) -> usize {
+        self.counts.get(&paddr).copied().unwrap_or(0)
+    }
+
+    /// Record a new mapping of the frame at `paddr`.
+    pub fn inc(&mut self, paddr: PhysAddr) {
+        *self.counts.entry(paddr).or_insert(0) += 1;
+    }
+
+    /// Drop a mapping of the frame at `paddr`, returns true if it's no longer mapped and can be
+    /// freed. Frames that were never counted (e.g. mapped before the table was adopted with
+    /// `from_root()`) are not shared, so they can always be freed.
+    pub fn dec(&mut self, paddr: PhysAddr) -> bool {
+        match self.counts.entry(paddr) {
+            Entry::Occupied(mut e) => {
+                *e.get_mut() -= 1;
+                if *e.get() == 0 {
+                    e.remove();
+                    true
+                } else {
+                    false
+                }
+            }
+            Entry::Vacant(_) => true,
+        }
+    }
+
+    /// Drop a mapping of each of the unmapped `pages`, returns the ones that can be freed.
+    pub fn release(&mut self, pages: Vec<(PhysAddr, PageSize)>) -> Vec<(PhysAddr, PageSize)> {
+        pages
+            .into_iter()
+            .filter(|&(paddr, _)| self.dec(paddr))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inc_dec() {
+        let mut refs = FrameRefCount::new();
+        refs.inc(0x1000);
+        refs.inc(0x1000);
+        assert_eq!(refs.count(0x1000), 2);
+        assert!(!refs.dec(0x1000));
+        assert!(refs.dec(0x1000));
+        assert_eq!(refs.count(0x1000), 0);
+        // Untracked frames are not shared.
+        assert!(refs.dec(0x2000));
+    }
+
+    #[test]
+    fn test_release() {
+        use PageSize::*;
+
+        let mut refs = FrameRefCount::new();
+        // 0x1000 and 0x2000 are mapped twice, 0x20_0000 once.
+        for _ in 0..2 {
+            refs.inc(0x1000);
+            refs.inc(0x2000);
+        }
+        refs.inc(0x20_0000);
+
+        let first = vec![(0x1000, Size4K), (0x2000, Size4K), (0x20_0000, Size2M)];
+        assert_eq!(refs.release(first), [(0x20_0000, Size2M)]);
+        assert_eq!(refs.count(0x1000), 1);
+
+        let second = vec![(0x1000, Size4K), (0x2000, Size4K)];
+        assert_eq!(refs.release(second), [(0x1000, Size4K), (0x2000, Size4K)]);
+        assert_eq!(refs.count(0x1000) + refs.count(0x2000), 0);
+    }
+}
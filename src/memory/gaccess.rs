@@ -195,11 +195,8 @@ impl<T> GuestPtr<'_, T> {
         privilige_level: PrivilegeLevel,
         flags_required: MemFlags,
     ) -> HyperCallResult<(PhysAddr, PageSize)> {
-        let generate_pf = |is_present: bool| -> EnclaveExceptionInfo {
-            let mut error_code = PageFaultErrorCode::USER_MODE;
-            if is_present {
-                error_code |= PageFaultErrorCode::PROTECTION_VIOLATION;
-            }
+        let generate_pf = |cause: PageFaultErrorCode| -> EnclaveExceptionInfo {
+            let mut error_code = PageFaultErrorCode::USER_MODE | cause;
             if privilige_level == PrivilegeLevel::User {
                 error_code |= PageFaultErrorCode::USER_MODE;
             }
@@ -219,10 +216,19 @@ impl<T> GuestPtr<'_, T> {
                 Ok((gpaddr, mem_flags, pg_size)) => (gpaddr, mem_flags, pg_size),
                 Err(PagingError::NotMapped(_)) | Err(PagingError::NotPresent(_)) => {
                     return Err(hypercall_excep_err!(
-                        generate_pf(false),
+                        generate_pf(PageFaultErrorCode::empty()),
                         format!("GuestPtr::translate_to_gpa(): Cannot get gpaddr for gvaddr: {:#x?}, inject #PF", gvaddr)
                     ));
                 }
+                Err(PagingError::ReservedBits(level)) => {
+                    return Err(hypercall_excep_err!(
+                        generate_pf(
+                            PageFaultErrorCode::PROTECTION_VIOLATION
+                                | PageFaultErrorCode::MALFORMED_TABLE
+                        ),
+                        format!("GuestPtr::translate_to_gpa(): Reserved bits set at {:?} for gvaddr: {:#x?}, inject #PF", level, gvaddr)
+                    ));
+                }
                 Err(e) => return Err(HvError::from(e).into()),
             },
             PtrType::Secure(enclave) => {
@@ -232,7 +238,7 @@ impl<T> GuestPtr<'_, T> {
 
         if !pte_flags.contains(flags_required) {
             return Err(hypercall_excep_err!(
-                generate_pf(true),
+                generate_pf(PageFaultErrorCode::PROTECTION_VIOLATION),
                 format!("GuestPtr::translate_to_gpa(): Flags mismatch: flags in PTE: {:?}, flags must contain: {:?}", pte_flags, flags_required)
             ));
         }
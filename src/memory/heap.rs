@@ -16,9 +16,9 @@
 
 use buddy_system_allocator::LockedHeap;
 
-use crate::config::HvSystemConfig;
-use crate::consts::{HV_BASE, PER_CPU_SIZE};
+use crate::consts::HV_BASE;
 use crate::header::HvHeader;
+use crate::layout;
 use crate::memory::addr::{align_up, is_aligned, virt_to_phys};
 use crate::memory::HostVirtAddr;
 
@@ -40,16 +40,7 @@ pub(super) fn init() {
 }
 
 lazy_static! {
-    pub static ref HV_HEAP_START_HVA: HostVirtAddr = {
-        let header = HvHeader::get();
-        let sys_config = HvSystemConfig::get();
-        align_up(
-            HV_BASE as usize
-                + header.core_size as usize
-                + header.max_cpus as usize * PER_CPU_SIZE
-                + sys_config.size(),
-        )
-    };
+    pub static ref HV_HEAP_START_HVA: HostVirtAddr = align_up(HV_BASE + layout::total_hv_size());
     pub static ref HV_HEAP_SIZE: usize = {
         let hv_heap_size = HvHeader::get().hv_heap_size;
         if !is_aligned(hv_heap_size) {
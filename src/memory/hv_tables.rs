@@ -0,0 +1,241 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Host page table of the hypervisor's own view of the memory described by the configuration.
+
+use super::{check_null_guard, AddrRange, GenericPageTable, MemFlags, MemoryRegion, MemorySet};
+use super::{HostVirtAddr, PAGE_SIZE};
+use crate::config::{HvSystemConfig, RegionView};
+use crate::consts::HV_BASE;
+use crate::error::HvResult;
+
+/// Build the memory set of the memory described by `config`, with the largest pages possible:
+///
+/// - the hypervisor memory at `HV_BASE`: the `core_size` bytes of the hypervisor core, then its
+///   free memory after the `percpu_size` bytes of per-CPU areas, which each CPU maps itself.
+/// - the `DMA` regions in the linear mapping (see `phys_to_virt()`), and with SME also encrypted
+///   at their guest physical address (see `phys_to_virt_encrypted()`).
+///
+/// The configuration is validated first, and no mapping may cover the null guard page (see
+/// `check_null_guard()`). The devices are left to the caller.
+pub fn build_hypervisor_tables<PT>(
+    config: &HvSystemConfig,
+    core_size: usize,
+    percpu_size: usize,
+) -> HvResult<MemorySet<PT>>
+where
+    PT: GenericPageTable<VA = HostVirtAddr>,
+{
+    config.validate()?;
+    let (hv_region, _) = config.hypervisor_region();
+    build_tables(
+        hv_region,
+        (core_size, percpu_size),
+        &config.sorted_regions(),
+        config.phys_virt_offset(),
+        cfg!(feature = "sme"),
+    )
+}
+
+fn build_tables<PT>(
+    hv_region: AddrRange,
+    (core_size, percpu_size): (usize, usize),
+    sorted: &[RegionView],
+    offset: usize,
+    sme: bool,
+) -> HvResult<MemorySet<PT>>
+where
+    PT: GenericPageTable<VA = HostVirtAddr>,
+{
+    let mut hvm = MemorySet::new();
+    let mut insert = |vaddr: usize, paddr: usize, size: usize, flags: MemFlags| {
+        check_null_guard(vaddr, size, false)?;
+        hvm.insert(MemoryRegion::new_with_offset_mapper(
+            vaddr, paddr, size, flags,
+        ))
+    };
+
+    // hypervisor core
+    insert(
+        HV_BASE,
+        hv_region.start,
+        core_size,
+        MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE | MemFlags::ENCRYPTED,
+    )?;
+    // configurations & hypervisor free memory
+    let core_and_percpu_size = core_size + percpu_size;
+    insert(
+        HV_BASE + core_and_percpu_size,
+        hv_region.start + core_and_percpu_size,
+        hv_region.size - core_and_percpu_size,
+        MemFlags::READ | MemFlags::WRITE | MemFlags::ENCRYPTED,
+    )?;
+
+    for region in sorted.iter().filter(|r| r.flags.contains(MemFlags::DMA)) {
+        let (gpaddr, paddr, size) = (
+            region.virt_start as usize,
+            region.phys_start as usize,
+            region.size as usize,
+        );
+        let hv_virt_start = gpaddr.checked_add(offset).ok_or_else(|| {
+            hv_err!(
+                EINVAL,
+                format!("Guest physical address {:#x} is too large", gpaddr)
+            )
+        })?;
+        insert(hv_virt_start, paddr, size, MemFlags::READ | MemFlags::WRITE)?;
+        // Support hardware encrypt when swap out EPC page to guest RAM. The page at VA 0 is left
+        // unmapped as the null guard.
+        if sme {
+            let skip = if gpaddr == 0 { PAGE_SIZE } else { 0 };
+            if size > skip {
+                insert(
+                    gpaddr + skip,
+                    paddr + skip,
+                    size - skip,
+                    MemFlags::READ | MemFlags::WRITE | MemFlags::ENCRYPTED,
+                )?;
+            }
+        }
+    }
+    Ok(hvm)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::memory::{GenericPageTableImmut, PageSize, PagingError, PagingResult, PhysAddr};
+
+    /// Records the mapped regions instead of building a real page table.
+    struct MockPageTable(Vec<MemoryRegion<HostVirtAddr>>);
+
+    impl GenericPageTableImmut for MockPageTable {
+        type VA = HostVirtAddr;
+
+        unsafe fn from_root(_root_paddr: PhysAddr) -> Self {
+            Self::new()
+        }
+        fn root_paddr(&self) -> PhysAddr {
+            0
+        }
+        fn query(&self, vaddr: HostVirtAddr) -> PagingResult<(PhysAddr, MemFlags, PageSize)> {
+            self.0
+                .iter()
+                .find(|r| r.start <= vaddr && vaddr < r.start + r.size)
+                .map(|r| (r.mapper.map_fn(vaddr), r.flags, PageSize::Size4K))
+                .ok_or(PagingError::NotMapped(vaddr))
+        }
+    }
+
+    impl GenericPageTable for MockPageTable {
+        fn new() -> Self {
+            Self(Vec::new())
+        }
+        fn map(&mut self, region: &MemoryRegion<HostVirtAddr>) -> PagingResult {
+            self.0.push(region.clone());
+            Ok(())
+        }
+        fn unmap(
+            &mut self,
+            _region: &MemoryRegion<HostVirtAddr>,
+        ) -> PagingResult<Vec<(PhysAddr, PageSize)>> {
+            Ok(Vec::new())
+        }
+        fn update(&mut self, _region: &MemoryRegion<HostVirtAddr>) -> PagingResult {
+            unimplemented!()
+        }
+        fn clone(&self) -> Self {
+            unimplemented!()
+        }
+        unsafe fn activate(&self) {}
+        fn flush(&self, _vaddr: Option<HostVirtAddr>) {}
+    }
+
+    const HV_PHYS: usize = 0x1_0000_0000;
+    const OFFSET: usize = HV_BASE - HV_PHYS;
+    const CORE_SIZE: usize = 0x20_0000;
+    const PERCPU_SIZE: usize = 0x10_0000;
+
+    fn region(phys_start: u64, size: u64, flags: MemFlags) -> RegionView {
+        RegionView {
+            phys_start,
+            virt_start: phys_start,
+            size,
+            flags,
+        }
+    }
+
+    fn build(regions: &[RegionView], sme: bool) -> HvResult<MemorySet<MockPageTable>> {
+        let hv_region = AddrRange::new(HV_PHYS, 0x400_0000);
+        let sizes = (CORE_SIZE, PERCPU_SIZE);
+        build_tables(hv_region, sizes, regions, OFFSET, sme)
+    }
+
+    #[test]
+    fn test_build_tables() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let ram = rw | MemFlags::EXECUTE;
+        let regions = [
+            region(0x0, 0x9_f000, ram | MemFlags::DMA),
+            region(0x10_0000, 0x3ff0_0000, ram | MemFlags::DMA),
+            region(0xc000_0000, 0x100_0000, rw | MemFlags::IO),
+        ];
+        let hvm = build(&regions, true).unwrap();
+        let pt = hvm.page_table();
+
+        // The hypervisor core is executable, the per-CPU areas are left out.
+        let (paddr, flags, _) = pt.query(HV_BASE + 0x1000).unwrap();
+        assert_eq!(paddr, HV_PHYS + 0x1000);
+        assert!(flags.contains(MemFlags::EXECUTE | MemFlags::ENCRYPTED));
+        assert!(pt.query(HV_BASE + CORE_SIZE).is_err());
+        let (paddr, flags, _) = pt.query(HV_BASE + CORE_SIZE + PERCPU_SIZE).unwrap();
+        assert_eq!(paddr, HV_PHYS + CORE_SIZE + PERCPU_SIZE);
+        assert_eq!(flags, rw | MemFlags::ENCRYPTED);
+
+        // The DMA regions are in the linear mapping, and encrypted at their identity address
+        // except the null guard page. Other regions aren't mapped.
+        assert_eq!(
+            pt.query(OFFSET + 0x1234).unwrap(),
+            (0x1234, rw, PageSize::Size4K)
+        );
+        assert_eq!(pt.query(OFFSET + 0x3fff_ffff).unwrap().0, 0x3fff_ffff);
+        assert!(pt.query(OFFSET + 0x9_f000).is_err());
+        assert!(pt.query(OFFSET + 0xc000_0000).is_err());
+        assert!(pt.query(0).is_err());
+        let (paddr, flags, _) = pt.query(PAGE_SIZE).unwrap();
+        assert_eq!(paddr, PAGE_SIZE);
+        assert_eq!(flags, rw | MemFlags::ENCRYPTED);
+
+        let hvm = build(&regions, false).unwrap();
+        assert!(hvm.page_table().query(PAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_null_guard() {
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let dma = rw | MemFlags::DMA;
+        // Without a linear mapping offset, a DMA region at physical 0 covers VA 0.
+        let hv_region = AddrRange::new(HV_PHYS, 0x400_0000);
+        let sizes = (CORE_SIZE, PERCPU_SIZE);
+        let res: HvResult<MemorySet<MockPageTable>> =
+            build_tables(hv_region, sizes, &[region(0, 0x2000, dma)], 0, false);
+        assert!(res.is_err());
+        let regions = [region(PAGE_SIZE as _, 0x1000, dma)];
+        let hvm: MemorySet<MockPageTable> =
+            build_tables(hv_region, sizes, &regions, 0, false).unwrap();
+        assert_eq!(hvm.page_table().query(PAGE_SIZE).unwrap().0, PAGE_SIZE);
+    }
+}
@@ -14,6 +14,7 @@
 
 use super::addr::{align_down, phys_encrypted, virt_to_phys};
 use super::{AlignedPage2M, MemFlags, MemoryRegion, PhysAddr};
+use crate::consts::SME_C_BIT_OFFSET;
 
 static EMPTY_PAGE: AlignedPage2M = AlignedPage2M::new();
 
@@ -32,12 +33,25 @@ impl Mapper {
     }
 }
 
+/// Returns the physical address to map for `paddr`: with the C-bit set for `ENCRYPTED` regions,
+/// and always in plaintext for `COMM_REGION`s.
+pub(super) fn region_paddr(paddr: PhysAddr, flags: MemFlags) -> PhysAddr {
+    debug_assert!(
+        !flags.contains(MemFlags::COMM_REGION | MemFlags::ENCRYPTED),
+        "COMM_REGION can't be ENCRYPTED"
+    );
+    if flags.contains(MemFlags::ENCRYPTED) {
+        phys_encrypted(paddr)
+    } else if flags.contains(MemFlags::COMM_REGION) {
+        paddr & !SME_C_BIT_OFFSET
+    } else {
+        paddr
+    }
+}
+
 impl<VA: From<usize> + Into<usize> + Copy> MemoryRegion<VA> {
     pub fn new_with_empty_mapper(start: VA, size: usize, flags: MemFlags) -> Self {
-        let mut paddr = virt_to_phys(EMPTY_PAGE.as_ptr() as usize);
-        if flags.contains(MemFlags::ENCRYPTED) {
-            paddr = phys_encrypted(paddr);
-        }
+        let paddr = region_paddr(virt_to_phys(EMPTY_PAGE.as_ptr() as usize), flags);
         Self::new(start, size, flags, Mapper::Fixed(paddr))
     }
 
@@ -48,10 +62,7 @@ impl<VA: From<usize> + Into<usize> + Copy> MemoryRegion<VA> {
         flags: MemFlags,
     ) -> Self {
         let start_vaddr = align_down(start_vaddr.into());
-        let mut start_paddr = align_down(start_paddr);
-        if flags.contains(MemFlags::ENCRYPTED) {
-            start_paddr = phys_encrypted(start_paddr);
-        }
+        let start_paddr = region_paddr(align_down(start_paddr), flags);
         let phys_virt_offset = start_vaddr - start_paddr;
         Self::new(
             start_vaddr.into(),
@@ -18,9 +18,30 @@ use alloc::collections::btree_map::{BTreeMap, Entry};
 use core::fmt::{Debug, Formatter, Result};
 
 use super::addr::{align_down, align_up};
-use super::{mapper::Mapper, paging::GenericPageTable, MemFlags};
+use super::{mapper::Mapper, paging::GenericPageTable, MemFlags, PAGE_SIZE};
 use crate::error::HvResult;
 
+/// Check that `[start, start + size)` doesn't cover the page at virtual address 0, unless
+/// `allow_null` is set.
+///
+/// The page is left unmapped in the hypervisor and enclave page tables, so a null pointer
+/// dereference faults instead of silently reading or corrupting whatever is mapped there. A
+/// mapping over it is a bug in the caller, it's rejected when the tables are built rather than
+/// found later through its side effects.
+pub fn check_null_guard(start: usize, size: usize, allow_null: bool) -> HvResult {
+    if !allow_null && size != 0 && start < PAGE_SIZE {
+        return hv_result_err!(
+            EINVAL,
+            format!(
+                "Mapping [{:#x}, {:#x}) over the null guard page",
+                start,
+                start.wrapping_add(size)
+            )
+        );
+    }
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct MemoryRegion<VA> {
     pub start: VA,
@@ -175,3 +196,17 @@ where
         self.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_null_guard() {
+        assert!(check_null_guard(0, PAGE_SIZE, false).is_err());
+        assert!(check_null_guard(PAGE_SIZE - 1, 1, false).is_err());
+        assert!(check_null_guard(0, PAGE_SIZE, true).is_ok());
+        assert!(check_null_guard(PAGE_SIZE, PAGE_SIZE, false).is_ok());
+        assert!(check_null_guard(0, 0, false).is_ok());
+    }
+}
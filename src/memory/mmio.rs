@@ -14,8 +14,77 @@
 
 #![allow(dead_code)]
 
-use super::VirtAddr;
-use core::mem::MaybeUninit;
+use super::addr::{phys_to_virt, AddrRange};
+use super::{PhysAddr, VirtAddr};
+use crate::config::HvSystemConfig;
+use crate::header::HvHeader;
+use core::mem::{align_of, size_of, MaybeUninit};
+
+/// Read a `T` from the device register at `paddr`, through the mapping of the hypervisor.
+///
+/// # Safety
+///
+/// `paddr` must be a device register of the size of `T` and aligned to it. Panics if it's not
+/// in the device memory mapped by the hypervisor (the IOMMU units and the TPM MMIO).
+pub unsafe fn mmio_read<T>(paddr: PhysAddr) -> T {
+    read_at(device_vaddr(paddr, size_of::<T>()))
+}
+
+/// Write `value` to the device register at `paddr`, through the mapping of the hypervisor.
+///
+/// # Safety
+///
+/// See [`mmio_read()`].
+pub unsafe fn mmio_write<T>(paddr: PhysAddr, value: T) {
+    write_at(device_vaddr(paddr, size_of::<T>()), value)
+}
+
+/// Returns the virtual address of the device registers `[paddr, paddr + len)` in the host
+/// mapping built by `Cell::new_root()`: the IOMMU units are in the linear mapping, the TPM MMIO
+/// is identity mapped.
+fn device_vaddr(paddr: PhysAddr, len: usize) -> VirtAddr {
+    let header = HvHeader::get();
+    let iommus = HvSystemConfig::get()
+        .iommu_units()
+        .iter()
+        .map(|u| AddrRange::new(u.base as usize, u.size as usize));
+    let tpm = AddrRange::new(header.tpm_mmio_pa, header.tpm_mmio_size as usize);
+    match find_device_vaddr(paddr, len, iommus, Some(tpm), phys_to_virt) {
+        Some(vaddr) => vaddr,
+        None => panic!(
+            "MMIO access to {:#x} out of the mapped device memory",
+            paddr
+        ),
+    }
+}
+
+fn find_device_vaddr(
+    paddr: PhysAddr,
+    len: usize,
+    linear: impl IntoIterator<Item = AddrRange>,
+    identity: impl IntoIterator<Item = AddrRange>,
+    phys_to_virt: impl FnOnce(PhysAddr) -> VirtAddr,
+) -> Option<VirtAddr> {
+    let end = paddr.checked_add(len)?;
+    let within = |r: AddrRange| r.start <= paddr && end <= r.end();
+    if linear.into_iter().any(within) {
+        Some(phys_to_virt(paddr))
+    } else if identity.into_iter().any(within) {
+        Some(paddr)
+    } else {
+        None
+    }
+}
+
+unsafe fn read_at<T>(vaddr: VirtAddr) -> T {
+    debug_assert_eq!(vaddr % align_of::<T>(), 0);
+    core::ptr::read_volatile(vaddr as *const T)
+}
+
+unsafe fn write_at<T>(vaddr: VirtAddr, value: T) {
+    debug_assert_eq!(vaddr % align_of::<T>(), 0);
+    core::ptr::write_volatile(vaddr as *mut T, value)
+}
 
 #[repr(transparent)]
 pub struct Mmio<T> {
@@ -56,3 +125,43 @@ impl<T> Mmio<T> {
         self.value.as_ptr() as VirtAddr
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volatile_access() {
+        // A mock bank of 64-bit device registers.
+        let mut regs = [0u64; 4];
+        let base = regs.as_mut_ptr() as VirtAddr;
+        unsafe {
+            write_at::<u64>(base + 8, 0x1122_3344_5566_7788);
+            write_at::<u32>(base + 16, 0xdead_beef);
+            write_at::<u8>(base + 25, 0x5a);
+            assert_eq!(read_at::<u64>(base + 8), 0x1122_3344_5566_7788);
+            assert_eq!(read_at::<u32>(base + 12), 0x1122_3344);
+            assert_eq!(read_at::<u16>(base + 16), 0xbeef);
+        }
+        assert_eq!(regs, [0, 0x1122_3344_5566_7788, 0xdead_beef, 0x5a00]);
+    }
+
+    #[test]
+    fn test_find_device_vaddr() {
+        const OFFSET: usize = 0xffff_ff00_0000_0000;
+        let iommus = [
+            AddrRange::new(0xfed9_0000, 0x1000),
+            AddrRange::new(0xfed9_1000, 0x1000),
+        ];
+        let tpm = AddrRange::new(0xfed4_0000, 0x5000);
+        let find = |paddr, len| find_device_vaddr(paddr, len, iommus, Some(tpm), |p| p + OFFSET);
+
+        // IOMMU registers are linear mapped, the TPM MMIO is identity mapped.
+        assert_eq!(find(0xfed9_1ff8, 8), Some(0xfed9_1ff8 + OFFSET));
+        assert_eq!(find(0xfed4_0f00, 4), Some(0xfed4_0f00));
+        // Straddling the end of a unit, or out of any.
+        assert_eq!(find(0xfed9_1ffc, 8), None);
+        assert_eq!(find(0xfed4_5000, 4), None);
+        assert_eq!(find(usize::MAX, 8), None);
+    }
+}
@@ -14,13 +14,18 @@
 
 pub mod addr;
 pub mod cmr;
+mod fault;
 mod frame;
+mod frame_bitmap;
+mod frame_ref;
 pub mod gaccess;
 mod heap;
+mod hv_tables;
 mod mapper;
 mod mm;
 mod mmio;
 mod paging;
+pub mod root_hook;
 
 use crate::cell::ROOT_CELL;
 use crate::error::HvResult;
@@ -28,17 +33,23 @@ use core::ops::{Deref, DerefMut};
 
 use bitflags::bitflags;
 
-pub use addr::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr, PhysAddr, VirtAddr};
+pub use addr::{
+    AddrRange, GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr, PhysAddr, VirtAddr,
+};
+pub use fault::{FaultAccess, PageFault};
 pub use frame::Frame;
+pub use frame_bitmap::{check_frame_pool, FrameAllocator, FrameBitmap};
+pub use frame_ref::FrameRefCount;
 pub use heap::{HV_HEAP_SIZE, HV_HEAP_START_HVA};
-pub use mm::{MemoryRegion, MemorySet};
-pub use mmio::Mmio;
+pub use hv_tables::build_hypervisor_tables;
+pub use mm::{check_null_guard, MemoryRegion, MemorySet};
+pub use mmio::{mmio_read, mmio_write, Mmio};
 pub use paging::{EmptyPagingInstr, GenericPTE, PageSize, PageTableLevel, PagingInstr};
 pub use paging::{
     GenericPageTable, GenericPageTableImmut, GenericPageTableMut, Level4PageTable,
     Level4PageTableImmut, Level4PageTableUnlocked,
 };
-pub use paging::{PagingError, PagingResult};
+pub use paging::{PagingError, PagingResult, Stage2PageTable};
 
 pub const PAGE_SIZE: usize = paging::PageSize::Size4K as usize;
 
@@ -49,11 +60,38 @@ bitflags! {
         const EXECUTE       = 1 << 2;
         const DMA           = 1 << 3;
         const IO            = 1 << 4;
+        /// Memory shared with the untrusted host (e.g. the enclave communication region). It is
+        /// always mapped cacheable and in plaintext, so it can't be `ENCRYPTED`.
         const COMM_REGION   = 1 << 5;
         const NO_HUGEPAGES  = 1 << 8;
         const USER          = 1 << 9;
         const ENCRYPTED     = 1 << 10;
         const NO_PRESENT    = 1 << 11;
+        /// Write-combining memory, e.g. a framebuffer. Takes precedence over `IO`.
+        const WRITE_COMBINE = 1 << 12;
+    }
+}
+
+/// Caching policy of a mapping, derived from its `MemFlags`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// The default for regions without a cache flag.
+    WriteBack,
+    /// `WRITE_COMBINE` regions.
+    WriteCombining,
+    /// `IO` regions without `WRITE_COMBINE`.
+    Uncached,
+}
+
+impl CachePolicy {
+    pub fn from_flags(flags: MemFlags) -> Self {
+        if flags.contains(MemFlags::WRITE_COMBINE) {
+            Self::WriteCombining
+        } else if flags.contains(MemFlags::IO) {
+            Self::Uncached
+        } else {
+            Self::WriteBack
+        }
     }
 }
 
@@ -18,9 +18,11 @@ use core::{cmp::Ordering, convert::TryFrom, fmt::Debug, marker::PhantomData, sli
 use numeric_enum_macro::numeric_enum;
 use spin::Mutex;
 
-use super::addr::{phys_to_virt, PhysAddr};
-use super::{Frame, MemFlags, MemoryRegion, VirtAddr};
-use crate::error::{HvError, HvResult};
+use super::addr::{phys_to_virt, GuestPhysAddr, HostPhysAddr, PhysAddr};
+use super::mapper::{region_paddr, Mapper};
+use super::{Frame, FrameRefCount, MemFlags, MemoryRegion, VirtAddr, PAGE_SIZE};
+use crate::config::HvSystemConfig;
+use crate::error::{HvError, HvErrorNum, HvResult};
 use crate::header::MemRange;
 use crate::hypercall::error::HyperCallError;
 
@@ -35,25 +37,55 @@ pub enum PagingError {
     NotPresent((VirtAddr, PhysAddr, MemFlags, PageSize)),
     AlreadyMapped((VirtAddr, PhysAddr, MemFlags, PageSize)),
     MappedToHugePage((VirtAddr, PhysAddr, MemFlags, PageSize)),
+    /// The virtual address, physical address or size is not aligned to the huge page size.
+    MisalignedHugePage((VirtAddr, PhysAddr, usize, PageTableLevel)),
+    /// The requested permissions (the last flags) add some permissions to the current ones.
+    PermissionUpgrade((VirtAddr, MemFlags, MemFlags)),
+    /// The raw page attributes are refused by the architecture code, e.g. a page accessible from
+    /// user mode that is also executable at a privileged level.
+    InsecureAttr(u64),
+    /// A present entry at the given level has some reserved bits set, the hardware would fault
+    /// on it instead of following it.
+    ReservedBits(PageTableLevel),
+    /// A present table entry at the given level points back to a table of its own walk (the physical
+    /// address), see `check_self_reference()`.
+    SelfReference((PageTableLevel, PhysAddr)),
 }
 
 pub type PagingResult<T = ()> = Result<T, PagingError>;
 
+impl PagingError {
+    /// The errno of this error, once converted to `HvError` or `HyperCallError`.
+    pub fn errno(&self) -> HvErrorNum {
+        use HvErrorNum::*;
+        match self {
+            Self::NoMemory => ENOMEM,
+            Self::AlreadyMapped(_) => EEXIST,
+            Self::MisalignedHugePage(_) | Self::InsecureAttr(_) => EINVAL,
+            Self::PermissionUpgrade(_) => EPERM,
+            Self::UnexpectedError
+            | Self::NotMapped(_)
+            | Self::NotPresent(_)
+            | Self::MappedToHugePage(_)
+            | Self::ReservedBits(_)
+            | Self::SelfReference(_) => EFAULT,
+        }
+    }
+}
+
 impl From<PagingError> for HvError {
     fn from(err: PagingError) -> Self {
-        match err {
-            PagingError::NoMemory => hv_err!(ENOMEM),
-            _ => hv_err!(EFAULT, format!("{:?}", err)),
-        }
+        let msg = match err {
+            PagingError::NoMemory => None,
+            _ => Some(format!("{:?}", err)),
+        };
+        HvError::new(err.errno(), file!(), line!(), column!(), msg)
     }
 }
 
 impl From<PagingError> for HyperCallError {
     fn from(err: PagingError) -> Self {
-        match err {
-            PagingError::NoMemory => hv_err!(ENOMEM).into(),
-            _ => hv_err!(EFAULT, format!("{:?}", err)).into(),
-        }
+        HvError::from(err).into()
     }
 }
 
@@ -81,6 +113,36 @@ impl PageTableLevel {
         Self::L4 as usize
     }
 
+    /// Returns whether an entry of this level can map a page or a block.
+    pub const fn is_leaf_allowed(&self) -> bool {
+        matches!(
+            self,
+            PageTableLevel::L1 | PageTableLevel::L2 | PageTableLevel::L3
+        )
+    }
+
+    /// Size of the memory mapped by an entry of this level.
+    pub const fn entry_size(&self) -> usize {
+        match self {
+            PageTableLevel::L0 => 0,
+            _ => 1 << (12 + (*self as usize - 1) * 9),
+        }
+    }
+
+    /// Check that `vaddr`, `paddr` and `size` can be mapped by entries of this level, i.e.
+    /// this level supports leaf entries, and all of them are aligned to `entry_size()`.
+    pub fn validate_huge(&self, vaddr: VirtAddr, paddr: PhysAddr, size: usize) -> PagingResult {
+        if !self.is_leaf_allowed() {
+            error!("Leaf entry is not allowed in {:?}", self);
+            return Err(PagingError::UnexpectedError);
+        }
+        let mask = self.entry_size() - 1;
+        if size == 0 || (vaddr | paddr | size) & mask != 0 {
+            return Err(PagingError::MisalignedHugePage((vaddr, paddr, size, *self)));
+        }
+        Ok(())
+    }
+
     fn page_size(&self) -> PagingResult<PageSize> {
         match *self {
             PageTableLevel::L1 => Ok(PageSize::Size4K),
@@ -169,6 +231,10 @@ pub trait GenericPTE: Debug + Clone {
     fn addr(&self) -> PhysAddr;
     /// Returns the flags of this entry.
     fn flags(&self) -> MemFlags;
+    /// Returns the raw descriptor value of this entry.
+    fn raw(&self) -> u64;
+    /// Creates an entry from its raw descriptor value.
+    fn from_raw(raw: u64) -> Self;
     /// Returns whether this entry is zero.
     fn is_unused(&self) -> bool;
     /// Returns whether this entry flag indicates present.
@@ -184,6 +250,12 @@ pub trait GenericPTE: Debug + Clone {
     fn set_addr(&mut self, paddr: PhysAddr);
     /// Set flags for terminal entries.
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool) -> PagingResult;
+    /// Set physical address and flags for an empty terminal entry. The address carries the
+    /// C-bit for `ENCRYPTED` memory, and is always plaintext for a `COMM_REGION`.
+    fn set_leaf(&mut self, paddr: PhysAddr, flags: MemFlags, is_huge: bool) -> PagingResult {
+        self.set_addr(region_paddr(paddr, flags));
+        self.set_flags(flags, is_huge)
+    }
     /// Set physical address and flags for intermediate entry,
     /// `is_present` controls whether to setting its P bit.
     fn set_table(
@@ -198,15 +270,66 @@ pub trait GenericPTE: Debug + Clone {
     fn set_notpresent(&mut self) -> PagingResult;
     /// Set this entry to zero.
     fn clear(&mut self);
+    /// Check that none of the bits reserved at `level` is set in this present entry. Entries of a
+    /// guest-controlled table must pass it before the hypervisor follows them.
+    fn check_reserved(&self, _level: PageTableLevel) -> PagingResult {
+        Ok(())
+    }
+    /// Change this live entry mapping `vaddr` with `f`. Architectures requiring break-before-make
+    /// override it to invalidate the entry and flush its translation with `I` before `f`, by
+    /// default `f` is applied in place.
+    fn update_bbm<I: PagingInstr>(
+        &mut self,
+        _vaddr: VirtAddr,
+        f: impl FnOnce(&mut Self),
+    ) -> PagingResult {
+        f(self);
+        Ok(())
+    }
 }
 
 const ENTRY_COUNT: usize = 512;
 
 pub trait PagingInstr {
     unsafe fn activate(root_paddr: PhysAddr);
+    /// Synchronize the instruction stream after `activate()`, so that the following
+    /// instructions are fetched and executed with the new translation. The generic activation
+    /// code calls it right after `activate()`.
+    ///
+    /// ARM needs an explicit `isb` since system register writes are not context synchronizing,
+    /// while on x86 the `mov` to CR3 is itself serializing, so the default is a no-op.
+    fn post_activate_barrier() {}
     fn flush(vaddr: Option<VirtAddr>);
+    /// Invalidate the translations of `[start, start + size)`: page by page, or the whole TLB
+    /// if the range has more than `FLUSH_ALL_THRESHOLD` pages, where invalidating every page
+    /// costs more than refilling the TLB.
+    fn flush_range(start: VirtAddr, size: usize) {
+        if size / PAGE_SIZE > FLUSH_ALL_THRESHOLD {
+            return Self::flush(None);
+        }
+        let end = start + size;
+        let mut vaddr = start & !(PAGE_SIZE - 1);
+        while vaddr < end {
+            Self::flush(Some(vaddr));
+            vaddr += PAGE_SIZE;
+        }
+    }
+
+    /// The largest page size supported by the hardware.
+    fn max_page_size() -> PageSize {
+        PageSize::Size1G
+    }
+    /// Invalidate all cached translations tagged with `asid`. Must be called before a
+    /// recycled ASID is assigned to a new address space.
+    fn flush_asid(_asid: u16) {}
+    /// Invalidate all cached stage-1 and stage-2 translations tagged with `vmid`. Must be
+    /// called before a recycled VMID is assigned to a new guest.
+    fn flush_vmid(_vmid: u16) {}
 }
 
+/// Number of pages above which `PagingInstr::flush_range()` flushes the whole TLB.
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
 pub struct EmptyPagingInstr;
 
 impl PagingInstr for EmptyPagingInstr {
@@ -259,6 +382,18 @@ pub trait GenericPageTableMut<PTE: GenericPTE>: GenericPageTable {
     fn get_pte_mut(&mut self, vaddr: Self::VA) -> PagingResult<&mut PTE>;
 }
 
+/// A stage-2 page table (EPT, NPT) translating guest physical addresses.
+pub trait Stage2PageTable: GenericPageTableImmut<VA = GuestPhysAddr> {
+    /// Walk the stage-2 translation of `gpa`, returns the host physical address backing it (the
+    /// host frame plus the offset of `gpa` in it).
+    ///
+    /// Fails with `PagingError::NotMapped` if `gpa` is not mapped, see `query()` for the other
+    /// errors.
+    fn gpa_to_hpa(&self, gpa: GuestPhysAddr) -> PagingResult<HostPhysAddr> {
+        self.query(gpa).map(|(hpa, _, _)| hpa)
+    }
+}
+
 /// A immutable level-4 page table implements `GenericPageTableImmut`.
 pub struct Level4PageTableImmut<VA, PTE: GenericPTE> {
     /// Root table frame.
@@ -286,7 +421,8 @@ where
         use PageTableLevel::*;
 
         let vaddr = vaddr.into();
-        let p4 = table_of_mut::<PTE>(self.root_paddr());
+        let root = self.root_paddr();
+        let p4 = table_of_mut::<PTE>(root);
         let p4e = &mut p4[p4_index(vaddr)];
         if p4e.is_unused() {
             return Ok((p4e, L4));
@@ -294,6 +430,8 @@ where
             // Illegal case: PGD is not zero and but non-present.
             return Err(PagingError::UnexpectedError);
         }
+        p4e.check_reserved(L4)?;
+        check_self_reference(p4e, L4, &[root])?;
 
         let p3 = table_of_mut::<PTE>(p4e.addr());
         let p3e = &mut p3[p3_index(vaddr)];
@@ -303,6 +441,8 @@ where
             // Illegal case: PUD is not zero and but non-present.
             return Err(PagingError::UnexpectedError);
         }
+        p3e.check_reserved(L3)?;
+        check_self_reference(p3e, L3, &[root, p4e.addr()])?;
 
         let p2 = table_of_mut::<PTE>(p3e.addr());
         let p2e = &mut p2[p2_index(vaddr)];
@@ -312,12 +452,22 @@ where
             // Illegal case: PMD is not zero and but non-present.
             return Err(PagingError::UnexpectedError);
         }
+        p2e.check_reserved(L2)?;
+        check_self_reference(p2e, L2, &[root, p4e.addr(), p3e.addr()])?;
 
         let p1 = table_of_mut::<PTE>(p2e.addr());
         let p1e = &mut p1[p1_index(vaddr)];
         Ok((p1e, L1))
     }
 
+    /// Walk the page table and collect the entry of each level leading to `vaddr`, from the
+    /// top level down to the leaf entry, or to the first unused or non-present entry.
+    ///
+    /// The entries are copied, use their `Debug` output to see the raw descriptors.
+    pub fn walk_path(&self, vaddr: VA) -> PagingResult<Vec<(PageTableLevel, PTE)>> {
+        walk_path_in(table_of(self.root_paddr()), vaddr.into(), table_of)
+    }
+
     fn walk(
         &self,
         table: &[PTE],
@@ -358,7 +508,18 @@ where
         Ok(())
     }
 
-    fn dump(&self, limit: usize) -> PagingResult {
+    /// Check the structural integrity of the page table: present entries point to frames inside
+    /// the physical memory of the system configuration, blocks only appear in levels that
+    /// support them, and no entry points back to a table of its walk. Returns an error
+    /// describing the first violation.
+    pub fn self_check(&self) -> HvResult {
+        let phys_limit = HvSystemConfig::get().total_memory_size();
+        let mut path = vec![self.root_paddr()];
+        check_table(&mut path, PageTableLevel::L4, 0, phys_limit, &table_of)
+    }
+
+    /// Print the present entries of the page table, at most `limit` of them per table.
+    pub fn dump(&self, limit: usize) -> PagingResult {
         static LOCK: Mutex<()> = Mutex::new(());
         let _lock = LOCK.lock();
 
@@ -419,6 +580,7 @@ where
                 size,
             )));
         }
+        entry.check_reserved(level)?;
         let off = size.page_offset(vaddr.into());
         Ok((entry.addr() + off, entry.flags(), size))
     }
@@ -505,6 +667,22 @@ where
         entry.clear();
         Ok((paddr, size))
     }
+
+    /// Restrict the permissions of the page mapped at `vaddr` to the permissions in `new`, the
+    /// other flags are kept. Only downgrades are allowed, it fails with `PermissionUpgrade` if
+    /// `new` has any permission the page doesn't have.
+    ///
+    /// The TLB is not flushed.
+    pub fn restrict_perms(&mut self, vaddr: VA, new: MemFlags) -> PagingResult {
+        let (paddr, flags, size) = self.query(vaddr)?;
+        let flags = restricted_flags(vaddr.into(), flags, new)?;
+        self.update(&MemoryRegion::new(
+            size.align_down(vaddr.into()).into(),
+            size as usize,
+            flags,
+            Mapper::Fixed(size.align_down(paddr)),
+        ))
+    }
 }
 
 impl<VA, PTE, I> GenericPageTable for Level4PageTableUnlocked<VA, PTE, I>
@@ -524,23 +702,10 @@ where
     fn map(&mut self, region: &MemoryRegion<VA>) -> PagingResult {
         let mut vaddr = region.start.into();
         let mut size = region.size;
+        let max_page_size = I::max_page_size();
         while size > 0 {
             let paddr = region.mapper.map_fn(vaddr);
-            let page_size = if PageSize::Size1G.is_aligned(vaddr)
-                && PageSize::Size1G.is_aligned(paddr)
-                && size >= PageSize::Size1G as usize
-                && !region.flags.contains(MemFlags::NO_HUGEPAGES)
-            {
-                PageSize::Size1G
-            } else if PageSize::Size2M.is_aligned(vaddr)
-                && PageSize::Size2M.is_aligned(paddr)
-                && size >= PageSize::Size2M as usize
-                && !region.flags.contains(MemFlags::NO_HUGEPAGES)
-            {
-                PageSize::Size2M
-            } else {
-                PageSize::Size4K
-            };
+            let page_size = fit_page_size(vaddr, paddr, size, region.flags, max_page_size);
             let page = Page::new_aligned(vaddr.into(), page_size);
             let entry = self.get_empty_entry_mut_or_create(page).map_err(|e| {
                 match e {
@@ -559,8 +724,11 @@ where
                 }
                 e
             })?;
-            entry.set_addr(page.size.align_down(paddr));
-            entry.set_flags(region.flags, page_size.is_huge())?;
+            entry.set_leaf(
+                page.size.align_down(paddr),
+                region.flags,
+                page_size.is_huge(),
+            )?;
 
             vaddr += page_size as usize;
             size -= page_size as usize;
@@ -628,9 +796,14 @@ where
             Ordering::Equal => {}
         }
 
-        entry.set_addr(entry_size.align_down(paddr));
-        entry.set_flags(flags, entry_size.is_huge())?;
-        Ok(())
+        let mut res = Ok(());
+        entry.update_bbm::<I>(vaddr.into(), |entry| {
+            // Rewrite the whole entry: `set_addr()` keeps the C-bit of an encrypted entry, the
+            // new flags must decide it instead.
+            entry.clear();
+            res = entry.set_leaf(entry_size.align_down(paddr), flags, entry_size.is_huge());
+        })?;
+        res
     }
 
     fn clone(&self) -> Self {
@@ -638,7 +811,8 @@ where
     }
 
     unsafe fn activate(&self) {
-        I::activate(self.root_paddr())
+        I::activate(self.root_paddr());
+        I::post_activate_barrier();
     }
 
     fn flush(&self, vaddr: Option<Self::VA>) {
@@ -691,8 +865,9 @@ where
 /// racing between it and its clonees.
 pub struct Level4PageTable<VA, PTE: GenericPTE, I: PagingInstr> {
     inner: Level4PageTableUnlocked<VA, PTE, I>,
-    /// Make sure all accesses to the page table and its clonees is exclusive.
-    clonee_lock: Arc<Mutex<()>>,
+    /// Make sure all accesses to the page table and its clonees is exclusive. It guards the
+    /// reference counts of the frames they map, which they share (see `FrameRefCount`).
+    clonee_lock: Arc<Mutex<FrameRefCount>>,
 }
 
 impl<VA, PTE, I> Level4PageTable<VA, PTE, I>
@@ -706,6 +881,12 @@ where
         self.inner.inner.dump(limit)
     }
 
+    /// See [`Level4PageTableImmut::self_check`].
+    pub fn self_check(&self) -> HvResult {
+        let _lock = self.clonee_lock.lock();
+        self.inner.inner.self_check()
+    }
+
     /// Clone only the top level page table mapping from `src`.
     pub fn clone_from(src: &impl GenericPageTableImmut) -> Self {
         // XXX: The clonee won't track intermediate tables, must ensure it lives shorter than the
@@ -796,7 +977,7 @@ where
     unsafe fn from_root(root_paddr: PhysAddr) -> Self {
         Self {
             inner: Level4PageTableUnlocked::from_root(root_paddr),
-            clonee_lock: Arc::new(Mutex::new(())),
+            clonee_lock: Arc::new(Mutex::new(FrameRefCount::new())),
         }
     }
 
@@ -819,7 +1000,7 @@ where
     fn new() -> Self {
         Self {
             inner: Level4PageTableUnlocked::new(),
-            clonee_lock: Arc::new(Mutex::new(())),
+            clonee_lock: Arc::new(Mutex::new(FrameRefCount::new())),
         }
     }
 
@@ -829,23 +1010,32 @@ where
             core::any::type_name::<Self>(),
             region
         );
-        let _lock = self.clonee_lock.lock();
-        self.inner.map(region)
+        let mut refs = self.clonee_lock.lock();
+        self.inner.map(region)?;
+        let query = |vaddr: VirtAddr| self.inner.query(vaddr.into());
+        for paddr in mapped_pages(region.start.into(), region.size, query)? {
+            refs.inc(paddr);
+        }
+        Ok(())
     }
 
+    /// Unmap `region`, returns the unmapped pages that neither this page table nor its clonees
+    /// map anymore, so they can be freed.
     fn unmap(&mut self, region: &MemoryRegion<VA>) -> PagingResult<Vec<(PhysAddr, PageSize)>> {
         trace!(
             "destroy mapping in {}: {:#x?}",
             core::any::type_name::<Self>(),
             region
         );
-        let _lock = self.clonee_lock.lock();
-        self.inner.unmap(region)
+        let mut refs = self.clonee_lock.lock();
+        let pages = self.inner.unmap(region)?;
+        Ok(refs.release(pages))
     }
 
     fn clone(&self) -> Self {
         let mut pt = Self::clone_from(self);
-        // clone with lock to avoid data racing between it and its clonees.
+        // clone with lock to avoid data racing between it and its clonees, the clonee shares the
+        // mapped frames and their reference counts.
         pt.clonee_lock = self.clonee_lock.clone();
         pt
     }
@@ -859,9 +1049,176 @@ where
     }
 
     fn update(&mut self, region: &MemoryRegion<Self::VA>) -> PagingResult {
-        let _lock = self.clonee_lock.lock();
-        self.inner.update(region)
+        let mut refs = self.clonee_lock.lock();
+        let (old, _, page_size) = self.inner.query(region.start)?;
+        self.inner.update(region)?;
+        let (new, _, _) = self.inner.query(region.start)?;
+        let (old, new) = (page_size.align_down(old), page_size.align_down(new));
+        if old != new {
+            refs.dec(old);
+            refs.inc(new);
+        }
+        Ok(())
+    }
+}
+
+/// The largest page size, up to `max_page_size`, that can map `vaddr` to `paddr` and fits in
+/// `size`.
+fn fit_page_size(
+    vaddr: VirtAddr,
+    paddr: PhysAddr,
+    size: usize,
+    flags: MemFlags,
+    max_page_size: PageSize,
+) -> PageSize {
+    if flags.contains(MemFlags::NO_HUGEPAGES) {
+        return PageSize::Size4K;
     }
+    [PageSize::Size1G, PageSize::Size2M]
+        .iter()
+        .copied()
+        .filter(|&page_size| page_size as usize <= max_page_size as usize)
+        .find(|&page_size| {
+            PageTableLevel::from(page_size)
+                .validate_huge(vaddr, paddr, page_size.align_down(size))
+                .is_ok()
+        })
+        .unwrap_or(PageSize::Size4K)
+}
+
+/// Returns the start address of each page mapping `[vaddr, vaddr + size)`, found with `query`.
+fn mapped_pages(
+    vaddr: VirtAddr,
+    size: usize,
+    query: impl Fn(VirtAddr) -> PagingResult<(PhysAddr, MemFlags, PageSize)>,
+) -> PagingResult<Vec<PhysAddr>> {
+    let mut pages = Vec::new();
+    let end = vaddr + size;
+    let mut vaddr = vaddr;
+    while vaddr < end {
+        let (paddr, _, page_size) = query(vaddr)?;
+        pages.push(page_size.align_down(paddr));
+        vaddr = page_size.align_down(vaddr) + page_size as usize;
+    }
+    Ok(pages)
+}
+
+/// Returns `current` with its permissions replaced by the ones in `new`, which must be a subset of
+/// the current permissions.
+fn restricted_flags(vaddr: VirtAddr, current: MemFlags, new: MemFlags) -> PagingResult<MemFlags> {
+    let perms = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
+    if !(current & perms).contains(new & perms) {
+        return Err(PagingError::PermissionUpgrade((vaddr, current, new)));
+    }
+    Ok((current - perms) | (new & perms))
+}
+
+/// Fails with `SelfReference` if the present table `entry` of `level` points to one of the
+/// `tables` of its walk (the table containing it included).
+///
+/// Such a recursive mapping makes the page tables reachable as data: e.g. a top-level entry
+/// pointing to the root maps every table of the address space at a fixed virtual address, so
+/// whoever can access that range reads and rewrites the translations directly, bypassing the
+/// checks done on the mappings. Only the table entries are checked, as a leaf mapping a table
+/// frame is legitimate, e.g. the linear mapping of the memory maps every table frame.
+fn check_self_reference<PTE: GenericPTE>(
+    entry: &PTE,
+    level: PageTableLevel,
+    tables: &[PhysAddr],
+) -> PagingResult {
+    let is_table = level != PageTableLevel::L1 && !entry.is_leaf();
+    if entry.is_present() && is_table && tables.contains(&entry.addr()) {
+        return Err(PagingError::SelfReference((level, entry.addr())));
+    }
+    Ok(())
+}
+
+/// Check the entries of the last table of `path` (the table frames from the root), of `level`
+/// and mapping from `start_vaddr`, and of their subtables got by `table_of`. Every present entry
+/// must pass `check_reserved()` and `check_self_reference()`, and point to a frame aligned to its
+/// size below `phys_limit`, and leaf entries must be in a level allowing them.
+fn check_table<'a, PTE: GenericPTE + 'a>(
+    path: &mut Vec<PhysAddr>,
+    level: PageTableLevel,
+    start_vaddr: usize,
+    phys_limit: PhysAddr,
+    table_of: &impl Fn(PhysAddr) -> &'a [PTE],
+) -> HvResult {
+    let table = table_of(*path.last().unwrap());
+    for (i, entry) in table.iter().enumerate() {
+        if !entry.is_present() {
+            continue;
+        }
+        let mut vaddr = start_vaddr + i * level.entry_size();
+        if vaddr & (1 << 47) != 0 {
+            vaddr |= !((1 << 47) - 1);
+        }
+        let err = |msg: &str| {
+            hv_result_err!(
+                EFAULT,
+                format!("{:?} entry {:x?} for {:#x}: {}", level, entry, vaddr, msg)
+            )
+        };
+
+        if entry.check_reserved(level).is_err() {
+            return err("reserved bits set");
+        }
+        let is_leaf = level == PageTableLevel::L1 || entry.is_leaf();
+        if is_leaf && !level.is_leaf_allowed() {
+            return err("leaf entry in a level without blocks");
+        }
+        let frame_size = if is_leaf {
+            level.entry_size()
+        } else {
+            PAGE_SIZE
+        };
+        if entry.addr() % frame_size != 0 {
+            return err("misaligned frame");
+        }
+        if entry.addr() + frame_size > phys_limit {
+            return err("frame out of the physical memory");
+        }
+        if check_self_reference(entry, level, path).is_err() {
+            return err("recursive mapping of a table of the walk");
+        }
+        if !is_leaf {
+            path.push(entry.addr());
+            check_table(path, level.next_level()?, vaddr, phys_limit, table_of)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+/// See [`Level4PageTableImmut::walk_path()`], with the tables at `root` and got from their
+/// physical address with `table_of`.
+fn walk_path_in<'a, PTE: GenericPTE + 'a>(
+    root: &'a [PTE],
+    vaddr: usize,
+    table_of: impl Fn(PhysAddr) -> &'a [PTE],
+) -> PagingResult<Vec<(PageTableLevel, PTE)>> {
+    let mut path = Vec::with_capacity(PageTableLevel::max_level());
+    let mut level = PageTableLevel::L4;
+    let mut table = root;
+    loop {
+        let entry = &table[entry_index(vaddr, level)];
+        path.push((level, entry.clone()));
+        if entry.is_unused()
+            || !entry.is_present()
+            || entry.is_leaf()
+            || level == PageTableLevel::L1
+        {
+            break;
+        }
+        table = table_of(entry.addr());
+        level = level.next_level()?;
+    }
+    Ok(path)
+}
+
+/// Index of the entry translating `vaddr` in a table of `level`.
+const fn entry_index(vaddr: usize, level: PageTableLevel) -> usize {
+    (vaddr >> (12 + (level as usize - 1) * 9)) & (ENTRY_COUNT - 1)
 }
 
 const fn p4_index(vaddr: usize) -> usize {
@@ -929,3 +1286,447 @@ fn next_table_mut_or_create<'pt, 'vec, VA: From<usize> + Into<usize> + Copy, E:
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Activations and barriers of `BarrierPagingInstr`, in order.
+    static ACTIVATE_EVENTS: Mutex<Vec<(&str, PhysAddr)>> = Mutex::new(Vec::new());
+
+    /// Records the activations and the barriers following them.
+    struct BarrierPagingInstr;
+
+    impl PagingInstr for BarrierPagingInstr {
+        unsafe fn activate(root_paddr: PhysAddr) {
+            ACTIVATE_EVENTS.lock().push(("activate", root_paddr));
+        }
+        fn post_activate_barrier() {
+            ACTIVATE_EVENTS.lock().push(("barrier", 0));
+        }
+        fn flush(_vaddr: Option<VirtAddr>) {}
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestPTE {
+        paddr: PhysAddr,
+        flags: MemFlags,
+        huge: bool,
+        young: bool,
+    }
+
+    impl TestPTE {
+        const fn empty() -> Self {
+            Self {
+                paddr: 0,
+                flags: MemFlags::empty(),
+                huge: false,
+                young: false,
+            }
+        }
+
+        fn leaf(paddr: PhysAddr, flags: MemFlags) -> Self {
+            Self {
+                paddr,
+                flags,
+                huge: false,
+                young: false,
+            }
+        }
+    }
+
+    impl GenericPTE for TestPTE {
+        fn addr(&self) -> PhysAddr {
+            self.paddr
+        }
+        fn flags(&self) -> MemFlags {
+            self.flags
+        }
+        /// The address in bits 0..48, the flags from bit 48, the accessed bit in bit 62 and the
+        /// huge bit in bit 63.
+        fn raw(&self) -> u64 {
+            let bits = ((self.young as u64) << 62) | ((self.huge as u64) << 63);
+            self.paddr as u64 | (self.flags.bits() << 48) | bits
+        }
+        fn from_raw(raw: u64) -> Self {
+            Self {
+                paddr: (raw & ((1 << 48) - 1)) as _,
+                flags: MemFlags::from_bits_truncate(raw >> 48),
+                huge: raw & (1 << 63) != 0,
+                young: raw & (1 << 62) != 0,
+            }
+        }
+        fn is_unused(&self) -> bool {
+            self.paddr == 0 && self.flags.is_empty()
+        }
+        fn is_present(&self) -> bool {
+            !self.is_unused() && !self.flags.contains(MemFlags::NO_PRESENT)
+        }
+        fn is_leaf(&self) -> bool {
+            self.huge
+        }
+        fn is_young(&self) -> bool {
+            self.young
+        }
+        fn set_old(&mut self) {
+            self.young = false;
+        }
+        fn set_addr(&mut self, paddr: PhysAddr) {
+            self.paddr = paddr;
+        }
+        fn set_flags(&mut self, flags: MemFlags, is_huge: bool) -> PagingResult {
+            self.flags = flags;
+            self.huge = is_huge;
+            Ok(())
+        }
+        fn set_table(
+            &mut self,
+            paddr: PhysAddr,
+            _next_level: PageTableLevel,
+            is_present: bool,
+        ) -> PagingResult {
+            self.paddr = paddr;
+            self.flags = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
+            if !is_present {
+                self.flags |= MemFlags::NO_PRESENT;
+            }
+            self.huge = false;
+            Ok(())
+        }
+        fn set_present(&mut self) -> PagingResult {
+            self.flags.remove(MemFlags::NO_PRESENT);
+            Ok(())
+        }
+        fn set_notpresent(&mut self) -> PagingResult {
+            self.flags.insert(MemFlags::NO_PRESENT);
+            Ok(())
+        }
+        fn clear(&mut self) {
+            *self = Self::empty();
+        }
+    }
+
+    type TestPageTableImmut = Level4PageTableImmut<VirtAddr, TestPTE>;
+
+    #[test]
+    fn test_walk_path() {
+        use PageTableLevel::*;
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let table = |paddr, present| {
+            let mut e = TestPTE::empty();
+            e.set_table(paddr, L1, present).unwrap();
+            e
+        };
+        // The table at physical address `i * PAGE_SIZE` is `tables[i]`, the root is `tables[0]`.
+        let mut tables = vec![vec![TestPTE::empty(); ENTRY_COUNT]; 4];
+        tables[0][0] = table(0x1000, true);
+        tables[1][0] = table(0x2000, true);
+        tables[1][1] = TestPTE {
+            huge: true,
+            ..TestPTE::leaf(0x4000_0000, rw)
+        };
+        tables[2][0] = table(0x3000, true);
+        tables[2][1] = table(0x3000, false);
+        tables[3][1] = TestPTE::leaf(0x9000, rw);
+        let walk = |vaddr| {
+            let path =
+                walk_path_in(&tables[0], vaddr, |paddr| &tables[paddr / PAGE_SIZE][..]).unwrap();
+            path.into_iter()
+                .map(|(level, e)| (level, e.addr(), e.is_present()))
+                .collect::<Vec<_>>()
+        };
+
+        // Down to a present 4K page.
+        assert_eq!(
+            walk(0x1000),
+            [
+                (L4, 0x1000, true),
+                (L3, 0x2000, true),
+                (L2, 0x3000, true),
+                (L1, 0x9000, true)
+            ]
+        );
+        // Stops at the first unused or non-present entry, or at a huge page.
+        assert_eq!(walk(0x2000)[3], (L1, 0, false));
+        assert_eq!(
+            walk(0x20_0000),
+            [(L4, 0x1000, true), (L3, 0x2000, true), (L2, 0x3000, false)]
+        );
+        assert_eq!(
+            walk(0x4000_0000),
+            [(L4, 0x1000, true), (L3, 0x4000_0000, true)]
+        );
+        assert_eq!(walk(1 << 39), [(L4, 0, false)]);
+    }
+
+    #[test]
+    fn test_validate_huge() {
+        use PageTableLevel::*;
+
+        assert_eq!(L1.entry_size(), 0x1000);
+        assert_eq!(L2.entry_size(), 0x20_0000);
+        assert_eq!(L3.entry_size(), 0x4000_0000);
+
+        assert!(L1.validate_huge(0x1000, 0x2000, 0x3000).is_ok());
+        assert!(L2.validate_huge(0x20_0000, 0x40_0000, 0x40_0000).is_ok());
+        assert!(L3.validate_huge(0x4000_0000, 0, 0x4000_0000).is_ok());
+
+        let misaligned = |res| matches!(res, Err(PagingError::MisalignedHugePage(_)));
+        // Misaligned virtual address.
+        assert!(misaligned(L2.validate_huge(0x1000, 0x20_0000, 0x20_0000)));
+        // Misaligned physical address.
+        assert!(misaligned(L2.validate_huge(0x20_0000, 0x1000, 0x20_0000)));
+        // Misaligned or empty size.
+        assert!(misaligned(L2.validate_huge(0x20_0000, 0x20_0000, 0x1000)));
+        assert!(misaligned(L3.validate_huge(0, 0, 0x20_0000)));
+        assert!(misaligned(L2.validate_huge(0, 0, 0)));
+        // No leaf entry in the top level.
+        assert!(matches!(
+            L4.validate_huge(0, 0, 1 << 39),
+            Err(PagingError::UnexpectedError)
+        ));
+    }
+
+    #[test]
+    fn test_restricted_flags() {
+        let rwx = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
+        let current = rwx | MemFlags::USER | MemFlags::ENCRYPTED;
+
+        // Downgrades keep the non-permission flags.
+        let flags = restricted_flags(0x1000, current, MemFlags::READ).unwrap();
+        assert_eq!(flags, MemFlags::READ | MemFlags::USER | MemFlags::ENCRYPTED);
+        assert_eq!(restricted_flags(0x1000, current, rwx).unwrap(), current);
+
+        // Adding WRITE or EXECUTE is rejected.
+        let current = MemFlags::READ | MemFlags::USER;
+        for new in [MemFlags::WRITE, MemFlags::READ | MemFlags::EXECUTE] {
+            match restricted_flags(0x2000, current, new) {
+                Err(PagingError::PermissionUpgrade((0x2000, f, n))) => {
+                    assert_eq!((f, n), (current, new))
+                }
+                res => panic!("upgrade to {:?} accepted: {:?}", new, res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_paging_error_errno() {
+        use crate::hypercall::error::HyperCallErrorType;
+        use HvErrorNum::*;
+
+        let flags = MemFlags::READ;
+        let mapped = (0x1000, 0x2000, flags, PageSize::Size4K);
+        let misaligned = (0x1000, 0x2000, 0x1000, PageTableLevel::L2);
+        let cases = [
+            (PagingError::UnexpectedError, EFAULT),
+            (PagingError::NoMemory, ENOMEM),
+            (PagingError::NotMapped(0x1000), EFAULT),
+            (PagingError::NotPresent(mapped), EFAULT),
+            (PagingError::AlreadyMapped(mapped), EEXIST),
+            (PagingError::MappedToHugePage(mapped), EFAULT),
+            (PagingError::MisalignedHugePage(misaligned), EINVAL),
+            (PagingError::PermissionUpgrade((0, flags, flags)), EPERM),
+            (PagingError::InsecureAttr(0), EINVAL),
+            (PagingError::ReservedBits(PageTableLevel::L2), EFAULT),
+            (PagingError::SelfReference((PageTableLevel::L4, 0)), EFAULT),
+        ];
+        for (err, num) in cases {
+            assert_eq!(err.errno(), num, "{:?}", err);
+            assert_eq!(HvError::from(err).num(), num);
+        }
+        match HyperCallError::from(PagingError::AlreadyMapped(mapped)).error() {
+            HyperCallErrorType::HvError(num) => assert_eq!(*num, EEXIST),
+            _ => panic!("not an HvError"),
+        }
+    }
+
+    #[test]
+    fn test_shared_frame_refs() {
+        use PageSize::*;
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        // 0x1000..0x3000 maps the frames 0x8000 and 0x9000, 0x5000 maps 0x8000 again, and
+        // 0x20_0000..0x40_0000 is a 2M page at 0x40_0000.
+        let query = |vaddr: VirtAddr| match vaddr {
+            0x1000..=0x2fff => Ok((vaddr + 0x7000, rw, Size4K)),
+            0x5000..=0x5fff => Ok((vaddr + 0x3000, rw, Size4K)),
+            0x20_0000..=0x3f_ffff => Ok((vaddr + 0x20_0000, rw, Size2M)),
+            _ => Err(PagingError::NotMapped(vaddr)),
+        };
+        let pages = mapped_pages(0x1000, 0x2000, query).unwrap();
+        assert_eq!(pages, [0x8000, 0x9000]);
+        let pages = mapped_pages(0x20_0000, 0x20_0000, query).unwrap();
+        assert_eq!(pages, [0x40_0000]);
+        assert!(mapped_pages(0x3000, 0x1000, query).is_err());
+
+        let mut refs = FrameRefCount::new();
+        for (vaddr, size) in [(0x1000, 0x2000), (0x5000, 0x1000), (0x20_0000, 0x20_0000)] {
+            mapped_pages(vaddr, size, query)
+                .unwrap()
+                .into_iter()
+                .for_each(|paddr| refs.inc(paddr));
+        }
+        // Unmapping one of the two mappings of 0x8000 keeps it alive.
+        let freed = refs.release(vec![(0x8000, Size4K), (0x9000, Size4K)]);
+        assert_eq!(freed, [(0x9000, Size4K)]);
+        assert_eq!(refs.count(0x8000), 1);
+        // It's freed after the other one is unmapped too.
+        assert_eq!(refs.release(vec![(0x8000, Size4K)]), [(0x8000, Size4K)]);
+        assert_eq!(refs.count(0x40_0000), 1);
+    }
+
+    #[test]
+    fn test_fit_page_size() {
+        use PageSize::*;
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let giga = Size1G as usize;
+        assert_eq!(fit_page_size(giga, giga, giga, rw, Size1G), Size1G);
+        // No 1GB pages, fall back to 2MB.
+        assert_eq!(fit_page_size(giga, giga, giga, rw, Size2M), Size2M);
+        assert_eq!(fit_page_size(giga, giga, giga, rw, Size4K), Size4K);
+        assert_eq!(fit_page_size(0x20_0000, 0, giga, rw, Size1G), Size2M);
+        assert_eq!(fit_page_size(0x1000, 0x1000, giga, rw, Size1G), Size4K);
+        assert_eq!(
+            fit_page_size(giga, giga, giga, rw | MemFlags::NO_HUGEPAGES, Size1G),
+            Size4K
+        );
+    }
+
+    #[test]
+    fn test_activate_barrier() {
+        type Table = Level4PageTableUnlocked<VirtAddr, TestPTE, BarrierPagingInstr>;
+
+        let pt = unsafe { Table::from_root(0x8_0000) };
+        unsafe { pt.activate() };
+        let events = ACTIVATE_EVENTS.lock();
+        assert_eq!(*events, [("activate", 0x8_0000), ("barrier", 0)]);
+    }
+
+    #[test]
+    fn test_gpa_to_hpa() {
+        /// Maps 2M at GPA 0x20_0000 to HPA 0x1_0020_0000, and 4K at GPA 0 to HPA 0x5000.
+        struct SyntheticStage2;
+
+        impl GenericPageTableImmut for SyntheticStage2 {
+            type VA = GuestPhysAddr;
+
+            unsafe fn from_root(_root_paddr: PhysAddr) -> Self {
+                Self
+            }
+            fn root_paddr(&self) -> PhysAddr {
+                0
+            }
+            fn query(&self, gpa: GuestPhysAddr) -> PagingResult<(PhysAddr, MemFlags, PageSize)> {
+                let rw = MemFlags::READ | MemFlags::WRITE;
+                match gpa {
+                    0x20_0000..=0x3f_ffff => Ok((gpa + 0x1_0000_0000, rw, PageSize::Size2M)),
+                    0..=0xfff => Ok((gpa + 0x5000, rw, PageSize::Size4K)),
+                    _ => Err(PagingError::NotMapped(gpa)),
+                }
+            }
+        }
+
+        impl Stage2PageTable for SyntheticStage2 {}
+
+        let npt = SyntheticStage2;
+        assert_eq!(npt.gpa_to_hpa(0x20_0000).unwrap(), 0x1_0020_0000);
+        assert_eq!(npt.gpa_to_hpa(0x2f_1234).unwrap(), 0x1_002f_1234);
+        assert_eq!(npt.gpa_to_hpa(0x123).unwrap(), 0x5123);
+        assert!(matches!(
+            npt.gpa_to_hpa(0x1000),
+            Err(PagingError::NotMapped(0x1000))
+        ));
+    }
+
+    #[test]
+    fn test_check_table() {
+        use PageTableLevel::*;
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let phys_limit = 0x100_0000;
+        // Emulate the physical memory with tables, table `n` is at physical address `n << 12`.
+        let mut tables = vec![vec![TestPTE::empty(); ENTRY_COUNT]; 5];
+        tables[1][0].set_table(0x2000, L3, true).unwrap();
+        tables[2][0].set_table(0x3000, L2, true).unwrap();
+        tables[3][0].set_leaf(0x20_0000, rw, true).unwrap();
+        tables[3][1].set_table(0x4000, L1, true).unwrap();
+        tables[4][0] = TestPTE::leaf(0x5000, rw);
+        tables[4][1] = TestPTE::leaf(phys_limit - 0x1000, rw);
+        // Non-present entries are not checked.
+        tables[4][2] = TestPTE::leaf(phys_limit, rw | MemFlags::NO_PRESENT);
+
+        let check = |tables: &Vec<Vec<TestPTE>>| {
+            let table_of = |paddr: PhysAddr| &tables[paddr >> 12][..];
+            check_table(&mut vec![0x1000], L4, 0, phys_limit, &table_of)
+        };
+        assert!(check(&tables).is_ok());
+
+        // A frame out of the physical memory.
+        let mut corrupted = tables.clone();
+        corrupted[4][3] = TestPTE::leaf(phys_limit, rw);
+        assert!(check(&corrupted).is_err());
+
+        // A block in the top level.
+        let mut corrupted = tables.clone();
+        corrupted[1][1].set_leaf(0, rw, true).unwrap();
+        assert!(check(&corrupted).is_err());
+
+        // A 2M block not aligned to its size.
+        let mut corrupted = tables.clone();
+        corrupted[3][0].set_addr(0x20_1000);
+        assert!(check(&corrupted).is_err());
+
+        // A table entry pointing out of the physical memory.
+        let mut corrupted = tables.clone();
+        corrupted[2][0].set_addr(phys_limit);
+        assert!(check(&corrupted).is_err());
+
+        // A top-level entry pointing to the root itself.
+        let mut corrupted = tables.clone();
+        corrupted[1][0x1ff].set_table(0x1000, L3, true).unwrap();
+        assert!(check(&corrupted).is_err());
+
+        // A 4K page aliasing an ancestor table.
+        let mut corrupted = tables.clone();
+        corrupted[4][5] = TestPTE::leaf(0x2000, rw);
+        assert!(check(&corrupted).is_err());
+
+        // A 2M block containing the tables is fine.
+        let mut huge = tables;
+        huge[3][2].set_leaf(0, rw, true).unwrap();
+        assert!(check(&huge).is_ok());
+    }
+
+    #[test]
+    fn test_check_self_reference() {
+        use PageTableLevel::*;
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let mut table_entry = TestPTE::empty();
+        table_entry.set_table(0x1000, L3, true).unwrap();
+        let res = check_self_reference(&table_entry, L4, &[0x1000]);
+        assert!(matches!(res, Err(PagingError::SelfReference((L4, 0x1000)))));
+        assert!(check_self_reference(&table_entry, L4, &[0x2000]).is_ok());
+
+        // Leaves may map the tables of their own walk, and non-present entries are ignored.
+        let tables = [0x1000, 0x2000, 0x3000, 0x4000];
+        let mut block = TestPTE::empty();
+        block.set_leaf(0x2000, rw, true).unwrap();
+        assert!(check_self_reference(&block, L2, &tables).is_ok());
+        let mut absent = TestPTE::empty();
+        absent.set_table(0x3000, L1, false).unwrap();
+        assert!(check_self_reference(&absent, L2, &tables).is_ok());
+    }
+
+    #[test]
+    fn test_leaf_maps_own_table() {
+        use PageTableLevel::*;
+
+        // A 4K page mapping the L1 table holding its own entry, e.g. in the linear mapping.
+        let tables = [0x1000, 0x2000, 0x3000, 0x4000];
+        let page = TestPTE::leaf(0x4000, MemFlags::READ | MemFlags::WRITE);
+        assert!(check_self_reference(&page, L1, &tables).is_ok());
+    }
+}
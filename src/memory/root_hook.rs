@@ -0,0 +1,100 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Notification of guest page-table root switches (CR3 on x86, TTBR on ARM).
+//!
+//! The nested-paging layer registers a hook here to keep shadow or nested tables
+//! coherent when the guest switches address spaces.
+
+use spin::RwLock;
+
+/// Called with the old and new page-table root when the guest loads a new one.
+pub type RootWriteHook = fn(old: u64, new: u64);
+
+pub struct GuestRootHook {
+    hook: RwLock<Option<RootWriteHook>>,
+}
+
+impl GuestRootHook {
+    pub const fn new() -> Self {
+        Self {
+            hook: RwLock::new(None),
+        }
+    }
+
+    /// Install `hook`, returning the previously registered one. The guest root switches are
+    /// only trapped on the vCPUs set up after a hook is registered.
+    pub fn register(&self, hook: RootWriteHook) -> Option<RootWriteHook> {
+        self.hook.write().replace(hook)
+    }
+
+    pub fn unregister(&self) -> Option<RootWriteHook> {
+        self.hook.write().take()
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.hook.read().is_some()
+    }
+
+    pub fn notify(&self, old: u64, new: u64) {
+        // Copy the fn pointer out so the hook itself may re-register.
+        let hook = *self.hook.read();
+        if let Some(hook) = hook {
+            hook(old, new);
+        }
+    }
+}
+
+#[cfg(feature = "intel")]
+pub static GUEST_CR3_HOOK: GuestRootHook = GuestRootHook::new();
+#[cfg(target_arch = "aarch64")]
+pub static GUEST_TTBR_HOOK: GuestRootHook = GuestRootHook::new();
+
+/// Called from the MOV-to-CR3 exit handler, not when the hypervisor itself sets the guest CR3.
+#[cfg(feature = "intel")]
+pub fn on_guest_cr3_write(old_cr3: u64, new_cr3: u64) {
+    GUEST_CR3_HOOK.notify(old_cr3, new_cr3);
+}
+
+/// Called from the TTBR0_EL1/TTBR1_EL1 write trap (HCR_EL2.TVM) on ARM.
+#[cfg(target_arch = "aarch64")]
+pub fn on_guest_ttbr_write(old_ttbr: u64, new_ttbr: u64) {
+    GUEST_TTBR_HOOK.notify(old_ttbr, new_ttbr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spin::Mutex;
+
+    static RECORDED: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+    fn record(old: u64, new: u64) {
+        RECORDED.lock().push((old, new));
+    }
+
+    #[test]
+    fn test_hook_records_old_and_new() {
+        let hook = GuestRootHook::new();
+        hook.notify(0x1000, 0x2000); // nothing registered yet
+        assert!(!hook.is_registered());
+        assert!(hook.register(record).is_none());
+        assert!(hook.is_registered());
+        hook.notify(0x1000, 0x2000);
+        hook.notify(0x2000, 0x3000);
+        assert!(hook.unregister().is_some());
+        hook.notify(0x3000, 0x4000);
+        assert_eq!(*RECORDED.lock(), [(0x1000, 0x2000), (0x2000, 0x3000)]);
+    }
+}
@@ -15,18 +15,19 @@
 use alloc::sync::Arc;
 use core::fmt::{Debug, Formatter, Result};
 use core::mem::size_of;
-use core::sync::atomic::{AtomicIsize, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::arch::vmm::{Vcpu, VcpuAccessGuestState};
+use crate::arch::{cpu, topology};
 use crate::arch::{ExceptionType, HostPageTable, LinuxContext};
 use crate::cell::Cell;
 use crate::consts::{HV_STACK_SIZE, LOCAL_PER_CPU_BASE};
+use crate::cpumask;
 use crate::enclave::epcm::EpcmManager;
 use crate::enclave::{sgx::MiscSgx, AexException, Enclave, EnclaveStatsId, EnclaveThread};
 use crate::error::HvResult;
-use crate::ffi::PER_CPU_ARRAY_PTR;
-use crate::header::HvHeader;
 use crate::hypercall::error::HyperCallResult;
+use crate::layout;
 use crate::logging;
 use crate::memory::addr::{virt_to_phys, GuestVirtAddr};
 use crate::memory::{GenericPageTable, MemFlags, MemoryRegion, MemorySet};
@@ -34,7 +35,28 @@ use crate::stats::Instant;
 
 pub const PER_CPU_SIZE: usize = size_of::<PerCpu>();
 
-static ACTIVATED_CPUS: AtomicIsize = AtomicIsize::new(0);
+/// `cpu::id()` of the boot CPU, `NO_BOOT_CPU` until `init_boot_cpu()` is called.
+static BOOT_CPU_ID: AtomicUsize = AtomicUsize::new(NO_BOOT_CPU);
+const NO_BOOT_CPU: usize = usize::MAX;
+
+/// Record the current CPU as the boot CPU, only the first call takes effect.
+pub fn init_boot_cpu() {
+    record_boot_cpu(&BOOT_CPU_ID, cpu::id());
+}
+
+/// Returns whether the current CPU is the one that called `init_boot_cpu()` first. The boot CPU
+/// is not assumed to have id 0.
+pub fn is_boot_cpu() -> bool {
+    is_boot_cpu_id(&BOOT_CPU_ID, cpu::id())
+}
+
+fn record_boot_cpu(boot_cpu: &AtomicUsize, id: usize) {
+    let _ = boot_cpu.compare_exchange(NO_BOOT_CPU, id, Ordering::AcqRel, Ordering::Acquire);
+}
+
+fn is_boot_cpu_id(boot_cpu: &AtomicUsize, id: usize) -> bool {
+    boot_cpu.load(Ordering::Acquire) == id
+}
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum CpuState {
@@ -56,18 +78,17 @@ pub struct PerCpu {
 
 impl PerCpu {
     pub fn from_id<'a>(cpu_id: usize) -> &'a Self {
-        unsafe {
-            &core::slice::from_raw_parts(PER_CPU_ARRAY_PTR, HvHeader::get().max_cpus as usize)
-                [cpu_id]
-        }
+        unsafe { &*(Self::base_of(cpu_id) as *const Self) }
     }
 
     pub fn from_id_mut<'a>(cpu_id: usize) -> &'a mut Self {
-        unsafe {
-            &mut core::slice::from_raw_parts_mut(
-                PER_CPU_ARRAY_PTR,
-                HvHeader::get().max_cpus as usize,
-            )[cpu_id]
+        unsafe { &mut *(Self::base_of(cpu_id) as *mut Self) }
+    }
+
+    fn base_of(cpu_id: usize) -> usize {
+        match layout::percpu_base(cpu_id) {
+            Some(base) => base,
+            None => panic!("Invalid CPU id: {}", cpu_id),
         }
     }
 
@@ -84,15 +105,20 @@ impl PerCpu {
     }
 
     pub fn activated_cpus() -> usize {
-        ACTIVATED_CPUS.load(Ordering::Acquire) as _
+        cpumask::online_cpus().iter().count()
     }
 
     pub fn init(&mut self, cpu_id: usize, linux_sp: usize, cell: &Cell) -> HvResult {
-        info!("CPU {} init...", cpu_id);
+        info!(
+            "CPU {} init, {:?}...",
+            cpu_id,
+            topology::topology(cpu::id())
+        );
 
         self.cpu_id = cpu_id;
         self.state = CpuState::HvDisabled;
-        self.linux = LinuxContext::load_from(linux_sp);
+        self.linux = LinuxContext::load_from(linux_sp)?;
+        crate::arch::cpu::calibrate_tsc();
 
         let mut hvm = cell.hvm.clone();
         let vaddr = self as *const _ as usize;
@@ -123,9 +149,9 @@ impl PerCpu {
         Ok(())
     }
 
-    pub fn return_to_linux(&self) {
+    pub fn return_to_linux(&self) -> HvResult {
         logging::hhbox_disable();
-        self.linux.restore();
+        self.linux.restore()
     }
 
     #[inline(never)]
@@ -137,15 +163,16 @@ impl PerCpu {
     #[inline(never)]
     fn deactivate_vmm_common(&mut self) -> HvResult {
         self.vcpu.exit(&mut self.linux)?;
-        self.return_to_linux();
+        self.return_to_linux()?;
         self.state = CpuState::HvDisabled;
-        self.vcpu.deactivate_vmm(&self.linux)?;
+        self.vcpu.deactivate_vmm(&mut self.linux)?;
         unreachable!()
     }
 
     pub fn activate_vmm(&mut self) -> HvResult {
         println!("Activating hypervisor on CPU {}...", self.cpu_id);
-        ACTIVATED_CPUS.fetch_add(1, Ordering::SeqCst);
+        // The only fallible step goes first, so nothing is left to roll back when it fails.
+        cpumask::set_online(self.cpu_id)?;
         logging::set_vmm_state(self.cpu_id, 1);
 
         let local_cpu_data = Self::from_local_base_mut();
@@ -159,7 +186,7 @@ impl PerCpu {
 
     pub fn deactivate_vmm(&mut self, ret_code: usize) -> HvResult {
         println!("Deactivating hypervisor on CPU {}...", self.cpu_id);
-        ACTIVATED_CPUS.fetch_add(-1, Ordering::SeqCst);
+        cpumask::set_offline(self.cpu_id)?;
         logging::set_vmm_state(self.cpu_id, 0);
 
         self.vcpu.set_return_val(ret_code);
@@ -331,3 +358,23 @@ impl Debug for PerCpu {
         res.field("enclave_thread", &self.enclave_thread).finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_cpu() {
+        let boot_cpu = AtomicUsize::new(NO_BOOT_CPU);
+        assert!(!is_boot_cpu_id(&boot_cpu, 0));
+
+        // The boot CPU needs not to be CPU 0, later callers don't override it.
+        record_boot_cpu(&boot_cpu, 3);
+        record_boot_cpu(&boot_cpu, 0);
+        record_boot_cpu(&boot_cpu, 5);
+        assert!(is_boot_cpu_id(&boot_cpu, 3));
+        for id in [0, 1, 5, NO_BOOT_CPU] {
+            assert!(!is_boot_cpu_id(&boot_cpu, id));
+        }
+    }
+}
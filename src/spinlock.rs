@@ -0,0 +1,128 @@
+// Copyright (C) 2023 Ant Group CO., Ltd. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A spin lock that detects re-entrant locking on the same CPU.
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::{Mutex, MutexGuard};
+
+const NO_OWNER: usize = usize::MAX;
+
+#[cfg(not(test))]
+fn current_cpu() -> usize {
+    crate::arch::cpu::id()
+}
+
+/// Test threads may migrate between CPUs, treat them all as the same one.
+#[cfg(test)]
+fn current_cpu() -> usize {
+    0
+}
+
+pub struct SpinLock<T> {
+    inner: Mutex<T>,
+    /// The CPU holding the lock, or `NO_OWNER`.
+    owner: AtomicUsize,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    guard: MutexGuard<'a, T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: Mutex::new(data),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    /// Spin until the lock is acquired.
+    ///
+    /// Panics if the lock is already held by the current CPU, which would otherwise spin
+    /// forever (e.g. locking again in an exception handler). The owner is only checked when
+    /// the lock is contended.
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        if let Some(guard) = self.try_lock() {
+            return guard;
+        }
+        let cpu_id = current_cpu();
+        assert_ne!(
+            self.owner.load(Ordering::Acquire),
+            cpu_id,
+            "SpinLock: re-entrant lock on CPU {}",
+            cpu_id
+        );
+        self.guard(self.inner.lock())
+    }
+
+    /// Try to acquire the lock once, returns `None` if it's held.
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        self.inner.try_lock().map(|guard| self.guard(guard))
+    }
+
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, T>) -> SpinLockGuard<'a, T> {
+        self.owner.store(current_cpu(), Ordering::Release);
+        SpinLockGuard { lock: self, guard }
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // Runs before `guard` is dropped, i.e. while the lock is still held.
+        self.lock.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_unlock() {
+        let lock = SpinLock::new(1);
+        {
+            let mut guard = lock.lock();
+            *guard += 1;
+            assert!(lock.try_lock().is_none());
+        }
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+        assert_eq!(*lock.lock(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "re-entrant lock")]
+    fn test_double_lock_same_cpu() {
+        let lock = SpinLock::new(());
+        let _guard = lock.lock();
+        let _again = lock.lock();
+    }
+}